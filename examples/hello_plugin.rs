@@ -0,0 +1,37 @@
+// Minimal shell plugin: adds a `hello` builtin that greets its arguments.
+//
+//     $ cargo build --example hello_plugin
+//     $ load_plugin target/debug/examples/libhello_plugin.so
+//     $ hello world
+//     hello, world!
+//
+// The ABI types come straight from the shell's own definition.
+
+#[path = "../src/plugin_api.rs"]
+pub mod plugin_api;
+
+use plugin_api::{PluginIo, ShellPluginContext, PLUGIN_STDOUT};
+use std::ffi::{c_char, c_int, CStr};
+
+extern "C" fn hello(io: *const PluginIo, argc: c_int, argv: *const *const c_char) -> c_int {
+    let Some(io) = (unsafe { io.as_ref() }) else { return 1 };
+
+    let mut names = Vec::new();
+    for i in 1..argc as usize {
+        let arg = unsafe { CStr::from_ptr(*argv.add(i)) };
+        names.push(arg.to_string_lossy().into_owned());
+    }
+    let who = if names.is_empty() { "world".to_string() } else { names.join(" ") };
+
+    let msg = format!("hello, {who}!\n");
+    (io.write)(io.sink, PLUGIN_STDOUT, msg.as_ptr(), msg.len());
+    0
+}
+
+/// # Safety
+/// Called by the shell with a valid context.
+#[no_mangle]
+pub unsafe extern "C" fn shell_plugin_init(ctx: *mut ShellPluginContext) -> c_int {
+    let Some(shell) = ctx.as_ref() else { return 1 };
+    (shell.register_builtin)(ctx, c"hello".as_ptr(), hello)
+}
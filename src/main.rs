@@ -1,28 +1,40 @@
-#[allow(unused_imports)]
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, Read, Write};
+use std::io::{self, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
+#[cfg(not(unix))]
 use is_executable::IsExecutable;
+use serde::Deserialize;
+
+mod platform;
+mod plugin_api;
+mod ssh_hosts;
+
+use plugin_api::{
+    PluginBuiltinFn, PluginCandidates, PluginCompletionFn, PluginIo, PluginPrecmdFn,
+    PluginPreexecFn, ShellPluginContext, ShellPluginInitFn,
+};
 
 // ---------- rustyline ----------
 use rustyline::completion::{Completer, Pair};
-use rustyline::config::{CompletionType, Config};
+use rustyline::config::{CompletionType, Config, Configurer, EditMode};
 use rustyline::error::ReadlineError;
-use rustyline::highlight::Highlighter;
+use rustyline::highlight::{CmdKind, Highlighter};
 use rustyline::hint::Hinter;
-use rustyline::history::DefaultHistory;
-use rustyline::validate::Validator;
-use rustyline::{Context, Editor, Helper};
+use rustyline::history::{DefaultHistory, History, SearchDirection};
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Cmd, Context, Editor, ExternalPrinter, Helper, KeyCode, KeyEvent, Modifiers};
 
 #[cfg(unix)]
-use std::os::unix::io::FromRawFd;
-
-// ---------- libc pipe ----------
-use libc;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 
 // ---------------- Redirect enums ----------------
 #[derive(Debug, Clone)]
@@ -30,6 +42,8 @@ enum StdoutRedirect {
     Inherit,
     Truncate(String),
     Append(String),
+    // `>&N`
+    Fd(i32),
 }
 
 #[derive(Debug, Clone)]
@@ -37,6 +51,8 @@ enum StderrRedirect {
     Inherit,
     Truncate(String),
     Append(String),
+    // `2>&N`
+    Fd(i32),
 }
 
 // State for "<TAB><TAB>" listing behavior when ambiguous and no further LCP progress
@@ -44,19 +60,422 @@ enum StderrRedirect {
 struct CompletionState {
     last_prefix: Option<String>,
     armed_for_list: bool,
+    // After a listing with a history section: what was typed before the
+    // cursor, the listed commands, and how many Tabs have stepped into them
+    history_cycle: Option<(String, Vec<String>, usize)>,
+}
+
+// ---------- completion candidates ----------
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum CandidateKind {
+    // Command names, subcommands, flags, hosts: finished with a space
+    Word,
+    File,
+    // Finished with `/` so the next Tab continues inside it
+    Directory,
+    // Ends in `=` (`NAME=`): the value is typed right after it
+    Assignment,
+    // A variable name after `${`, closed with `}`
+    BracedName,
+    // `host:` after scp: the remote path is typed right after it
+    RemoteHost,
+    // From a `complete -o nospace` spec: nothing goes after it
+    Bare,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Candidate {
+    text: String,
+    kind: CandidateKind,
+    // Shown next to the text in listings (a job's command line)
+    description: Option<String>,
+}
+
+impl Candidate {
+    fn new(text: String, kind: CandidateKind) -> Self {
+        Self { text, kind, description: None }
+    }
+
+    fn word(text: String) -> Self {
+        // Spec functions can describe a candidate after a tab
+        if let Some((text, description)) = text.split_once('\t') {
+            let mut candidate = Self::word(text.to_string());
+            candidate.description = Some(description.to_string());
+            return candidate;
+        }
+        // and mark directories (e.g. remote paths) with a trailing `/`
+        match text.strip_suffix('/') {
+            Some(dir) if !dir.is_empty() => Self::new(dir.to_string(), CandidateKind::Directory),
+            _ if text.len() > 1 && text.ends_with('=') => Self::new(text, CandidateKind::Assignment),
+            _ if text.len() > 1 && text.ends_with(':') => Self::new(text, CandidateKind::RemoteHost),
+            _ => Self::new(text, CandidateKind::Word),
+        }
+    }
+
+    // How a listing shows it
+    fn listed(&self) -> String {
+        match &self.description {
+            Some(description) => format!("{}  {}", display_str(&self.text), display_str(description)),
+            None => display_str(&self.text),
+        }
+    }
+
+    // What goes after the completed word
+    fn suffix(&self) -> &'static str {
+        match self.kind {
+            CandidateKind::Directory => "/",
+            CandidateKind::Assignment | CandidateKind::RemoteHost | CandidateKind::Bare => "",
+            CandidateKind::BracedName => "}",
+            CandidateKind::Word | CandidateKind::File => " ",
+        }
+    }
+}
+
+// ---------- completion specs ----------
+// Argument completion for a specific command: gets the words before the cursor
+// (words[0] is the command name) and the partial word being completed.
+type CompletionFn = fn(&[String], &str) -> Vec<String>;
+
+#[derive(Clone)]
+enum CompletionAction {
+    Function(CompletionFn),
+    // Registered by a plugin through `register_completion`
+    Plugin(PluginCompletionFn),
+    // `complete -d`: directory names only
+    DirectoriesOnly,
+    // `complete -f`: names of files that aren't directories
+    FilesOnly,
+    // `complete -W 'words'`
+    Wordlist(Vec<String>),
+    // `complete -W` with `$(...)` or `$` in it: expanded by sh when completing,
+    // the words kept for $COMPLETION_CACHE_TTL seconds. sh gets the line as
+    // $COMP_LINE, the cursor's byte offset in it as $COMP_POINT, the index
+    // of the word being completed as $COMP_CWORD, and the words up to it as
+    // $COMP_WORDS (joined by blanks, sh having no arrays) and as "$@". The
+    // output is the word list: $COMPREPLY is only read back from -F.
+    WordlistCmd(String, RefCell<Option<(Instant, Vec<String>)>>),
+    // `complete -F name`: the shell function fills $COMPREPLY
+    ShellFunction(String),
+}
+
+#[derive(Clone)]
+struct CompletionSpec {
+    action: CompletionAction,
+    // `complete -X pattern`: candidates matching it are dropped (with a
+    // leading `!`, those not matching it)
+    exclude: Option<String>,
+    // `complete -o nospace`: nothing is added after a completed word
+    no_space: bool,
+    // `complete -o filenames`: words naming directories get a `/`
+    filenames: bool,
+}
+
+impl CompletionSpec {
+    fn function(f: CompletionFn) -> Self {
+        Self::action(CompletionAction::Function(f))
+    }
+
+    fn action(action: CompletionAction) -> Self {
+        Self {
+            action,
+            exclude: None,
+            no_space: false,
+            filenames: false,
+        }
+    }
+
+    // The `complete` arguments that recreate this spec, None for the ones
+    // only the shell or a plugin can set up
+    fn definition(&self) -> Option<String> {
+        let mut def = match &self.action {
+            CompletionAction::Function(_) | CompletionAction::Plugin(_) => return None,
+            CompletionAction::DirectoriesOnly => "-d".to_string(),
+            CompletionAction::FilesOnly => "-f".to_string(),
+            CompletionAction::Wordlist(words) => format!("-W {}", quote_alias(&words.join(" "))),
+            CompletionAction::WordlistCmd(raw, _) => format!("-W {}", quote_alias(raw)),
+            CompletionAction::ShellFunction(name) => format!("-F {name}"),
+        };
+        if let Some(pattern) = &self.exclude {
+            def.push_str(&format!(" -X {}", quote_alias(pattern)));
+        }
+        if self.no_space {
+            def.push_str(" -o nospace");
+        }
+        if self.filenames {
+            def.push_str(" -o filenames");
+        }
+        Some(def)
+    }
+
+    // Whether this completes file names, which get the case-folding prefix
+    fn completes_files(&self) -> bool {
+        matches!(self.action, CompletionAction::DirectoriesOnly | CompletionAction::FilesOnly)
+    }
+
+    // `line` and `point` are the whole line and the cursor's byte offset in it
+    fn candidates(&self, words: &[String], current: &str, line: &str, point: usize) -> Vec<Candidate> {
+        let listed = |found: Vec<String>| found.into_iter().map(Candidate::word).collect();
+        let mut found: Vec<Candidate> = match &self.action {
+            CompletionAction::Function(f) => listed(f(words, current)),
+            CompletionAction::Plugin(f) => listed(plugin_candidates(*f, words, current)),
+            CompletionAction::DirectoriesOnly => filenames_of_kind(current, CandidateKind::Directory),
+            CompletionAction::FilesOnly => filenames_of_kind(current, CandidateKind::File),
+            CompletionAction::Wordlist(words) => listed(words.clone()),
+            CompletionAction::WordlistCmd(raw, cache) => listed(cached_wordlist(raw, cache, line, point, words, current)),
+            CompletionAction::ShellFunction(name) => {
+                found_by_function(name, words, current, line, point).into_iter().map(Candidate::word).collect()
+            }
+        };
+        if let Some(pattern) = &self.exclude {
+            let (keep_matching, pattern) = match pattern.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, pattern.as_str()),
+            };
+            found.retain(|c| wildcard_match(pattern, &c.text) == keep_matching);
+        }
+        for c in &mut found {
+            if self.filenames && c.kind == CandidateKind::Word && Path::new(&encode_os(&c.text)).is_dir() {
+                c.kind = CandidateKind::Directory;
+            }
+            if self.no_space && matches!(c.kind, CandidateKind::Word | CandidateKind::File) {
+                c.kind = CandidateKind::Bare;
+            }
+        }
+        found
+    }
+}
+
+#[derive(Default)]
+struct CompletionRegistry {
+    specs: HashMap<String, CompletionSpec>,
+}
+
+impl CompletionRegistry {
+    fn register(&mut self, cmd: &str, spec: CompletionSpec) {
+        self.specs.insert(cmd.to_string(), spec);
+    }
+
+    fn get(&self, cmd: &str) -> Option<&CompletionSpec> {
+        self.specs.get(cmd)
+    }
+
+    fn remove(&mut self, cmd: &str) -> bool {
+        self.specs.remove(cmd).is_some()
+    }
+
+    // Specs shipped with the shell; only registered when the tool is installed
+    fn register_builtin_specs(&mut self) {
+        if find_executable_in_path("git").is_some() {
+            self.register("git", CompletionSpec::function(complete_git));
+        }
+        if find_executable_in_path("cargo").is_some() {
+            self.register("cargo", CompletionSpec::function(complete_cargo));
+        }
+        for cmd in ["ssh", "scp", "sftp", "rsync", "mosh"] {
+            self.register(cmd, CompletionSpec::function(complete_ssh_host));
+        }
+        for cmd in ["export", "unset", "readonly"] {
+            self.register(cmd, CompletionSpec::function(complete_variable_name));
+        }
+        for cmd in ["kill", "fg", "bg", "wait"] {
+            self.register(cmd, CompletionSpec::function(complete_job_arg));
+        }
+        for cmd in ["cd", "rmdir"] {
+            self.register(cmd, CompletionSpec::action(CompletionAction::DirectoriesOnly));
+        }
+        for cmd in ["source", "."] {
+            self.register(cmd, CompletionSpec::action(CompletionAction::FilesOnly));
+        }
+    }
 }
 
 struct ShellHelper {
     state: RefCell<CompletionState>,
+    completions: CompletionRegistry,
+    // Prompt of the current readline call, to redraw it after paging a listing
+    prompt: RefCell<String>,
+    // [completion] case_insensitive from the config file
+    case_insensitive: bool,
+    // [completion] menu_complete: Tab cycles candidates into the line
+    menu_complete: bool,
+    // [completion] query_items: listings longer than this ask first
+    query_items: usize,
+    // [completion] hide_system_users: `~name` completion skips nologin accounts
+    hide_system_users: bool,
 }
 
 impl ShellHelper {
-    fn new() -> Self {
+    fn new(completions: CompletionRegistry) -> Self {
         Self {
             state: RefCell::new(CompletionState {
                 last_prefix: None,
                 armed_for_list: false,
+                history_cycle: None,
             }),
+            completions,
+            prompt: RefCell::new(String::new()),
+            case_insensitive: false,
+            menu_complete: false,
+            query_items: 100,
+            hide_system_users: false,
+        }
+    }
+
+    // Forget a half-done Tab Tab, so the next Tab starts over
+    fn reset_completion(&self) {
+        let mut st = self.state.borrow_mut();
+        st.last_prefix = None;
+        st.armed_for_list = false;
+        st.history_cycle = None;
+    }
+
+    // Tab right after a history listing (or a step through it) puts the next
+    // listed command in the line, then what was typed, and round again
+    fn next_history_entry(&self, before_cursor: &str) -> Option<Pair> {
+        let mut st = self.state.borrow_mut();
+        let (typed, entries, step) = st.history_cycle.as_mut()?;
+        let shown = if *step == 0 { typed.as_str() } else { entries[*step - 1].as_str() };
+        if before_cursor != shown {
+            st.history_cycle = None;
+            return None;
+        }
+        *step = (*step + 1) % (entries.len() + 1);
+        let text = if *step == 0 { typed.clone() } else { entries[*step - 1].clone() };
+        Some(Pair {
+            display: display_str(&text),
+            replacement: text,
+        })
+    }
+
+    fn matches_prefix(&self, candidate: &str, prefix: &str) -> bool {
+        if !self.case_insensitive {
+            return candidate.starts_with(prefix);
+        }
+        let mut cand = candidate.chars().flat_map(char::to_lowercase);
+        prefix
+            .chars()
+            .flat_map(char::to_lowercase)
+            .all(|p| cand.next() == Some(p))
+    }
+
+    // What to hand the command/file name generators, which filter by exact
+    // prefix themselves: without case folding only the directory part can be
+    // trusted, the rest is matched in `complete`
+    fn lookup_prefix<'a>(&self, prefix: &'a str) -> &'a str {
+        if self.case_insensitive {
+            &prefix[..prefix.rfind('/').map_or(0, |i| i + 1)]
+        } else {
+            prefix
+        }
+    }
+
+    // Listing too long for the screen: show it a page at a time, then redraw
+    // the prompt and the typed line below it. `count` is the number of
+    // candidates in it, for the "Display all" question.
+    fn page_listing(&self, entries: &[String], count: usize, line: &str, pos: usize) {
+        let rows = terminal_rows().unwrap_or(24).max(3);
+        let theme = active_theme();
+        let mut out = io::stdout();
+        let mut shown = 0;
+        let mut page_end = rows - 1;
+
+        let _ = out.write_all(b"\r\n");
+        if count > self.query_items {
+            let _ = write!(out, "Display all {count} possibilities? (y or n)");
+            let _ = out.flush();
+            loop {
+                match read_key() {
+                    Some(b'y' | b'Y' | b' ') => break,
+                    Some(b'n' | b'N' | 0x7f | 0x03 | 0x07) | None => {
+                        shown = entries.len();
+                        break;
+                    }
+                    Some(_) => {}
+                }
+            }
+            let _ = out.write_all(b"\r\n");
+        }
+        while shown < entries.len() {
+            if shown == page_end {
+                let _ = out.write_all(paint("--More--", theme.description).as_bytes());
+                let _ = out.flush();
+                let key = read_key();
+                let _ = out.write_all(b"\r\x1b[K");
+                match key {
+                    Some(b' ' | b'y' | b'Y') => page_end += rows - 1,
+                    Some(b'\r' | b'\n') => page_end += 1,
+                    _ => break,
+                }
+            }
+            let _ = write!(out, "{}\r\n", entries[shown]);
+            shown += 1;
+        }
+
+        // Back to a fresh prompt with the cursor where it was in the line
+        let _ = write!(out, "{}{}", self.prompt.borrow(), line);
+        let back = line[pos..].chars().count();
+        if back > 0 {
+            let _ = write!(out, "\x1b[{back}D");
+        }
+        let _ = out.flush();
+    }
+
+    // Listing with a history section after the word candidates. Menu
+    // completion cycles through both, so every pair replaces the whole line
+    // up to the cursor; a plain listing is printed here, sections and all,
+    // and the Tabs after it step through the history commands.
+    fn list_with_history(
+        &self,
+        matches: &[Candidate],
+        history: &[String],
+        line: &str,
+        start: usize,
+        pos: usize,
+    ) -> (usize, Vec<Pair>) {
+        if self.menu_complete {
+            let words = matches.iter().map(|m| Pair {
+                display: m.listed(),
+                replacement: format!("{}{}", &line[..start], quote_word(&m.text)),
+            });
+            let commands = history.iter().map(|h| Pair {
+                display: display_str(h),
+                replacement: h.clone(),
+            });
+            return (0, words.chain(commands).collect());
+        }
+
+        let theme = active_theme();
+        let words: Vec<String> = matches.iter().map(Candidate::listed).collect();
+        let mut entries = column_rows(&words, theme.candidate);
+        entries.push(paint("-- history --", theme.description));
+        entries.extend(history.iter().map(|h| paint(&display_str(h), theme.candidate)));
+        self.page_listing(&entries, matches.len() + history.len(), line, pos);
+        self.state.borrow_mut().history_cycle = Some((line[..pos].to_string(), history.to_vec(), 0));
+        (pos, vec![])
+    }
+
+    // Words of the current pipeline stage before the word under the cursor
+    // Candidates for an argument word; `current` is already unquoted.
+    // Commands with a registered spec use it, everything else gets file names.
+    // `line` and `point` are the whole line and the cursor's byte offset in it
+    fn argument_candidates(&self, stage: &[Token], current: &str, line: &str, point: usize) -> Vec<Candidate> {
+        let Some(cmd) = stage.first().and_then(Token::as_word) else {
+            return command_candidates(self.lookup_prefix(current));
+        };
+        // Right after a redirection operator the word is a file name, not an argument
+        if matches!(stage.last(), Some(Token::Op(_))) {
+            return filename_candidates(self.lookup_prefix(current));
+        }
+        let words: Vec<String> = stage
+            .iter()
+            .filter_map(Token::as_word)
+            .map(str::to_string)
+            .collect();
+
+        match self.completions.get(cmd) {
+            Some(spec) if spec.completes_files() => spec.candidates(&words, self.lookup_prefix(current), line, point),
+            Some(spec) => spec.candidates(&words, current, line, point),
+            None => filename_candidates(self.lookup_prefix(current)),
         }
     }
 }
@@ -68,8 +487,40 @@ impl Hinter for ShellHelper {
         None
     }
 }
-impl Highlighter for ShellHelper {}
-impl Validator for ShellHelper {}
+impl Highlighter for ShellHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        Cow::Owned(highlight_line(line, active_theme()))
+    }
+
+    fn highlight_candidate<'c>(&self, candidate: &'c str, completion: CompletionType) -> Cow<'c, str> {
+        // Circular completion cycles the selected candidate into the line
+        let theme = active_theme();
+        let color = match completion {
+            CompletionType::Circular => theme.selected,
+            _ => theme.candidate,
+        };
+        match color {
+            Some(c) => Cow::Owned(c.paint(candidate)),
+            None => Cow::Borrowed(candidate),
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        kind != CmdKind::MoveCursor
+    }
+}
+impl Validator for ShellHelper {
+    // A `[[` line missing its `]]`, a `select` its `done` or a function its
+    // `}` goes on to the next line
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let (_, line) = leading_negations(ctx.input());
+        Ok(match cond_command(line) {
+            Some(Err(_)) => ValidationResult::Incomplete,
+            _ if continues_on_next_line(line) => ValidationResult::Incomplete,
+            _ => ValidationResult::Valid(None),
+        })
+    }
+}
 
 // ---- helpers for completion ----
 fn executables_in_path_starting_with(prefix: &str) -> Vec<String> {
@@ -83,7 +534,7 @@ fn executables_in_path_starting_with(prefix: &str) -> Vec<String> {
         let Ok(entries) = fs::read_dir(&dir) else { continue };
         for entry in entries.flatten() {
             let path = entry.path();
-            if !path.is_file() || !path.is_executable() {
+            if !is_executable_file(&path) {
                 continue;
             }
             if let Some(name_os) = path.file_name() {
@@ -99,799 +550,11091 @@ fn executables_in_path_starting_with(prefix: &str) -> Vec<String> {
     out
 }
 
-fn longest_common_prefix(strs: &[String]) -> String {
-    if strs.is_empty() {
-        return String::new();
+// Most recent distinct history entries that start with `typed` and go further
+const HISTORY_CANDIDATES_MAX: usize = 10;
+
+fn history_candidates(history: &dyn History, typed: &str) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    if typed.trim().is_empty() {
+        return out;
     }
-    if strs.len() == 1 {
-        return strs[0].clone();
+    for i in (0..history.len()).rev() {
+        let Ok(Some(entry)) = history.get(i, SearchDirection::Reverse) else { continue };
+        let entry = entry.entry.trim_end();
+        if entry.len() > typed.len() && entry.starts_with(typed) && !out.iter().any(|e| e == entry) {
+            out.push(entry.to_string());
+            if out.len() == HISTORY_CANDIDATES_MAX {
+                break;
+            }
+        }
     }
+    out
+}
 
-    let first = strs[0].as_bytes();
-    let mut end = first.len();
+// Longest common prefix, cut on char boundaries so a multibyte character is
+// never split in half, and short of a character the next one combines with
+// (an accent, an emoji modifier or ZWJ sequence) so no cluster is either
+fn longest_common_prefix(strs: &[String]) -> String {
+    let Some((first, rest)) = strs.split_first() else {
+        return String::new();
+    };
 
-    for s in &strs[1..] {
-        let b = s.as_bytes();
-        let mut i = 0usize;
-        while i < end && i < b.len() && first[i] == b[i] {
-            i += 1;
-        }
-        end = end.min(i);
+    let mut end = first.len();
+    for s in rest {
+        let common = first[..end]
+            .char_indices()
+            .zip(s.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((i, _), _)| i)
+            // No mismatch: the shorter string is a prefix of the other
+            .unwrap_or(end.min(s.len()));
+        end = end.min(common);
         if end == 0 {
             break;
         }
     }
 
-    String::from_utf8_lossy(&first[..end]).to_string()
+    while let Some(before) = first[..end].chars().next_back() {
+        let joined = before == '\u{200d}' || strs.iter().any(|s| s[end..].chars().next().is_some_and(extends_cluster));
+        if !joined {
+            break;
+        }
+        end -= before.len_utf8();
+    }
+    first[..end].to_string()
 }
 
-impl Completer for ShellHelper {
-    type Candidate = Pair;
-
-    fn complete(
-        &self,
-        line: &str,
-        pos: usize,
-        _ctx: &Context<'_>,
-    ) -> rustyline::Result<(usize, Vec<Pair>)> {
-        // Only complete first token (command position)
-        let start = line[..pos]
-            .rfind(|c: char| c.is_whitespace())
-            .map(|i| i + 1)
-            .unwrap_or(0);
+// Characters that belong with the one before them: combining marks, the
+// zero-width joiner, variation selectors, skin tones and emoji tags
+fn extends_cluster(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036f}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{200d}'
+        | '\u{20d0}'..='\u{20ff}'
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{fe20}'..='\u{fe2f}'
+        | '\u{1f3fb}'..='\u{1f3ff}'
+        | '\u{e0020}'..='\u{e007f}'
+        | '\u{e0100}'..='\u{e01ef}')
+}
 
-        if start != 0 {
-            return Ok((pos, vec![]));
-        }
+fn command_candidates(prefix: &str) -> Vec<Candidate> {
+    let mut matches: Vec<String> = Vec::new();
+    let optional = OPTIONAL_BUILTINS.iter().filter(|b| optional_builtin_active(b.name));
 
-        let prefix = &line[start..pos];
-        if prefix.is_empty() {
-            return Ok((pos, vec![]));
+    for b in BUILTINS.iter().chain(optional) {
+        if b.name.starts_with(prefix) {
+            matches.push(b.name.to_string());
         }
+    }
+    matches.extend(executables_in_path_starting_with(prefix));
+    matches.into_iter().map(Candidate::word).collect()
+}
 
-        let mut matches: Vec<String> = Vec::new();
-        let builtins = ["echo", "exit", "type", "pwd", "cd", "history"];
-
-        for b in builtins {
-            if b.starts_with(prefix) {
-                matches.push(b.to_string());
+// File names matching `prefix` (a path as the shell sees it, unquoted).
+// Candidates keep the typed directory part; dotfiles only when asked for.
+fn filename_candidates(prefix: &str) -> Vec<Candidate> {
+    let (dir_part, name_part) = match prefix.rfind('/') {
+        Some(i) => prefix.split_at(i + 1),
+        None => ("", prefix),
+    };
+    // `~/` and `~user/` are looked up but stay as typed in the candidates
+    let dir = match dir_part.strip_prefix('~').and_then(|rest| rest.split_once('/')) {
+        Some((user, rest)) => {
+            let home = if user.is_empty() { home_dir() } else { passwd_home(Some(user)) };
+            match home {
+                Some(home) => home.join(encode_os(rest)),
+                None => return Vec::new(),
             }
         }
-        matches.extend(executables_in_path_starting_with(prefix));
-
-        matches.sort();
-        matches.dedup();
+        None if dir_part.is_empty() => PathBuf::from("."),
+        None => PathBuf::from(encode_os(dir_part)),
+    };
 
-        if matches.is_empty() {
-            let mut st = self.state.borrow_mut();
-            st.last_prefix = None;
-            st.armed_for_list = false;
-            return Ok((pos, vec![]));
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut out = Vec::new();
+    for entry in entries.flatten() {
+        let name = decode_os(&entry.file_name());
+        if !name.starts_with(name_part) || (name.starts_with('.') && !name_part.starts_with('.')) {
+            continue;
         }
+        // Follows symlinks, so a link to a directory completes like one
+        let is_dir = fs::metadata(entry.path()).is_ok_and(|m| m.is_dir());
+        let kind = if is_dir { CandidateKind::Directory } else { CandidateKind::File };
+        out.push(Candidate::new(format!("{dir_part}{name}"), kind));
+    }
+    apply_fignore(&mut out, prefix);
+    out
+}
 
-        if matches.len() == 1 {
-            let mut st = self.state.borrow_mut();
-            st.last_prefix = None;
-            st.armed_for_list = false;
+// $FIGNORE (`.o:.pyc:~`): drops files ending in one of the suffixes, unless
+// the typed prefix already reaches into the suffix or nothing else would be
+// left. Directories always stay.
+fn apply_fignore(candidates: &mut Vec<Candidate>, typed: &str) {
+    let Some(fignore) = shell_setting("FIGNORE") else { return };
+    let suffixes: Vec<&str> = fignore.split(':').filter(|s| !s.is_empty()).collect();
+    let ignored = |c: &Candidate| {
+        c.kind != CandidateKind::Directory
+            && suffixes.iter().any(|s| c.text.ends_with(s) && c.text.len() - s.len() >= typed.len())
+    };
+    if candidates.iter().any(|c| !ignored(c)) {
+        candidates.retain(|c| !ignored(c));
+    }
+}
 
-            let m = &matches[0];
-            return Ok((
-                start,
-                vec![Pair {
-                    display: m.clone(),
-                    replacement: format!("{m} "),
-                }],
-            ));
+// Shell pattern match of the whole text: `*`, `?`, `[abc]` / `[a-z]` /
+// `[!abc]`, and `\` quoting the next character
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Where the last `*` was and the text position it resumes from
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => bracket_match(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+            (None, None) => return false,
         }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
 
-        let lcp = longest_common_prefix(&matches);
-        if lcp.len() > prefix.len() {
-            let mut st = self.state.borrow_mut();
-            st.last_prefix = None;
-            st.armed_for_list = false;
-
-            return Ok((
-                start,
-                vec![Pair {
-                    display: lcp.clone(),
-                    replacement: lcp,
-                }],
-            ));
+// `[...]` at the start of `pattern` against `c`: the bracket's length when
+// it matches; a `[` with no closing `]` only matches itself
+fn bracket_match(pattern: &[char], c: char) -> Option<usize> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while let Some(&ch) = pattern.get(i) {
+        if ch == ']' && !first {
+            return (matched != negated).then_some(i + 1);
         }
+        first = false;
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|&end| end != ']') {
+            matched |= (ch..=pattern[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= ch == c;
+            i += 1;
+        }
+    }
+    (c == '[').then_some(1)
+}
 
-        let mut st = self.state.borrow_mut();
-        if st.last_prefix.as_deref() == Some(prefix) && st.armed_for_list {
-            st.armed_for_list = false;
-
-            let pairs: Vec<Pair> = matches
-                .into_iter()
-                .map(|m| Pair {
-                    display: m.clone(),
-                    replacement: m,
-                })
-                .collect();
+// Default seconds a `complete -W "$(cmd)"` word list is reused
+const COMPLETION_CACHE_TTL: u64 = 5;
 
-            Ok((start, pairs))
-        } else {
-            st.last_prefix = Some(prefix.to_string());
-            st.armed_for_list = true;
-            Ok((pos, vec![]))
+fn cached_wordlist(
+    raw: &str,
+    cache: &RefCell<Option<(Instant, Vec<String>)>>,
+    line: &str,
+    point: usize,
+    words: &[String],
+    current: &str,
+) -> Vec<String> {
+    // A list reading $COMP_... or the words as $1... depends on the line, so isn't reused
+    let per_line = raw.contains("COMP_")
+        || raw.match_indices('$').any(|(i, _)| raw[i + 1..].starts_with(|c: char| c.is_ascii_digit() || matches!(c, '@' | '*' | '#')));
+    let ttl = shell_setting("COMPLETION_CACHE_TTL").and_then(|v| v.trim().parse().ok());
+    let ttl = Duration::from_secs(ttl.unwrap_or(COMPLETION_CACHE_TTL));
+    if let (Some((at, words)), false) = (&*cache.borrow(), per_line) {
+        if at.elapsed() < ttl {
+            return words.clone();
         }
     }
+    // sh does the substitutions and the word splitting
+    let comp_words: Vec<&str> = words.iter().map(String::as_str).chain([current]).collect();
+    let quoted: Vec<String> = comp_words.iter().map(|w| quote_alias(w)).collect();
+    let script = format!(
+        "export COMP_LINE={} COMP_POINT={point} COMP_CWORD={} COMP_WORDS={}\nset -- {}\nprintf '%s\\n' {raw}",
+        quote_alias(line),
+        words.len(),
+        quote_alias(&comp_words.join(" ")),
+        quoted.join(" "),
+    );
+    let output = tool_output("sh", &["-c", &script], Duration::from_secs(2)).unwrap_or_default();
+    let words: Vec<String> = output.split_whitespace().map(str::to_string).collect();
+    if !per_line {
+        *cache.borrow_mut() = Some((Instant::now(), words.clone()));
+    }
+    words
 }
 
-// ---------- PATH helper for execution ----------
-fn find_executable_in_path(name: &str) -> Option<PathBuf> {
-    let paths = env::var_os("PATH")?;
-    for dir in env::split_paths(&paths) {
-        let candidate = dir.join(name);
-        if candidate.is_file() && candidate.is_executable() {
-            return Some(candidate);
+// `complete -F name`: a copy of the shell gets the functions and unexported
+// variables as last published, the line as $COMP_LINE, $COMP_POINT,
+// $COMP_WORDS and $COMP_CWORD, and runs `name command current previous`;
+// the candidates are the elements of $COMPREPLY it leaves
+fn found_by_function(name: &str, words: &[String], current: &str, line: &str, point: usize) -> Vec<String> {
+    let Ok(exe) = env::current_exe() else { return vec![] };
+    let mut script = String::new();
+    for (fname, body) in SHELL_FUNCTIONS.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        script.push_str(&function_text(fname, body));
+        script.push('\n');
+    }
+    for (var, value) in SHELL_VALUES.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        if is_identifier(var) && !value.contains('\n') {
+            script.push_str(&format!("{var}={}\n", quote_alias(value)));
         }
     }
-    None
+    let comp_words: Vec<String> = words.iter().map(String::as_str).chain([current]).map(quote_alias).collect();
+    let command = words.first().map_or("", String::as_str);
+    let previous = words.last().map_or("", String::as_str);
+    script.push_str(&format!(
+        "COMP_LINE={}\nCOMP_POINT={point}\nCOMP_CWORD={}\nCOMP_WORDS=({})\nCOMPREPLY=()\n{name} {} {} {}\nprintf '%s\\n' \"${{COMPREPLY[@]}}\"\n",
+        quote_alias(&line.replace('\n', " ")),
+        words.len(),
+        comp_words.join(" "),
+        quote_alias(command),
+        quote_alias(current),
+        quote_alias(previous),
+    ));
+    let output = tool_output(&exe, &["--norc", "-c", &script], Duration::from_secs(2)).unwrap_or_default();
+    output.lines().filter(|l| !l.is_empty()).map(str::to_string).collect()
 }
 
-// ---------- tokenization (supports quotes + backslash + PIPE token) ----------
-fn tokenize(line: &str) -> Vec<String> {
-    let mut args: Vec<String> = Vec::new();
-    let mut current = String::new();
+fn filenames_of_kind(prefix: &str, kind: CandidateKind) -> Vec<Candidate> {
+    let mut found = filename_candidates(prefix);
+    found.retain(|c| c.kind == kind);
+    found
+}
 
+// Byte offset where the word under the cursor starts: after the last
+// unquoted, unescaped blank or `|` `<` `>` (quote-aware, so `my\ fi` and
+// `"my fi` are one word)
+fn current_word_start(before: &str) -> usize {
+    let mut start = 0;
     let mut in_single = false;
     let mut in_double = false;
     let mut backslash = false;
 
-    let dq_escapable = ['\\', '"', '$', '`'];
-
-    for ch in line.chars() {
+    for (i, ch) in before.char_indices() {
         if backslash {
-            if in_single {
-                current.push('\\');
-                current.push(ch);
-            } else if in_double {
-                if dq_escapable.contains(&ch) {
-                    current.push(ch);
-                } else {
-                    current.push('\\');
-                    current.push(ch);
-                }
-            } else {
-                current.push(ch);
-            }
             backslash = false;
             continue;
         }
-
-        if ch == '\\' && !in_single {
-            backslash = true;
-            continue;
+        match ch {
+            '\\' if !in_single => backslash = true,
+            '\'' if !in_double => in_single = !in_single,
+            '"' if !in_single => in_double = !in_double,
+            c if !in_single && !in_double && (c.is_whitespace() || matches!(c, '|' | '<' | '>')) => {
+                start = i + c.len_utf8();
+            }
+            _ => {}
         }
+    }
+    start
+}
 
-        if ch == '\'' && !in_double {
-            in_single = !in_single;
-            continue;
-        }
-        if ch == '"' && !in_single {
-            in_double = !in_double;
-            continue;
-        }
-
-        if !in_single && !in_double && ch == '|' {
-            if !current.is_empty() {
-                args.push(current);
-                current = String::new();
-            }
-            args.push("|".to_string());
-            continue;
-        }
-
-        if !in_single && !in_double && ch.is_whitespace() {
-            if !current.is_empty() {
-                args.push(current);
-                current = String::new();
-            }
-            continue;
-        }
+// ---------- git completion ----------
+const GIT_SUBCOMMANDS: &[&str] = &[
+    "add", "am", "bisect", "blame", "branch", "checkout", "cherry-pick", "clean", "clone",
+    "commit", "config", "diff", "fetch", "grep", "init", "log", "merge", "mv", "pull", "push",
+    "rebase", "reflog", "remote", "reset", "restore", "revert", "rm", "show", "stash", "status",
+    "switch", "tag", "worktree",
+];
 
-        current.push(ch);
-    }
+const GIT_GLOBAL_FLAGS: &[&str] = &[
+    "--bare", "--git-dir", "--help", "--no-pager", "--paginate", "--version", "--work-tree",
+    "-C", "-c",
+];
 
-    if backslash {
-        current.push('\\');
+fn git_subcommand_flags(sub: &str) -> &'static [&'static str] {
+    match sub {
+        "add" => &["--all", "--dry-run", "--force", "--intent-to-add", "--patch", "--update"],
+        "branch" => &["--all", "--delete", "--list", "--move", "--remotes", "--verbose"],
+        "checkout" => &["--detach", "--orphan", "--track", "-B", "-b"],
+        "commit" => &["--all", "--amend", "--fixup", "--message", "--no-edit", "--signoff"],
+        "diff" => &["--cached", "--name-only", "--stat", "--staged"],
+        "log" => &["--graph", "--oneline", "--patch", "--stat"],
+        "pull" => &["--autostash", "--ff-only", "--no-rebase", "--rebase"],
+        "push" => &["--delete", "--dry-run", "--force", "--force-with-lease", "--set-upstream", "--tags"],
+        "status" => &["--branch", "--porcelain", "--short", "--untracked-files"],
+        _ => &[],
     }
-    if !current.is_empty() {
-        args.push(current);
-    }
-
-    args
 }
 
-#[derive(Debug, Clone)]
-struct ParsedCommand {
-    cmd: String,
-    args: Vec<String>,
-    stdout: StdoutRedirect,
-    stderr: StderrRedirect,
-}
-
-fn parse_command(tokens: &[String]) -> Option<ParsedCommand> {
-    if tokens.is_empty() {
-        return None;
-    }
-    let cmd = tokens[0].clone();
+// Run a helper tool for completion, giving up (and killing it) after `timeout`
+// so a slow tool can't freeze the prompt. None on failure or timeout.
+fn tool_output(program: impl AsRef<std::ffi::OsStr>, args: &[&str], timeout: Duration) -> Option<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
 
-    let mut args: Vec<String> = Vec::new();
-    let mut stdout = StdoutRedirect::Inherit;
-    let mut stderr = StderrRedirect::Inherit;
+    // Read on a thread so a chatty tool can't block on a full pipe while we poll
+    let mut stdout = child.stdout.take()?;
+    let reader = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stdout.read_to_end(&mut buf);
+        buf
+    });
 
-    let mut i = 1;
-    while i < tokens.len() {
-        match tokens[i].as_str() {
-            ">" | "1>" => {
-                if i + 1 >= tokens.len() {
-                    eprintln!("{cmd}: syntax error near unexpected token `newline`");
-                    return None;
-                }
-                stdout = StdoutRedirect::Truncate(tokens[i + 1].clone());
-                i += 2;
-            }
-            ">>" | "1>>" => {
-                if i + 1 >= tokens.len() {
-                    eprintln!("{cmd}: syntax error near unexpected token `newline`");
-                    return None;
-                }
-                stdout = StdoutRedirect::Append(tokens[i + 1].clone());
-                i += 2;
-            }
-            "2>" => {
-                if i + 1 >= tokens.len() {
-                    eprintln!("{cmd}: syntax error near unexpected token `newline`");
-                    return None;
-                }
-                stderr = StderrRedirect::Truncate(tokens[i + 1].clone());
-                i += 2;
-            }
-            "2>>" => {
-                if i + 1 >= tokens.len() {
-                    eprintln!("{cmd}: syntax error near unexpected token `newline`");
-                    return None;
-                }
-                stderr = StderrRedirect::Append(tokens[i + 1].clone());
-                i += 2;
-            }
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
             _ => {
-                args.push(tokens[i].clone());
-                i += 1;
-            }
-        }
-    }
-
-    Some(ParsedCommand {
-        cmd,
-        args,
-        stdout,
-        stderr,
-    })
-}
-
-fn split_pipeline(tokens: &[String]) -> Option<Vec<Vec<String>>> {
-    let mut out: Vec<Vec<String>> = Vec::new();
-    let mut cur: Vec<String> = Vec::new();
-
-    for t in tokens {
-        if t == "|" {
-            if cur.is_empty() {
-                eprintln!("syntax error near unexpected token `|`");
+                let _ = child.kill();
+                let _ = child.wait();
                 return None;
             }
-            out.push(cur);
-            cur = Vec::new();
-        } else {
-            cur.push(t.clone());
         }
-    }
+    };
 
-    if cur.is_empty() {
-        eprintln!("syntax error near unexpected token `|`");
+    let buf = reader.join().ok()?;
+    if !status.success() {
         return None;
     }
-    out.push(cur);
-    Some(out)
+    Some(String::from_utf8_lossy(&buf).into_owned())
 }
 
-fn open_for_stdout(redir: &StdoutRedirect) -> io::Result<Option<File>> {
-    match redir {
-        StdoutRedirect::Inherit => Ok(None),
-        StdoutRedirect::Truncate(path) => Ok(Some(File::create(path)?)),
-        StdoutRedirect::Append(path) => Ok(Some(
-            OpenOptions::new().create(true).append(true).open(path)?,
-        )),
+fn git_output(args: &[&str]) -> Option<String> {
+    tool_output("git", args, Duration::from_secs(2))
+}
+
+// Tracked + untracked (but not ignored) files, relative to the cwd
+fn git_repo_paths() -> Vec<String> {
+    if git_output(&["rev-parse", "--show-toplevel"]).is_none() {
+        return Vec::new();
     }
+    git_output(&["ls-files", "--cached", "--others", "--exclude-standard"])
+        .map(|s| s.lines().map(str::to_string).collect())
+        .unwrap_or_default()
 }
 
-fn open_for_stderr(redir: &StderrRedirect) -> io::Result<Option<File>> {
-    match redir {
-        StderrRedirect::Inherit => Ok(None),
-        StderrRedirect::Truncate(path) => Ok(Some(File::create(path)?)),
-        StderrRedirect::Append(path) => Ok(Some(
-            OpenOptions::new().create(true).append(true).open(path)?,
-        )),
+fn complete_git(words: &[String], current: &str) -> Vec<String> {
+    // The subcommand is the first non-flag argument after "git"
+    let sub = words.iter().skip(1).find(|w| !w.starts_with('-'));
+
+    let to_vec = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+    match sub {
+        None if current.starts_with('-') => to_vec(GIT_GLOBAL_FLAGS),
+        None => to_vec(GIT_SUBCOMMANDS),
+        Some(sub) if current.starts_with('-') => to_vec(git_subcommand_flags(sub)),
+        Some(sub) if sub == "add" => git_repo_paths(),
+        Some(_) => Vec::new(),
     }
 }
 
-fn is_builtin(cmd: &str) -> bool {
-    matches!(cmd, "exit" | "echo" | "pwd" | "type" | "cd" | "history")
+// ---------- cargo completion ----------
+const CARGO_SUBCOMMANDS: &[&str] = &[
+    "bench", "build", "check", "clean", "clippy", "doc", "fmt", "install", "publish", "run",
+    "search", "test", "uninstall", "update",
+];
+
+const CARGO_COMMON_FLAGS: &[&str] = &[
+    "--all-features", "--all-targets", "--bin", "--example", "--features", "--package",
+    "--release", "--workspace",
+];
+
+// `cargo-foo` executables on PATH are invokable as `cargo foo`
+fn cargo_external_subcommands() -> Vec<String> {
+    executables_in_path_starting_with("cargo-")
+        .into_iter()
+        .map(|name| name["cargo-".len()..].to_string())
+        .collect()
 }
 
-// -------- history printing helper (matches tester formatting) --------
-fn history_output(history: &[String], n: Option<usize>) -> Vec<u8> {
-    let len = history.len();
-    let start = match n {
-        Some(k) if k < len => len - k,
-        Some(_) => 0,
-        None => 0,
+// Binary targets of the package in the cwd: explicit [[bin]] tables plus the
+// implicit one named after the package when src/main.rs exists
+fn cargo_bin_names() -> Vec<String> {
+    let Ok(manifest) = fs::read_to_string("Cargo.toml") else {
+        return Vec::new();
     };
 
-    let mut out = String::new();
-    for (idx0, cmd) in history.iter().enumerate().skip(start) {
-        out.push_str(&format!("{:>5}  {}\n", idx0 + 1, cmd));
+    let mut out = Vec::new();
+    let mut section = String::new();
+    for raw in manifest.lines() {
+        let line = raw.trim();
+        if line.starts_with('[') {
+            section = line.to_string();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if key.trim() != "name" {
+            continue;
+        }
+        let name = value.trim().trim_matches('"').to_string();
+        match section.as_str() {
+            "[[bin]]" => out.push(name),
+            "[package]" if Path::new("src/main.rs").is_file() => out.push(name),
+            _ => {}
+        }
     }
-    out.into_bytes()
+    out
 }
 
-// ---------- builtin output bytes ----------
-fn builtin_bytes(cmd: &str, args: &[String], history: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
-    match cmd {
-        "echo" => (format!("{}\n", args.join(" ")).into_bytes(), vec![], 0),
-        "pwd" => match env::current_dir() {
-            Ok(p) => (format!("{}\n", p.display()).into_bytes(), vec![], 0),
-            Err(e) => (vec![], format!("pwd: {e}\n").into_bytes(), 1),
-        },
-        "type" => {
-            if args.is_empty() {
-                return (vec![], b"type: missing operand\n".to_vec(), 1);
-            }
-            let target = args[0].as_str();
-            let builtins = ["exit", "echo", "type", "pwd", "cd", "history"];
-            if builtins.contains(&target) {
-                (format!("{target} is a shell builtin\n").into_bytes(), vec![], 0)
-            } else if let Some(p) = find_executable_in_path(target) {
-                (format!("{target} is {}\n", p.display()).into_bytes(), vec![], 0)
-            } else {
-                (format!("{target} not found\n").into_bytes(), vec![], 0)
-            }
+// Test names listed by the test binaries already built under
+// target/debug/deps, the newest of each. Completion never compiles, so there
+// are none until the first `cargo test` (or `cargo build --tests`).
+fn cargo_test_names() -> Vec<String> {
+    let Some(deps) = cargo_deps_dir() else {
+        return Vec::new();
+    };
+    // Test binary name without its `-hash` to (modified, path)
+    let mut newest: HashMap<String, (SystemTime, PathBuf)> = HashMap::new();
+    for entry in fs::read_dir(&deps).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let Some((stem, hash)) = name.strip_suffix(env::consts::EXE_SUFFIX).and_then(|n| n.rsplit_once('-')) else {
+            continue;
+        };
+        if hash.len() != 16 || !hash.chars().all(|c| c.is_ascii_hexdigit()) || !is_executable_file(&path) {
+            continue;
         }
-        "history" => {
-            let n = if args.len() == 1 {
-                args[0].parse::<usize>().ok()
-            } else {
-                None
-            };
-            (history_output(history, n), vec![], 0)
+        let Ok(modified) = entry.metadata().and_then(|m| m.modified()) else {
+            continue;
+        };
+        if !is_test_binary(&path, modified) {
+            continue;
+        }
+        if newest.get(stem).is_none_or(|(t, _)| *t < modified) {
+            newest.insert(stem.to_string(), (modified, path));
         }
-        // pipeline "cd"/"exit": treated as no-op (parent effects only in single-command mode)
-        "cd" => (vec![], vec![], 0),
-        "exit" => (vec![], vec![], 0),
-        _ => (vec![], format!("{cmd}: command not found\n").into_bytes(), 127),
     }
+    let mut names: Vec<String> = newest
+        .into_values()
+        .filter_map(|(_, path)| tool_output(&path, &["--list"], Duration::from_secs(2)))
+        .flat_map(|s| s.lines().filter_map(|l| l.strip_suffix(": test")).map(str::to_string).collect::<Vec<_>>())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
 }
 
-// ---------- single builtin output routing ----------
-fn write_routed_output(
-    stdout_bytes: &[u8],
-    stderr_bytes: &[u8],
-    stdout_redir: &StdoutRedirect,
-    stderr_redir: &StderrRedirect,
-    cmd_name: &str,
-) {
-    // stderr
-    match stderr_redir {
-        StderrRedirect::Inherit => {
-            if !stderr_bytes.is_empty() {
-                let mut e = io::stderr();
-                let _ = e.write_all(stderr_bytes);
-                let _ = e.flush();
-            }
-        }
-        _ => match open_for_stderr(stderr_redir) {
-            Ok(Some(mut f)) => {
-                let _ = f.write_all(stderr_bytes);
-                let _ = f.flush();
-            }
-            Ok(None) => {}
-            Err(e) => eprintln!("{cmd_name}: {e}"),
-        },
+// $CARGO_TARGET_DIR/debug/deps, else the nearest target/debug/deps above the cwd
+fn cargo_deps_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("CARGO_TARGET_DIR").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir).join("debug").join("deps"));
     }
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors().map(|d| d.join("target").join("debug").join("deps")).find(|d| d.is_dir())
+}
 
-    // stdout
-    match stdout_redir {
-        StdoutRedirect::Inherit => {
-            if !stdout_bytes.is_empty() {
-                let mut o = io::stdout();
-                let _ = o.write_all(stdout_bytes);
-                let _ = o.flush();
-            }
+// Executables already checked by is_test_binary, with when they were modified
+static TEST_BINARIES: Mutex<std::collections::BTreeMap<PathBuf, (SystemTime, bool)>> =
+    Mutex::new(std::collections::BTreeMap::new());
+
+// Whether an executable links libtest (so `--list` lists instead of running
+// it), by its help text; remembered until the file changes
+fn is_test_binary(path: &Path, modified: SystemTime) -> bool {
+    let mut seen = TEST_BINARIES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((at, known)) = seen.get(path) {
+        if *at == modified {
+            return *known;
         }
-        _ => match open_for_stdout(stdout_redir) {
-            Ok(Some(mut f)) => {
-                let _ = f.write_all(stdout_bytes);
-                let _ = f.flush();
-            }
-            Ok(None) => {}
-            Err(e) => eprintln!("{cmd_name}: {e}"),
-        },
     }
+    const MARKER: &[u8] = b"List all tests and benchmarks";
+    let known = fs::read(path).is_ok_and(|bytes| bytes.windows(MARKER.len()).any(|w| w == MARKER));
+    seen.insert(path.to_path_buf(), (modified, known));
+    known
 }
 
-// ---------- run single external ----------
-fn run_single_external(stage: &ParsedCommand) {
-    if find_executable_in_path(&stage.cmd).is_none() {
-        eprintln!("{}: command not found", stage.cmd);
-        return;
-    }
+fn cargo_installed_crates() -> Vec<String> {
+    tool_output("cargo", &["install", "--list"], Duration::from_secs(2))
+        .map(|s| {
+            s.lines()
+                .filter(|l| !l.starts_with(char::is_whitespace))
+                .filter_map(|l| l.split_whitespace().next())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-    let mut cmd = Command::new(&stage.cmd);
-    cmd.args(&stage.args);
+fn rustup_toolchains() -> Vec<String> {
+    tool_output("rustup", &["toolchain", "list"], Duration::from_secs(2))
+        .map(|s| {
+            s.lines()
+                .filter_map(|l| l.split_whitespace().next())
+                .map(|t| format!("+{t}"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-    // stdout
-    match &stage.stdout {
-        StdoutRedirect::Inherit => {
-            cmd.stdout(Stdio::inherit());
-        }
-        _ => match open_for_stdout(&stage.stdout) {
-            Ok(Some(f)) => {
-                cmd.stdout(Stdio::from(f));
-            }
-            Ok(None) => {
-                cmd.stdout(Stdio::inherit());
-            }
-            Err(e) => {
-                eprintln!("{}: {e}", stage.cmd);
-                return;
-            }
-        },
-    }
+fn complete_cargo(words: &[String], current: &str) -> Vec<String> {
+    let to_vec = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
 
-    // stderr
-    match &stage.stderr {
-        StderrRedirect::Inherit => {
-            cmd.stderr(Stdio::inherit());
-        }
-        _ => match open_for_stderr(&stage.stderr) {
-            Ok(Some(f)) => {
-                cmd.stderr(Stdio::from(f));
-            }
-            Ok(None) => {
-                cmd.stderr(Stdio::inherit());
-            }
-            Err(e) => {
-                eprintln!("{}: {e}", stage.cmd);
-                return;
-            }
-        },
-    }
+    // `cargo +toolchain sub ...`: the subcommand is the first plain word
+    let sub = words
+        .iter()
+        .skip(1)
+        .find(|w| !w.starts_with('-') && !w.starts_with('+'));
+    let prev = words.last().map(String::as_str).unwrap_or("");
 
-    let mut child = match cmd.spawn() {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("{}: {e}", stage.cmd);
-            return;
+    let Some(sub) = sub else {
+        if current.starts_with('+') && words.len() == 1 {
+            return rustup_toolchains();
         }
+        let mut subs = to_vec(CARGO_SUBCOMMANDS);
+        subs.extend(cargo_external_subcommands());
+        return subs;
     };
 
-    let _ = child.wait();
+    if current.starts_with('-') {
+        return to_vec(CARGO_COMMON_FLAGS);
+    }
+    match (sub.as_str(), prev) {
+        (_, "--bin") => cargo_bin_names(),
+        ("test", _) => cargo_test_names(),
+        ("uninstall", _) => cargo_installed_crates(),
+        _ => Vec::new(),
+    }
 }
 
-// ---------- POSIX pipe helper ----------
-#[cfg(unix)]
-fn make_pipe() -> io::Result<(File, File)> {
-    let mut fds = [0i32; 2];
-    let rc = unsafe { libc::pipe(fds.as_mut_ptr()) };
-    if rc != 0 {
-        return Err(io::Error::last_os_error());
-    }
-    let read_end = unsafe { File::from_raw_fd(fds[0]) };
-    let write_end = unsafe { File::from_raw_fd(fds[1]) };
-    Ok((read_end, write_end))
+// ---------- ssh host completion ----------
+// Hosts parsed from ~/.ssh/{config,known_hosts}, re-read only when either file's mtime changes
+// (files the config includes are read then too, but not watched)
+struct SshHostCache {
+    stamps: Vec<Option<SystemTime>>,
+    hosts: Vec<String>,
 }
 
-#[cfg(not(unix))]
-fn make_pipe() -> io::Result<(File, File)> {
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "pipes are only supported on unix in this project",
-    ))
+static SSH_HOST_CACHE: Mutex<Option<SshHostCache>> = Mutex::new(None);
+
+fn ssh_files() -> Option<(PathBuf, PathBuf, PathBuf)> {
+    let ssh_dir = home_dir()?.join(".ssh");
+    Some((ssh_dir.join("config"), ssh_dir.join("known_hosts"), ssh_dir))
 }
 
+fn ssh_known_hosts() -> Vec<String> {
+    let Some((config, known_hosts, ssh_dir)) = ssh_files() else {
+        return Vec::new();
+    };
+    let stamps: Vec<Option<SystemTime>> = [&config, &known_hosts]
+        .iter()
+        .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect();
 
-// Drain stdin for pipeline builtins that don't use stdin (prevents upstream from blocking)
-fn drain_to_eof(mut f: File) {
-    let mut buf = [0u8; 8192];
-    loop {
-        match f.read(&mut buf) {
-            Ok(0) => break,
-            Ok(_) => continue,
-            Err(_) => break,
+    let mut cache = SSH_HOST_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(c) = cache.as_ref() {
+        if c.stamps == stamps {
+            return c.hosts.clone();
         }
     }
+
+    let mut hosts = ssh_hosts::config_hosts(&config, &ssh_dir);
+    hosts.extend(ssh_hosts::known_hosts(&fs::read_to_string(&known_hosts).unwrap_or_default()));
+    hosts.sort();
+    hosts.dedup();
+
+    *cache = Some(SshHostCache {
+        stamps,
+        hosts: hosts.clone(),
+    });
+    hosts
 }
 
-// Builtin stage writes output either to next pipe writer OR to terminal/file (if last stage)
-fn builtin_stage_run(
-    stage: ParsedCommand,
-    stdin_file: Option<File>,
-    stdout_pipe: Option<File>,
-    history_snapshot: Vec<String>,
-) {
-    // IMPORTANT: If we have stdin from previous stage, drain it so upstream doesn't block
-    if let Some(f) = stdin_file {
-        drain_to_eof(f);
+// `host:path` completion over ssh; opt-in because it needs a (fast, key-based) connection
+fn ssh_remote_paths(host: &str, path: &str) -> Vec<String> {
+    if shell_setting("COMPLETE_REMOTE_PATHS").as_deref() != Some("1") {
+        return Vec::new();
     }
+    let quoted = format!("'{}'*", path.replace('\'', "'\\''"));
+    let remote_cmd = format!("ls -dp -- {quoted} 2>/dev/null");
+    let args = ["-o", "BatchMode=yes", "-o", "ConnectTimeout=2", host, remote_cmd.as_str()];
+    tool_output("ssh", &args, Duration::from_secs(3))
+        .map(|s| s.lines().map(|p| format!("{host}:{p}")).collect())
+        .unwrap_or_default()
+}
 
-    let (out, err, _code) = builtin_bytes(&stage.cmd, &stage.args, &history_snapshot);
+// Hosts (after a typed `user@`); scp's get a `:` so the remote path follows
+fn complete_ssh_host(words: &[String], current: &str) -> Vec<String> {
+    // Keep a typed `user@` in front of the host
+    let (user, rest) = match current.rfind('@') {
+        Some(i) => current.split_at(i + 1),
+        None => ("", current),
+    };
 
-    // stderr routing
-    match stage.stderr {
-        StderrRedirect::Inherit => {
-            if !err.is_empty() {
-                let mut e = io::stderr();
-                let _ = e.write_all(&err);
-                let _ = e.flush();
-            }
-        }
-        _ => {
-            if let Ok(Some(mut f)) = open_for_stderr(&stage.stderr) {
-                let _ = f.write_all(&err);
-                let _ = f.flush();
-            }
-        }
+    if let Some((host, path)) = rest.split_once(':') {
+        return ssh_remote_paths(host, path)
+            .into_iter()
+            .map(|p| format!("{user}{p}"))
+            .collect();
     }
 
-    // stdout routing
-    if let Some(mut pipe_writer) = stdout_pipe {
-        // Pipeline semantics: write stdout into pipe (ignore stdout redirection here)
-        let _ = pipe_writer.write_all(&out);
-        let _ = pipe_writer.flush();
-        return; // drop closes pipe
-    }
+    let colon = if words.first().is_some_and(|w| w == "scp") { ":" } else { "" };
+    ssh_known_hosts()
+        .into_iter()
+        .map(|h| format!("{user}{h}{colon}"))
+        .collect()
+}
 
-    // Last stage: honor stdout redirection
-    match stage.stdout {
-        StdoutRedirect::Inherit => {
-            if !out.is_empty() {
-                let mut o = io::stdout();
-                let _ = o.write_all(&out);
-                let _ = o.flush();
+// ---------- user name completion ----------
+// Login names and their shells from the passwd database, read once
+static PASSWD_USERS: std::sync::OnceLock<Vec<(String, String)>> = std::sync::OnceLock::new();
+
+#[cfg(unix)]
+fn passwd_users() -> &'static [(String, String)] {
+    PASSWD_USERS.get_or_init(|| {
+        let field = |p: *const c_char| {
+            if p.is_null() {
+                String::new()
+            } else {
+                decode_bytes(unsafe { CStr::from_ptr(p) }.to_bytes())
             }
-        }
-        _ => {
-            if let Ok(Some(mut f)) = open_for_stdout(&stage.stdout) {
-                let _ = f.write_all(&out);
-                let _ = f.flush();
+        };
+        let mut users = Vec::new();
+        unsafe {
+            libc::setpwent();
+            loop {
+                let pw = libc::getpwent();
+                if pw.is_null() {
+                    break;
+                }
+                users.push((field((*pw).pw_name), field((*pw).pw_shell)));
             }
+            libc::endpwent();
         }
-    }
+        users.sort();
+        users.dedup();
+        users
+    })
 }
 
-// ---------- FULL pipeline execution (supports N stages, builtins + externals) ----------
-fn execute_pipeline(stages: &[ParsedCommand], history_vec: &[String]) {
-    if stages.is_empty() {
-        return;
-    }
-
-    // Create N-1 pipes as (Option<read>, Option<write>) so we can take() ownership
-    let mut pipes: Vec<(Option<File>, Option<File>)> = Vec::new();
-    for _ in 0..stages.len().saturating_sub(1) {
-        match make_pipe() {
-            Ok((r, w)) => pipes.push((Some(r), Some(w))),
-            Err(e) => {
-                eprintln!("pipe: {e}");
-                return;
+#[cfg(not(unix))]
+fn passwd_users() -> &'static [(String, String)] {
+    PASSWD_USERS.get_or_init(Vec::new)
+}
+
+// `~name` for each user whose name continues the word after `~`, completed
+// like a directory; `hide_system` skips accounts whose shell is nologin or false
+fn user_candidates(prefix: &str, hide_system: bool) -> Vec<Candidate> {
+    let Some(partial) = prefix.strip_prefix('~') else {
+        return Vec::new();
+    };
+    let no_login = |shell: &str| shell.ends_with("/nologin") || shell.ends_with("/false");
+    passwd_users()
+        .iter()
+        .filter(|(name, shell)| name.starts_with(partial) && !(hide_system && no_login(shell)))
+        .map(|(name, _)| Candidate::new(format!("~{name}"), CandidateKind::Directory))
+        .collect()
+}
+
+// ---------- job completion ----------
+const SIGNAL_NAMES: &[&str] = &[
+    "ABRT", "ALRM", "CHLD", "CONT", "HUP", "INT", "KILL", "PIPE", "QUIT", "STOP", "TERM", "TSTP", "TTIN", "TTOU",
+    "USR1", "USR2", "WINCH",
+];
+
+// `%n` jobspecs from the job table (described by their command line); after
+// kill also -SIGNAL names, and the PIDs of the jobs' processes
+fn complete_job_arg(words: &[String], current: &str) -> Vec<String> {
+    let kill = words.first().is_some_and(|w| w == "kill");
+    if kill && current.starts_with('-') {
+        return SIGNAL_NAMES.iter().map(|name| format!("-{name}")).collect();
+    }
+    let table = job_table();
+    let mut out = Vec::new();
+    if current.is_empty() || current.starts_with('%') {
+        out.extend(table.jobs.iter().map(|job| format!("%{}\t{}", job.id, job.command)));
+    }
+    if kill && current.chars().all(|c| c.is_ascii_digit()) {
+        for job in &table.jobs {
+            for stage in &job.stages {
+                if let StageHandle::External(child) = stage {
+                    out.push(format!("{}\t{}", child.id(), job.command));
+                }
             }
         }
     }
+    out
+}
 
-    let mut children: Vec<Child> = Vec::new();
-    let mut builtin_threads: Vec<std::thread::JoinHandle<()>> = Vec::new();
+// ---------- variable name completion ----------
+// Names of variables that aren't in the environment (arrays, $RANDOM and the
+// like), refreshed before each prompt and each command line
+static SHELL_VARIABLES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// Values of the unexported scalars among them, for code that has no
+// ShellContext at hand: completion, the audit log, the slow-command report
+static SHELL_VALUES: Mutex<std::collections::BTreeMap<String, String>> = Mutex::new(std::collections::BTreeMap::new());
+// Shell functions by name, for `complete -F`
+static SHELL_FUNCTIONS: Mutex<std::collections::BTreeMap<String, Vec<String>>> = Mutex::new(std::collections::BTreeMap::new());
 
-    for i in 0..stages.len() {
-        let stage = stages[i].clone();
-        let is_first = i == 0;
-        let is_last = i + 1 == stages.len();
+fn publish_shell_variables(ctx: &ShellContext) {
+    let mut names: Vec<String> = ctx.arrays.keys().cloned().collect();
+    names.push("BASH_ALIASES".to_string());
+    names.extend(DYNAMIC_VARS.iter().map(|(name, _)| name.to_string()));
+    names.extend(ctx.variables.keys().cloned());
+    *SHELL_VARIABLES.lock().unwrap_or_else(|e| e.into_inner()) = names;
+    let values = ctx.variables.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    *SHELL_VALUES.lock().unwrap_or_else(|e| e.into_inner()) = values;
+    let functions = ctx.functions.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    *SHELL_FUNCTIONS.lock().unwrap_or_else(|e| e.into_inner()) = functions;
+}
 
-        // stdin: take read-end of previous pipe
-        let stdin_file: Option<File> = if is_first {
-            None
+// A variable as last published, exported or not
+fn shell_setting(name: &str) -> Option<String> {
+    let value = SHELL_VALUES.lock().unwrap_or_else(|e| e.into_inner()).get(name).cloned();
+    value.or_else(|| env::var_os(name).map(|v| decode_os(&v)))
+}
+
+// Variable names starting with `prefix`, sorted. Names starting with `_` are
+// left out when others match, unless the prefix asks for them or
+// $COMPLETE_HIDDEN_VARS is set.
+fn variable_names(prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = env::vars_os().map(|(name, _)| decode_os(&name)).collect();
+    names.extend(SHELL_VARIABLES.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned());
+    names.retain(|name| name.starts_with(prefix) && is_identifier(name));
+    names.sort();
+    names.dedup();
+    let show_hidden = prefix.starts_with('_') || shell_setting("COMPLETE_HIDDEN_VARS").is_some_and(|v| !v.is_empty());
+    if !show_hidden && names.iter().any(|name| !name.starts_with('_')) {
+        names.retain(|name| !name.starts_with('_'));
+    }
+    names
+}
+
+// Where the variable name being typed after `$` or `${` starts, and whether
+// it was `${`; None when the cursor isn't in one
+fn variable_reference(before: &str) -> Option<(usize, bool)> {
+    let start = before.trim_end_matches(|c: char| c == '_' || c.is_ascii_alphanumeric()).len();
+    if before[start..].starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let head = &before[..start];
+    if head.ends_with("${") {
+        return Some((start, true));
+    }
+    (head.ends_with('$') && !head.ends_with("\\$")).then_some((start, false))
+}
+
+// Variable names for export / unset / readonly; export offers `NAME=` so the
+// value can follow at once
+fn complete_variable_name(words: &[String], current: &str) -> Vec<String> {
+    if current.contains('=') {
+        return Vec::new();
+    }
+    let names = variable_names(current);
+    let assign = words.first().is_some_and(|w| w == "export");
+    names.into_iter().map(|name| if assign { format!("{name}=") } else { name }).collect()
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        if let Some(pair) = self.next_history_entry(&line[..pos]) {
+            return Ok((0, vec![pair]));
+        }
+        // After `$` or `${` only the name is completed
+        let variable = variable_reference(&line[..pos]);
+        let start = variable.map_or_else(|| current_word_start(&line[..pos]), |(start, _)| start);
+
+        // Candidates are matched against the word as the shell will see it
+        // (quotes/escapes removed) and quoted again on insertion
+        let typed = tokenize_literal(&line[start..pos]);
+        let unquoted = typed.first().map(|t| t.to_string()).unwrap_or_default();
+        let prefix = unquoted.as_str();
+
+        let tokens = tokenize_literal(&line[..start]);
+        let stage_start = tokens
+            .iter()
+            .rposition(|t| t.is_op("|"))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let stage = &tokens[stage_start..];
+
+        // First word of a stage: builtins + PATH executables; later words:
+        // per-command specs or file names
+        let mut matches = if let Some((_, braced)) = variable {
+            let kind = if braced { CandidateKind::BracedName } else { CandidateKind::Word };
+            variable_names(prefix).into_iter().map(|text| Candidate::new(text, kind)).collect()
+        } else if prefix.starts_with('~') && !prefix.contains('/') {
+            user_candidates(prefix, self.hide_system_users)
+        } else if stage.is_empty() {
+            if prefix.is_empty() {
+                return Ok((pos, vec![]));
+            }
+            command_candidates(self.lookup_prefix(prefix))
         } else {
-            pipes[i - 1].0.take()
+            self.argument_candidates(stage, prefix, line, pos)
         };
+        matches.retain(|m| self.matches_prefix(&m.text, prefix));
 
-        // stdout pipe: take write-end of current pipe (if not last)
-        let stdout_pipe: Option<File> = if is_last { None } else { pipes[i].1.take() };
+        matches.sort();
+        matches.dedup_by(|a, b| a.text == b.text);
 
-        if is_builtin(&stage.cmd) {
-            let hist_snapshot = history_vec.to_vec();
-            let h = std::thread::spawn(move || {
-                builtin_stage_run(stage, stdin_file, stdout_pipe, hist_snapshot);
-            });
-            builtin_threads.push(h);
-            continue;
+        // Whole earlier commands extending what's typed, listed once the
+        // word itself can't be completed any further
+        let history = history_candidates(ctx.history(), &line[..pos]);
+
+        if matches.is_empty() && history.is_empty() {
+            let mut st = self.state.borrow_mut();
+            st.last_prefix = None;
+            st.armed_for_list = false;
+            return Ok((pos, vec![]));
         }
 
-        // External
-        if find_executable_in_path(&stage.cmd).is_none() {
-            eprintln!("{}: command not found", stage.cmd);
-            return;
+        if matches.len() == 1 {
+            let mut st = self.state.borrow_mut();
+            st.last_prefix = None;
+            st.armed_for_list = false;
+
+            let m = &matches[0];
+            return Ok((
+                start,
+                vec![Pair {
+                    display: display_str(&m.text),
+                    replacement: format!("{}{}", quote_word(&m.text), m.suffix()),
+                }],
+            ));
         }
 
-        let mut cmd = Command::new(&stage.cmd);
-        cmd.args(&stage.args);
+        let texts: Vec<String> = matches.iter().map(|m| m.text.clone()).collect();
+        let lcp = longest_common_prefix(&texts);
+        if lcp.len() > prefix.len() {
+            let mut st = self.state.borrow_mut();
+            st.last_prefix = None;
+            st.armed_for_list = false;
 
-        // stdin
-        if let Some(f) = stdin_file {
-            cmd.stdin(Stdio::from(f));
-        } else {
-            cmd.stdin(Stdio::inherit());
+            return Ok((
+                start,
+                vec![Pair {
+                    display: display_str(&lcp),
+                    replacement: quote_word(&lcp),
+                }],
+            ));
         }
 
-        // stdout
-        if let Some(f) = stdout_pipe {
-            // Not last stage: connect to pipe
-            cmd.stdout(Stdio::from(f));
-        } else {
-            // Last stage: honor stdout redirection
-            match &stage.stdout {
-                StdoutRedirect::Inherit => {
-                    cmd.stdout(Stdio::inherit());
-                }
-                _ => match open_for_stdout(&stage.stdout) {
-                    Ok(Some(f)) => {
-                        cmd.stdout(Stdio::from(f));
-                    }
-                    Ok(None) => {
-                        cmd.stdout(Stdio::inherit());
-                    }
-                    Err(e) => {
-                        eprintln!("{}: {e}", stage.cmd);
-                        return;
-                    }
-                },
+        let mut st = self.state.borrow_mut();
+        if st.last_prefix.as_deref() == Some(prefix) && st.armed_for_list {
+            st.armed_for_list = false;
+
+            // LIST_MAX hard-limits how many candidates get listed
+            if let Some(max) = shell_setting("LIST_MAX").and_then(|v| v.parse::<usize>().ok()) {
+                matches.truncate(max);
             }
-        }
 
-        // stderr
-        match &stage.stderr {
-            StderrRedirect::Inherit => {
-                cmd.stderr(Stdio::inherit());
+            if !history.is_empty() {
+                drop(st);
+                return Ok(self.list_with_history(&matches, &history, line, start, pos));
             }
-            _ => match open_for_stderr(&stage.stderr) {
-                Ok(Some(f)) => {
-                    cmd.stderr(Stdio::from(f));
-                }
-                Ok(None) => {
-                    cmd.stderr(Stdio::inherit());
-                }
-                Err(e) => {
-                    eprintln!("{}: {e}", stage.cmd);
-                    return;
-                }
-            },
-        }
 
-        match cmd.spawn() {
-            Ok(child) => children.push(child),
-            Err(e) => {
-                eprintln!("{}: {e}", stage.cmd);
-                return;
+            // More candidates than we'd list unasked or than fit on screen:
+            // lay them out and page them ourselves
+            let words: Vec<String> = matches.iter().map(Candidate::listed).collect();
+            let entries = column_rows(&words, active_theme().candidate);
+            let too_tall = terminal_rows().is_some_and(|rows| entries.len() > rows.saturating_sub(2));
+            if too_tall || matches.len() > self.query_items {
+                self.page_listing(&entries, matches.len(), line, pos);
+                return Ok((pos, vec![]));
             }
+
+            let pairs: Vec<Pair> = matches
+                .into_iter()
+                .map(|m| Pair {
+                    display: m.listed(),
+                    replacement: quote_word(&m.text),
+                })
+                .collect();
+
+            Ok((start, pairs))
+        } else {
+            st.last_prefix = Some(prefix.to_string());
+            st.armed_for_list = true;
+            Ok((pos, vec![]))
         }
     }
+}
 
-    // Drop any remaining pipe ends still owned by parent
-    drop(pipes);
+// ---------- terminal helpers ----------
+// Terminal height: $LINES if set, else the window size of stdout
+fn terminal_rows() -> Option<usize> {
+    if let Some(n) = shell_setting("LINES").and_then(|v| v.parse::<usize>().ok()) {
+        return Some(n);
+    }
+    #[cfg(unix)]
+    {
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        let rc = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+        if rc == 0 && ws.ws_row > 0 {
+            return Some(ws.ws_row as usize);
+        }
+    }
+    None
+}
 
-    // Join builtin threads
-    for h in builtin_threads {
-        let _ = h.join();
+// Terminal width: $COLUMNS if set, else the window size of stdout
+fn terminal_cols() -> Option<usize> {
+    if let Some(n) = shell_setting("COLUMNS").and_then(|v| v.parse::<usize>().ok()) {
+        return Some(n);
+    }
+    #[cfg(unix)]
+    {
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        let rc = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) };
+        if rc == 0 && ws.ws_col > 0 {
+            return Some(ws.ws_col as usize);
+        }
     }
+    None
+}
+
+// Candidates in as many columns as fit the terminal, filled top to bottom
+// like readline does; one painted string per screen row
+fn column_rows(words: &[String], color: Option<Color>) -> Vec<String> {
+    let width = terminal_cols().unwrap_or(80);
+    let col_width = words.iter().map(|w| w.chars().count()).max().unwrap_or(0) + 2;
+    let cols = (width / col_width).max(1);
+    let rows = words.len().div_ceil(cols);
+
+    (0..rows)
+        .map(|r| {
+            let mut row = String::new();
+            for (c, word) in words.iter().skip(r).step_by(rows.max(1)).enumerate() {
+                row.push_str(&paint(word, color));
+                // Pad all but the last column
+                if (c + 1) * rows + r < words.len() {
+                    row.push_str(&" ".repeat(col_width - word.chars().count()));
+                }
+            }
+            row
+        })
+        .collect()
+}
+
+// One raw byte from the terminal (rustyline already has it in raw mode
+// while completing); None on EOF/error. Reads the fd directly so nothing is
+// left sitting in std's stdin buffer where rustyline can't see it.
+#[cfg(unix)]
+fn read_key() -> Option<u8> {
+    let mut b = 0u8;
+    let n = unsafe { libc::read(libc::STDIN_FILENO, (&mut b as *mut u8).cast(), 1) };
+    (n == 1).then_some(b)
+}
 
-    // Wait external children
-    for mut c in children {
-        let _ = c.wait();
+#[cfg(not(unix))]
+fn read_key() -> Option<u8> {
+    let mut b = [0u8; 1];
+    match io::stdin().read(&mut b) {
+        Ok(1) => Some(b[0]),
+        _ => None,
     }
 }
 
-fn main() {
-    let config = Config::builder()
-        .completion_type(CompletionType::List)
-        .completion_show_all_if_ambiguous(true)
-        .build();
+// ---------- PATH helper for execution ----------
+// A regular file the current user may actually execute (mode bits alone can lie,
+// e.g. a 0700 file owned by someone else)
+fn is_executable_file(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        unsafe { libc::access(c_path.as_ptr(), libc::X_OK) == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_executable()
+    }
+}
 
-    let mut rl: Editor<ShellHelper, DefaultHistory> = Editor::with_config(config).unwrap();
-    rl.set_helper(Some(ShellHelper::new()));
+fn find_executable_in_path(name: &str) -> Option<PathBuf> {
+    match resolve_command(name) {
+        CommandLookup::Found(p) => Some(p),
+        _ => None,
+    }
+}
 
-    // Our own history list for the "history" builtin output (must include invalid commands + history itself)
-    let mut history_vec: Vec<String> = Vec::new();
+#[derive(Debug)]
+enum CommandLookup {
+    Found(PathBuf),
+    NotExecutable,
+    IsDirectory,
+    NotFound,
+}
+
+// Resolve a command name the way execve + PATH search would, keeping enough
+// detail to report why it can't be run
+fn resolve_command(name: &str) -> CommandLookup {
+    let file_name = encode_os(name);
+    // Names with a slash are paths, no PATH search
+    if name.contains('/') {
+        let path = Path::new(&file_name);
+        return if path.is_dir() {
+            CommandLookup::IsDirectory
+        } else if is_executable_file(path) {
+            CommandLookup::Found(path.to_path_buf())
+        } else if path.exists() {
+            CommandLookup::NotExecutable
+        } else {
+            CommandLookup::NotFound
+        };
+    }
+
+    let Some(paths) = env::var_os("PATH") else {
+        return CommandLookup::NotFound;
+    };
+    let mut saw_non_executable = false;
+    for dir in env::split_paths(&paths) {
+        let candidate = dir.join(&file_name);
+        if is_executable_file(&candidate) {
+            return CommandLookup::Found(candidate);
+        }
+        if candidate.is_file() {
+            saw_non_executable = true;
+        }
+    }
+    if saw_non_executable {
+        CommandLookup::NotExecutable
+    } else {
+        CommandLookup::NotFound
+    }
+}
+
+// Print why `name` couldn't be run and return the matching exit status
+fn report_unrunnable(name: &str, lookup: &CommandLookup) -> i32 {
+    match lookup {
+        CommandLookup::Found(_) => 0,
+        CommandLookup::NotExecutable => {
+            print_error(&format!("{name}: Permission denied"));
+            126
+        }
+        CommandLookup::IsDirectory => {
+            print_error(&format!("{name}: Is a directory"));
+            126
+        }
+        CommandLookup::NotFound if name.contains('/') => {
+            print_error(&format!("{name}: No such file or directory"));
+            127
+        }
+        CommandLookup::NotFound => {
+            print_error(&format!("{name}: command not found"));
+            127
+        }
+    }
+}
+
+// Spawn errors that slip past resolve_command (races, bad interpreters, ...)
+fn report_spawn_error(name: &str, e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => {
+            print_error(&format!("{name}: Permission denied"));
+            126
+        }
+        io::ErrorKind::NotFound => {
+            print_error(&format!("{name}: No such file or directory"));
+            127
+        }
+        _ => {
+            print_error(&format!("{name}: {e}"));
+            126
+        }
+    }
+}
+
+// Command for a resolved executable, keeping the typed name as argv[0] and
+// with its path in $_, as bash passes it
+fn external_command(name: &str, path: &Path) -> Command {
+    let mut cmd = Command::new(path);
+    cmd.env("_", path);
+    #[cfg(unix)]
+    cmd.arg0(name);
+    cmd
+}
+
+// ---------- home directory ----------
+// $HOME if set and non-empty, otherwise the passwd entry of the current user.
+// Everything that needs "~" goes through here; None means callers should
+// degrade (skip the feature / report "HOME not set") rather than guess.
+fn home_dir() -> Option<PathBuf> {
+    match env::var_os("HOME") {
+        Some(h) if !h.is_empty() => Some(PathBuf::from(h)),
+        _ => passwd_home(None),
+    }
+}
+
+// Home directory from the passwd database: `user`, or the current uid when None
+#[cfg(unix)]
+fn passwd_home(user: Option<&str>) -> Option<PathBuf> {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::os::unix::ffi::OsStrExt;
 
+    let c_user = match user {
+        Some(u) => Some(CString::new(u).ok()?),
+        None => None,
+    };
+
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
     loop {
-        let line = match rl.readline("$ ") {
-            Ok(l) => l,
-            Err(ReadlineError::Interrupted) => continue,
-            Err(ReadlineError::Eof) => break,
-            Err(e) => {
-                eprintln!("readline error: {e}");
-                break;
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let rc = unsafe {
+            match &c_user {
+                Some(name) => libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                ),
+                None => libc::getpwuid_r(
+                    libc::getuid(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                ),
             }
         };
 
-        let line = line.trim_end().to_string();
-        if line.is_empty() {
+        if rc == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
             continue;
         }
+        if rc != 0 || result.is_null() || pwd.pw_dir.is_null() {
+            return None;
+        }
 
-        // Add to rustyline history so up/down arrows work
-        let _ = rl.add_history_entry(line.as_str());
+        let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+        if dir.to_bytes().is_empty() {
+            return None;
+        }
+        return Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())));
+    }
+}
 
-        // Add to our command history so "history" builtin prints what tester expects
-        history_vec.push(line.clone());
+#[cfg(not(unix))]
+fn passwd_home(_user: Option<&str>) -> Option<PathBuf> {
+    None
+}
 
-        let tokens = tokenize(&line);
-        let Some(chunks) = split_pipeline(&tokens) else { continue };
+// ---------- prompt info ----------
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if rc != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    decode_bytes(&buf[..len])
+}
 
-        let mut stages: Vec<ParsedCommand> = Vec::new();
-        for chunk in chunks {
-            let Some(pc) = parse_command(&chunk) else {
-                stages.clear();
-                break;
-            };
-            stages.push(pc);
-        }
-        if stages.is_empty() {
-            continue;
-        }
+#[cfg(not(unix))]
+fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
 
-        // SINGLE COMMAND: parent effects + builtins + externals
-        if stages.len() == 1 {
-            let s = &stages[0];
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
 
-            if s.cmd == "exit" {
-                break;
-            }
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
 
-            if s.cmd == "cd" {
-                if s.args.is_empty() {
-                    continue;
-                }
-                let dest = s.args[0].as_str();
-                let target = if dest == "~" {
-                    match env::home_dir() {
-                        Some(h) => h,
-                        None => {
-                            eprintln!("cd: ~: No such file or directory");
-                            continue;
-                        }
-                    }
-                } else {
-                    Path::new(dest).to_path_buf()
-                };
+// ---------- raw bytes in shell strings ----------
+// Words are Strings, but file names and arguments may not be UTF-8. Bytes that
+// don't decode are carried as chars U+10FF80..=U+10FFFF (one per byte, like
+// Python's surrogateescape) and turned back into the exact bytes whenever a
+// string crosses into the OS (argv, paths, env). Only display is lossy. Real
+// U+10FF80.. chars in valid input are escaped byte by byte too, so they can't
+// be mistaken for raw bytes on the way out; typed text goes through
+// escape_raw_chars for the same reason.
+const RAW_BYTE_BASE: u32 = 0x10_FF00;
 
-                if env::set_current_dir(&target).is_err() {
-                    eprintln!("cd: {}: No such file or directory", dest);
-                }
-                continue;
-            }
+fn raw_byte_char(b: u8) -> char {
+    char::from_u32(RAW_BYTE_BASE + b as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
 
-            if is_builtin(&s.cmd) {
-                let (out, err, _code) = builtin_bytes(&s.cmd, &s.args, &history_vec);
-                write_routed_output(&out, &err, &s.stdout, &s.stderr, &s.cmd);
-                continue;
-            }
+fn raw_byte_of(ch: char) -> Option<u8> {
+    let v = ch as u32;
+    (RAW_BYTE_BASE + 0x80..=RAW_BYTE_BASE + 0xFF)
+        .contains(&v)
+        .then(|| (v - RAW_BYTE_BASE) as u8)
+}
 
-            run_single_external(s);
-            continue;
+fn decode_bytes(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            match raw_byte_of(ch) {
+                Some(_) => out.extend(ch.encode_utf8(&mut [0; 4]).bytes().map(raw_byte_char)),
+                None => out.push(ch),
+            }
         }
+        out.extend(chunk.invalid().iter().map(|&b| raw_byte_char(b)));
+    }
+    out
+}
+
+fn escape_raw_chars(s: &str) -> String {
+    decode_bytes(s.as_bytes())
+}
+
+fn encode_bytes(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    for ch in s.chars() {
+        match raw_byte_of(ch) {
+            Some(b) => out.push(b),
+            None => out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    out
+}
+
+#[cfg(unix)]
+fn decode_os(os: &std::ffi::OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    decode_bytes(os.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn decode_os(os: &std::ffi::OsStr) -> String {
+    os.to_string_lossy().into_owned()
+}
+
+#[cfg(unix)]
+fn encode_os(s: &str) -> std::ffi::OsString {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(encode_bytes(s))
+}
+
+#[cfg(not(unix))]
+fn encode_os(s: &str) -> std::ffi::OsString {
+    display_str(s).into()
+}
+
+// Human-readable form: escaped bytes become U+FFFD
+fn display_str(s: &str) -> String {
+    s.chars()
+        .map(|c| match raw_byte_of(c) {
+            Some(_) => char::REPLACEMENT_CHARACTER,
+            None => c,
+        })
+        .collect()
+}
+
+// Quote a word so `tokenize` reads it back unchanged: backslash-escape shell
+// metacharacters, or use $'...' when it holds raw bytes / control chars
+fn quote_word(s: &str) -> String {
+    use std::fmt::Write as _;
+
+    if s.chars().any(|c| raw_byte_of(c).is_some() || c.is_control()) {
+        let mut out = String::from("$'");
+        for ch in s.chars() {
+            match (raw_byte_of(ch), ch) {
+                (Some(b), _) => {
+                    let _ = write!(out, "\\x{b:02x}");
+                }
+                (None, '\'') => out.push_str("\\'"),
+                (None, '\\') => out.push_str("\\\\"),
+                (None, '\n') => out.push_str("\\n"),
+                (None, '\t') => out.push_str("\\t"),
+                (None, c) if c.is_control() => {
+                    let _ = write!(out, "\\x{:02x}", c as u32);
+                }
+                (None, c) => out.push(c),
+            }
+        }
+        out.push('\'');
+        return out;
+    }
+
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        if ch.is_whitespace() || "'\"\\|<>&;$`*?()[]{}#!".contains(ch) {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+// Body of a $'...' word (opening quote already consumed): C-style escapes,
+// \xHH and \0NNN may produce arbitrary bytes
+fn read_ansi_c_quoted(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> String {
+    let mut bytes: Vec<u8> = Vec::new();
+    let push_char = |bytes: &mut Vec<u8>, c: char| {
+        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+    };
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' => break,
+            '\\' => {
+                let Some(esc) = chars.next() else {
+                    bytes.push(b'\\');
+                    break;
+                };
+                match esc {
+                    'n' => bytes.push(b'\n'),
+                    't' => bytes.push(b'\t'),
+                    'r' => bytes.push(b'\r'),
+                    'a' => bytes.push(0x07),
+                    'b' => bytes.push(0x08),
+                    'e' | 'E' => bytes.push(0x1b),
+                    'f' => bytes.push(0x0c),
+                    'v' => bytes.push(0x0b),
+                    '\\' | '\'' | '"' | '?' => push_char(&mut bytes, esc),
+                    'x' => {
+                        let mut v: u32 = 0;
+                        let mut n = 0;
+                        while n < 2 {
+                            match chars.peek().and_then(|c| c.to_digit(16)) {
+                                Some(d) => {
+                                    v = v * 16 + d;
+                                    chars.next();
+                                    n += 1;
+                                }
+                                None => break,
+                            }
+                        }
+                        if n == 0 {
+                            bytes.extend_from_slice(b"\\x");
+                        } else {
+                            bytes.push(v as u8);
+                        }
+                    }
+                    '0'..='7' => {
+                        let mut v = esc.to_digit(8).unwrap_or(0);
+                        let mut n = 1;
+                        while n < 3 {
+                            match chars.peek().and_then(|c| c.to_digit(8)) {
+                                Some(d) => {
+                                    v = v * 8 + d;
+                                    chars.next();
+                                    n += 1;
+                                }
+                                None => break,
+                            }
+                        }
+                        bytes.push(v as u8);
+                    }
+                    other => {
+                        bytes.push(b'\\');
+                        push_char(&mut bytes, other);
+                    }
+                }
+            }
+            c => push_char(&mut bytes, c),
+        }
+    }
+    decode_bytes(&bytes)
+}
+
+// ---------- tokenization (supports quotes + backslash + operator tokens) ----------
+// Operators are kept apart from words so a quoted or escaped `>` / `|` stays a
+// literal argument.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Op(String),
+    // A redirection target, as written, that expanded to no word or several
+    Ambiguous(String),
+    // The message of an expansion that failed; lex returns only this
+    Failed(String),
+}
+
+impl Token {
+    fn as_word(&self) -> Option<&str> {
+        match self {
+            Token::Word(w) => Some(w),
+            Token::Op(_) | Token::Ambiguous(_) | Token::Failed(_) => None,
+        }
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self, Token::Op(o) if o == op)
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(w) | Token::Op(w) | Token::Ambiguous(w) | Token::Failed(w) => f.write_str(w),
+        }
+    }
+}
+
+// Words as the shell sees them: quotes removed, an unquoted leading `~`
+// and `$` parameters expanded
+fn tokenize(line: &str, ctx: &ShellContext) -> Vec<Token> {
+    lex(line, Some(ctx))
+}
+
+// How many unquoted `!` words start the line: `! pipeline` inverts the
+// pipeline's status, `! ! pipeline` inverts it back (as 0 or 1); also the
+// rest of the line
+fn leading_negations(line: &str) -> (usize, &str) {
+    let mut rest = line.trim_start();
+    let mut count = 0;
+    while let Some(after) = rest.strip_prefix('!') {
+        if !after.is_empty() && !after.starts_with(char::is_whitespace) {
+            break;
+        }
+        count += 1;
+        rest = after.trim_start();
+    }
+    (count, rest)
+}
+
+// Quotes removed but nothing expanded: the words as the user typed them
+fn tokenize_literal(line: &str) -> Vec<Token> {
+    lex(line, None)
+}
+
+// `~` / `~name` / `~user` at the start of a word up to the first `/`, named
+// directories first; None leaves the word alone (unknown user, no home)
+fn expand_tilde_prefix(prefix: &str, named_dirs: &NamedDirs) -> Option<String> {
+    let home = match prefix.strip_prefix('~')? {
+        "" => home_dir()?,
+        name => match named_dirs.get(name) {
+            Some(dir) => dir.clone(),
+            None => passwd_home(Some(name))?,
+        },
+    };
+    Some(decode_os(home.as_os_str()))
+}
+
+// `ctx` is None for literal lexing (no tilde or parameter expansion)
+fn lex(line: &str, ctx: Option<&ShellContext>) -> Vec<Token> {
+    let named_dirs = ctx.map(|c| &c.named_dirs);
+    let mut args: Vec<Token> = Vec::new();
+    let mut current = String::new();
+    // true once any quote/escape contributed to `current` (so `"2">f` isn't `2>`)
+    let mut current_quoted = false;
+    // Quotes in the word, so it stays a word even if empty (`''`, `"$x"`),
+    // unless all it held was a `"$@"` with no parameters
+    let mut keep_empty = false;
+    // Still inside an unquoted `~user` prefix at the start of the word
+    let mut in_tilde = false;
+    // Parameter that made a redirection target several words, or none
+    let mut split_target: Option<String> = None;
+    let mut empty_target: Option<String> = None;
+    // Byte offsets in `current` of unquoted `*`, `?` and `[`
+    let mut glob_at: Vec<usize> = Vec::new();
+    let options = ctx.map(|c| &c.options);
+    let mut failed: Option<String> = None;
+
+    let mut in_single = false;
+    let mut in_double = false;
+    let mut backslash = false;
+
+    let dq_escapable = ['\\', '"', '$', '`'];
+
+    let mut chars = line.chars().peekable();
+    let mut prev: Option<char> = None;
+    while let Some(ch) = chars.next() {
+        let before = prev.replace(ch);
+        if backslash {
+            if in_single {
+                current.push('\\');
+                current.push(ch);
+            } else if in_double {
+                if dq_escapable.contains(&ch) {
+                    current.push(ch);
+                } else {
+                    current.push('\\');
+                    current.push(ch);
+                }
+            } else {
+                current.push(ch);
+            }
+            current_quoted = true;
+            keep_empty = true;
+            backslash = false;
+            continue;
+        }
+
+        if ch == '\\' && !in_single {
+            backslash = true;
+            in_tilde = false;
+            continue;
+        }
+
+        // $'...' (ANSI-C quoting)
+        if ch == '$' && !in_single && !in_double && chars.peek() == Some(&'\'') {
+            chars.next();
+            current.push_str(&read_ansi_c_quoted(&mut chars));
+            current_quoted = true;
+            keep_empty = true;
+            in_tilde = false;
+            continue;
+        }
+
+        if let (Some(ctx), '$', false) = (ctx, ch, in_single) {
+            if let Some(expr) = read_parameter(&mut chars) {
+                let values = parameter_values(&expr, ctx).unwrap_or_else(|msg| {
+                    failed.get_or_insert(msg);
+                    Vec::new()
+                });
+                // Unquoted values are split into words on blanks
+                let fields: Vec<&str> = if in_double {
+                    keep_empty &= !values.is_empty();
+                    values.iter().map(String::as_str).collect()
+                } else {
+                    values.iter().flat_map(|v| v.split_whitespace()).collect()
+                };
+                // A redirection target must stay one word
+                let target = matches!(args.last(), Some(Token::Op(op)) if op.contains(['<', '>']));
+                match fields.len() {
+                    0 if target => empty_target = Some(parameter_text(&expr)),
+                    1 => {}
+                    _ if target => split_target = Some(parameter_text(&expr)),
+                    _ => {}
+                }
+                for (i, field) in fields.into_iter().enumerate() {
+                    if i > 0 && split_target.is_none() {
+                        args.push(Token::Word(std::mem::take(&mut current)));
+                        glob_at.clear();
+                    }
+                    current.push_str(field);
+                }
+                current_quoted = true;
+                in_tilde = false;
+                continue;
+            }
+        }
+
+        if ch == '\'' && !in_double {
+            in_single = !in_single;
+            current_quoted = true;
+            keep_empty = true;
+            in_tilde = false;
+            continue;
+        }
+        if ch == '"' && !in_single {
+            // Opening quotes only: the closing one mustn't undo a `"$@"`
+            keep_empty |= !in_double;
+            in_double = !in_double;
+            current_quoted = true;
+            in_tilde = false;
+            continue;
+        }
+
+        if in_tilde && (ch == '/' || ch.is_whitespace() || matches!(ch, '|' | '>' | '<' | '&')) {
+            if let Some(home) = named_dirs.and_then(|d| expand_tilde_prefix(&current, d)) {
+                current = home;
+                glob_at.clear();
+            }
+            in_tilde = false;
+        }
+
+        if !in_single && !in_double && matches!(ch, '|' | '>' | '<') {
+            // `2>` / `1>>` / `3<`: a bare fd digit directly before the operator belongs to it
+            let mut op = String::new();
+            let fd_digit = current.len() == 1 && current.starts_with(|c: char| c.is_ascii_digit());
+            if ch != '|' && !current_quoted && fd_digit {
+                op = std::mem::take(&mut current);
+            } else {
+                end_word(&mut args, &mut current, keep_empty, &mut split_target, &mut empty_target, &mut glob_at, options);
+            }
+            current_quoted = false;
+            keep_empty = false;
+
+            op.push(ch);
+            if ch == '>' && chars.peek() == Some(&'>') {
+                chars.next();
+                op.push('>');
+            }
+            args.push(Token::Op(op));
+            continue;
+        }
+
+        // `&` / `&&`, except right after `>` or `<` where it belongs to the
+        // target (`2>&1`, `<&3`)
+        let glued_to_redirect = current.is_empty() && matches!(before, Some('>' | '<'));
+        if !in_single && !in_double && ch == '&' && !glued_to_redirect {
+            end_word(&mut args, &mut current, keep_empty, &mut split_target, &mut empty_target, &mut glob_at, options);
+            current_quoted = false;
+            keep_empty = false;
+            if chars.peek() == Some(&'&') {
+                chars.next();
+                args.push(Token::Op("&&".to_string()));
+            } else {
+                args.push(Token::Op("&".to_string()));
+            }
+            continue;
+        }
+
+        if !in_single && !in_double && ch.is_whitespace() {
+            end_word(&mut args, &mut current, keep_empty, &mut split_target, &mut empty_target, &mut glob_at, options);
+            current_quoted = false;
+            keep_empty = false;
+            continue;
+        }
+
+        if named_dirs.is_some() && ch == '~' && current.is_empty() && !current_quoted && !in_single && !in_double {
+            in_tilde = true;
+        }
+        if !in_single && !in_double && matches!(ch, '*' | '?' | '[') {
+            glob_at.push(current.len());
+        }
+        current.push(ch);
+    }
+
+    if in_tilde {
+        if let Some(home) = named_dirs.and_then(|d| expand_tilde_prefix(&current, d)) {
+            current = home;
+        }
+    }
+    if backslash {
+        current.push('\\');
+    }
+    end_word(&mut args, &mut current, keep_empty, &mut split_target, &mut empty_target, &mut glob_at, options);
+
+    match failed {
+        Some(msg) => vec![Token::Failed(msg)],
+        None => args,
+    }
+}
+
+// Ends the word being built, replacing a pattern by the paths it matches. A
+// redirection target that split into several words, or came to nothing, is
+// kept as written for parse_command to refuse. An empty word only counts
+// when `keep_empty` (it was quoted).
+fn end_word(
+    args: &mut Vec<Token>,
+    current: &mut String,
+    keep_empty: bool,
+    split: &mut Option<String>,
+    empty: &mut Option<String>,
+    glob_at: &mut Vec<usize>,
+    options: Option<&ShellOptions>,
+) {
+    let meta = std::mem::take(glob_at);
+    let empty = empty.take().filter(|_| current.is_empty());
+    match split.take().or(empty) {
+        Some(text) => {
+            current.clear();
+            args.push(Token::Ambiguous(text));
+        }
+        None if !current.is_empty() || keep_empty => {
+            let word = std::mem::take(current);
+            let mut paths = match options {
+                Some(options) if !meta.is_empty() && !options.noglob => glob_word(&word, &meta, options),
+                _ => Vec::new(),
+            };
+            let target = matches!(args.last(), Some(Token::Op(op)) if op.contains(['<', '>']));
+            match paths.len() {
+                0 => args.push(Token::Word(word)),
+                1 => args.push(Token::Word(paths.remove(0))),
+                _ if target => args.push(Token::Ambiguous(word)),
+                _ => args.extend(paths.into_iter().map(Token::Word)),
+            }
+        }
+        None => {}
+    }
+}
+
+// How a parameter read by read_parameter was written
+fn parameter_text(expr: &str) -> String {
+    let simple = expr.len() == 1 || expr.chars().all(|c| c == '_' || c.is_ascii_alphanumeric());
+    if simple {
+        format!("${expr}")
+    } else {
+        format!("${{{expr}}}")
+    }
+}
+
+#[derive(Debug, Clone)]
+enum StdinRedirect {
+    Inherit,
+    File(String),
+    // `<&N`
+    Fd(i32),
+}
+
+// Redirections of descriptors 3-9, and closing any of 0-9
+#[derive(Debug, Clone)]
+enum FdRedirect {
+    Read(i32, String),
+    Write(i32, String),
+    Append(i32, String),
+    // `N<&M` / `N>&M`
+    Dup(i32, i32),
+    // `N<&-` / `N>&-`, `<&-`, `>&-`
+    Close(i32),
+}
+
+impl FdRedirect {
+    fn fd(&self) -> i32 {
+        match self {
+            FdRedirect::Read(n, _) | FdRedirect::Write(n, _) | FdRedirect::Append(n, _) => *n,
+            FdRedirect::Dup(n, _) | FdRedirect::Close(n) => *n,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ParsedCommand {
+    cmd: String,
+    args: Vec<String>,
+    stdin: StdinRedirect,
+    stdout: StdoutRedirect,
+    stderr: StderrRedirect,
+    fds: Vec<FdRedirect>,
+}
+
+// Err holds the status to leave after the message: 2 for a syntax error, 1 for
+// a redirection that can't be used, 0 when there's no command word at all
+fn parse_command(tokens: &[Token]) -> Result<ParsedCommand, i32> {
+    let mut words: Vec<String> = Vec::new();
+    let mut stdin = StdinRedirect::Inherit;
+    let mut stdout = StdoutRedirect::Inherit;
+    let mut stderr = StderrRedirect::Inherit;
+    let mut fds = Vec::new();
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let op = match &tokens[i] {
+            Token::Word(w) => {
+                words.push(w.clone());
+                i += 1;
+                continue;
+            }
+            Token::Op(op) => op.as_str(),
+            // Only made right after a redirection operator
+            Token::Ambiguous(_) => {
+                i += 1;
+                continue;
+            }
+            Token::Failed(msg) => {
+                eprintln!("mysh: {msg}");
+                return Err(1);
+            }
+        };
+
+        let name = words.first().map(String::as_str).unwrap_or("syntax error");
+        let target = match tokens.get(i + 1) {
+            Some(Token::Word(w)) => w.clone(),
+            Some(Token::Ambiguous(text)) => {
+                eprintln!("{name}: {text}: ambiguous redirect");
+                return Err(1);
+            }
+            Some(Token::Failed(msg)) => {
+                eprintln!("mysh: {msg}");
+                return Err(1);
+            }
+            Some(Token::Op(next)) => {
+                eprintln!("{name}: syntax error near unexpected token `{next}`");
+                return Err(2);
+            }
+            None => {
+                eprintln!("{name}: syntax error near unexpected token `newline`");
+                return Err(2);
+            }
+        };
+
+        // `&N` duplicates descriptor N, `&-` closes (Some(None))
+        let dup = match target.strip_prefix('&') {
+            None => None,
+            Some("-") => Some(None),
+            Some(n) => match n.parse::<i32>() {
+                Ok(n) if n >= 0 => Some(Some(n)),
+                _ => {
+                    eprintln!("{name}: {target}: ambiguous redirect");
+                    return Err(1);
+                }
+            },
+        };
+        // 3-9 from `3<` / `4>>`
+        let extra = op
+            .chars()
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .map(|d| d as i32)
+            .filter(|n| *n >= 3);
+
+        match (op, dup, extra) {
+            (_, Some(None), Some(n)) => fds.push(FdRedirect::Close(n)),
+            ("<" | "0<", Some(None), _) => fds.push(FdRedirect::Close(0)),
+            (">" | "1>" | ">>" | "1>>", Some(None), _) => fds.push(FdRedirect::Close(1)),
+            ("2>" | "2>>", Some(None), _) => fds.push(FdRedirect::Close(2)),
+            (_, Some(Some(m)), Some(n)) => fds.push(FdRedirect::Dup(n, m)),
+            (_, None, Some(n)) if op.ends_with('<') => fds.push(FdRedirect::Read(n, target)),
+            (_, None, Some(n)) if op.ends_with(">>") => fds.push(FdRedirect::Append(n, target)),
+            (_, None, Some(n)) => fds.push(FdRedirect::Write(n, target)),
+            ("<" | "0<", Some(Some(n)), _) => stdin = StdinRedirect::Fd(n),
+            ("<" | "0<", None, _) => stdin = StdinRedirect::File(target),
+            (">" | "1>" | ">>" | "1>>", Some(Some(n)), _) => stdout = StdoutRedirect::Fd(n),
+            (">" | "1>", None, _) => stdout = StdoutRedirect::Truncate(target),
+            (">>" | "1>>", None, _) => stdout = StdoutRedirect::Append(target),
+            ("2>" | "2>>", Some(Some(n)), _) => stderr = StderrRedirect::Fd(n),
+            ("2>", None, _) => stderr = StderrRedirect::Truncate(target),
+            ("2>>", None, _) => stderr = StderrRedirect::Append(target),
+            _ => {
+                eprintln!("{name}: syntax error near unexpected token `{op}`");
+                return Err(2);
+            }
+        }
+        i += 2;
+    }
+
+    if words.is_empty() {
+        return Err(0);
+    }
+    let cmd = words.remove(0);
+
+    Ok(ParsedCommand {
+        cmd,
+        args: words,
+        stdin,
+        stdout,
+        stderr,
+        fds,
+    })
+}
+
+// The quote still open at the end of `line`, if any
+fn open_quote(line: &str) -> Option<char> {
+    // `$` for a $'...', where a backslash escapes the `'` too
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (quote, ch) {
+            (Some('\''), '\'') | (Some('"'), '"') | (Some('$'), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                chars.next();
+            }
+            (None, '$') if chars.peek() == Some(&'\'') => {
+                chars.next();
+                quote = Some('$');
+            }
+            (None, '\'' | '"') => quote = Some(ch),
+            _ => {}
+        }
+    }
+    quote.map(|q| if q == '$' { '\'' } else { q })
+}
+
+fn split_pipeline(tokens: &[Token]) -> Option<Vec<Vec<Token>>> {
+    let mut out: Vec<Vec<Token>> = Vec::new();
+    let mut cur: Vec<Token> = Vec::new();
+
+    for t in tokens {
+        if t.is_op("|") {
+            if cur.is_empty() {
+                eprintln!("syntax error near unexpected token `|`");
+                return None;
+            }
+            out.push(cur);
+            cur = Vec::new();
+        } else {
+            cur.push(t.clone());
+        }
+    }
+
+    if cur.is_empty() {
+        eprintln!("syntax error near unexpected token `|`");
+        return None;
+    }
+    out.push(cur);
+    Some(out)
+}
+
+fn open_for_stdin(redir: &StdinRedirect) -> io::Result<Option<File>> {
+    match redir {
+        StdinRedirect::Inherit => Ok(None),
+        StdinRedirect::File(path) => Ok(Some(File::open(encode_os(path))?)),
+        StdinRedirect::Fd(n) => dup_shell_fd(*n).map(Some),
+    }
+}
+
+// With noclobber, `>` refuses to truncate an existing regular file
+fn open_for_stdout(redir: &StdoutRedirect, options: &ShellOptions) -> io::Result<Option<File>> {
+    match redir {
+        StdoutRedirect::Inherit => Ok(None),
+        StdoutRedirect::Truncate(path) => {
+            if options.noclobber && fs::metadata(encode_os(path)).is_ok_and(|m| m.is_file()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{}: cannot overwrite existing file", display_str(path)),
+                ));
+            }
+            Ok(Some(File::create(encode_os(path))?))
+        }
+        StdoutRedirect::Fd(n) => dup_shell_fd(*n).map(Some),
+        StdoutRedirect::Append(path) => Ok(Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(encode_os(path))?,
+        )),
+    }
+}
+
+fn open_for_stderr(redir: &StderrRedirect) -> io::Result<Option<File>> {
+    match redir {
+        StderrRedirect::Inherit => Ok(None),
+        StderrRedirect::Truncate(path) => Ok(Some(File::create(encode_os(path))?)),
+        StderrRedirect::Fd(n) => dup_shell_fd(*n).map(Some),
+        StderrRedirect::Append(path) => Ok(Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(encode_os(path))?,
+        )),
+    }
+}
+
+// ---------- pathname expansion ----------
+// A word with an unquoted `*`, `?` or `[...]` is a pattern, replaced by the
+// paths it matches in sorted order, or kept as written when nothing matches.
+// Names starting with `.` need the `.` written, unless `dotglob` is set;
+// `nocaseglob` ignores case and `noglob` (`set -f`) turns all this off. With
+// `globstar` a `**` component matches any depth of directories; the walk
+// doesn't descend into symlinked directories (no cycles) and skips those it
+// can't read.
+
+#[derive(Debug, Clone, PartialEq)]
+enum GlobPart {
+    Literal(char),
+    // `*`
+    Any,
+    // `?`
+    One,
+    // `[...]`: ranges, and whether a leading `!` or `^` negates them
+    Class(Vec<(char, char)>, bool),
+}
+
+// One path component of the pattern; the flag marks unquoted characters
+fn glob_component(chars: &[(char, bool)]) -> Vec<GlobPart> {
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while let Some(&(ch, meta)) = chars.get(i) {
+        i += 1;
+        match (ch, meta) {
+            ('*', true) => parts.push(GlobPart::Any),
+            ('?', true) => parts.push(GlobPart::One),
+            ('[', true) => match glob_class(&chars[i..]) {
+                Some((class, used)) => {
+                    parts.push(class);
+                    i += used;
+                }
+                // No closing `]`: a plain `[`
+                None => parts.push(GlobPart::Literal('[')),
+            },
+            _ => parts.push(GlobPart::Literal(ch)),
+        }
+    }
+    parts
+}
+
+// The class after a `[`, and how many characters it takes through its `]`
+// (a `]` first in the class is one of its characters)
+fn glob_class(chars: &[(char, bool)]) -> Option<(GlobPart, usize)> {
+    let negated = matches!(chars.first(), Some(('!' | '^', _)));
+    let start = usize::from(negated);
+    let mut ranges = Vec::new();
+    let mut i = start;
+    while let Some(&(ch, _)) = chars.get(i) {
+        if ch == ']' && i > start {
+            return Some((GlobPart::Class(ranges, negated), i + 1));
+        }
+        match (chars.get(i + 1), chars.get(i + 2)) {
+            (Some(('-', _)), Some(&(hi, _))) if hi != ']' => {
+                ranges.push((ch, hi));
+                i += 3;
+            }
+            _ => {
+                ranges.push((ch, ch));
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+fn glob_match(pattern: &[GlobPart], name: &[char], nocase: bool) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((GlobPart::Any, rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..], nocase)),
+        Some((part, rest)) => match name.split_first() {
+            Some((&c, tail)) => glob_char(part, c, nocase) && glob_match(rest, tail, nocase),
+            None => false,
+        },
+    }
+}
+
+fn glob_char(part: &GlobPart, c: char, nocase: bool) -> bool {
+    // With nocase `c` also matches as its lower and upper case forms
+    let single = |mapped: String| {
+        let mut chars = mapped.chars();
+        match (chars.next(), chars.next()) {
+            (Some(m), None) if nocase => m,
+            _ => c,
+        }
+    };
+    let forms = [c, single(c.to_lowercase().collect()), single(c.to_uppercase().collect())];
+    match part {
+        GlobPart::Literal(l) => forms.contains(l),
+        GlobPart::One => true,
+        GlobPart::Class(ranges, negated) => {
+            forms.iter().any(|&f| ranges.iter().any(|&(lo, hi)| lo <= f && f <= hi)) != *negated
+        }
+        GlobPart::Any => false,
+    }
+}
+
+// A hidden name is matched only by a component starting with `.`, or by any
+// with dotglob
+fn glob_shows(component: &[GlobPart], name: &str, options: &ShellOptions) -> bool {
+    !name.starts_with('.') || options.dotglob || component.first() == Some(&GlobPart::Literal('.'))
+}
+
+// Entries of `dir` (never `.` or `..`) with whether each is a directory, and
+// a real one rather than a symlink to one; unreadable directories have none
+fn glob_entries(dir: &str) -> Vec<(String, bool, bool)> {
+    let Ok(entries) = fs::read_dir(encode_os(dir)) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| {
+            let real_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            let is_dir = real_dir || entry.path().is_dir();
+            (decode_os(&entry.file_name()), is_dir, real_dir)
+        })
+        .collect()
+}
+
+// Paths matching `word`, whose unquoted `*`, `?` and `[` are at the byte
+// offsets in `meta`
+fn glob_word(word: &str, meta: &[usize], options: &ShellOptions) -> Vec<String> {
+    let chars: Vec<(char, bool)> = word.char_indices().map(|(i, c)| (c, meta.contains(&i))).collect();
+    let components: Vec<Vec<GlobPart>> = chars.split(|&(c, _)| c == '/').map(glob_component).collect();
+    if components.iter().flatten().all(|p| matches!(p, GlobPart::Literal(_))) {
+        return Vec::new();
+    }
+    let mut found = Vec::new();
+    glob_walk(String::new(), &components, options, &mut found);
+    found.sort();
+    found.dedup();
+    found
+}
+
+// Matches of `components` below `prefix`, which is empty or ends in `/`
+fn glob_walk(prefix: String, components: &[Vec<GlobPart>], options: &ShellOptions, found: &mut Vec<String>) {
+    let Some((component, rest)) = components.split_first() else {
+        return;
+    };
+    let literal: Option<String> = component
+        .iter()
+        .map(|p| match p {
+            GlobPart::Literal(c) => Some(*c),
+            _ => None,
+        })
+        .collect();
+    let dir = if prefix.is_empty() { "." } else { prefix.as_str() };
+
+    match literal {
+        // A leading `/`, `//`, or a trailing `/` (only directories got here)
+        Some(name) if name.is_empty() => {
+            if rest.is_empty() {
+                found.push(prefix);
+            } else {
+                glob_walk(format!("{prefix}/"), rest, options, found);
+            }
+        }
+        Some(name) => {
+            let path = format!("{prefix}{name}");
+            if rest.is_empty() {
+                if fs::symlink_metadata(encode_os(&path)).is_ok() {
+                    found.push(path);
+                }
+            } else if Path::new(&encode_os(&path)).is_dir() {
+                glob_walk(format!("{path}/"), rest, options, found);
+            }
+        }
+        // `**`: no directories, then each real directory below
+        None if options.globstar && component[..] == [GlobPart::Any, GlobPart::Any] => {
+            let everything = [vec![GlobPart::Any]];
+            glob_walk(prefix.clone(), if rest.is_empty() { &everything } else { rest }, options, found);
+            for (name, _, real_dir) in glob_entries(dir) {
+                if real_dir && glob_shows(&[], &name, options) {
+                    glob_walk(format!("{prefix}{name}/"), components, options, found);
+                }
+            }
+        }
+        None => {
+            for (name, is_dir, _) in glob_entries(dir) {
+                let chars: Vec<char> = name.chars().collect();
+                if !glob_shows(component, &name, options) || !glob_match(component, &chars, options.nocaseglob) {
+                    continue;
+                }
+                if rest.is_empty() {
+                    found.push(format!("{prefix}{name}"));
+                } else if is_dir {
+                    glob_walk(format!("{prefix}{name}/"), rest, options, found);
+                }
+            }
+        }
+    }
+}
+
+// ---------- shell file descriptors ----------
+// `exec 3< file`, `exec 4> file` and `exec 3<&-` open and close descriptors
+// the shell keeps. Each is held close-on-exec at a spare number from 10 up;
+// `<&3` and `>&3` get a duplicate sharing its offset, and external commands
+// get every one at its own number.
+
+// Descriptor 3-9 to the one the shell holds for it
+static SHELL_FDS: Mutex<std::collections::BTreeMap<i32, i32>> = Mutex::new(std::collections::BTreeMap::new());
+
+fn shell_fds() -> std::sync::MutexGuard<'static, std::collections::BTreeMap<i32, i32>> {
+    SHELL_FDS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn bad_fd(n: i32) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{n}: Bad file descriptor"))
+}
+
+// A new handle on what descriptor `n` is in the shell: 0-2 its own stdio,
+// 3-9 from the table
+#[cfg(unix)]
+fn dup_shell_fd(n: i32) -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+    let held = match n {
+        0..=2 => Some(n),
+        _ => shell_fds().get(&n).copied(),
+    };
+    let fd = held.ok_or_else(|| bad_fd(n))?;
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) } {
+        new if new >= 0 => Ok(unsafe { File::from_raw_fd(new) }),
+        _ => Err(bad_fd(n)),
+    }
+}
+
+#[cfg(not(unix))]
+fn dup_shell_fd(n: i32) -> io::Result<File> {
+    Err(bad_fd(n))
+}
+
+// The file a 3-9 redirection puts at its number, None for `N<&-`
+fn open_fd_redirect(redir: &FdRedirect, options: &ShellOptions) -> io::Result<Option<File>> {
+    match redir {
+        FdRedirect::Read(_, path) => open_for_stdin(&StdinRedirect::File(path.clone())),
+        FdRedirect::Write(_, path) => open_for_stdout(&StdoutRedirect::Truncate(path.clone()), options),
+        FdRedirect::Append(_, path) => open_for_stdout(&StdoutRedirect::Append(path.clone()), options),
+        FdRedirect::Dup(_, m) => dup_shell_fd(*m).map(Some),
+        FdRedirect::Close(_) => Ok(None),
+    }
+}
+
+// `exec` without a command: its redirections become the shell's own
+#[cfg(unix)]
+fn exec_redirections(stage: &ParsedCommand, options: &ShellOptions) -> i32 {
+    use std::os::unix::io::AsRawFd;
+    let mut status = 0;
+    let standard = [
+        (0, open_for_stdin(&stage.stdin)),
+        (1, open_for_stdout(&stage.stdout, options)),
+        (2, open_for_stderr(&stage.stderr)),
+    ];
+    for (n, file) in standard {
+        match file {
+            Ok(Some(f)) => {
+                unsafe { libc::dup2(f.as_raw_fd(), n) };
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("exec: {e}");
+                status = 1;
+            }
+        }
+    }
+    for redir in &stage.fds {
+        let n = redir.fd();
+        // The shell's own stdin, stdout or stderr, closed for good
+        if n <= 2 {
+            unsafe { libc::close(n) };
+            continue;
+        }
+        let opened = match open_fd_redirect(redir, options) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("exec: {e}");
+                status = 1;
+                continue;
+            }
+        };
+        // Moved up out of the way of 3-9
+        let held = opened.map(|f| unsafe { libc::fcntl(f.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 10) });
+        let mut table = shell_fds();
+        if let Some(old) = table.remove(&n) {
+            unsafe { libc::close(old) };
+        }
+        match held {
+            Some(fd) if fd >= 0 => {
+                table.insert(n, fd);
+            }
+            Some(_) => {
+                eprintln!("exec: {}", io::Error::last_os_error());
+                status = 1;
+            }
+            None => {}
+        }
+    }
+    status
+}
+
+// `exec cmd args`: the shell turns into the command, which gets its
+// redirections and the shell's descriptors; only returns on failure
+#[cfg(unix)]
+fn exec_command(stage: &ParsedCommand, options: &ShellOptions) -> i32 {
+    let name = &stage.args[0];
+    let path = match resolve_command(name) {
+        CommandLookup::Found(p) => p,
+        lookup => return report_unrunnable(name, &lookup),
+    };
+    let status = exec_redirections(stage, options);
+    if status != 0 {
+        return status;
+    }
+    let mut cmd = external_command(name, &path);
+    cmd.args(stage.args[1..].iter().map(|a| encode_os(a)));
+    if let Err(e) = pass_shell_fds(&mut cmd, &[], options) {
+        eprintln!("exec: {e}");
+        return 1;
+    }
+    let e = cmd.exec();
+    eprintln!("exec: {name}: {e}");
+    126
+}
+
+#[cfg(not(unix))]
+fn exec_command(stage: &ParsedCommand, _options: &ShellOptions) -> i32 {
+    eprintln!("exec: {}: not supported on this system", stage.args[0]);
+    1
+}
+
+#[cfg(not(unix))]
+fn exec_redirections(_stage: &ParsedCommand, _options: &ShellOptions) -> i32 {
+    eprintln!("exec: redirections need a unix system");
+    1
+}
+
+// Sets up an external command's descriptors 3-9: the shell's, then its own
+// redirections. The returned files must stay open until it has spawned.
+#[cfg(unix)]
+fn pass_shell_fds(cmd: &mut Command, redirects: &[FdRedirect], options: &ShellOptions) -> io::Result<Vec<File>> {
+    use std::os::unix::io::AsRawFd;
+    // (held descriptor, number in the child)
+    let mut moves: Vec<(i32, i32)> = shell_fds().iter().map(|(n, fd)| (*fd, *n)).collect();
+    let mut closes = Vec::new();
+    let mut keep = Vec::new();
+    for redir in redirects {
+        let n = redir.fd();
+        moves.retain(|(_, to)| *to != n);
+        match open_fd_redirect(redir, options)? {
+            Some(f) => {
+                let f = above_nine(f)?;
+                moves.push((f.as_raw_fd(), n));
+                keep.push(f);
+            }
+            None => closes.push(n),
+        }
+    }
+    if moves.is_empty() && closes.is_empty() {
+        return Ok(keep);
+    }
+    // Held descriptors are all 10 and up, so no move clobbers another's source
+    unsafe {
+        cmd.pre_exec(move || {
+            for (from, to) in &moves {
+                if libc::dup2(*from, *to) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            for fd in &closes {
+                libc::close(*fd);
+            }
+            Ok(())
+        });
+    }
+    Ok(keep)
+}
+
+// `file` on a descriptor of 10 or more, out of the way of pass_shell_fds's
+// dup2s to 3-9
+#[cfg(unix)]
+fn above_nine(file: File) -> io::Result<File> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    if file.as_raw_fd() >= 10 {
+        return Ok(file);
+    }
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 10) } {
+        new if new >= 0 => Ok(unsafe { File::from_raw_fd(new) }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+fn pass_shell_fds(_cmd: &mut Command, _redirects: &[FdRedirect], _options: &ShellOptions) -> io::Result<Vec<File>> {
+    Ok(Vec::new())
+}
+
+// What `help` shows for a builtin
+struct BuiltinInfo {
+    name: &'static str,
+    summary: &'static str,
+    usage: &'static str,
+    details: &'static str,
+}
+
+// Every builtin; `type`, completion and `help` all read this list
+const BUILTINS: &[BuiltinInfo] = &[
+    BuiltinInfo {
+        name: "cd",
+        summary: "Change the working directory",
+        usage: "cd [dir | -]",
+        details: "With no dir, cd goes to $HOME.\n\
+                  ~ and ~user at the start of dir are expanded to home directories.\n\
+                  cd - goes back to $OLDPWD and prints it; PWD and OLDPWD follow each cd.",
+    },
+    BuiltinInfo {
+        name: "echo",
+        summary: "Write arguments to standard output",
+        usage: "echo [-neE] [arg ...]",
+        details: "Prints the arguments separated by spaces, followed by a newline.\n\
+                  -n leaves out the newline; -e interprets escapes like \\n, \\t, \\xHH,\n\
+                  \\0NNN and \\uXXXX (\\c stops the output); -E turns that back off.",
+    },
+    BuiltinInfo {
+        name: "exit",
+        summary: "Exit the shell",
+        usage: "exit [n]",
+        details: "Exits with status n, or the status of the last command.",
+    },
+    BuiltinInfo {
+        name: "return",
+        summary: "Leave a shell function",
+        usage: "return [n]",
+        details: "The function's remaining commands are skipped and its status is n, or\n\
+                  the status of the last command. Functions are defined with\n\
+                  name() { command; ...; } and removed with unset -f.",
+    },
+    BuiltinInfo {
+        name: "pwd",
+        summary: "Print the working directory",
+        usage: "pwd",
+        details: "",
+    },
+    BuiltinInfo {
+        name: "type",
+        summary: "Tell how a command name would be run",
+        usage: "type name",
+        details: "Reports whether name is a shell builtin or where it is found on PATH.",
+    },
+    BuiltinInfo {
+        name: "history",
+        summary: "Show the command history",
+        usage: "history [n] | history -a|-n|-r|-w [file]",
+        details: "With n, only the last n entries are shown. -a appends the new entries to\n\
+                  the history file, -n reads the lines other shells appended since the last\n\
+                  -n, -r reads the whole file and -w writes the whole history to it.",
+    },
+    BuiltinInfo {
+        name: "fc",
+        summary: "List, edit and re-run history entries",
+        usage: "fc [-e editor] [first [last]] | fc -l [-nr] [first [last]] | fc -s [old=new] [command]",
+        details: "first/last are entry numbers, -n for n back, or the start of a command.\n\
+                  Without -l or -s the entries open in -e editor, $FCEDIT, $EDITOR or vi,\n\
+                  and whatever is saved runs; a failing editor runs nothing. -s runs the\n\
+                  latest matching command again with the first old replaced by new.",
+    },
+    BuiltinInfo {
+        name: "record",
+        summary: "Record the session to a file",
+        usage: "record [file]",
+        details: "Runs a nested shell and logs its output to file (default: typescript)\n\
+                  with timing in file.timing, for scriptreplay or `--replay file`.",
+    },
+    BuiltinInfo {
+        name: "set",
+        summary: "Show or change shell options",
+        usage: "set [-o|+o name] [-bCeHr|+bCeH]",
+        details: "Without arguments lists every option. -o name turns an option on,\n\
+                  +o name turns it off; -b, -C, -e, -H and -r are notify, noclobber,\n\
+                  errexit, histexpand and restricted. errexit stops at the first failing\n\
+                  command: sourced files return, the shell exits. A restricted shell\n\
+                  refuses output redirections, commands named by path, cd to absolute\n\
+                  paths, read into PATH/SHELL/ENV, exec and load_plugin, and can't be\n\
+                  made unrestricted again.",
+    },
+    BuiltinInfo {
+        name: "complete",
+        summary: "Choose how a command's arguments complete",
+        usage: "complete [-d|-f|-W wordlist|-F function] [-X pattern] [-o option] name ... | complete -p|-r [name ...]",
+        details: "-d completes directory names only, -f names of other files only, -W the\n\
+                  words of wordlist. A wordlist with $(...) or $ in it is expanded again\n\
+                  when completing, at most every $COMPLETION_CACHE_TTL seconds (5), by sh\n\
+                  with $COMP_LINE, $COMP_POINT, $COMP_CWORD and $COMP_WORDS (blank-joined)\n\
+                  exported and the words set as \"$@\".\n\
+                  -F runs the shell function with the command, the word being completed and\n\
+                  the word before it as $1 $2 $3, and $COMP_WORDS, $COMP_CWORD, $COMP_LINE\n\
+                  and $COMP_POINT set; the elements it puts in $COMPREPLY are the candidates.\n\
+                  -X drops candidates matching pattern (with a leading !, those that don't).\n\
+                  -o nospace adds no space after a completed word; -o filenames gives words\n\
+                  naming directories a trailing /.\n\
+                  -p prints the registrations as complete commands, -r removes them.",
+    },
+    BuiltinInfo {
+        name: "load_plugin",
+        summary: "Load a shared-library plugin",
+        usage: "load_plugin path",
+        details: "The library's shell_plugin_init can add builtins, completions and hooks.",
+    },
+    BuiltinInfo {
+        name: "jobs",
+        summary: "List background jobs",
+        usage: "jobs",
+        details: "Finished jobs are listed once more, then forgotten.",
+    },
+    BuiltinInfo {
+        name: "wait",
+        summary: "Wait for background jobs to finish",
+        usage: "wait [-n] [id ...]",
+        details: "Waits for each job named by a %spec or a PID, or for all of them, and reports\n\
+                  them as they end; the status is that of the last one. -n returns as soon\n\
+                  as any one of them ends, with its status, or 127 if there are none.",
+    },
+    BuiltinInfo {
+        name: "exec",
+        summary: "Replace the shell, or redirect its own descriptors",
+        usage: "exec [command [arg ...]] [redirection ...]",
+        details: "With a command the shell becomes it. Without one the redirections stay in\n\
+                  effect for the shell: `exec 3< file` opens descriptor 3 (3-9) for later\n\
+                  commands to use with <&3, `exec 3<&-` closes it; `exec 2>&-` closes the\n\
+                  shell's own stderr (likewise <&- and >&- for stdin and stdout).",
+    },
+    BuiltinInfo {
+        name: "kill",
+        summary: "Send a signal to processes or jobs",
+        usage: "kill [-s sig | -sig] id ... | kill -l",
+        details: "Sends sig (TERM by default, by name or number) to each PID or job; a\n\
+                  job is named %N, %string (its command starts with string), %?string\n\
+                  (contains it), %% or %+ (current) or %- (previous). -l lists the names.",
+    },
+    BuiltinInfo {
+        name: "fg",
+        summary: "Bring a job to the foreground",
+        usage: "fg [job]",
+        details: "Continues the job (the current one by default) if stopped and waits for it.",
+    },
+    BuiltinInfo {
+        name: "bg",
+        summary: "Continue a stopped job in the background",
+        usage: "bg [job]",
+        details: "Continues the job, the current one by default, without waiting for it.",
+    },
+    BuiltinInfo {
+        name: "disown",
+        summary: "Forget jobs",
+        usage: "disown [-a] [job ...]",
+        details: "Drops the jobs (the current one by default, all with -a) from the table,\n\
+                  leaving their processes running.",
+    },
+    BuiltinInfo {
+        name: "read",
+        summary: "Read a line into variables",
+        usage: "read [-r] [name ...]",
+        details: "Splits a line of input on whitespace into the named variables, the last\n\
+                  one taking the rest of the line; without names the line goes to REPLY.\n\
+                  -r keeps backslashes as they are.",
+    },
+    BuiltinInfo {
+        name: "enable",
+        summary: "Switch optional builtins on or off",
+        usage: "enable [-s|-n name ...]",
+        details: "-s turns name on, -n turns it off; without arguments lists them.\n\
+                  An executable of the same name on PATH still takes precedence.",
+    },
+    BuiltinInfo {
+        name: "clear",
+        summary: "Clear the terminal screen",
+        usage: "clear",
+        details: "Prints nothing when the shell isn't on a terminal.",
+    },
+    BuiltinInfo {
+        name: "help",
+        summary: "Describe builtins",
+        usage: "help [name]",
+        details: "Without a name lists every builtin.",
+    },
+    BuiltinInfo {
+        name: "hash",
+        summary: "Name directories for ~name",
+        usage: "hash -d [name=path ...]",
+        details: "After hash -d proj=~/src/proj, ~proj/docs means ~/src/proj/docs and the\n\
+                  prompt shows the directory as ~proj. Without definitions lists them.",
+    },
+    BuiltinInfo {
+        name: "dirs",
+        summary: "Show the working directory",
+        usage: "dirs [-l]",
+        details: "Abbreviated with ~ and named directories unless -l is given.",
+    },
+    BuiltinInfo {
+        name: "z",
+        summary: "Jump to a frequently used directory",
+        usage: "z [-l] [pattern ...]",
+        details: "Each cd in an interactive shell is recorded in $XDG_DATA_HOME/myshell/z.\n\
+                  z pattern ... goes to the highest-scoring directory whose path contains\n\
+                  every pattern; the score counts visits, weighted by how recent the last\n\
+                  one was. -l (or no patterns) lists the matches with their scores.",
+    },
+    BuiltinInfo {
+        name: "alias",
+        summary: "Define or list aliases",
+        usage: "alias [-p] [name[=value] ...]",
+        details: "Without arguments lists every alias. The command word of each pipeline\n\
+                  stage is replaced by its alias, again for aliases of aliases; an alias\n\
+                  ending in a blank expands the next word too. ${BASH_ALIASES[name]} reads one.",
+    },
+    BuiltinInfo {
+        name: "unalias",
+        summary: "Remove aliases",
+        usage: "unalias [-a] name [name ...]",
+        details: "-a removes every alias.",
+    },
+    BuiltinInfo {
+        name: "export",
+        summary: "Set variables for the shell and the commands it runs",
+        usage: "export [-p] [name[=value] ...]",
+        details: "Marks each name for export, so commands the shell runs see it in their\n\
+                  environment; name=value assigns it first. Plain assignments stay in the\n\
+                  shell until exported. Without names, or with -p, lists what's exported.",
+    },
+    BuiltinInfo {
+        name: "unset",
+        summary: "Remove variables, array elements or arrays",
+        usage: "unset [-f] name ...",
+        details: "unset 'name[i]' empties an array element; unset 'BASH_ALIASES[name]'\n\
+                  removes an alias. unset -f removes shell functions.",
+    },
+    BuiltinInfo {
+        name: "readonly",
+        summary: "Make variables unchangeable",
+        usage: "readonly [-p] [name[=value] ...]",
+        details: "Assigning to or unsetting a readonly variable fails for the rest of the\n\
+                  session; it is still exported to commands as before. Without names, or\n\
+                  with -p, lists the readonly variables.",
+    },
+    BuiltinInfo {
+        name: "let",
+        summary: "Evaluate arithmetic expressions",
+        usage: "let expr ...",
+        details: "C-style integer expressions: names are variables, and =, +=, ++, -- and\n\
+                  the like assign to them. The status is 0 if the last value is non-zero,\n\
+                  1 if it is zero. (( expr )) as a command line does the same for one expr.",
+    },
+    BuiltinInfo {
+        name: "trap",
+        summary: "Run commands on exit, on failure or before each command",
+        usage: "trap [-lp] [[action] condition ...]",
+        details: "trap 'commands' EXIT runs the commands once as the shell ends: exit, end\n\
+                  of input, SIGHUP or SIGTERM. trap 'commands' ERR runs them after every\n\
+                  failing command, $? still its status; DEBUG before every command line,\n\
+                  $BASH_COMMAND holding it. trap '' runs nothing for the condition,\n\
+                  trap - removes the trap. Without arguments lists the traps.",
+    },
+    BuiltinInfo {
+        name: "bind",
+        summary: "Show or change key bindings",
+        usage: "bind [-p] [-r keys] ['keys: action' ...]",
+        details: "Actions are \"text\" macros, run right away when they end in \\n, or\n\
+                  readline function names: bind '\"\\C-g\": \"git status\\n\"',\n\
+                  bind 'M-u: upcase-word'. -p lists bindings, -r removes one.",
+    },
+    BuiltinInfo {
+        name: "source",
+        summary: "Run a file's commands in this shell",
+        usage: "source file [args ...]",
+        details: "While the file runs, $0 is file and $1... are args, and file is on top\n\
+                  of BASH_SOURCE. The status is that of the last command run. `.` is the\n\
+                  same builtin.",
+    },
+    BuiltinInfo {
+        name: ".",
+        summary: "Same as source",
+        usage: ". file [args ...]",
+        details: "See help source.",
+    },
+    BuiltinInfo {
+        name: "mapfile",
+        summary: "Read lines into an array",
+        usage: "mapfile [-t] [-n count] [-s count] [array] [file]",
+        details: "Stores each line of file, or of standard input, in array (default MAPFILE).\n\
+                  -t drops the newlines, -n keeps at most count lines, -s skips the first\n\
+                  count. A single operand that isn't a valid name is taken as the file.",
+    },
+];
+
+// Builtins standing in for tools a minimal system may lack. Off until
+// `enable -s name`, and even then an executable of the same name wins.
+const OPTIONAL_BUILTINS: &[BuiltinInfo] = &[
+    BuiltinInfo {
+        name: "wc",
+        summary: "Count lines, words and bytes (optional)",
+        usage: "wc [-lwc] [file ...]",
+        details: "-l, -w and -c pick the counts; all three without flags.\n\
+                  Reads standard input when no files are given.",
+    },
+    BuiltinInfo {
+        name: "cat",
+        summary: "Concatenate files (optional)",
+        usage: "cat [file ...]",
+        details: "Reads standard input for - or when no files are given.",
+    },
+];
+
+fn is_builtin(cmd: &str) -> bool {
+    BUILTINS.iter().any(|b| b.name == cmd) || optional_builtin_active(cmd)
+}
+
+fn builtin_info(name: &str) -> Option<&'static BuiltinInfo> {
+    BUILTINS.iter().chain(OPTIONAL_BUILTINS).find(|b| b.name == name)
+}
+
+// `help` lists the builtins, `help name` shows one in full
+fn help_builtin(args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let Some(name) = args.first() else {
+        let mut out = String::new();
+        for b in BUILTINS.iter().chain(OPTIONAL_BUILTINS) {
+            out.push_str(&format!("{:<30}{}\n", b.usage, b.summary));
+        }
+        return (out.into_bytes(), vec![], 0);
+    };
+
+    let Some(info) = builtin_info(name) else {
+        let mut err = format!("help: no help topics match `{}'\n", display_str(name));
+        let close: Vec<&str> = BUILTINS
+            .iter()
+            .chain(OPTIONAL_BUILTINS)
+            .map(|b| b.name)
+            .filter(|b| b.starts_with(name.as_str()) || edit_distance(b, name) <= 2)
+            .collect();
+        if !close.is_empty() {
+            err.push_str(&format!("help: did you mean: {}?\n", close.join(", ")));
+        }
+        return (vec![], err.into_bytes(), 1);
+    };
+
+    let mut out = format!("{}: {}\n    {}.\n", info.name, info.usage, info.summary);
+    for line in info.details.lines().filter(|l| !l.is_empty()) {
+        out.push_str(&format!("\n    {}", line.trim_start()));
+    }
+    if !info.details.is_empty() {
+        out.push('\n');
+    }
+    (out.into_bytes(), vec![], 0)
+}
+
+// Levenshtein distance, for suggesting names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = if ca == cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Home the cursor, clear the screen and the scrollback; nothing when the
+// shell isn't on a terminal that understands it
+fn clear_screen_bytes() -> Vec<u8> {
+    let dumb = env::var("TERM").map_or(true, |t| t.is_empty() || t == "dumb");
+    if dumb || !io::stdout().is_terminal() {
+        return Vec::new();
+    }
+    b"\x1b[H\x1b[2J\x1b[3J".to_vec()
+}
+
+static ENABLED_OPTIONAL: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn optional_builtin_active(cmd: &str) -> bool {
+    OPTIONAL_BUILTINS.iter().any(|b| b.name == cmd)
+        && ENABLED_OPTIONAL
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .any(|n| n == cmd)
+        && find_executable_in_path(cmd).is_none()
+}
+
+// `enable -s name...` / `enable -n name...` switch optional builtins on and
+// off; no arguments lists them
+fn enable_builtin(args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let mut enabled = ENABLED_OPTIONAL.lock().unwrap_or_else(|e| e.into_inner());
+    let (on, names) = match args.split_first() {
+        None => {
+            let mut out = String::new();
+            for b in OPTIONAL_BUILTINS {
+                let flag = if enabled.iter().any(|n| n == b.name) { "-s" } else { "-n" };
+                out.push_str(&format!("enable {flag} {}\n", b.name));
+            }
+            return (out.into_bytes(), vec![], 0);
+        }
+        Some((flag, names)) if flag == "-s" => (true, names),
+        Some((flag, names)) if flag == "-n" => (false, names),
+        Some((flag, _)) => {
+            return (vec![], format!("enable: {}: invalid option\n", display_str(flag)).into_bytes(), 2);
+        }
+    };
+
+    let mut err = String::new();
+    for name in names {
+        if !OPTIONAL_BUILTINS.iter().any(|b| b.name == name) {
+            err.push_str(&format!("enable: {}: not an optional builtin\n", display_str(name)));
+            continue;
+        }
+        enabled.retain(|n| n != name);
+        if on {
+            enabled.push(name.clone());
+        }
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err.into_bytes(), code)
+}
+
+// `cat [file...]`: the files one after another, `-` or no files for stdin
+fn cat_builtin(args: &[String], stdin_bytes: Option<Vec<u8>>) -> (Vec<u8>, Vec<u8>, i32) {
+    if args.is_empty() {
+        return (stdin_bytes.unwrap_or_default(), vec![], 0);
+    }
+    let mut stdin_bytes = stdin_bytes;
+    let (mut out, mut err, mut code) = (Vec::new(), String::new(), 0);
+    for file in args {
+        if file == "-" {
+            // Only the first `-` gets anything, as stdin is at EOF afterwards
+            out.extend(stdin_bytes.take().unwrap_or_default());
+            continue;
+        }
+        match fs::read(encode_os(file)) {
+            Ok(data) => out.extend(data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                err.push_str(&format!("cat: {}: No such file or directory\n", display_str(file)));
+                code = 1;
+            }
+            Err(e) => {
+                err.push_str(&format!("cat: {}: {e}\n", display_str(file)));
+                code = 1;
+            }
+        }
+    }
+    (out, err.into_bytes(), code)
+}
+
+// `wc [-lwc] [file...]`: newline, word and byte counts in that order, all
+// three without flags; reads stdin when no files are given
+fn wc_builtin(args: &[String], stdin_bytes: Option<Vec<u8>>) -> (Vec<u8>, Vec<u8>, i32) {
+    let (mut lines, mut words, mut bytes) = (false, false, false);
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('-') {
+            Some(flags) if !flags.is_empty() => {
+                for f in flags.chars() {
+                    match f {
+                        'l' => lines = true,
+                        'w' => words = true,
+                        'c' => bytes = true,
+                        other => {
+                            return (vec![], format!("wc: invalid option -- '{other}'\n").into_bytes(), 1);
+                        }
+                    }
+                }
+            }
+            _ => files.push(arg),
+        }
+    }
+    if !(lines || words || bytes) {
+        (lines, words, bytes) = (true, true, true);
+    }
+
+    let counts = |data: &[u8]| {
+        let mut fields = Vec::new();
+        if lines {
+            fields.push(data.iter().filter(|&&b| b == b'\n').count());
+        }
+        if words {
+            fields.push(data.split(|b| b.is_ascii_whitespace()).filter(|w| !w.is_empty()).count());
+        }
+        if bytes {
+            fields.push(data.len());
+        }
+        fields
+    };
+    let line = |fields: &[usize], name: Option<&str>| {
+        let mut text: Vec<String> = fields.iter().map(|n| n.to_string()).collect();
+        text.extend(name.map(str::to_string));
+        text.join(" ") + "\n"
+    };
+
+    if files.is_empty() {
+        let fields = counts(&stdin_bytes.unwrap_or_default());
+        return (line(&fields, None).into_bytes(), vec![], 0);
+    }
+
+    let (mut out, mut err, mut code) = (String::new(), String::new(), 0);
+    let mut total: Vec<usize> = Vec::new();
+    for file in &files {
+        match fs::read(encode_os(file)) {
+            Ok(data) => {
+                let fields = counts(&data);
+                total.resize(fields.len(), 0);
+                total.iter_mut().zip(&fields).for_each(|(t, n)| *t += n);
+                out.push_str(&line(&fields, Some(file)));
+            }
+            Err(_) => {
+                err.push_str(&format!("wc: {}: No such file or directory\n", display_str(file)));
+                code = 1;
+            }
+        }
+    }
+    if files.len() > 1 {
+        out.push_str(&line(&total, Some("total")));
+    }
+    (encode_bytes(&out), err.into_bytes(), code)
+}
+
+// ---------- parameters and aliases ----------
+// `$name` reads a shell variable, the environment or element 0 of an array;
+// `${name[i]}`, `${name[@]}`, `${!name[@]}` and `${#name}` work on mapfile
+// arrays and on BASH_ALIASES, a live view of the alias table. `${name:-word}`
+// and the other default/alternate operators work on any parameter.
+
+// Alias name -> replacement text, kept sorted for listing
+type AliasMap = std::collections::BTreeMap<String, String>;
+
+// The parameter after a `$`: the inside of `{...}`, a name, a digit or one
+// of `? $ # @ *`.
+// None leaves the `$` literal (and nothing consumed).
+fn read_parameter(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> Option<String> {
+    match *chars.peek()? {
+        '{' => {
+            let mut probe = chars.clone();
+            probe.next();
+            let mut expr = String::new();
+            for c in probe.by_ref() {
+                if c == '}' {
+                    *chars = probe;
+                    return Some(expr);
+                }
+                expr.push(c);
+            }
+            None
+        }
+        '?' | '$' | '#' | '@' | '*' => chars.next().map(String::from),
+        c if c.is_ascii_digit() => chars.next().map(String::from),
+        c if c == '_' || c.is_ascii_alphabetic() => {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if c != '_' && !c.is_ascii_alphanumeric() {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            Some(name)
+        }
+        _ => None,
+    }
+}
+
+// `name[sub]` -> (name, sub)
+fn split_subscript(expr: &str) -> Option<(&str, &str)> {
+    let (name, rest) = expr.split_once('[')?;
+    let sub = rest.strip_suffix(']')?;
+    is_identifier(name).then_some((name, sub))
+}
+
+// Key/value pairs of an array, None if `name` isn't one
+fn array_entries(ctx: &ShellContext, name: &str) -> Option<Vec<(String, String)>> {
+    if name == "BASH_ALIASES" {
+        return Some(ctx.aliases.iter().map(|(k, v)| (k.clone(), v.clone())).collect());
+    }
+    let values = ctx.arrays.get(name)?;
+    Some(values.iter().enumerate().map(|(i, v)| (i.to_string(), v.clone())).collect())
+}
+
+// Computes a dynamic variable's value when it's read
+type DynamicValue = fn(&ShellContext) -> String;
+
+// Variables computed each time they're read rather than stored
+const DYNAMIC_VARS: &[(&str, DynamicValue)] = &[
+    ("RANDOM", |_| next_random().to_string()),
+    ("SECONDS", |ctx| ctx.seconds_origin.map_or(0, |t| t.elapsed().as_secs()).to_string()),
+    ("EPOCHSECONDS", |_| {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        now.map_or(0, |d| d.as_secs()).to_string()
+    }),
+];
+
+// $RANDOM's generator state, 0 until first seeded
+static RANDOM_STATE: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+// 0..=32767, from a 64-bit LCG seeded from the clock and pid
+fn next_random() -> u64 {
+    use std::sync::atomic::Ordering;
+    let mut state = RANDOM_STATE.load(Ordering::Relaxed);
+    if state == 0 {
+        let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        state = nanos ^ u64::from(std::process::id()) << 32;
+    }
+    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    RANDOM_STATE.store(state, Ordering::Relaxed);
+    (state >> 33) & 0x7fff
+}
+
+// Assigning a dynamic variable resets what it's computed from: RANDOM=n
+// seeds the generator, SECONDS=n counts on from n; false for other names
+fn assign_dynamic(ctx: &mut ShellContext, name: &str, value: &str) -> bool {
+    let n = value.trim().parse::<u64>().unwrap_or(0);
+    match name {
+        "RANDOM" => RANDOM_STATE.store(n.max(1), std::sync::atomic::Ordering::Relaxed),
+        "SECONDS" => ctx.seconds_origin = Instant::now().checked_sub(Duration::from_secs(n)),
+        "EPOCHSECONDS" => {}
+        _ => return false,
+    }
+    true
+}
+
+fn scalar_value(ctx: &ShellContext, name: &str) -> String {
+    if let Some((_, value)) = DYNAMIC_VARS.iter().find(|(n, _)| *n == name) {
+        return value(ctx);
+    }
+    if let Some(entries) = array_entries(ctx, name) {
+        return entries.into_iter().find(|(k, _)| k == "0").map(|(_, v)| v).unwrap_or_default();
+    }
+    ctx.var(name).unwrap_or_default()
+}
+
+// What a parameter expands to; more than one value only for `[@]`. The
+// `-`, `=`, `?` and `+` operators (each also with a `:`, which makes an
+// empty value count as unset) are applied; any other form is an error, as is
+// `${name?msg}` with name unset.
+fn parameter_values(expr: &str, ctx: &ShellContext) -> Result<Vec<String>, String> {
+    let bad = || format!("{}: bad substitution", parameter_text(expr));
+    if let Some(rest) = expr.strip_prefix('#').filter(|rest| !rest.is_empty()) {
+        let len = match split_subscript(rest) {
+            Some((name, "@" | "*")) => array_entries(ctx, name).map_or(0, |e| e.len()),
+            _ if split_operator(rest).is_some_and(|(_, op)| op.is_empty()) => {
+                plain_values(rest, ctx).concat().chars().count()
+            }
+            _ => return Err(bad()),
+        };
+        return Ok(vec![len.to_string()]);
+    }
+    if let Some(rest) = expr.strip_prefix('!').filter(|rest| !rest.is_empty()) {
+        return match split_subscript(rest) {
+            Some((name, "@")) => Ok(array_entries(ctx, name).unwrap_or_default().into_iter().map(|(k, _)| k).collect()),
+            Some((name, "*")) => {
+                let keys: Vec<String> = array_entries(ctx, name).unwrap_or_default().into_iter().map(|(k, _)| k).collect();
+                Ok(vec![keys.join(" ")])
+            }
+            // `${!name}`: the parameter name's value names
+            None if is_identifier(rest) => match scalar_value(ctx, rest) {
+                target if target.is_empty() => Ok(vec![String::new()]),
+                target => match split_operator(&target) {
+                    Some((param, "")) => Ok(plain_values(param, ctx)),
+                    _ => Err(format!("{target}: invalid variable name")),
+                },
+            },
+            _ => Err(bad()),
+        };
+    }
+
+    let (param, op) = split_operator(expr).ok_or_else(bad)?;
+    let values = plain_values(param, ctx);
+    let Some((colon, kind, word)) = parameter_operator(op) else {
+        return Ok(values);
+    };
+    let missing = !parameter_set(param, ctx) || (colon && values.concat().is_empty());
+    match kind {
+        // Names were assigned by assign_parameter_defaults before the line was expanded
+        '=' if missing && !is_identifier(param) && split_subscript(param).is_none() => {
+            Err(format!("{}: cannot assign in this way", parameter_text(param)))
+        }
+        '-' | '=' if missing => Ok(vec![expand_operand(word, ctx)?]),
+        '?' if missing => match expand_operand(word, ctx)? {
+            msg if msg.is_empty() => Err(format!("{param}: parameter null or not set")),
+            msg => Err(format!("{param}: {msg}")),
+        },
+        '+' if missing => Ok(vec![String::new()]),
+        '+' => Ok(vec![expand_operand(word, ctx)?]),
+        _ => Ok(values),
+    }
+}
+
+// `${param<op>word}` -> (param, "<op>word"); None when what follows the
+// parameter isn't an operator the shell knows
+fn split_operator(expr: &str) -> Option<(&str, &str)> {
+    let end = match expr.chars().next()? {
+        '?' | '$' | '#' | '@' | '*' => 1,
+        c if c.is_ascii_digit() => expr.find(|c: char| !c.is_ascii_digit()).unwrap_or(expr.len()),
+        c if c == '_' || c.is_ascii_alphabetic() => {
+            let name = expr.find(|c: char| c != '_' && !c.is_ascii_alphanumeric()).unwrap_or(expr.len());
+            match expr[name..].starts_with('[') {
+                true => name + expr[name..].find(']')? + 1,
+                false => name,
+            }
+        }
+        _ => return None,
+    };
+    let (param, op) = expr.split_at(end);
+    parameter_operator(op).map_or(op.is_empty(), |_| true).then_some((param, op))
+}
+
+// `:-word` -> (true, '-', "word")
+fn parameter_operator(op: &str) -> Option<(bool, char, &str)> {
+    let (colon, rest) = match op.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, op),
+    };
+    let kind = rest.chars().next().filter(|c| matches!(c, '-' | '=' | '?' | '+'))?;
+    Some((colon, kind, &rest[1..]))
+}
+
+// The parameter's values with no operator applied
+fn plain_values(param: &str, ctx: &ShellContext) -> Vec<String> {
+    match param {
+        "?" => return vec![ctx.last_exit.to_string()],
+        "$" => return vec![std::process::id().to_string()],
+        "_" => return vec![ctx.last_argument.clone()],
+        "#" => return vec![ctx.positional.len().saturating_sub(1).to_string()],
+        "@" => return ctx.positional.iter().skip(1).cloned().collect(),
+        "*" => return vec![ctx.positional.get(1..).unwrap_or_default().join(" ")],
+        _ => {}
+    }
+    if let Ok(n) = param.parse::<usize>() {
+        return vec![ctx.positional.get(n).cloned().unwrap_or_default()];
+    }
+    match split_subscript(param) {
+        Some((name, "@")) => array_entries(ctx, name).unwrap_or_default().into_iter().map(|(_, v)| v).collect(),
+        Some((name, "*")) => {
+            let values: Vec<String> = array_entries(ctx, name).unwrap_or_default().into_iter().map(|(_, v)| v).collect();
+            vec![values.join(" ")]
+        }
+        Some((name, sub)) => match array_entries(ctx, name) {
+            Some(entries) => vec![entries.into_iter().find(|(k, _)| k == sub).map(|(_, v)| v).unwrap_or_default()],
+            None if sub == "0" => vec![scalar_value(ctx, name)],
+            None => vec![String::new()],
+        },
+        None => vec![scalar_value(ctx, param)],
+    }
+}
+
+// Whether the parameter is set at all, empty or not
+fn parameter_set(param: &str, ctx: &ShellContext) -> bool {
+    if let Ok(n) = param.parse::<usize>() {
+        return n < ctx.positional.len();
+    }
+    match split_subscript(param) {
+        Some((name, "@" | "*")) => array_entries(ctx, name).is_some_and(|e| !e.is_empty()),
+        Some((name, sub)) => match array_entries(ctx, name) {
+            Some(entries) => entries.iter().any(|(k, _)| k == sub),
+            None => sub == "0" && ctx.var(name).is_some(),
+        },
+        None if is_identifier(param) => {
+            ctx.var(param).is_some()
+                || array_entries(ctx, param).is_some()
+                || DYNAMIC_VARS.iter().any(|(name, _)| *name == param)
+        }
+        None => !matches!(param, "@" | "*") || ctx.positional.len() > 1,
+    }
+}
+
+// The word after an operator: parameters expanded, quotes removed
+fn expand_operand(word: &str, ctx: &ShellContext) -> Result<String, String> {
+    let mut out = String::new();
+    let mut in_single = false;
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => in_single = !in_single,
+            '"' if !in_single => {}
+            '\\' if !in_single => out.extend(chars.next()),
+            '$' if !in_single => match read_parameter(&mut chars) {
+                Some(expr) => out.push_str(&parameter_values(&expr, ctx)?.join(" ")),
+                None => out.push('$'),
+            },
+            _ => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+// `${name:=word}` and `${name=word}` assign before the line is expanded, so
+// that expansion and any later one on the line see the value
+fn assign_parameter_defaults(ctx: &mut ShellContext, line: &str) -> Result<(), String> {
+    let (mut in_single, mut in_double) = (false, false);
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !in_single => {
+                chars.next();
+            }
+            '\'' if !in_double => in_single = !in_single,
+            '"' if !in_single => in_double = !in_double,
+            '$' if !in_single && chars.peek() == Some(&'{') => {
+                let Some(expr) = read_parameter(&mut chars) else { continue };
+                let Some((param, op)) = split_operator(&expr) else { continue };
+                let Some((colon, '=', word)) = parameter_operator(op) else { continue };
+                let assignable = is_identifier(param) || split_subscript(param).is_some();
+                let missing = !parameter_set(param, ctx) || (colon && plain_values(param, ctx).concat().is_empty());
+                if assignable && missing {
+                    let value = expand_operand(word, ctx)?;
+                    if let Some(Err(msg)) = assign_word(ctx, &format!("{param}={value}")) {
+                        return Err(msg);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// `name=value` / `name[sub]=value` as a command of its own; None if the
+// word isn't an assignment
+fn assign_word(ctx: &mut ShellContext, word: &str) -> Option<Result<(), String>> {
+    let (target, value) = word.split_once('=')?;
+    // Read-only: the shell sets it before each command
+    if target == "BASH_COMMAND" {
+        return Some(Ok(()));
+    }
+    let name = split_subscript(target).map_or(target, |(name, _)| name);
+    if ctx.is_readonly(name) {
+        return Some(Err(format!("{name}: readonly variable")));
+    }
+    if assign_dynamic(ctx, target, value) {
+        return Some(Ok(()));
+    }
+    if is_identifier(target) {
+        match ctx.arrays.get_mut(target) {
+            Some(values) if !values.is_empty() => values[0] = value.to_string(),
+            _ => ctx.set_var(target, value),
+        }
+        variable_changed(ctx, target);
+        return Some(Ok(()));
+    }
+    let (name, sub) = split_subscript(target)?;
+    if name == "BASH_ALIASES" {
+        ctx.aliases.insert(sub.to_string(), value.to_string());
+        return Some(Ok(()));
+    }
+    let Ok(index) = sub.parse::<usize>() else {
+        return Some(Err(format!("{}: bad array subscript", display_str(target))));
+    };
+    let values = ctx.arrays.entry(name.to_string()).or_default();
+    if values.len() <= index {
+        values.resize(index + 1, String::new());
+    }
+    values[index] = value.to_string();
+    Some(Ok(()))
+}
+
+// Name a variable assignment or `unset` would change, for restricted mode
+fn assignment_target(word: &str) -> Option<&str> {
+    let (target, _) = word.split_once('=')?;
+    let name = split_subscript(target).map_or(target, |(name, _)| name);
+    is_identifier(name).then_some(name)
+}
+
+// Shell state that follows a variable. HISTFILE names the file history is
+// saved to (unset or empty: not saved). PATH and PS1 need nothing here:
+// commands are looked up and the prompt built afresh each time.
+fn variable_changed(ctx: &mut ShellContext, name: &str) {
+    if name == "HISTFILE" {
+        ctx.history_settings.file = ctx.var("HISTFILE").filter(|f| !f.is_empty()).map(|f| PathBuf::from(encode_os(&f)));
+    }
+}
+
+// `unset name...`, also `unset 'name[sub]'` for an array element or alias
+fn unset_builtin(ctx: &mut ShellContext, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let mut err = String::new();
+    if let Some(("-f", names)) = args.split_first().map(|(flag, rest)| (flag.as_str(), rest)) {
+        for name in names {
+            ctx.functions.remove(name);
+        }
+        return (vec![], vec![], 0);
+    }
+    for arg in args {
+        let name = split_subscript(arg).map_or(arg.as_str(), |(name, _)| name);
+        if ctx.is_readonly(name) {
+            err.push_str(&format!("unset: {name}: readonly variable\n"));
+            continue;
+        }
+        if is_identifier(arg) {
+            ctx.arrays.remove(arg);
+            ctx.unset_var(arg);
+            variable_changed(ctx, arg);
+            continue;
+        }
+        match split_subscript(arg) {
+            Some(("BASH_ALIASES", sub)) => {
+                ctx.aliases.remove(sub);
+            }
+            Some((name, sub)) => match (ctx.arrays.get_mut(name), sub.parse::<usize>()) {
+                // Arrays here are dense: the last element goes, earlier ones are emptied
+                (Some(values), Ok(i)) if i + 1 == values.len() => {
+                    values.pop();
+                }
+                (Some(values), Ok(i)) if i < values.len() => values[i].clear(),
+                (_, Ok(_)) => {}
+                (_, Err(_)) => err.push_str(&format!("unset: {}: bad array subscript\n", display_str(arg))),
+            },
+            None => err.push_str(&format!("unset: `{}': not a valid identifier\n", display_str(arg))),
+        }
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err.into_bytes(), code)
+}
+
+// `export name[=value] ...` assigns and puts the variables in the
+// environment of commands run from now on; `export` / `export -p` lists them
+fn export_builtin(ctx: &mut ShellContext, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let names = match args.first() {
+        Some(flag) if flag == "-p" || flag == "--" => &args[1..],
+        Some(flag) if flag.starts_with('-') => {
+            return (vec![], format!("export: {}: invalid option\n", display_str(flag)).into_bytes(), 2);
+        }
+        _ => args,
+    };
+    if names.is_empty() {
+        let mut vars: Vec<(String, String)> =
+            env::vars_os().map(|(name, value)| (decode_os(&name), decode_os(&value))).collect();
+        vars.sort();
+        let mut out = String::new();
+        for (name, value) in vars.iter().filter(|(name, _)| is_identifier(name)) {
+            out.push_str(&format!("declare -x {name}={}\n", quote_alias(value)));
+        }
+        return (encode_bytes(&out), vec![], 0);
+    }
+
+    let mut err = String::new();
+    for arg in names {
+        let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+        if !is_identifier(name) {
+            err.push_str(&format!("export: `{}': not a valid identifier\n", display_str(arg)));
+            continue;
+        }
+        if arg.contains('=') {
+            if let Some(Err(msg)) = assign_word(ctx, arg) {
+                err.push_str(&format!("export: {msg}\n"));
+                continue;
+            }
+        }
+        ctx.export_var(name);
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err.into_bytes(), code)
+}
+
+// `readonly name[=value] ...` assigns and marks; `readonly` / `readonly -p` lists
+fn readonly_builtin(ctx: &mut ShellContext, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let names = match args.first() {
+        Some(flag) if flag == "-p" || flag == "--" => &args[1..],
+        Some(flag) if flag.starts_with('-') => {
+            return (vec![], format!("readonly: {}: invalid option\n", display_str(flag)).into_bytes(), 2);
+        }
+        _ => args,
+    };
+    if names.is_empty() {
+        let mut listed: Vec<&String> = ctx.var_attrs.iter().filter(|(_, a)| a.readonly).map(|(n, _)| n).collect();
+        listed.sort();
+        let mut out = String::new();
+        for name in listed {
+            match (array_entries(ctx, name), ctx.var(name)) {
+                (Some(entries), _) => {
+                    let values: Vec<String> = entries.iter().map(|(k, v)| format!("[{k}]={}", quote_word(v))).collect();
+                    out.push_str(&format!("declare -ar {name}=({})\n", values.join(" ")));
+                }
+                (None, Some(value)) => out.push_str(&format!("declare -r {name}={}\n", quote_alias(&value))),
+                (None, None) => out.push_str(&format!("declare -r {name}\n")),
+            }
+        }
+        return (encode_bytes(&out), vec![], 0);
+    }
+
+    let mut err = String::new();
+    for arg in names {
+        let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+        if !is_identifier(name) {
+            err.push_str(&format!("readonly: `{}': not a valid identifier\n", display_str(arg)));
+            continue;
+        }
+        if arg.contains('=') {
+            if let Some(Err(msg)) = assign_word(ctx, arg) {
+                err.push_str(&format!("readonly: {msg}\n"));
+                continue;
+            }
+        }
+        ctx.var_attrs.entry(name.to_string()).or_default().readonly = true;
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err.into_bytes(), code)
+}
+
+fn quote_alias(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+// `alias` lists, `alias name` shows one, `alias name=value` defines
+fn alias_builtin(aliases: &mut AliasMap, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let mut out = String::new();
+    let mut err = String::new();
+    if args.is_empty() || args.iter().all(|a| a == "-p") {
+        for (name, value) in aliases.iter() {
+            out.push_str(&format!("alias {name}={}\n", quote_alias(value)));
+        }
+    }
+    for arg in args.iter().filter(|a| *a != "-p") {
+        match arg.split_once('=') {
+            Some((name, _)) if name.is_empty() || name.contains(['/', '$', '`', '\'', '"', ' ']) => {
+                err.push_str(&format!("alias: `{}': invalid alias name\n", display_str(name)));
+            }
+            Some((name, value)) => {
+                aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match aliases.get(arg) {
+                Some(value) => out.push_str(&format!("alias {arg}={}\n", quote_alias(value))),
+                None => err.push_str(&format!("alias: {}: not found\n", display_str(arg))),
+            },
+        }
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (encode_bytes(&out), err.into_bytes(), code)
+}
+
+// `unalias [-a] name...`
+fn unalias_builtin(aliases: &mut AliasMap, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    if args.is_empty() {
+        return (vec![], b"unalias: usage: unalias [-a] name [name ...]\n".to_vec(), 2);
+    }
+    let mut err = String::new();
+    for arg in args {
+        if arg == "-a" {
+            aliases.clear();
+        } else if aliases.remove(arg).is_none() {
+            err.push_str(&format!("unalias: {}: not found\n", display_str(arg)));
+        }
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err.into_bytes(), code)
+}
+
+// The command word of each pipeline stage replaced by its alias, and that
+// expansion's own command word again, until an alias comes round a second
+// time. An alias ending in a blank (`alias sudo='sudo '`) gets the word
+// after it expanded too.
+fn expand_aliases(tokens: Vec<Token>, ctx: &ShellContext) -> Vec<Token> {
+    substitute_aliases(tokens, ctx, |value| tokenize(value, ctx))
+}
+
+// $BASH_COMMAND: the line with aliases expanded and nothing else
+fn bash_command_text(line: &str, ctx: &ShellContext) -> String {
+    let mut tokens = tokenize_literal(line);
+    let negations = leading_negations(line).0.min(tokens.len());
+    let command = tokens.split_off(negations);
+    let words: Vec<String> = tokens
+        .into_iter()
+        .chain(substitute_aliases(command, ctx, tokenize_literal))
+        .map(|t| match t {
+            // Quoted only where the word boundaries would be lost
+            Token::Word(w) if w.is_empty() || w.contains(char::is_whitespace) => quote_word(&w),
+            Token::Word(w) | Token::Ambiguous(w) | Token::Failed(w) => w,
+            Token::Op(op) => op,
+        })
+        .collect();
+    words.join(" ")
+}
+
+fn substitute_aliases(tokens: Vec<Token>, ctx: &ShellContext, split: impl Fn(&str) -> Vec<Token>) -> Vec<Token> {
+    if ctx.aliases.is_empty() {
+        return tokens;
+    }
+    alias_pass(tokens, ctx, &split, &HashSet::new()).0
+}
+
+// One level of substitution with `seen` the aliases being expanded around
+// it; also whether the word after these tokens is still a command word
+fn alias_pass(
+    tokens: Vec<Token>,
+    ctx: &ShellContext,
+    split: &impl Fn(&str) -> Vec<Token>,
+    seen: &HashSet<String>,
+) -> (Vec<Token>, bool) {
+    let mut out = Vec::with_capacity(tokens.len());
+    let mut at_command = true;
+    let mut after_redirect = false;
+    for token in tokens {
+        match &token {
+            Token::Op(op) => {
+                at_command |= op == "|";
+                after_redirect = op != "|";
+            }
+            Token::Word(_) if after_redirect => after_redirect = false,
+            Token::Ambiguous(_) | Token::Failed(_) => after_redirect = false,
+            Token::Word(word) if at_command => {
+                at_command = false;
+                if let Some(value) = ctx.aliases.get(word).filter(|_| !seen.contains(word)) {
+                    let mut inner = seen.clone();
+                    inner.insert(word.clone());
+                    let (expanded, open) = alias_pass(split(value), ctx, split, &inner);
+                    out.extend(expanded);
+                    at_command = open || value.ends_with([' ', '\t']);
+                    continue;
+                }
+            }
+            Token::Word(_) => {}
+        }
+        out.push(token);
+    }
+    (out, at_command)
+}
+
+// ---------- arithmetic ----------
+// `let expr ...` and `(( expr ))` evaluate C-style integer expressions for
+// their effect: names read shell variables (unset or empty is 0) and the
+// assignment, `++` and `--` operators write them back. The status is 0 when
+// the value is non-zero, 1 when it is zero and 2 when the expression is bad.
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArithToken {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// Longest first, so `<<=` isn't read as `<` `<=`
+const ARITH_OPS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<", ">>", "<=", ">=",
+    "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!", "~", "=", "?", ":", ",",
+    "(", ")",
+];
+
+fn arith_tokens(expr: &str) -> Result<Vec<ArithToken>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            tokens.push(ArithToken::Num(arith_number(&rest[..len]).ok_or_else(|| arith_error(rest))?));
+            len
+        } else if c == '_' || c.is_ascii_alphabetic() {
+            let len = rest.find(|c: char| c != '_' && !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            tokens.push(ArithToken::Name(rest[..len].to_string()));
+            len
+        } else {
+            let op = ARITH_OPS.iter().find(|op| rest.starts_with(**op)).ok_or_else(|| arith_error(rest))?;
+            tokens.push(ArithToken::Op(op));
+            op.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// Decimal, 0x hex or 0 octal
+fn arith_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return i64::from_str_radix(hex, 16).ok();
+    }
+    if text.len() > 1 && text.starts_with('0') {
+        return i64::from_str_radix(&text[1..], 8).ok();
+    }
+    text.parse().ok()
+}
+
+fn arith_error(at: &str) -> String {
+    format!("syntax error in expression (error token is \"{}\")", at.trim())
+}
+
+// Variable values are expressions too; this deep and no further
+const ARITH_MAX_DEPTH: usize = 32;
+
+struct Arith<'a> {
+    tokens: Vec<ArithToken>,
+    pos: usize,
+    ctx: &'a mut ShellContext,
+    // False inside the branch a `&&`, `||` or `?:` skips: no assignments
+    live: bool,
+    depth: usize,
+}
+
+impl Arith<'_> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(ArithToken::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let found = self.peek_op() == Some(op);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.eat(op) {
+            return Ok(());
+        }
+        Err(self.unexpected())
+    }
+
+    fn unexpected(&self) -> String {
+        if self.pos >= self.tokens.len() {
+            return "syntax error: operand expected".to_string();
+        }
+        let rest: Vec<String> = self.tokens[self.pos..]
+            .iter()
+            .map(|t| match t {
+                ArithToken::Num(n) => n.to_string(),
+                ArithToken::Name(name) => name.clone(),
+                ArithToken::Op(op) => op.to_string(),
+            })
+            .collect();
+        arith_error(&rest.join(" "))
+    }
+
+    fn value_of(&mut self, name: &str) -> Result<i64, String> {
+        let text = scalar_value(self.ctx, name);
+        if text.trim().is_empty() {
+            return Ok(0);
+        }
+        if self.depth >= ARITH_MAX_DEPTH {
+            return Err(format!("{name}: expression recursion level exceeded"));
+        }
+        let tokens = arith_tokens(&text)?;
+        let mut inner = Arith { tokens, pos: 0, ctx: &mut *self.ctx, live: true, depth: self.depth + 1 };
+        inner.full()
+    }
+
+    fn store(&mut self, name: &str, value: i64) -> Result<i64, String> {
+        if self.ctx.options.restricted && restricted_variable(name) {
+            return Err(format!("{name}: restricted: cannot set variable"));
+        }
+        if self.live {
+            if let Some(Err(msg)) = assign_word(self.ctx, &format!("{name}={value}")) {
+                return Err(msg);
+            }
+        }
+        Ok(value)
+    }
+
+    fn full(&mut self) -> Result<i64, String> {
+        if self.tokens.is_empty() {
+            return Ok(0);
+        }
+        let value = self.comma()?;
+        if self.pos < self.tokens.len() {
+            return Err(self.unexpected());
+        }
+        Ok(value)
+    }
+
+    fn comma(&mut self) -> Result<i64, String> {
+        let mut value = self.assignment()?;
+        while self.eat(",") {
+            value = self.assignment()?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self) -> Result<i64, String> {
+        let op = match (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            (Some(ArithToken::Name(name)), Some(ArithToken::Op(op))) if op.ends_with('=') && !is_comparison(op) => {
+                Some((name.clone(), *op))
+            }
+            _ => None,
+        };
+        let Some((name, op)) = op else {
+            return self.conditional();
+        };
+        self.pos += 2;
+        let rhs = self.assignment()?;
+        let value = match op {
+            "=" => rhs,
+            _ => arith_op(&op[..op.len() - 1], self.value_of(&name)?, rhs)?,
+        };
+        self.store(&name, value)
+    }
+
+    fn conditional(&mut self) -> Result<i64, String> {
+        let cond = self.binary(0)?;
+        if !self.eat("?") {
+            return Ok(cond);
+        }
+        let live = self.live;
+        self.live = live && cond != 0;
+        let yes = self.comma()?;
+        self.expect(":")?;
+        self.live = live && cond == 0;
+        let no = self.conditional()?;
+        self.live = live;
+        Ok(if cond != 0 { yes } else { no })
+    }
+
+    // Binary operators from `||` (level 0) down to `*` `/` `%`
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        const LEVELS: &[&[&str]] = &[
+            &["||"],
+            &["&&"],
+            &["|"],
+            &["^"],
+            &["&"],
+            &["==", "!="],
+            &["<", "<=", ">", ">="],
+            &["<<", ">>"],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+        if level == LEVELS.len() {
+            return self.power();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| LEVELS[level].contains(op)) {
+            self.pos += 1;
+            let live = self.live;
+            // The right side of a decided `&&` / `||` is parsed, not run
+            if (op == "&&" && lhs == 0) || (op == "||" && lhs != 0) {
+                self.live = false;
+            }
+            let rhs = self.binary(level + 1)?;
+            self.live = live;
+            lhs = match op {
+                "&&" => i64::from(lhs != 0 && rhs != 0),
+                "||" => i64::from(lhs != 0 || rhs != 0),
+                // A skipped division by zero isn't an error
+                _ if !live => 0,
+                _ => arith_op(op, lhs, rhs)?,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn power(&mut self) -> Result<i64, String> {
+        let base = self.unary()?;
+        if !self.eat("**") {
+            return Ok(base);
+        }
+        let exp = self.power()?;
+        arith_op("**", base, exp)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        if let Some(op @ ("++" | "--")) = self.peek_op() {
+            self.pos += 1;
+            let Some(ArithToken::Name(name)) = self.tokens.get(self.pos).cloned() else {
+                return Err(self.unexpected());
+            };
+            self.pos += 1;
+            let value = self.value_of(&name)?;
+            return self.store(&name, if op == "++" { value.wrapping_add(1) } else { value.wrapping_sub(1) });
+        }
+        if let Some(op @ ("!" | "~" | "-" | "+")) = self.peek_op() {
+            self.pos += 1;
+            let value = self.unary()?;
+            return Ok(match op {
+                "!" => i64::from(value == 0),
+                "~" => !value,
+                "-" => value.wrapping_neg(),
+                _ => value,
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        match self.tokens.get(self.pos).cloned() {
+            Some(ArithToken::Num(n)) => {
+                self.pos += 1;
+                Ok(n)
+            }
+            Some(ArithToken::Name(name)) => {
+                self.pos += 1;
+                let value = self.value_of(&name)?;
+                if let Some(op @ ("++" | "--")) = self.peek_op() {
+                    self.pos += 1;
+                    self.store(&name, if op == "++" { value.wrapping_add(1) } else { value.wrapping_sub(1) })?;
+                }
+                Ok(value)
+            }
+            Some(ArithToken::Op("(")) => {
+                self.pos += 1;
+                let value = self.comma()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+fn is_comparison(op: &str) -> bool {
+    matches!(op, "==" | "!=" | "<=" | ">=")
+}
+
+fn arith_op(op: &str, lhs: i64, rhs: i64) -> Result<i64, String> {
+    Ok(match op {
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err("division by 0".to_string()),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        "**" if rhs < 0 => return Err("exponent less than 0".to_string()),
+        "**" => lhs.wrapping_pow(rhs.min(u32::MAX as i64) as u32),
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "&" => lhs & rhs,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "<" => i64::from(lhs < rhs),
+        "<=" => i64::from(lhs <= rhs),
+        ">" => i64::from(lhs > rhs),
+        ">=" => i64::from(lhs >= rhs),
+        "==" => i64::from(lhs == rhs),
+        "!=" => i64::from(lhs != rhs),
+        _ => return Err(format!("{op}: unknown operator")),
+    })
+}
+
+// The value of `expr`, variables updated as it says
+fn eval_arith(ctx: &mut ShellContext, expr: &str) -> Result<i64, String> {
+    let tokens = arith_tokens(expr).map_err(|msg| format!("{}: {msg}", expr.trim()))?;
+    let mut arith = Arith { tokens, pos: 0, ctx, live: true, depth: 0 };
+    arith.full().map_err(|msg| format!("{}: {msg}", expr.trim()))
+}
+
+// `$name` and `${...}` in an arithmetic command, expanded before it's read
+fn expand_arith_params(text: &str, ctx: &ShellContext) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '$' {
+            if let Some(expr) = read_parameter(&mut chars) {
+                out.push_str(&parameter_values(&expr, ctx)?.join(" "));
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    Ok(out)
+}
+
+// The inside of a `(( expr ))` line; None for any other line, `( (cmd) )` included
+fn arith_command(line: &str) -> Option<&str> {
+    line.trim().strip_prefix("((")?.strip_suffix("))")
+}
+
+// `(( expr ))`: 0 when the value is non-zero, 1 when zero, 2 on an error
+fn arith_status(ctx: &mut ShellContext, expr: &str) -> i32 {
+    let expr = match expand_arith_params(expr, ctx) {
+        Ok(expr) => expr,
+        Err(msg) => {
+            print_error(&format!("mysh: {msg}"));
+            return 1;
+        }
+    };
+    match eval_arith(ctx, &expr) {
+        Ok(value) => i32::from(value == 0),
+        Err(msg) => {
+            print_error(&format!("mysh: ((: {msg}"));
+            2
+        }
+    }
+}
+
+// `let expr ...`: each argument is an expression; the last decides the status
+fn let_builtin(ctx: &mut ShellContext, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let args = match args.first() {
+        Some(first) if first == "--" => &args[1..],
+        _ => args,
+    };
+    if args.is_empty() {
+        return (vec![], b"let: expression expected\n".to_vec(), 2);
+    }
+    let mut value = 0;
+    for arg in args {
+        match eval_arith(ctx, arg) {
+            Ok(v) => value = v,
+            Err(msg) => return (vec![], format!("let: {msg}\n").into_bytes(), 2),
+        }
+    }
+    (vec![], vec![], i32::from(value == 0))
+}
+
+// ---------- [[ ]] ----------
+// `[[ expr ]]` lines test strings, numbers and files, joined with `&&`, `||`,
+// `!` and parentheses. Words are expanded but never split or globbed. The
+// right side of `==` / `!=` is a pattern and of `=~` an extended regex; in
+// both, quoted characters stand for themselves. `=~` leaves the match and
+// its groups in BASH_REMATCH.
+
+// The inside of a `[[ expr ]]` line, or an error for a `[[` line missing its
+// `]]`; None for any other line
+fn cond_command(line: &str) -> Option<Result<&str, String>> {
+    let rest = line.trim().strip_prefix("[[")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let inner = rest.strip_suffix("]]").filter(|inner| inner.ends_with(char::is_whitespace));
+    Some(inner.ok_or_else(|| "missing `]]'".to_string()))
+}
+
+// A word of the expression, each character marked true when unquoted, or an
+// unquoted `(`, `)`, `&&`, `||`, `<` or `>`
+#[derive(Debug, Clone, PartialEq)]
+enum CondToken {
+    Word(Vec<(char, bool)>),
+    Op(&'static str),
+}
+
+fn cond_text(word: &[(char, bool)]) -> String {
+    word.iter().map(|&(c, _)| c).collect()
+}
+
+// The word as an operator: written unquoted
+fn cond_op(word: &[(char, bool)]) -> Option<String> {
+    word.iter().all(|&(_, unquoted)| unquoted).then(|| cond_text(word))
+}
+
+fn cond_tokens(text: &str, ctx: &ShellContext) -> Result<Vec<CondToken>, String> {
+    let mut tokens = Vec::new();
+    let mut word: Vec<(char, bool)> = Vec::new();
+    // A quoted empty string is still a word
+    let mut started = false;
+    let mut in_double = false;
+    let end_word = |tokens: &mut Vec<CondToken>, word: &mut Vec<(char, bool)>, started: &mut bool| {
+        if *started || !word.is_empty() {
+            tokens.push(CondToken::Word(cond_tilde(std::mem::take(word), &ctx.named_dirs)));
+        }
+        *started = false;
+    };
+
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if in_double {
+            match ch {
+                '"' => in_double = false,
+                '\\' => match chars.next_if(|c| matches!(c, '\\' | '"' | '$' | '`')) {
+                    Some(c) => word.push((c, false)),
+                    None => word.push(('\\', false)),
+                },
+                '$' => match read_parameter(&mut chars) {
+                    Some(expr) => word.extend(parameter_values(&expr, ctx)?.join(" ").chars().map(|c| (c, false))),
+                    None => word.push(('$', false)),
+                },
+                _ => word.push((ch, false)),
+            }
+            continue;
+        }
+        // The regex after `=~` may hold `(`, `)`, `|`, `<` and `>`
+        let regex = matches!(tokens.last(), Some(CondToken::Word(w)) if cond_op(w).as_deref() == Some("=~"));
+        match ch {
+            c if c.is_whitespace() => end_word(&mut tokens, &mut word, &mut started),
+            '\'' => {
+                started = true;
+                word.extend(chars.by_ref().take_while(|&c| c != '\'').map(|c| (c, false)));
+            }
+            '"' => {
+                started = true;
+                in_double = true;
+            }
+            '\\' => {
+                if let Some(c) = chars.next().filter(|&c| c != '\n') {
+                    word.push((c, false));
+                }
+            }
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                started = true;
+                word.extend(read_ansi_c_quoted(&mut chars).chars().map(|c| (c, false)));
+            }
+            '$' => match read_parameter(&mut chars) {
+                Some(expr) => {
+                    started = true;
+                    word.extend(parameter_values(&expr, ctx)?.join(" ").chars().map(|c| (c, true)));
+                }
+                None => word.push(('$', true)),
+            },
+            '(' | ')' | '<' | '>' if !regex => {
+                end_word(&mut tokens, &mut word, &mut started);
+                tokens.push(CondToken::Op(match ch {
+                    '(' => "(",
+                    ')' => ")",
+                    '<' => "<",
+                    _ => ">",
+                }));
+            }
+            '&' | '|' if !regex && chars.peek() == Some(&ch) => {
+                chars.next();
+                end_word(&mut tokens, &mut word, &mut started);
+                tokens.push(CondToken::Op(if ch == '&' { "&&" } else { "||" }));
+            }
+            _ => word.push((ch, true)),
+        }
+    }
+    end_word(&mut tokens, &mut word, &mut started);
+    Ok(tokens)
+}
+
+// An unquoted `~` / `~name` prefix, up to the first `/`, expanded
+fn cond_tilde(word: Vec<(char, bool)>, named_dirs: &NamedDirs) -> Vec<(char, bool)> {
+    let end = word.iter().position(|&(c, _)| c == '/').unwrap_or(word.len());
+    let prefix = &word[..end];
+    if prefix.first() != Some(&('~', true)) || !prefix.iter().all(|&(_, unquoted)| unquoted) {
+        return word;
+    }
+    match expand_tilde_prefix(&cond_text(prefix), named_dirs) {
+        Some(home) => home.chars().map(|c| (c, false)).chain(word[end..].iter().copied()).collect(),
+        None => word,
+    }
+}
+
+#[derive(Debug)]
+enum CondExpr {
+    Word(String),
+    Unary(String, String),
+    Binary(Vec<(char, bool)>, String, Vec<(char, bool)>),
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+}
+
+const COND_UNARY: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-n", "-o", "-p", "-r", "-s", "-t", "-u", "-v", "-w", "-x",
+    "-z", "-G", "-L", "-N", "-O", "-S",
+];
+const COND_BINARY: &[&str] = &[
+    "=", "==", "!=", "=~", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+struct CondParser {
+    tokens: Vec<CondToken>,
+    pos: usize,
+}
+
+impl CondParser {
+    fn peek(&self) -> Option<&CondToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<CondToken> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // The next token as a binary operator, if it is one
+    fn binary_op(&self) -> Option<String> {
+        match self.peek()? {
+            CondToken::Op(op @ ("<" | ">")) => Some(op.to_string()),
+            CondToken::Word(w) => cond_op(w).filter(|op| COND_BINARY.contains(&op.as_str())),
+            CondToken::Op(_) => None,
+        }
+    }
+
+    fn or(&mut self) -> Result<CondExpr, String> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some(&CondToken::Op("||")) {
+            self.pos += 1;
+            lhs = CondExpr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<CondExpr, String> {
+        let mut lhs = self.not()?;
+        while self.peek() == Some(&CondToken::Op("&&")) {
+            self.pos += 1;
+            lhs = CondExpr::And(Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<CondExpr, String> {
+        match self.peek() {
+            Some(CondToken::Word(w)) if cond_op(w).as_deref() == Some("!") => {
+                self.pos += 1;
+                Ok(CondExpr::Not(Box::new(self.not()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<CondExpr, String> {
+        let word = match self.next() {
+            Some(CondToken::Op("(")) => {
+                let inner = self.or()?;
+                return match self.next() {
+                    Some(CondToken::Op(")")) => Ok(inner),
+                    _ => Err("expected `)'".to_string()),
+                };
+            }
+            Some(CondToken::Op(op)) => return Err(format!("syntax error near `{op}'")),
+            Some(CondToken::Word(w)) => w,
+            None => return Err("unexpected end of expression".to_string()),
+        };
+
+        if let Some(op) = self.binary_op() {
+            self.pos += 1;
+            return match self.next() {
+                Some(CondToken::Word(rhs)) => Ok(CondExpr::Binary(word, op, rhs)),
+                _ => Err(format!("argument expected after `{op}'")),
+            };
+        }
+        match (cond_op(&word), self.peek()) {
+            (Some(op), Some(CondToken::Word(arg))) if COND_UNARY.contains(&op.as_str()) => {
+                let arg = cond_text(arg);
+                self.pos += 1;
+                Ok(CondExpr::Unary(op, arg))
+            }
+            _ => Ok(CondExpr::Word(cond_text(&word))),
+        }
+    }
+}
+
+// `[[ expr ]]`: 0 when true, 1 when false, 2 on an error
+fn cond_status(ctx: &mut ShellContext, inner: Result<&str, String>) -> i32 {
+    // A failed expansion is the command failing, not a syntax error
+    let tokens = match inner.as_ref().map(|text| cond_tokens(text, ctx)) {
+        Ok(Err(msg)) => {
+            print_error(&format!("mysh: {msg}"));
+            return 1;
+        }
+        Ok(Ok(tokens)) => Ok(tokens),
+        Err(msg) => Err(msg.clone()),
+    };
+    let result = tokens.and_then(|tokens| {
+        let mut parser = CondParser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => cond_eval(ctx, &expr),
+            Some(CondToken::Op(op)) => Err(format!("syntax error near `{op}'")),
+            Some(CondToken::Word(w)) => Err(format!("syntax error near `{}'", cond_text(w))),
+        }
+    });
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(msg) => {
+            print_error(&format!("mysh: [[: {msg}"));
+            2
+        }
+    }
+}
+
+fn cond_eval(ctx: &mut ShellContext, expr: &CondExpr) -> Result<bool, String> {
+    Ok(match expr {
+        CondExpr::Word(w) => !w.is_empty(),
+        CondExpr::Not(e) => !cond_eval(ctx, e)?,
+        CondExpr::And(a, b) => cond_eval(ctx, a)? && cond_eval(ctx, b)?,
+        CondExpr::Or(a, b) => cond_eval(ctx, a)? || cond_eval(ctx, b)?,
+        CondExpr::Unary(op, arg) => cond_unary(ctx, op, arg),
+        CondExpr::Binary(lhs, op, rhs) => {
+            let (left, right) = (cond_text(lhs), cond_text(rhs));
+            let name: Vec<char> = left.chars().collect();
+            match op.as_str() {
+                "=" | "==" => glob_match(&glob_component(rhs), &name, false),
+                "!=" => !glob_match(&glob_component(rhs), &name, false),
+                "=~" => cond_regex(ctx, &left, rhs)?,
+                "<" => left < right,
+                ">" => left > right,
+                "-nt" | "-ot" => {
+                    let modified = |p: &str| fs::metadata(encode_os(p)).and_then(|m| m.modified()).ok();
+                    let (l, r) = (modified(&left), modified(&right));
+                    if op == "-nt" {
+                        l.is_some() && (r.is_none() || l > r)
+                    } else {
+                        r.is_some() && (l.is_none() || l < r)
+                    }
+                }
+                "-ef" => same_file(Path::new(&encode_os(&left)), Path::new(&encode_os(&right))),
+                _ => {
+                    let (l, r) = (eval_arith(ctx, &left)?, eval_arith(ctx, &right)?);
+                    match op.as_str() {
+                        "-eq" => l == r,
+                        "-ne" => l != r,
+                        "-lt" => l < r,
+                        "-le" => l <= r,
+                        "-gt" => l > r,
+                        _ => l >= r,
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn cond_unary(ctx: &ShellContext, op: &str, arg: &str) -> bool {
+    let path = encode_os(arg);
+    let meta = fs::metadata(&path);
+    match op {
+        "-z" => arg.is_empty(),
+        "-n" => !arg.is_empty(),
+        "-v" => ctx.var(arg).is_some() || ctx.arrays.contains_key(arg),
+        "-o" => ctx.options.get(arg) == Some(true),
+        "-a" | "-e" => meta.is_ok(),
+        "-f" => meta.is_ok_and(|m| m.is_file()),
+        "-d" => meta.is_ok_and(|m| m.is_dir()),
+        "-s" => meta.is_ok_and(|m| m.len() > 0),
+        "-h" | "-L" => fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()),
+        _ => unix_file_test(op, arg, meta.ok()),
+    }
+}
+
+// Tests that need the file's mode, owner or access rights
+#[cfg(unix)]
+fn unix_file_test(op: &str, arg: &str, meta: Option<fs::Metadata>) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let access = |mode| match CString::new(encode_bytes(arg)) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    };
+    match op {
+        "-r" => access(libc::R_OK),
+        "-w" => access(libc::W_OK),
+        "-x" => access(libc::X_OK),
+        "-t" => arg.parse().is_ok_and(|fd: i32| unsafe { libc::isatty(fd) == 1 }),
+        _ => {
+            let Some(m) = meta else {
+                return false;
+            };
+            match op {
+                "-b" => m.file_type().is_block_device(),
+                "-c" => m.file_type().is_char_device(),
+                "-p" => m.file_type().is_fifo(),
+                "-S" => m.file_type().is_socket(),
+                "-u" => m.mode() & libc::S_ISUID != 0,
+                "-g" => m.mode() & libc::S_ISGID != 0,
+                "-k" => m.mode() & libc::S_ISVTX != 0,
+                "-O" => m.uid() == unsafe { libc::geteuid() },
+                "-G" => m.gid() == unsafe { libc::getegid() },
+                "-N" => m.mtime() > m.atime(),
+                _ => false,
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_file_test(op: &str, _arg: &str, meta: Option<fs::Metadata>) -> bool {
+    match op {
+        "-r" | "-x" => meta.is_some(),
+        "-w" => meta.is_some_and(|m| !m.permissions().readonly()),
+        _ => false,
+    }
+}
+
+// `left =~ rhs`, quoted characters of `rhs` matching literally. Sets
+// BASH_REMATCH to the match and its groups, or to nothing.
+fn cond_regex(ctx: &mut ShellContext, left: &str, rhs: &[(char, bool)]) -> Result<bool, String> {
+    let mut pattern = String::new();
+    for &(c, unquoted) in rhs {
+        if !unquoted && "\\.[]()*+?{}|^$".contains(c) {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    let groups = regex_match(&pattern, left)?;
+    let matched = groups.is_some();
+    ctx.arrays.insert("BASH_REMATCH".to_string(), groups.unwrap_or_default());
+    Ok(matched)
+}
+
+// How many groups an extended regex has: its `(`s outside brackets
+fn ere_groups(pattern: &str) -> usize {
+    let mut count = 0;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '(' => count += 1,
+            '[' => {
+                // `]` right after `[` or `[^` belongs to the bracket
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+                while let Some(c) = chars.next() {
+                    match c {
+                        ']' => break,
+                        // `[:alpha:]`, `[.x.]`, `[=e=]`
+                        '[' if matches!(chars.peek(), Some(':' | '.' | '=')) => {
+                            let close = chars.next();
+                            let mut prev = None;
+                            for c in chars.by_ref() {
+                                if c == ']' && prev == close {
+                                    break;
+                                }
+                                prev = Some(c);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    count
+}
+
+// The C library's extended regexes, as bash uses: the match and each group
+// (empty when it took no part), or None when `text` doesn't match
+#[cfg(unix)]
+fn regex_match(pattern: &str, text: &str) -> Result<Option<Vec<String>>, String> {
+    let invalid = || format!("{pattern}: invalid regular expression");
+    let c_pattern = CString::new(encode_bytes(pattern)).map_err(|_| invalid())?;
+    let c_text = CString::new(encode_bytes(text)).map_err(|_| invalid())?;
+    let mut matches = vec![libc::regmatch_t { rm_so: -1, rm_eo: -1 }; ere_groups(pattern) + 1];
+    unsafe {
+        let mut re: libc::regex_t = std::mem::zeroed();
+        if libc::regcomp(&mut re, c_pattern.as_ptr(), libc::REG_EXTENDED) != 0 {
+            return Err(invalid());
+        }
+        let rc = libc::regexec(&re, c_text.as_ptr(), matches.len(), matches.as_mut_ptr(), 0);
+        libc::regfree(&mut re);
+        if rc != 0 {
+            return Ok(None);
+        }
+    }
+    let bytes = c_text.as_bytes();
+    let group = |m: &libc::regmatch_t| match (usize::try_from(m.rm_so), usize::try_from(m.rm_eo)) {
+        (Ok(start), Ok(end)) if start <= end && end <= bytes.len() => decode_bytes(&bytes[start..end]),
+        _ => String::new(),
+    };
+    Ok(Some(matches.iter().map(group).collect()))
+}
+
+#[cfg(not(unix))]
+fn regex_match(_pattern: &str, _text: &str) -> Result<Option<Vec<String>>, String> {
+    Err("=~ is not supported on this platform".to_string())
+}
+
+// ---------- select ----------
+// `select name [in words]; do commands; done`, on one line (or over several
+// in the line editor), shows the words as a numbered menu on stderr and
+// reads a reply after $PS3. The commands then run with $name set to the
+// chosen word (empty for anything else) and the reply in $REPLY; then the
+// prompt comes back, until `break` or end of input. An empty reply shows the
+// menu again.
+
+struct SelectLoop {
+    name: String,
+    // As written; None without `in`, for the positional parameters
+    words: Option<String>,
+    body: Vec<String>,
+}
+
+// A `select` line cut at unquoted `;` and newlines; None for any other line
+fn select_pieces(line: &str) -> Option<Vec<String>> {
+    let rest = line.trim_start().strip_prefix("select")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(split_commands(line.trim()))
+}
+
+// Text cut at unquoted `;` and newlines
+fn split_commands(text: &str) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    let (mut in_single, mut in_double, mut backslash) = (false, false, false);
+    for ch in text.chars() {
+        match ch {
+            _ if backslash => backslash = false,
+            '\\' if !in_single => backslash = true,
+            '\'' if !in_double => in_single = !in_single,
+            '"' if !in_single => in_double = !in_double,
+            ';' | '\n' if !in_single && !in_double => {
+                pieces.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        if let Some(piece) = pieces.last_mut() {
+            piece.push(ch);
+        }
+    }
+    pieces
+}
+
+// A `select` line still waiting for its `done`
+fn select_unfinished(line: &str) -> bool {
+    select_pieces(line).is_some_and(|pieces| pieces.last().is_none_or(|p| p.trim() != "done"))
+}
+
+fn select_command(line: &str) -> Option<Result<SelectLoop, String>> {
+    let pieces = select_pieces(line)?;
+    let parse = || {
+        let header = pieces[0].trim().strip_prefix("select").unwrap_or_default().trim_start();
+        let (name, rest) = header.split_once(char::is_whitespace).unwrap_or((header, ""));
+        if !is_identifier(name) {
+            return Err(format!("`{name}': not a valid identifier"));
+        }
+        let rest = rest.trim_start();
+        let words = match rest.strip_prefix("in") {
+            Some(words) if words.is_empty() || words.starts_with(char::is_whitespace) => Some(words.to_string()),
+            _ if rest.is_empty() => None,
+            _ => return Err(format!("syntax error near `{}'", rest.split_whitespace().next().unwrap_or(rest))),
+        };
+        let (Some(first), Some(last)) = (pieces.get(1), pieces.last().filter(|_| pieces.len() > 2)) else {
+            return Err("missing `do'".to_string());
+        };
+        let first = first.trim_start();
+        let first = match first.strip_prefix("do") {
+            Some(cmd) if cmd.is_empty() || cmd.starts_with(char::is_whitespace) => cmd,
+            _ => return Err("missing `do'".to_string()),
+        };
+        if last.trim() != "done" {
+            return Err("missing `done'".to_string());
+        }
+        let body = std::iter::once(first)
+            .chain(pieces[2..pieces.len() - 1].iter().map(String::as_str))
+            .map(str::trim)
+            .filter(|cmd| !cmd.is_empty())
+            .map(str::to_string)
+            .collect();
+        Ok(SelectLoop { name: name.to_string(), words, body })
+    };
+    Some(parse())
+}
+
+fn select_menu(words: &[String]) -> String {
+    let width = words.len().to_string().len();
+    let mut menu = String::new();
+    for (i, word) in words.iter().enumerate() {
+        menu.push_str(&format!("{:>width$}) {}\n", i + 1, display_str(word)));
+    }
+    menu
+}
+
+// One round of the loop for `line`: the menu (not when `again`), a reply,
+// then the commands queued to run, followed by `line` again. 1 at end of input.
+fn select_round(ctx: &mut ShellContext, select: &SelectLoop, line: &str, again: bool, queued: &mut LineQueue) -> i32 {
+    let words: Vec<String> = match &select.words {
+        Some(text) => tokenize(text, ctx).iter().filter_map(Token::as_word).map(str::to_string).collect(),
+        None => ctx.positional.iter().skip(1).cloned().collect(),
+    };
+    if words.is_empty() {
+        return 0;
+    }
+    let prompt = ctx.var("PS3").unwrap_or_else(|| "#? ".to_string());
+    let mut show_menu = !again;
+    let reply = loop {
+        if show_menu {
+            eprint!("{}", select_menu(&words));
+        }
+        show_menu = true;
+        eprint!("{prompt}");
+        let _ = io::stderr().flush();
+        let reply = match read_input_line(&mut io::stdin().lock(), false) {
+            Ok(reply) if !reply.is_empty() => reply,
+            Ok(_) => {
+                eprintln!();
+                return 1;
+            }
+            Err(e) => {
+                eprintln!("select: {e}");
+                return 1;
+            }
+        };
+        let (assignments, _) = read_fields(false, &[], &reply);
+        let reply = assignments.into_iter().next().map(|(_, value)| value).unwrap_or_default();
+        if !reply.trim().is_empty() {
+            break reply;
+        }
+    };
+
+    if ctx.is_readonly(&select.name) {
+        eprintln!("select: {}: readonly variable", select.name);
+        return 1;
+    }
+    let chosen = match reply.trim().parse::<usize>() {
+        Ok(n) if (1..=words.len()).contains(&n) => words[n - 1].clone(),
+        _ => String::new(),
+    };
+    ctx.set_var("REPLY", &reply);
+    ctx.set_var(&select.name, &chosen);
+    queued.push_front((line.to_string(), Some("select-again")));
+    for cmd in select.body.iter().rev() {
+        queued.push_front((cmd.clone(), Some("select")));
+    }
+    0
+}
+
+// `break` / `continue` among a select's commands: drops the rest of them,
+// and with `break` the next round too
+fn select_jump(line: &str, queued: &mut LineQueue) {
+    while let Some((_, trap)) = queued.front() {
+        match *trap {
+            Some("select") => {}
+            Some("select-again") if line == "break" => {}
+            _ => break,
+        }
+        let again = *trap == Some("select-again");
+        queued.pop_front();
+        if again {
+            break;
+        }
+    }
+}
+
+// ---------- shell functions ----------
+// `name() { cmd; ...; }` or `function name { ...; }` defines a function, its
+// commands cut at `;` and newlines as in select. A call queues the commands to
+// run next with $1... set to its arguments, then a marker that gives the
+// caller's back; `return [n]` drops what's left of them.
+
+// Deepest function nesting before giving up (a function calling itself)
+const FUNCTION_DEPTH_MAX: usize = 1000;
+
+// Name and the text after `{` of a function definition; None for any other line
+fn function_header(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    let (name, rest) = match line.strip_prefix("function") {
+        Some(rest) if rest.starts_with(char::is_whitespace) => {
+            let rest = rest.trim_start();
+            let end = rest.find(|c: char| c.is_whitespace() || matches!(c, '(' | '{')).unwrap_or(rest.len());
+            let (name, rest) = rest.split_at(end);
+            let rest = rest.trim_start();
+            (name, rest.strip_prefix("()").unwrap_or(rest))
+        }
+        _ => {
+            let end = line.find(|c: char| c.is_whitespace() || c == '(').unwrap_or(line.len());
+            let (name, rest) = line.split_at(end);
+            (name, rest.trim_start().strip_prefix("()")?)
+        }
+    };
+    let body = rest.trim_start().strip_prefix('{')?;
+    (is_identifier(name) && (body.is_empty() || body.starts_with(char::is_whitespace))).then_some((name, body))
+}
+
+// A function definition still waiting for its `}`
+fn function_unfinished(line: &str) -> bool {
+    function_header(line).is_some_and(|(_, body)| split_commands(body).last().is_none_or(|p| p.trim() != "}"))
+}
+
+fn function_definition(line: &str) -> Option<Result<(String, Vec<String>), String>> {
+    let (name, body) = function_header(line)?;
+    let mut pieces = split_commands(body);
+    if pieces.pop().is_none_or(|p| p.trim() != "}") {
+        return Some(Err("syntax error: unexpected end of file".to_string()));
+    }
+    let body: Vec<String> = pieces.iter().map(|p| p.trim()).filter(|p| !p.is_empty()).map(str::to_string).collect();
+    if body.is_empty() {
+        return Some(Err("syntax error near unexpected token `}'".to_string()));
+    }
+    Some(Ok((name.to_string(), body)))
+}
+
+// The definition as one line, for `type` and the completion shell
+fn function_text(name: &str, body: &[String]) -> String {
+    format!("{name}() {{ {}; }}", body.join("; "))
+}
+
+// A line that goes on to the next one: a select without its `done` or a
+// function without its `}`
+fn continues_on_next_line(line: &str) -> bool {
+    let (_, line) = leading_negations(line);
+    select_unfinished(line) || function_unfinished(line)
+}
+
+// Lines of a script, with the ones continuing a select or function joined
+fn script_lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut open = false;
+    for l in text.lines() {
+        match lines.last_mut() {
+            Some(last) if open => {
+                last.push('\n');
+                last.push_str(l);
+            }
+            _ => lines.push(l.to_string()),
+        }
+        open = lines.last().is_some_and(|last| continues_on_next_line(last));
+    }
+    lines
+}
+
+fn call_function(ctx: &mut ShellContext, name: &str, args: &[String], queued: &mut LineQueue) -> i32 {
+    if ctx.function_frames.len() >= FUNCTION_DEPTH_MAX {
+        print_error(&format!("mysh: {name}: maximum function nesting level exceeded ({FUNCTION_DEPTH_MAX})"));
+        return 1;
+    }
+    let Some(body) = ctx.functions.get(name) else { return 127 };
+    queued.push_front((String::new(), Some("function-return")));
+    for cmd in body.iter().rev() {
+        queued.push_front((cmd.clone(), Some("function")));
+    }
+    let mut positional = vec![ctx.positional.first().cloned().unwrap_or_default()];
+    positional.extend(args.iter().cloned());
+    let caller = std::mem::replace(&mut ctx.positional, positional);
+    ctx.function_frames.push(caller);
+    0
+}
+
+fn end_function(ctx: &mut ShellContext) {
+    if let Some(caller) = ctx.function_frames.pop() {
+        ctx.positional = caller;
+    }
+}
+
+// `return [n]`: the rest of the innermost function is dropped; its status
+// is n, or that of the last command
+fn return_builtin(ctx: &mut ShellContext, args: &[String], queued: &mut LineQueue) -> i32 {
+    if ctx.function_frames.is_empty() {
+        print_error("mysh: return: can only `return' from a function");
+        return 1;
+    }
+    let status = match args.first() {
+        None => ctx.last_exit,
+        Some(n) => match n.parse::<i64>() {
+            Ok(n) => (n & 0xff) as i32,
+            Err(_) => {
+                print_error(&format!("mysh: return: {}: numeric argument required", display_str(n)));
+                2
+            }
+        },
+    };
+    while let Some((_, trap)) = queued.pop_front() {
+        if trap == Some("function-return") {
+            break;
+        }
+    }
+    end_function(ctx);
+    status
+}
+
+// `name=(word ...)` / `name+=(word ...)` as a line of its own: the words,
+// expanded and split like arguments, become the array's elements. None for
+// any other line.
+fn array_assignment(ctx: &mut ShellContext, line: &str) -> Option<Result<(), String>> {
+    let (target, rest) = line.trim().split_once("=(")?;
+    let words = rest.strip_suffix(')')?;
+    let (name, append) = match target.strip_suffix('+') {
+        Some(name) => (name, true),
+        None => (target, false),
+    };
+    if !is_identifier(name) {
+        return None;
+    }
+    if ctx.is_readonly(name) {
+        return Some(Err(format!("{name}: readonly variable")));
+    }
+    if ctx.options.restricted && restricted_variable(name) {
+        return Some(Err(format!("{name}: restricted: cannot set variable")));
+    }
+    let tokens = tokenize(words, ctx);
+    if let Some(Token::Failed(msg)) = tokens.iter().find(|t| matches!(t, Token::Failed(_))) {
+        return Some(Err(msg.clone()));
+    }
+    let words = tokens.iter().filter_map(Token::as_word).map(str::to_string);
+    let values = ctx.arrays.entry(name.to_string()).or_default();
+    if !append {
+        values.clear();
+    }
+    values.extend(words);
+    Some(Ok(()))
+}
+
+// ---------- traps ----------
+// `trap 'commands' EXIT` runs the commands once as the shell ends, however it
+// ends: `exit`, end of input, or SIGHUP/SIGTERM while the trap is set. The
+// commands run like typed lines; an `exit` among them ends the shell for good.
+// `trap 'commands' ERR` runs them after each command that fails, with $? still
+// its status; failures in the ERR trap's own commands don't set it off again.
+// `trap 'commands' DEBUG` runs them before each command line, $BASH_COMMAND
+// holding the line; trap commands themselves don't set it off.
+
+// Lines to run before reading more input, with the trap condition or hook
+// they run for. Those stay out of history; lines fc hands back don't.
+type LineQueue = std::collections::VecDeque<(String, Option<&'static str>)>;
+
+// Conditions `trap` accepts, as `trap -l` lists them
+const TRAP_CONDITIONS: &[&str] = &["EXIT", "ERR", "DEBUG"];
+
+// Condition name to the commands run for it ("" runs nothing)
+#[derive(Debug, Clone, Default)]
+struct TrapTable {
+    actions: std::collections::BTreeMap<String, String>,
+}
+
+impl TrapTable {
+    // Commands set for `condition`, None when unset or empty
+    fn action(&self, condition: &str) -> Option<&str> {
+        self.actions.get(condition).map(String::as_str).filter(|a| !a.trim().is_empty())
+    }
+}
+
+// EXIT, 0 (its number) and any case
+fn trap_condition(spec: &str) -> Option<&'static str> {
+    let upper = spec.to_ascii_uppercase();
+    let name = if upper == "0" { "EXIT" } else { upper.as_str() };
+    TRAP_CONDITIONS.iter().find(|c| **c == name).copied()
+}
+
+fn list_traps(traps: &TrapTable, only: &[&str]) -> String {
+    let mut out = String::new();
+    for (condition, action) in &traps.actions {
+        if only.is_empty() || only.contains(&condition.as_str()) {
+            out.push_str(&format!("trap -- {} {condition}\n", quote_alias(action)));
+        }
+    }
+    out
+}
+
+// `trap` / `trap -p [condition ...]` list, `trap -l` names the conditions,
+// `trap action condition ...` sets and `trap [-] condition ...` resets
+fn trap_builtin(traps: &mut TrapTable, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let args = match args.first() {
+        Some(&"--") => &args[1..],
+        _ => &args[..],
+    };
+    let invalid = |spec: &str| format!("trap: {spec}: invalid signal specification\n").into_bytes();
+
+    match args {
+        [] => return (encode_bytes(&list_traps(traps, &[])), vec![], 0),
+        ["-l", ..] => return (format!("{}\n", TRAP_CONDITIONS.join(" ")).into_bytes(), vec![], 0),
+        ["-p", specs @ ..] => {
+            let mut only = Vec::new();
+            for spec in specs {
+                match trap_condition(spec) {
+                    Some(condition) => only.push(condition),
+                    None => return (vec![], invalid(spec), 1),
+                }
+            }
+            return (encode_bytes(&list_traps(traps, &only)), vec![], 0);
+        }
+        [flag, ..] if flag.starts_with('-') && flag.len() > 1 => {
+            return (vec![], format!("trap: {flag}: invalid option\n").into_bytes(), 2);
+        }
+        _ => {}
+    }
+
+    // A lone condition, or `-` first, resets
+    let (action, specs) = match args {
+        [_] => (None, args),
+        ["-", specs @ ..] => (None, specs),
+        [action, specs @ ..] => (Some(action.to_string()), specs),
+        [] => (None, args),
+    };
+    let mut err = Vec::new();
+    for spec in specs {
+        match (trap_condition(spec), &action) {
+            (Some(condition), Some(action)) => {
+                traps.actions.insert(condition.to_string(), action.clone());
+            }
+            (Some(condition), None) => {
+                traps.actions.remove(condition);
+            }
+            (None, _) => err.extend(invalid(spec)),
+        }
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err, code)
+}
+
+// SIGHUP or SIGTERM that arrived while an EXIT trap was set, 0 for none
+static CAUGHT_SIGNAL: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn note_signal(sig: libc::c_int) {
+    CAUGHT_SIGNAL.store(sig, std::sync::atomic::Ordering::SeqCst);
+}
+
+// With an EXIT trap set these signals end the shell through the main loop
+// (so the trap runs) instead of killing it outright
+#[cfg(unix)]
+fn watch_exit_signals(on: bool) {
+    let handler = if on { note_signal as extern "C" fn(libc::c_int) as libc::sighandler_t } else { libc::SIG_DFL };
+    for sig in [libc::SIGHUP, libc::SIGTERM] {
+        unsafe { libc::signal(sig, handler) };
+    }
+}
+
+#[cfg(not(unix))]
+fn watch_exit_signals(_on: bool) {}
+
+fn take_caught_signal() -> Option<i32> {
+    match CAUGHT_SIGNAL.swap(0, std::sync::atomic::Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+// Queues the EXIT trap's commands, taking the trap out so nothing run from
+// it (`exit` included) triggers it again; false when there is none
+fn run_exit_traps(ctx: &mut ShellContext, queued: &mut LineQueue) -> bool {
+    let action = ctx.traps.actions.remove("EXIT");
+    watch_exit_signals(false);
+    let Some(action) = action.filter(|a| !a.trim().is_empty()) else {
+        return false;
+    };
+    queued.extend(action.lines().filter(|l| !l.trim().is_empty()).map(|l| (l.to_string(), Some("EXIT"))));
+    true
+}
+
+// Queues a config hook's lines ahead of anything else, tagged with its name
+fn run_hook(queued: &mut LineQueue, hook: &[String], name: &'static str) {
+    let lines = hook.iter().flat_map(|h| h.lines()).filter(|l| !l.trim().is_empty());
+    for l in lines.collect::<Vec<_>>().into_iter().rev() {
+        queued.push_front((l.to_string(), Some(name)));
+    }
+}
+
+// Queues an ERR or DEBUG trap's commands ahead of anything else; false when unset
+fn run_trap(ctx: &ShellContext, queued: &mut LineQueue, condition: &'static str) -> bool {
+    let Some(action) = ctx.traps.action(condition) else {
+        return false;
+    };
+    for l in action.lines().rev().filter(|l| !l.trim().is_empty()) {
+        queued.push_front((l.to_string(), Some(condition)));
+    }
+    true
+}
+
+// Dies of the signal that ended the shell, as if the trap had not caught it
+#[cfg(unix)]
+fn resend_signal(sig: i32) {
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+        libc::raise(sig);
+    }
+}
+
+#[cfg(not(unix))]
+fn resend_signal(_sig: i32) {}
+
+// ---------- shell state ----------
+// `set -o` options
+#[derive(Debug, Clone, Default)]
+struct ShellOptions {
+    // A typed line naming only a directory (not a command) cds into it
+    autocd: bool,
+    // cd fixes a one-typo path component (see correct_path)
+    cdspell: bool,
+    // Patterns match names starting with `.` without one written
+    dotglob: bool,
+    // Stop at the first failing command
+    errexit: bool,
+    // Ctrl-R / Ctrl-T pick from history / files with fzf, when installed
+    fzf: bool,
+    // `**` in a pattern matches any depth of directories
+    globstar: bool,
+    histexpand: bool,
+    noclobber: bool,
+    // Patterns stay as written: no pathname expansion
+    noglob: bool,
+    // Patterns ignore case: `*.JPG` finds `a.jpg`
+    nocaseglob: bool,
+    notify: bool,
+    // Tell the terminal the cwd with OSC 7 (off: some terminals print it)
+    osc7: bool,
+    // vi key bindings in the line editor; `emacs` is just the opposite
+    vi: bool,
+    // Restricted shell: see restricted_violation. Can't be turned off again.
+    restricted: bool,
+}
+
+impl ShellOptions {
+    const NAMES: &'static [&'static str] =
+        &[
+        "autocd", "cdspell", "dotglob", "emacs", "errexit", "fzf", "globstar", "histexpand", "nocaseglob", "noclobber",
+        "noglob", "notify", "osc7", "restricted", "vi",
+    ];
+    // `set -X` / `set +X` spellings
+    const SHORT: &'static [(char, &'static str)] =
+        &[('b', "notify"), ('C', "noclobber"), ('e', "errexit"), ('f', "noglob"), ('H', "histexpand"), ('r', "restricted")];
+
+    fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "autocd" => Some(self.autocd),
+            "cdspell" => Some(self.cdspell),
+            "dotglob" => Some(self.dotglob),
+            "errexit" => Some(self.errexit),
+            "fzf" => Some(self.fzf),
+            "globstar" => Some(self.globstar),
+            "histexpand" => Some(self.histexpand),
+            "nocaseglob" => Some(self.nocaseglob),
+            "noclobber" => Some(self.noclobber),
+            "noglob" => Some(self.noglob),
+            "notify" => Some(self.notify),
+            "osc7" => Some(self.osc7),
+            "vi" => Some(self.vi),
+            "restricted" => Some(self.restricted),
+            "emacs" => Some(!self.vi),
+            _ => None,
+        }
+    }
+
+    // false if there is no such option
+    fn set(&mut self, name: &str, on: bool) -> bool {
+        match name {
+            "autocd" => self.autocd = on,
+            "cdspell" => self.cdspell = on,
+            "dotglob" => self.dotglob = on,
+            "errexit" => self.errexit = on,
+            "fzf" => self.fzf = on,
+            "globstar" => self.globstar = on,
+            "histexpand" => self.histexpand = on,
+            "nocaseglob" => self.nocaseglob = on,
+            "noclobber" => self.noclobber = on,
+            "noglob" => self.noglob = on,
+            "notify" => self.notify = on,
+            "osc7" => self.osc7 = on,
+            "vi" => self.vi = on,
+            "restricted" => self.restricted |= on,
+            "emacs" => self.vi = !on,
+            _ => return false,
+        }
+        true
+    }
+
+    fn edit_mode(&self) -> EditMode {
+        if self.vi {
+            EditMode::Vi
+        } else {
+            EditMode::Emacs
+        }
+    }
+
+    // Pushes options that other threads act on to where they look
+    fn publish(&self) {
+        NOTIFY_ASYNC.store(self.notify, std::sync::atomic::Ordering::Relaxed);
+        REPORT_CWD.store(self.osc7, std::sync::atomic::Ordering::Relaxed);
+        FZF_ENABLED.store(self.fzf, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+// Drops every earlier copy of `cmd`, then appends it: the other entries
+// keep their order
+fn apply_erasedups(cmd: &str, history: &mut Vec<String>) {
+    history.retain(|entry| entry != cmd);
+    history.push(cmd.to_string());
+}
+
+#[derive(Debug, Clone, Default)]
+struct HistorySettings {
+    // Entries kept (None = unlimited)
+    size: Option<usize>,
+    // Loaded at startup, written back on exit
+    file: Option<PathBuf>,
+    ignorespace: bool,
+    ignoredups: bool,
+    // Older copies of a line are dropped when it's added again
+    erasedups: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct VarAttrs {
+    // Set by `readonly`: no more assignments or `unset` this session
+    readonly: bool,
+    // Set by `export`, even before the variable has a value
+    exported: bool,
+}
+
+// `hash -d name=path`: `~name` stands for path
+type NamedDirs = HashMap<String, PathBuf>;
+
+// Everything a command can read or change about the running shell
+#[derive(Debug, Clone, Default)]
+struct ShellContext {
+    options: ShellOptions,
+    history_settings: HistorySettings,
+    // Our own history list for the "history" builtin output (must include invalid commands + history itself)
+    history: Vec<String>,
+    // Entries of `history` already in the history file (`history -a` writes the rest)
+    history_write_offset: usize,
+    // Lines of the history file already read (`history -n` reads the rest)
+    history_file_lines: usize,
+    // Exit status of the last command (what `$?` reports and `exit` defaults to)
+    last_exit: i32,
+    // What `$_` reports: the last word of the previous simple command
+    last_argument: String,
+    plugins: PluginTable,
+    // Shell variables that aren't exported. Exported ones live in the
+    // environment, which is all child processes get.
+    variables: HashMap<String, String>,
+    // Indexed arrays, filled by mapfile
+    arrays: HashMap<String, Vec<String>>,
+    // Attributes of variables and arrays, by name; values stay where they are
+    var_attrs: HashMap<String, VarAttrs>,
+    // When $SECONDS was 0
+    seconds_origin: Option<Instant>,
+    named_dirs: NamedDirs,
+    key_bindings: KeyBindings,
+    aliases: AliasMap,
+    // $0, $1, ... ($0 is the shell or script name)
+    positional: Vec<String>,
+    // Shell functions: the commands of each body, by name
+    functions: HashMap<String, Vec<String>>,
+    // Callers' positional parameters, one per function running
+    function_frames: Vec<Vec<String>>,
+    traps: TrapTable,
+    hooks: CommandHooks,
+}
+
+impl ShellContext {
+    // Records a line unless HISTCONTROL-style settings say otherwise;
+    // returns whether it was kept
+    fn add_history(&mut self, line: &str) -> bool {
+        let hs = &self.history_settings;
+        if hs.ignorespace && line.starts_with(' ') {
+            return false;
+        }
+        if hs.ignoredups && self.history.last().map(String::as_str) == Some(line) {
+            return false;
+        }
+        if hs.erasedups {
+            // Copies already in the history file count against what -a skips
+            let written = self.history[..self.history_write_offset.min(self.history.len())]
+                .iter()
+                .filter(|h| *h == line)
+                .count();
+            self.history_write_offset -= written;
+            apply_erasedups(line, &mut self.history);
+            self.trim_history();
+            return true;
+        }
+        self.history.push(line.to_string());
+        self.trim_history();
+        true
+    }
+
+    fn is_readonly(&self, name: &str) -> bool {
+        self.var_attrs.get(name).is_some_and(|a| a.readonly)
+    }
+
+    // A variable's value, whether exported or not
+    fn var(&self, name: &str) -> Option<String> {
+        match self.variables.get(name) {
+            Some(value) => Some(value.clone()),
+            None => env::var_os(name).map(|v| decode_os(&v)),
+        }
+    }
+
+    // An exported variable stays exported; anything else is the shell's own
+    fn set_var(&mut self, name: &str, value: &str) {
+        if env::var_os(name).is_some() || self.var_attrs.get(name).is_some_and(|a| a.exported) {
+            env::set_var(name, encode_os(value));
+        } else {
+            self.variables.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    fn unset_var(&mut self, name: &str) {
+        self.variables.remove(name);
+        env::remove_var(name);
+        if let Some(attrs) = self.var_attrs.get_mut(name) {
+            attrs.exported = false;
+        }
+    }
+
+    // Moves a shell variable into the environment; one without a value yet
+    // goes there when it gets one
+    fn export_var(&mut self, name: &str) {
+        if let Some(value) = self.variables.remove(name) {
+            env::set_var(name, encode_os(&value));
+        }
+        self.var_attrs.entry(name.to_string()).or_default().exported = true;
+    }
+
+    fn trim_history(&mut self) {
+        if let Some(size) = self.history_settings.size {
+            let excess = self.history.len().saturating_sub(size);
+            self.history.drain(..excess);
+            self.history_write_offset = self.history_write_offset.saturating_sub(excess);
+        }
+    }
+
+    fn load_history_file(&mut self) {
+        let Some(path) = &self.history_settings.file else { return };
+        let Ok(bytes) = fs::read(path) else { return };
+        let before = self.history.len();
+        self.history
+            .extend(decode_bytes(&bytes).lines().filter(|l| !l.is_empty()).map(str::to_string));
+        self.history_file_lines = self.history.len() - before;
+        self.history_write_offset = self.history.len();
+        self.trim_history();
+    }
+
+    fn save_history_file(&self) {
+        let Some(path) = &self.history_settings.file else { return };
+        if let Err(e) = write_history(path, &self.history) {
+            eprintln!("history: {}: {e}", path.display());
+        }
+    }
+}
+
+// `set` with no arguments or `-o` lists options; `set -o name` / `set +o name`
+// turns one on / off, as do the short forms (`set -b`, `set +C`)
+const RESTRICTED_UNSET: &[u8] = b"set: restricted: cannot be turned off\n";
+
+fn set_builtin(options: &mut ShellOptions, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    if args.is_empty() {
+        return set_builtin(options, &["-o".to_string()]);
+    }
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let on = match arg {
+            "-o" => true,
+            "+o" => false,
+            _ => {
+                let (on, letters) = match (arg.strip_prefix('-'), arg.strip_prefix('+')) {
+                    (Some(l), _) if !l.is_empty() => (true, l),
+                    (_, Some(l)) if !l.is_empty() => (false, l),
+                    _ => return (vec![], format!("set: {arg}: invalid option\n").into_bytes(), 2),
+                };
+                for c in letters.chars() {
+                    match ShellOptions::SHORT.iter().find(|(short, _)| *short == c) {
+                        Some((_, name)) if !on && *name == "restricted" && options.restricted => {
+                            return (vec![], RESTRICTED_UNSET.to_vec(), 1);
+                        }
+                        Some((_, name)) => {
+                            options.set(name, on);
+                        }
+                        None => {
+                            return (vec![], format!("set: -{c}: invalid option\n").into_bytes(), 2);
+                        }
+                    }
+                }
+                i += 1;
+                continue;
+            }
+        };
+        match args.get(i + 1) {
+            Some(name) if !on && name == "restricted" && options.restricted => {
+                return (vec![], RESTRICTED_UNSET.to_vec(), 1);
+            }
+            Some(name) => {
+                if !options.set(name, on) {
+                    return (vec![], format!("set: {name}: invalid option name\n").into_bytes(), 1);
+                }
+                i += 2;
+            }
+            None => {
+                for name in ShellOptions::NAMES {
+                    let state = if options.get(name) == Some(true) { "on" } else { "off" };
+                    out.extend(format!("{name:<15}\t{state}\n").into_bytes());
+                }
+                i += 1;
+            }
+        }
+    }
+    (out, vec![], 0)
+}
+
+// ---------- restricted mode ----------
+// What a restricted shell (`--restricted`, `set -r`) refuses to run, as the
+// message to print: output redirections, commands named by path, cd to an
+// absolute path, setting PATH/SHELL/ENV with read, exec and plugins.
+// Arithmetic and array assignments check restricted_variable themselves.
+fn restricted_violation(stages: &[ParsedCommand]) -> Option<String> {
+    for stage in stages {
+        let redirect = match (&stage.stdout, &stage.stderr) {
+            (StdoutRedirect::Truncate(t) | StdoutRedirect::Append(t), _) => Some(t),
+            (_, StderrRedirect::Truncate(t) | StderrRedirect::Append(t)) => Some(t),
+            _ => stage.fds.iter().find_map(|r| match r {
+                FdRedirect::Write(_, t) | FdRedirect::Append(_, t) => Some(t),
+                _ => None,
+            }),
+        };
+        if let Some(target) = redirect {
+            return Some(format!("{}: restricted: cannot redirect output", display_str(target)));
+        }
+
+        let cmd = stage.cmd.as_str();
+        let assignment = stage.args.is_empty() && assignment_target(cmd).is_some();
+        if cmd.contains('/') && !assignment {
+            return Some(format!("{}: restricted: cannot specify `/' in command names", display_str(cmd)));
+        }
+        match cmd {
+            "cd" if stage.args.first().is_some_and(|dir| Path::new(dir).is_absolute()) => {
+                let dir = display_str(&stage.args[0]);
+                return Some(format!("cd: {dir}: restricted: cannot change to an absolute path"));
+            }
+            "read" => {
+                let protected = |a: &&String| restricted_variable(a);
+                if let Some(name) = stage.args.iter().find(protected) {
+                    return Some(format!("read: {name}: restricted: cannot set variable"));
+                }
+            }
+            "exec" | "load_plugin" => return Some(format!("{cmd}: restricted")),
+            "source" | "." if stage.args.first().is_some_and(|f| f.contains('/')) => {
+                let file = display_str(&stage.args[0]);
+                return Some(format!("{cmd}: {file}: restricted"));
+            }
+            "unset" => {
+                let protected = |a: &&String| restricted_variable(a);
+                if let Some(name) = stage.args.iter().find(protected) {
+                    return Some(format!("unset: {name}: restricted: cannot unset variable"));
+                }
+            }
+            "export" | "readonly" => {
+                let protected = |a: &&String| assignment_target(a).is_some_and(restricted_variable);
+                if let Some(name) = stage.args.iter().find(protected).and_then(|a| assignment_target(a)) {
+                    return Some(format!("{cmd}: {name}: restricted: cannot set variable"));
+                }
+            }
+            _ if assignment && assignment_target(cmd).is_some_and(restricted_variable) => {
+                let name = assignment_target(cmd).unwrap_or_default();
+                return Some(format!("{name}: restricted: cannot set variable"));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Variables a restricted shell won't let anything set or unset
+fn restricted_variable(name: &str) -> bool {
+    matches!(name, "PATH" | "SHELL" | "ENV")
+}
+
+// ---------- plugins ----------
+// `load_plugin lib.so` dlopens a plugin and runs its `shell_plugin_init`
+// (ABI in plugin_api.rs). Registrations are collected first and only take
+// effect if init succeeds.
+#[derive(Debug, Clone, Default)]
+struct PluginTable {
+    builtins: HashMap<String, PluginBuiltinFn>,
+    preexec: Vec<PluginPreexecFn>,
+    precmd: Vec<PluginPrecmdFn>,
+}
+
+#[derive(Default)]
+struct PluginRegistrations {
+    builtins: Vec<(String, PluginBuiltinFn)>,
+    completions: Vec<(String, PluginCompletionFn)>,
+    preexec: Vec<PluginPreexecFn>,
+    precmd: Vec<PluginPrecmdFn>,
+}
+
+// The registrations behind `ctx.shell`, plus an optional name argument
+unsafe fn plugin_registrations<'a>(
+    ctx: *mut ShellPluginContext,
+    name: *const c_char,
+) -> Option<(&'a mut PluginRegistrations, String)> {
+    let ctx = ctx.as_mut()?;
+    let regs = (ctx.shell as *mut PluginRegistrations).as_mut()?;
+    let name = if name.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(name).to_str().ok()?.to_string()
+    };
+    Some((regs, name))
+}
+
+extern "C" fn plugin_register_builtin(
+    ctx: *mut ShellPluginContext,
+    name: *const c_char,
+    f: PluginBuiltinFn,
+) -> c_int {
+    match unsafe { plugin_registrations(ctx, name) } {
+        Some((regs, name)) if !name.is_empty() => {
+            regs.builtins.push((name, f));
+            0
+        }
+        _ => -1,
+    }
+}
+
+extern "C" fn plugin_register_completion(
+    ctx: *mut ShellPluginContext,
+    command: *const c_char,
+    f: PluginCompletionFn,
+) -> c_int {
+    match unsafe { plugin_registrations(ctx, command) } {
+        Some((regs, command)) if !command.is_empty() => {
+            regs.completions.push((command, f));
+            0
+        }
+        _ => -1,
+    }
+}
+
+extern "C" fn plugin_register_preexec(
+    ctx: *mut ShellPluginContext,
+    f: PluginPreexecFn,
+) -> c_int {
+    match unsafe { plugin_registrations(ctx, std::ptr::null()) } {
+        Some((regs, _)) => {
+            regs.preexec.push(f);
+            0
+        }
+        None => -1,
+    }
+}
+
+extern "C" fn plugin_register_precmd(ctx: *mut ShellPluginContext, f: PluginPrecmdFn) -> c_int {
+    match unsafe { plugin_registrations(ctx, std::ptr::null()) } {
+        Some((regs, _)) => {
+            regs.precmd.push(f);
+            0
+        }
+        None => -1,
+    }
+}
+
+// `complete [-d|-f|-W wordlist|-F function] [-X pattern] [-o nospace|filenames] name ...` registers,
+// `complete -p [name ...]` lists, `complete -r [name ...]` removes
+fn complete_builtin(completions: &mut CompletionRegistry, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let usage = || {
+        let msg = "complete: usage: complete [-d|-f|-W wordlist|-F function] [-X pattern] [-o option] name ... | -p|-r [name ...]\n";
+        (vec![], msg.as_bytes().to_vec(), 2)
+    };
+    let mut action = None;
+    let mut exclude = None;
+    let (mut no_space, mut filenames) = (false, false);
+    let mut print = args.is_empty();
+    let mut remove = false;
+    let mut iter = args.iter();
+    let mut names = Vec::new();
+    while let Some(arg) = iter.next() {
+        if !names.is_empty() || !arg.starts_with('-') || arg == "-" {
+            names.push(arg.as_str());
+            continue;
+        }
+        match arg.as_str() {
+            "--" => names.extend(iter.by_ref().map(String::as_str)),
+            "-d" => action = Some(CompletionAction::DirectoriesOnly),
+            "-f" => action = Some(CompletionAction::FilesOnly),
+            "-p" => print = true,
+            "-r" => remove = true,
+            "-W" => {
+                let Some(words) = iter.next() else { return usage() };
+                action = Some(if words.contains('$') || words.contains('`') {
+                    CompletionAction::WordlistCmd(words.clone(), RefCell::new(None))
+                } else {
+                    CompletionAction::Wordlist(words.split_whitespace().map(str::to_string).collect())
+                });
+            }
+            "-F" => {
+                let Some(name) = iter.next() else { return usage() };
+                action = Some(CompletionAction::ShellFunction(name.clone()));
+            }
+            "-X" => {
+                let Some(pattern) = iter.next() else { return usage() };
+                exclude = Some(pattern.clone());
+            }
+            "-o" => match iter.next().map(String::as_str) {
+                Some("nospace") => no_space = true,
+                Some("filenames") => filenames = true,
+                Some(other) => {
+                    return (vec![], format!("complete: {other}: invalid option name\n").into_bytes(), 2);
+                }
+                None => return usage(),
+            },
+            _ => return (vec![], format!("complete: {arg}: invalid option\n").into_bytes(), 2),
+        }
+    }
+
+    let missing = |name: &str| format!("complete: {name}: no completion specification\n");
+    if print {
+        let mut out = String::new();
+        let mut err = String::new();
+        let mut listed: Vec<(&String, &CompletionSpec)> = completions.specs.iter().collect();
+        listed.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, spec) in listed {
+            if names.is_empty() || names.contains(&name.as_str()) {
+                if let Some(def) = spec.definition() {
+                    out.push_str(&format!("complete {def} {name}\n"));
+                }
+            }
+        }
+        for name in names.iter().filter(|n| completions.get(n).is_none()) {
+            err.push_str(&missing(name));
+        }
+        let code = if err.is_empty() { 0 } else { 1 };
+        return (encode_bytes(&out), encode_bytes(&err), code);
+    }
+    if remove {
+        if names.is_empty() {
+            completions.specs.clear();
+            return (vec![], vec![], 0);
+        }
+        let err: String = names.iter().filter(|n| !completions.remove(n)).map(|n| missing(n)).collect();
+        let code = if err.is_empty() { 0 } else { 1 };
+        return (vec![], encode_bytes(&err), code);
+    }
+
+    let Some(action) = action else { return usage() };
+    if names.is_empty() {
+        return usage();
+    }
+    let spec = CompletionSpec {
+        action,
+        exclude,
+        no_space,
+        filenames,
+    };
+    for name in names {
+        completions.register(name, spec.clone());
+    }
+    (vec![], vec![], 0)
+}
+
+fn load_plugin(args: &[String], plugins: &mut PluginTable, completions: &mut CompletionRegistry) -> i32 {
+    let [path] = args else {
+        eprintln!("load_plugin: usage: load_plugin path/to/plugin.so");
+        return 2;
+    };
+    let shown = display_str(path);
+
+    let lib = match unsafe { libloading::Library::new(encode_os(path)) } {
+        Ok(lib) => lib,
+        Err(e) => {
+            eprintln!("load_plugin: {e}");
+            return 1;
+        }
+    };
+    let init: ShellPluginInitFn =
+        match unsafe { lib.get::<ShellPluginInitFn>(plugin_api::SHELL_PLUGIN_INIT_SYMBOL) } {
+            Ok(sym) => *sym,
+            Err(_) => {
+                eprintln!("load_plugin: {shown}: no shell_plugin_init symbol");
+                return 1;
+            }
+        };
+
+    let mut regs = PluginRegistrations::default();
+    let mut ctx = ShellPluginContext {
+        api_version: plugin_api::SHELL_PLUGIN_API_VERSION,
+        shell: &mut regs as *mut PluginRegistrations as *mut c_void,
+        register_builtin: plugin_register_builtin,
+        register_completion: plugin_register_completion,
+        register_preexec: plugin_register_preexec,
+        register_precmd: plugin_register_precmd,
+    };
+    let rc = unsafe { init(&mut ctx) };
+    if rc != 0 {
+        eprintln!("load_plugin: {shown}: initialization failed ({rc})");
+        return 1;
+    }
+
+    plugins.builtins.extend(regs.builtins);
+    for (cmd, f) in regs.completions {
+        completions.register(&cmd, CompletionSpec::action(CompletionAction::Plugin(f)));
+    }
+    plugins.preexec.extend(regs.preexec);
+    plugins.precmd.extend(regs.precmd);
+
+    // What was registered points into the library: keep it loaded for good
+    std::mem::forget(lib);
+    0
+}
+
+// NUL-terminated copies of `words` (a NUL inside a word cuts it short) and
+// the argv pointer array over them, NULL-terminated
+fn c_strings(words: &[String]) -> (Vec<CString>, Vec<*const c_char>) {
+    let owned: Vec<CString> = words
+        .iter()
+        .map(|w| {
+            let mut bytes = encode_bytes(w);
+            if let Some(nul) = bytes.iter().position(|&b| b == 0) {
+                bytes.truncate(nul);
+            }
+            CString::new(bytes).unwrap_or_default()
+        })
+        .collect();
+    let mut ptrs: Vec<*const c_char> = owned.iter().map(|c| c.as_ptr()).collect();
+    ptrs.push(std::ptr::null());
+    (owned, ptrs)
+}
+
+extern "C" fn plugin_write(sink: *mut c_void, stream: c_int, data: *const u8, len: usize) {
+    let Some(bufs) = (unsafe { (sink as *mut (Vec<u8>, Vec<u8>)).as_mut() }) else { return };
+    if data.is_null() || len == 0 {
+        return;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(data, len) };
+    match stream {
+        plugin_api::PLUGIN_STDOUT => bufs.0.extend_from_slice(bytes),
+        plugin_api::PLUGIN_STDERR => bufs.1.extend_from_slice(bytes),
+        _ => {}
+    }
+}
+
+fn run_plugin_builtin(f: PluginBuiltinFn, cmd: &str, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let words: Vec<String> = std::iter::once(cmd.to_string()).chain(args.iter().cloned()).collect();
+    let (_owned, argv) = c_strings(&words);
+    let mut bufs: (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
+    let io = PluginIo {
+        sink: &mut bufs as *mut (Vec<u8>, Vec<u8>) as *mut c_void,
+        write: plugin_write,
+    };
+    let code = f(&io, words.len() as c_int, argv.as_ptr());
+    (bufs.0, bufs.1, code)
+}
+
+extern "C" fn plugin_add_candidate(sink: *mut c_void, candidate: *const c_char) {
+    let Some(out) = (unsafe { (sink as *mut Vec<String>).as_mut() }) else { return };
+    if candidate.is_null() {
+        return;
+    }
+    let bytes = unsafe { CStr::from_ptr(candidate) }.to_bytes();
+    out.push(decode_bytes(bytes));
+}
+
+fn plugin_candidates(f: PluginCompletionFn, words: &[String], current: &str) -> Vec<String> {
+    let (_owned, argv) = c_strings(words);
+    let (_current_owned, current_ptr) = c_strings(&[current.to_string()]);
+    let mut found: Vec<String> = Vec::new();
+    let out = PluginCandidates {
+        sink: &mut found as *mut Vec<String> as *mut c_void,
+        add: plugin_add_candidate,
+    };
+    f(&out, words.len() as c_int, argv.as_ptr(), current_ptr[0]);
+    found
+}
+
+fn run_preexec_hooks(plugins: &PluginTable, line: &str) {
+    if plugins.preexec.is_empty() {
+        return;
+    }
+    let (_owned, ptrs) = c_strings(&[line.to_string()]);
+    for hook in &plugins.preexec {
+        hook(ptrs[0]);
+    }
+}
+
+fn run_precmd_hooks(plugins: &PluginTable, last_status: i32) {
+    for hook in &plugins.precmd {
+        hook(last_status);
+    }
+}
+
+// ---------- config file ----------
+// ~/.config/myshell/config.toml: options and preferences applied at startup,
+// before anything the user runs
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ShellConfig {
+    options: HashMap<String, bool>,
+    // `highlight = true`: colors from the default theme without SHELL_THEME
+    // or a theme.toml
+    highlight: bool,
+    history: HistoryConfig,
+    completion: CompletionConfig,
+    title: TitleConfig,
+    env: HashMap<String, String>,
+    // readline-style `"\C-g": "git status\n"` lines, as for `bind`
+    bindings: Vec<String>,
+    // `complete` arguments, one registration each: "-W 'build test' cargo"
+    completions: Vec<String>,
+    hooks: CommandHooks,
+}
+
+// [hooks]: shell command lines run around each command line the user enters.
+// preexec runs before it with $MYSH_COMMAND holding it, postexec after it
+// with $? still its status. Neither changes the $? the user sees.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct CommandHooks {
+    preexec: Vec<String>,
+    postexec: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct HistoryConfig {
+    size: Option<usize>,
+    file: Option<String>,
+    // HISTCONTROL syntax: colon-separated ignorespace / ignoredups / ignoreboth / erasedups
+    control: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CompletionConfig {
+    case_insensitive: bool,
+    menu_complete: bool,
+    // Ask before listing more candidates than this (readline's completion-query-items)
+    query_items: Option<usize>,
+    // Leave accounts with a nologin/false shell out of `~name` completion
+    hide_system_users: bool,
+}
+
+// Terminal title templates; {user} {host} {cwd} {command} are filled in
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct TitleConfig {
+    enabled: bool,
+    prompt: String,
+    running: String,
+}
+
+impl Default for TitleConfig {
+    fn default() -> Self {
+        TitleConfig {
+            enabled: false,
+            prompt: "mysh: {cwd}".to_string(),
+            running: "{command}".to_string(),
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/myshell, else ~/.config/myshell
+fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir()?.join(".config"),
+    };
+    Some(base.join("myshell"))
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+// $XDG_DATA_HOME/myshell, else ~/.local/share/myshell: state the shell keeps
+fn data_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir()?.join(".local").join("share"),
+    };
+    Some(base.join("myshell"))
+}
+
+// A missing default file is fine; an unreadable or malformed one only warns,
+// the shell still starts with defaults
+fn load_config(explicit: Option<&Path>) -> ShellConfig {
+    let Some(path) = explicit.map(Path::to_path_buf).or_else(default_config_path) else {
+        return ShellConfig::default();
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(t) => t,
+        Err(e) => {
+            if explicit.is_some() || e.kind() != io::ErrorKind::NotFound {
+                eprintln!("warning: {}: {e}", path.display());
+            }
+            return ShellConfig::default();
+        }
+    };
+    match toml::from_str(&text) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!(
+                "warning: {}: malformed config, using defaults\n{}",
+                path.display(),
+                e.to_string().trim_end()
+            );
+            ShellConfig::default()
+        }
+    }
+}
+
+fn apply_config(ctx: &mut ShellContext, cfg: &ShellConfig) {
+    for (name, on) in &cfg.options {
+        if !ctx.options.set(name, *on) {
+            eprintln!("warning: config: unknown option `{name}`");
+        }
+    }
+
+    let hs = &mut ctx.history_settings;
+    hs.size = cfg.history.size;
+    hs.file = cfg.history.file.as_deref().map(|f| match f.strip_prefix("~/") {
+        Some(rest) => home_dir().map(|h| h.join(rest)).unwrap_or_else(|| PathBuf::from(f)),
+        None => PathBuf::from(encode_os(f)),
+    });
+    for word in cfg.history.control.as_deref().unwrap_or("").split(':') {
+        match word {
+            "ignorespace" => hs.ignorespace = true,
+            "ignoredups" => hs.ignoredups = true,
+            "erasedups" => hs.erasedups = true,
+            "ignoreboth" => {
+                hs.ignorespace = true;
+                hs.ignoredups = true;
+            }
+            _ => {}
+        }
+    }
+
+    for (key, value) in &cfg.env {
+        env::set_var(key, value);
+    }
+    ctx.hooks = cfg.hooks.clone();
+}
+
+// ---------- theme ----------
+// Colors for the line editor and the shell's own messages, off unless asked
+// for. A bundled theme is picked with SHELL_THEME (also settable from the
+// config's [env]), and ~/.config/myshell/theme.toml overrides any of the
+// default theme's colors; `highlight = true` in the config turns the default
+// theme on by itself.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "ColorSpec")]
+enum Color {
+    Ansi(u8),
+    Rgb(u8, u8, u8),
+}
+
+// How a color is written in theme.toml: a 256-color code or "#RRGGBB"
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorSpec {
+    Code(u8),
+    Text(String),
+}
+
+impl TryFrom<ColorSpec> for Color {
+    type Error = String;
+
+    fn try_from(spec: ColorSpec) -> Result<Self, String> {
+        let text = match spec {
+            ColorSpec::Code(n) => return Ok(Color::Ansi(n)),
+            ColorSpec::Text(t) => t,
+        };
+        if let Ok(n) = text.parse::<u8>() {
+            return Ok(Color::Ansi(n));
+        }
+        let hex = text
+            .strip_prefix('#')
+            .filter(|h| h.len() == 6 && h.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| format!("invalid color `{text}` (expected 0-255 or #RRGGBB)"))?;
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0);
+        Ok(Color::Rgb(channel(0), channel(2), channel(4)))
+    }
+}
+
+impl Color {
+    fn paint(self, text: &str) -> String {
+        match self {
+            Color::Ansi(n) => format!("\x1b[38;5;{n}m{text}\x1b[0m"),
+            Color::Rgb(r, g, b) => format!("\x1b[38;2;{r};{g};{b}m{text}\x1b[0m"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct Theme {
+    command_not_found: Option<Color>,
+    command: Option<Color>,
+    string: Option<Color>,
+    redirect: Option<Color>,
+    candidate: Option<Color>,
+    description: Option<Color>,
+    selected: Option<Color>,
+    error: Option<Color>,
+}
+
+impl Theme {
+    fn bundled(name: &str) -> Option<Theme> {
+        let hex = |h: &str| Color::try_from(ColorSpec::Text(h.to_string())).ok();
+        let theme = match name {
+            "default" => Theme {
+                command_not_found: Some(Color::Ansi(9)),
+                command: Some(Color::Ansi(10)),
+                string: Some(Color::Ansi(11)),
+                redirect: Some(Color::Ansi(14)),
+                candidate: None,
+                description: Some(Color::Ansi(244)),
+                selected: Some(Color::Ansi(12)),
+                error: Some(Color::Ansi(9)),
+            },
+            "light" => Theme {
+                command_not_found: Some(Color::Ansi(160)),
+                command: Some(Color::Ansi(28)),
+                string: Some(Color::Ansi(130)),
+                redirect: Some(Color::Ansi(25)),
+                candidate: Some(Color::Ansi(236)),
+                description: Some(Color::Ansi(242)),
+                selected: Some(Color::Ansi(90)),
+                error: Some(Color::Ansi(160)),
+            },
+            "dracula" => Theme {
+                command_not_found: hex("#ff5555"),
+                command: hex("#50fa7b"),
+                string: hex("#f1fa8c"),
+                redirect: hex("#ff79c6"),
+                candidate: hex("#bd93f9"),
+                description: hex("#6272a4"),
+                selected: hex("#8be9fd"),
+                error: hex("#ff5555"),
+            },
+            "solarized" => Theme {
+                command_not_found: hex("#dc322f"),
+                command: hex("#859900"),
+                string: hex("#2aa198"),
+                redirect: hex("#cb4b16"),
+                candidate: hex("#268bd2"),
+                description: hex("#586e75"),
+                selected: hex("#b58900"),
+                error: hex("#dc322f"),
+            },
+            _ => return None,
+        };
+        Some(theme)
+    }
+
+    // Colors set in `other` win
+    fn overlay(&mut self, other: Theme) {
+        let fields = [
+            (&mut self.command_not_found, other.command_not_found),
+            (&mut self.command, other.command),
+            (&mut self.string, other.string),
+            (&mut self.redirect, other.redirect),
+            (&mut self.candidate, other.candidate),
+            (&mut self.description, other.description),
+            (&mut self.selected, other.selected),
+            (&mut self.error, other.error),
+        ];
+        for (mine, theirs) in fields {
+            if theirs.is_some() {
+                *mine = theirs;
+            }
+        }
+    }
+}
+
+fn paint(text: &str, color: Option<Color>) -> String {
+    match color {
+        Some(c) => c.paint(text),
+        None => text.to_string(),
+    }
+}
+
+static ACTIVE_THEME: std::sync::OnceLock<Theme> = std::sync::OnceLock::new();
+
+fn active_theme() -> &'static Theme {
+    ACTIVE_THEME.get_or_init(Theme::default)
+}
+
+// SHELL_THEME plus theme.toml overrides; no colors when neither is there and
+// the config doesn't ask for `highlight`. Problems only warn.
+fn load_theme(highlight: bool) -> Theme {
+    let path = config_dir().map(|d| d.join("theme.toml"));
+    let text = path.as_ref().map(fs::read_to_string);
+    // A theme.toml that's there, even if unreadable, asks for colors
+    let theme_file = matches!(&text, Some(Ok(_))) || matches!(&text, Some(Err(e)) if e.kind() != io::ErrorKind::NotFound);
+    let name = match env::var("SHELL_THEME") {
+        Ok(name) => name,
+        Err(_) if highlight || theme_file => "default".to_string(),
+        Err(_) => return Theme::default(),
+    };
+    let mut theme = Theme::bundled(&name).unwrap_or_else(|| {
+        eprintln!("warning: SHELL_THEME: unknown theme `{name}`");
+        Theme::bundled("default").unwrap_or_default()
+    });
+
+    let (Some(path), Some(text)) = (path, text) else {
+        return theme;
+    };
+    match text {
+        Ok(text) => match toml::from_str::<Theme>(&text) {
+            Ok(custom) => theme.overlay(custom),
+            Err(e) => eprintln!(
+                "warning: {}: malformed theme, ignoring it\n{}",
+                path.display(),
+                e.to_string().trim_end()
+            ),
+        },
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            eprintln!("warning: {}: {e}", path.display());
+        }
+        Err(_) => {}
+    }
+    theme
+}
+
+// The shell's own error lines, colored when stderr is a terminal
+fn print_error(msg: &str) {
+    let color = if io::stderr().is_terminal() { active_theme().error } else { None };
+    eprintln!("{}", paint(msg, color));
+}
+
+// Line-editor highlighting: the command word of each stage (known or not),
+// quoted strings and redirection/pipe operators. Only escape sequences are
+// added, so the display width stays that of `line`.
+fn highlight_line(line: &str, theme: &Theme) -> String {
+    let mut out = String::with_capacity(line.len() * 2);
+    let chars: Vec<(usize, char)> = line.char_indices().collect();
+    let byte_at = |k: usize| chars.get(k).map_or(line.len(), |&(i, _)| i);
+    let mut at_command = true;
+    let mut after_redirect = false;
+    let mut k = 0;
+
+    while k < chars.len() {
+        let c = chars[k].1;
+        if c.is_whitespace() {
+            out.push(c);
+            k += 1;
+            continue;
+        }
+        let glued_to_redirect = c == '&' && k > 0 && chars[k - 1].1 == '>';
+        if matches!(c, '|' | '<' | '>' | '&') && !glued_to_redirect {
+            let mut end = k + 1;
+            if matches!(c, '>' | '&') && chars.get(end).map(|&(_, n)| n) == Some(c) {
+                end += 1;
+            }
+            out.push_str(&paint(&line[byte_at(k)..byte_at(end)], theme.redirect));
+            if matches!(c, '|' | '&') {
+                at_command = true;
+            } else {
+                after_redirect = true;
+            }
+            k = end;
+            continue;
+        }
+
+        // One word, with the byte ranges of its quoted parts
+        let start = k;
+        let mut quoted: Vec<(usize, usize)> = Vec::new();
+        let mut quote: Option<(char, usize)> = None;
+        while k < chars.len() {
+            let (i, ch) = chars[k];
+            match quote {
+                Some((q, qs)) => {
+                    if ch == '\\' && q == '"' {
+                        k += 1;
+                    } else if ch == q {
+                        quoted.push((qs, i + 1));
+                        quote = None;
+                    }
+                }
+                None => {
+                    if k > start && (ch.is_whitespace() || matches!(ch, '|' | '<' | '>' | '&')) {
+                        break;
+                    }
+                    match ch {
+                        '\\' => k += 1,
+                        '\'' | '"' => quote = Some((ch, i)),
+                        _ => {}
+                    }
+                }
+            }
+            k += 1;
+        }
+        k = k.min(chars.len());
+        if let Some((_, qs)) = quote {
+            quoted.push((qs, line.len()));
+        }
+        let (ws, we) = (byte_at(start), byte_at(k));
+        let word = &line[ws..we];
+
+        // `2>` / `1>>`: the digit belongs to the operator
+        if matches!(word, "1" | "2") && chars.get(k).map(|&(_, n)| n) == Some('>') {
+            out.push_str(&paint(word, theme.redirect));
+            continue;
+        }
+
+        if at_command && !after_redirect {
+            let name = tokenize(word, &ShellContext::default()).first().map(|t| t.to_string()).unwrap_or_default();
+            let known = name == "[[" || is_builtin(&name) || matches!(resolve_command(&name), CommandLookup::Found(_));
+            let color = if known { theme.command } else { theme.command_not_found };
+            out.push_str(&paint(word, color));
+            at_command = false;
+        } else {
+            let mut pos = ws;
+            for (qs, qe) in quoted {
+                out.push_str(&line[pos..qs]);
+                out.push_str(&paint(&line[qs..qe], theme.string));
+                pos = qe;
+            }
+            out.push_str(&line[pos..we]);
+        }
+        after_redirect = false;
+    }
+    out
+}
+
+// PS1 escapes: \u \h \w \W \$ \n \a \e \\ and \[ \] (dropped). `\$` shows in
+// the theme's error color after a failed command.
+fn expand_ps1(ps1: &str, last_exit: i32, theme: &Theme, named_dirs: &NamedDirs) -> String {
+    let mut out = String::new();
+    let mut chars = ps1.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') => out.push_str(&env::var("USER").unwrap_or_default()),
+            Some('h') => out.push_str(hostname().split('.').next().unwrap_or("")),
+            Some('w') => out.push_str(&prompt_cwd(false, named_dirs)),
+            Some('W') => out.push_str(&prompt_cwd(true, named_dirs)),
+            Some('$') => {
+                let sign = if is_root() { "#" } else { "$" };
+                let color = if last_exit != 0 { theme.error } else { None };
+                out.push_str(&paint(sign, color));
+            }
+            Some('n') => out.push('\n'),
+            Some('a') => out.push('\x07'),
+            Some('e') => out.push('\x1b'),
+            Some('[' | ']') => {}
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// $HOME as the prompt abbreviates it: only when set, and never just `/`
+// (unlike `~`, no fallback to the passwd entry)
+fn prompt_home() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from).filter(|h| h.parent().is_some())
+}
+
+// Working directory for the prompt, with $HOME shown as `~` and named
+// directories as `~name`
+fn prompt_cwd(basename_only: bool, named_dirs: &NamedDirs) -> String {
+    let Ok(cwd) = shell_cwd() else {
+        return String::new();
+    };
+    if basename_only {
+        if prompt_home().as_deref() == Some(cwd.as_path()) {
+            return "~".to_string();
+        }
+        return cwd
+            .file_name()
+            .map(decode_os)
+            .unwrap_or_else(|| "/".to_string());
+    }
+    abbreviate_dir(&cwd, named_dirs)
+}
+
+// `path` under the deepest of $HOME and the named directories that contains
+// it, written `~` / `~name` plus the rest
+fn abbreviate_dir(path: &Path, named_dirs: &NamedDirs) -> String {
+    let home = prompt_home().map(|h| (String::new(), h));
+    let named = named_dirs.iter().map(|(name, dir)| (name.clone(), dir.clone()));
+    let best = named
+        .chain(home)
+        .filter(|(_, dir)| path.starts_with(dir))
+        .max_by_key(|(_, dir)| dir.components().count());
+    let Some((name, dir)) = best else {
+        return decode_os(path.as_os_str());
+    };
+    match path.strip_prefix(&dir) {
+        Ok(rest) if rest.as_os_str().is_empty() => format!("~{name}"),
+        Ok(rest) => format!("~{name}/{}", decode_os(rest.as_os_str())),
+        Err(_) => decode_os(path.as_os_str()),
+    }
+}
+
+// `hash -d` lists named directories, `hash -d name=path ...` adds them
+fn hash_builtin(named_dirs: &mut NamedDirs, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let Some(("-d", defs)) = args.split_first().map(|(f, rest)| (f.as_str(), rest)) else {
+        return (vec![], b"hash: only named directories are supported: hash -d name=path\n".to_vec(), 2);
+    };
+
+    if defs.is_empty() {
+        let mut names: Vec<&String> = named_dirs.keys().collect();
+        names.sort();
+        let mut out = String::new();
+        for name in names {
+            out.push_str(&format!("{name}={}\n", decode_os(named_dirs[name].as_os_str())));
+        }
+        return (encode_bytes(&out), vec![], 0);
+    }
+
+    let mut err = String::new();
+    for def in defs {
+        let Some((name, path)) = def.split_once('=').filter(|(n, p)| is_identifier(n) && !p.is_empty()) else {
+            err.push_str(&format!("hash: {}: expected name=path\n", display_str(def)));
+            continue;
+        };
+        let path = match path.split_once('/') {
+            Some(("~", rest)) => home_dir().map(|h| h.join(rest)),
+            None if path == "~" => home_dir(),
+            _ => None,
+        }
+        .unwrap_or_else(|| PathBuf::from(encode_os(path)));
+        let path = match path.is_absolute() {
+            true => path,
+            false => env::current_dir().map(|cwd| cwd.join(&path)).unwrap_or(path),
+        };
+        named_dirs.insert(name.to_string(), path);
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err.into_bytes(), code)
+}
+
+// `read [-r] [name...]` arguments: the -r flag and the variable names
+fn read_options(args: &[String]) -> Result<(bool, Vec<&str>), (String, i32)> {
+    let mut raw = false;
+    let mut names: Vec<&str> = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-r" if names.is_empty() => raw = true,
+            a if a.starts_with('-') && a.len() > 1 && names.is_empty() => {
+                return Err((format!("read: {}: invalid option\n", display_str(a)), 2));
+            }
+            a => names.push(a),
+        }
+    }
+    for name in &names {
+        if !is_identifier(name) {
+            return Err((format!("read: `{}': not a valid identifier\n", display_str(name)), 1));
+        }
+    }
+    Ok((raw, names))
+}
+
+// Splits one line of `input` on whitespace, a field per name with the rest of
+// the line going to the last one; the whole line goes to REPLY without names.
+// Without `raw` a backslash quotes the next character and joins lines.
+// Returns the assignments and the status, 1 when input ended before a newline.
+fn read_fields(raw: bool, names: &[&str], input: &[u8]) -> (Vec<(String, String)>, i32) {
+    let text = decode_bytes(input);
+    let mut chars = text.chars();
+    let whole_line = names.is_empty();
+    let mut fields: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut complete = false;
+    while let Some(c) = chars.next() {
+        let last_field = whole_line || fields.len() + 1 >= names.len();
+        match c {
+            '\n' => {
+                complete = true;
+                break;
+            }
+            '\\' if !raw => match chars.next() {
+                Some('\n') => {}
+                Some(escaped) => current.push(escaped),
+                None => break,
+            },
+            c if c.is_whitespace() && !whole_line => {
+                if !current.is_empty() && !last_field {
+                    fields.push(std::mem::take(&mut current));
+                } else if !current.is_empty() {
+                    current.push(c);
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if whole_line {
+        fields.push(current);
+    } else {
+        fields.push(current.trim_end().to_string());
+    }
+
+    let names = if whole_line { &["REPLY"][..] } else { names };
+    let mut fields = fields.into_iter();
+    let assignments = names
+        .iter()
+        .map(|name| (name.to_string(), fields.next().unwrap_or_default()))
+        .collect();
+    (assignments, if complete { 0 } else { 1 })
+}
+
+// One line for `read`, newline included; without -r a backslash-newline
+// continues onto the next line
+fn read_input_line(reader: &mut dyn io::BufRead, raw: bool) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    loop {
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(line);
+        }
+        let continued = !raw && line.ends_with(b"\\\n") && !line.ends_with(b"\\\\\n");
+        if !continued {
+            return Ok(line);
+        }
+    }
+}
+
+// Single-command `read`: one line from the `<` file or the shell's own stdin,
+// stored in shell variables
+fn read_into_vars(ctx: &mut ShellContext, stage: &ParsedCommand) -> i32 {
+    let (raw, names) = match read_options(&stage.args) {
+        Ok(opts) => opts,
+        Err((msg, code)) => {
+            eprint!("{msg}");
+            return code;
+        }
+    };
+    let line = match open_for_stdin(&stage.stdin) {
+        // A shared descriptor must be left just past the line
+        Ok(Some(f)) if matches!(stage.stdin, StdinRedirect::Fd(_)) => {
+            read_input_line(&mut io::BufReader::with_capacity(1, f), raw)
+        }
+        Ok(Some(f)) => read_input_line(&mut io::BufReader::new(f), raw),
+        Ok(None) => read_input_line(&mut io::stdin().lock(), raw),
+        Err(e) => Err(e),
+    };
+    let line = match line {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("read: {e}");
+            return 1;
+        }
+    };
+    let (assignments, mut code) = read_fields(raw, &names, &line);
+    for (name, value) in assignments {
+        if ctx.is_readonly(&name) {
+            eprintln!("read: {name}: readonly variable");
+            code = 1;
+            continue;
+        }
+        ctx.set_var(&name, &value);
+    }
+    code
+}
+
+struct MapfileOptions {
+    trim: bool,
+    count: Option<usize>,
+    skip: usize,
+    array: String,
+    file: Option<String>,
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+// `mapfile [-t] [-n count] [-s count] [array] [file]`
+fn mapfile_options(args: &[String]) -> Result<MapfileOptions, String> {
+    let mut opts = MapfileOptions {
+        trim: false,
+        count: None,
+        skip: 0,
+        array: "MAPFILE".to_string(),
+        file: None,
+    };
+    let mut operands = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-t" => opts.trim = true,
+            flag @ ("-n" | "-s") => {
+                let value = args.next().ok_or(format!("mapfile: {flag}: option requires an argument\n"))?;
+                let n: usize = value
+                    .parse()
+                    .map_err(|_| format!("mapfile: {}: invalid line count\n", display_str(value)))?;
+                if flag == "-n" {
+                    opts.count = Some(n).filter(|&n| n > 0);
+                } else {
+                    opts.skip = n;
+                }
+            }
+            a if a.starts_with('-') && a.len() > 1 && operands.is_empty() => {
+                return Err(format!("mapfile: {}: invalid option\n", display_str(a)));
+            }
+            a => operands.push(a.to_string()),
+        }
+    }
+
+    match operands.as_slice() {
+        [] => {}
+        [one] if is_identifier(one) => opts.array = one.clone(),
+        [one] => opts.file = Some(one.clone()),
+        [array, file] => {
+            opts.array = array.clone();
+            opts.file = Some(file.clone());
+        }
+        _ => return Err("mapfile: too many arguments\n".to_string()),
+    }
+    if !is_identifier(&opts.array) {
+        return Err(format!("mapfile: `{}': not a valid identifier\n", display_str(&opts.array)));
+    }
+    Ok(opts)
+}
+
+// The array's lines, from the file operand if there is one, else from stdin
+fn mapfile_input(opts: &MapfileOptions, stdin_bytes: Option<Vec<u8>>) -> Result<Vec<String>, String> {
+    let bytes = match &opts.file {
+        Some(file) => match fs::read(encode_os(file)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(format!("mapfile: {}: No such file or directory\n", display_str(file)));
+            }
+            Err(e) => return Err(format!("mapfile: {}: {e}\n", display_str(file))),
+        },
+        None => stdin_bytes.unwrap_or_default(),
+    };
+    let text = decode_bytes(&bytes);
+    let lines = text
+        .split_inclusive('\n')
+        .skip(opts.skip)
+        .take(opts.count.unwrap_or(usize::MAX))
+        .map(|line| match opts.trim {
+            true => line.strip_suffix('\n').unwrap_or(line).to_string(),
+            false => line.to_string(),
+        })
+        .collect();
+    Ok(lines)
+}
+
+// Single-command `mapfile`: reads the file operand, the `<` file or the
+// shell's own stdin into one of the shell's arrays
+fn mapfile_into_array(ctx: &mut ShellContext, stage: &ParsedCommand) -> i32 {
+    let opts = match mapfile_options(&stage.args) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprint!("{msg}");
+            return 2;
+        }
+    };
+    let stdin_bytes = match (&opts.file, open_for_stdin(&stage.stdin)) {
+        (Some(_), _) => None,
+        (None, Ok(Some(f))) => Some(read_stdin_bytes(Some(f))),
+        (None, Ok(None)) => {
+            let mut buf = Vec::new();
+            let _ = io::stdin().lock().read_to_end(&mut buf);
+            Some(buf)
+        }
+        (None, Err(e)) => {
+            eprintln!("mapfile: {e}");
+            return 1;
+        }
+    };
+    if ctx.is_readonly(&opts.array) {
+        eprintln!("mapfile: {}: readonly variable", opts.array);
+        return 1;
+    }
+    match mapfile_input(&opts, stdin_bytes) {
+        Ok(lines) => {
+            ctx.arrays.insert(opts.array, lines);
+            0
+        }
+        Err(msg) => {
+            eprint!("{msg}");
+            1
+        }
+    }
+}
+
+// ---------- terminal title ----------
+// With [title] enabled = true, the xterm title shows the prompt template while
+// reading a line and the running template while a foreground command runs.
+static TERMINAL_TITLE: std::sync::OnceLock<TitleConfig> = std::sync::OnceLock::new();
+
+fn title_config() -> Option<&'static TitleConfig> {
+    let cfg = TERMINAL_TITLE.get().filter(|t| t.enabled)?;
+    let dumb = env::var("TERM").map_or(true, |t| t.is_empty() || t == "dumb");
+    (!dumb && io::stdout().is_terminal()).then_some(cfg)
+}
+
+fn format_title(template: &str, command: &str) -> String {
+    let title = template
+        .replace("{user}", &env::var("USER").unwrap_or_default())
+        .replace("{host}", hostname().split('.').next().unwrap_or(""))
+        .replace("{cwd}", &prompt_cwd(false, &NamedDirs::new()))
+        .replace("{command}", command);
+    // Control characters would end the escape sequence early
+    title.chars().filter(|c| !c.is_control()).collect()
+}
+
+fn set_title(title: &str) {
+    let mut out = io::stdout();
+    let _ = write!(out, "\x1b]0;{title}\x07");
+    let _ = out.flush();
+}
+
+fn show_prompt_title() {
+    if let Some(cfg) = title_config() {
+        set_title(&format_title(&cfg.prompt, ""));
+    }
+}
+
+// Shows the running title until dropped, then puts the prompt title back
+struct RunningTitle;
+
+impl RunningTitle {
+    fn show(command: &str) -> RunningTitle {
+        if let Some(cfg) = title_config() {
+            set_title(&format_title(&cfg.running, command));
+        }
+        RunningTitle
+    }
+}
+
+impl Drop for RunningTitle {
+    fn drop(&mut self) {
+        show_prompt_title();
+    }
+}
+
+// ---------- OSC 7 ----------
+// With `set -o osc7`, the cwd is sent as `ESC ]7;file://host/path BEL` at
+// startup and after each cd, so terminals can open new tabs in the same place
+static REPORT_CWD: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+fn report_cwd() {
+    if !REPORT_CWD.load(std::sync::atomic::Ordering::Relaxed) || !io::stdout().is_terminal() {
+        return;
+    }
+    let Ok(cwd) = env::current_dir() else {
+        return;
+    };
+    let path = percent_encode_path(cwd.as_os_str().as_encoded_bytes());
+    let mut out = io::stdout();
+    let _ = write!(out, "\x1b]7;file://{}{path}\x07", hostname());
+    let _ = out.flush();
+}
+
+// Everything but unreserved URL characters and `/` becomes %XX
+fn percent_encode_path(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+// Whether two names are one typo apart: a wrong, missing or extra character,
+// or two neighbouring ones swapped
+fn one_typo_apart(a: &str, b: &str) -> bool {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let same = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[same..], &b[same..]);
+    match (a.len(), b.len()) {
+        (0, 0) => false,
+        (x, y) if x == y => a[1..] == b[1..] || (x >= 2 && a[0] == b[1] && a[1] == b[0] && a[2..] == b[2..]),
+        (x, y) if x == y + 1 => a[1..] == *b,
+        (x, y) if x + 1 == y => *a == b[1..],
+        _ => false,
+    }
+}
+
+// The one directory in `dir` a typo away from `name`; None if there are none
+// or several
+fn spell_component(dir: &Path, name: &str) -> Option<String> {
+    let mut found = None;
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let entry_name = decode_os(&entry.file_name());
+        if one_typo_apart(name, &entry_name) && fs::metadata(entry.path()).is_ok_and(|m| m.is_dir()) {
+            if found.is_some() {
+                return None;
+            }
+            found = Some(entry_name);
+        }
+    }
+    found
+}
+
+// `set -o cdspell`: `path` with each missing component replaced by its
+// correction, None when nothing was missing or one can't be corrected
+fn correct_path(path: &str) -> Option<String> {
+    let mut base = PathBuf::from(if path.starts_with('/') { "/" } else { "." });
+    let mut fixed = Vec::new();
+    let mut changed = false;
+    for component in path.split('/') {
+        let next = base.join(encode_os(component));
+        if component.is_empty() || next.is_dir() {
+            fixed.push(component.to_string());
+            base = next;
+            continue;
+        }
+        let corrected = spell_component(&base, component)?;
+        base = base.join(encode_os(&corrected));
+        fixed.push(corrected);
+        changed = true;
+    }
+    changed.then(|| fixed.join("/"))
+}
+
+// The first directory of `cdpath` ($CDPATH) holding `dest`, for a relative
+// name that doesn't start with `.` or `..`
+fn cdpath_lookup(dest: &str, cdpath: &str) -> Option<String> {
+    let explicit = matches!(dest, "." | "..") || dest.starts_with("./") || dest.starts_with("../");
+    if dest.starts_with('/') || explicit {
+        return None;
+    }
+    // Empty entries stand for the current directory, tried already
+    cdpath
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| format!("{}/{dest}", dir.trim_end_matches('/')))
+        .find(|path| Path::new(&encode_os(path)).is_dir())
+}
+
+// The working directory as $PWD names it (through any symlinks cd followed),
+// or the real one when $PWD is unset or names some other directory
+fn shell_cwd() -> io::Result<PathBuf> {
+    let cwd = env::current_dir()?;
+    if let Some(pwd) = env::var_os("PWD").map(PathBuf::from) {
+        if pwd.is_absolute() && same_file(&pwd, &cwd) {
+            return Ok(pwd);
+        }
+    }
+    Ok(cwd)
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Path, b: &Path) -> bool {
+    fs::canonicalize(a).ok() == fs::canonicalize(b).ok()
+}
+
+// Points $PWD (which children inherit) at the working directory; after every
+// chdir, and at startup over a stale inherited value
+fn update_pwd() {
+    if let Ok(cwd) = shell_cwd() {
+        env::set_var("PWD", cwd);
+    }
+}
+
+// `cd dir`, or `cd` alone for $HOME; `~` forms are already expanded by the
+// tokenizer, `typed` holds the arguments as written so errors show what the
+// user typed. A relative dir not found here is looked for along `cdpath`.
+// With `spell` a mistyped dir is corrected (printing the fix), with `record`
+// the new directory goes into z's file.
+fn cd_builtin(args: &[String], typed: &[String], cdpath: &str, spell: bool, record: bool) -> i32 {
+    let home;
+    let dest = match args.first() {
+        Some(dest) => dest,
+        None => match env::var_os("HOME") {
+            // An empty HOME leaves us where we are, as in bash
+            Some(h) if h.is_empty() => return 0,
+            Some(h) => {
+                home = decode_os(&h);
+                &home
+            }
+            None => {
+                eprintln!("cd: HOME not set");
+                return 1;
+            }
+        },
+    };
+    let mut shown = typed.first().unwrap_or(dest).clone();
+
+    // `cd -` goes back to the previous directory and says where that is
+    let back = dest == "-";
+    let dest = if back {
+        match env::var_os("OLDPWD") {
+            Some(old) => {
+                shown = decode_os(&old);
+                shown.clone()
+            }
+            None => {
+                eprintln!("cd: OLDPWD not set");
+                return 1;
+            }
+        }
+    } else {
+        dest.clone()
+    };
+
+    let previous = shell_cwd().ok();
+    let mut result = env::set_current_dir(encode_os(&dest));
+    if result.is_err() && !back {
+        // Found through $CDPATH: say where we ended up
+        if let Some(found) = cdpath_lookup(&dest, cdpath) {
+            result = env::set_current_dir(encode_os(&found));
+            if result.is_ok() {
+                println!("{}", display_str(&found));
+            }
+        }
+    }
+    if result.is_err() && spell && !back {
+        if let Some(fixed) = correct_path(&dest) {
+            println!("{}", display_str(&fixed));
+            result = env::set_current_dir(encode_os(&fixed));
+        }
+    }
+    match result {
+        Ok(()) => {
+            if let Some(previous) = previous {
+                env::set_var("OLDPWD", previous);
+            }
+            if let Ok(cwd) = env::current_dir() {
+                if back {
+                    println!("{}", decode_os(cwd.as_os_str()));
+                }
+                if record {
+                    z_record(&cwd);
+                }
+            }
+            update_pwd();
+            report_cwd();
+            0
+        }
+        Err(_) => {
+            eprintln!("cd: {}: No such file or directory", display_str(&shown));
+            1
+        }
+    }
+}
+
+// ---------- z ----------
+// Directories cd'd into, one `path|rank|time` line each in data_dir()/z.
+// rank counts visits; the score weights it by the age of the last one.
+
+// Once the ranks add up to more than this they all shrink, so directories
+// no longer used fade out
+const Z_MAX_TOTAL_RANK: f64 = 9000.0;
+
+struct ZEntry {
+    path: String,
+    rank: f64,
+    time: u64,
+}
+
+fn z_file() -> Option<PathBuf> {
+    Some(data_dir()?.join("z"))
+}
+
+fn z_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn z_load(file: &Path) -> Vec<ZEntry> {
+    let text = fs::read(file).map(|b| decode_bytes(&b)).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.rsplitn(3, '|');
+            let time = fields.next()?.parse().ok()?;
+            let rank = fields.next()?.parse().ok()?;
+            let path = fields.next()?.to_string();
+            Some(ZEntry { path, rank, time })
+        })
+        .collect()
+}
+
+// Written beside the file and renamed over it, so another shell reads
+// either the old or the new version whole
+fn z_save(file: &Path, entries: &[ZEntry]) -> io::Result<()> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut out = String::new();
+    for e in entries {
+        out.push_str(&format!("{}|{}|{}\n", e.path, e.rank, e.time));
+    }
+    let tmp = file.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, encode_bytes(&out))?;
+    fs::rename(&tmp, file)
+}
+
+// One more visit to `dir`; $HOME isn't worth recording
+fn z_record(dir: &Path) {
+    let Some(file) = z_file() else { return };
+    if home_dir().as_deref() == Some(dir) {
+        return;
+    }
+    let path = decode_os(dir.as_os_str());
+    let now = z_now();
+    let mut entries = z_load(&file);
+    match entries.iter_mut().find(|e| e.path == path) {
+        Some(e) => {
+            e.rank += 1.0;
+            e.time = now;
+        }
+        None => entries.push(ZEntry { path, rank: 1.0, time: now }),
+    }
+    if entries.iter().map(|e| e.rank).sum::<f64>() > Z_MAX_TOTAL_RANK {
+        for e in &mut entries {
+            e.rank *= 0.99;
+        }
+        entries.retain(|e| e.rank >= 1.0);
+    }
+    let _ = z_save(&file, &entries);
+}
+
+fn z_score(e: &ZEntry, now: u64) -> f64 {
+    let weight = match now.saturating_sub(e.time) {
+        age if age < 3600 => 4.0,
+        age if age < 86400 => 2.0,
+        age if age < 604800 => 0.5,
+        _ => 0.25,
+    };
+    e.rank * weight
+}
+
+// Recorded directories containing every pattern, best first (a tie goes to
+// the latest visit). Ones that no longer exist leave the file on the way.
+fn z_matches(patterns: &[String]) -> Vec<(f64, ZEntry)> {
+    let Some(file) = z_file() else { return Vec::new() };
+    let mut entries = z_load(&file);
+    let before = entries.len();
+    entries.retain(|e| Path::new(&encode_os(&e.path)).is_dir());
+    if entries.len() != before {
+        let _ = z_save(&file, &entries);
+    }
+    let now = z_now();
+    let mut found: Vec<(f64, ZEntry)> = entries
+        .into_iter()
+        .filter(|e| patterns.iter().all(|p| e.path.contains(p.as_str())))
+        .map(|e| (z_score(&e, now), e))
+        .collect();
+    found.sort_by(|a, b| b.0.total_cmp(&a.0).then(b.1.time.cmp(&a.1.time)));
+    found
+}
+
+// `z -l [pattern ...]`: the matches, best last like z prints them
+fn z_listing(patterns: &[String]) -> Vec<u8> {
+    let mut out = String::new();
+    for (score, e) in z_matches(patterns).iter().rev() {
+        out.push_str(&format!("{score:<10.1} {}\n", e.path));
+    }
+    encode_bytes(&out)
+}
+
+// `z pattern ...`: cd to the best match
+fn z_jump(patterns: &[String], record: bool) -> i32 {
+    match z_matches(patterns).into_iter().next() {
+        Some((_, best)) => cd_builtin(&[best.path], &[], "", false, record),
+        None => {
+            eprintln!("z: no match for {}", display_str(&patterns.join(" ")));
+            1
+        }
+    }
+}
+
+// -------- history printing helper (matches tester formatting) --------
+fn history_output(history: &[String], n: Option<usize>) -> Vec<u8> {
+    let len = history.len();
+    // `history 0` lists nothing, a count past the end lists everything
+    let start = match n {
+        Some(k) => len.saturating_sub(k),
+        None => 0,
+    };
+
+    let mut out = String::new();
+    for (idx0, cmd) in history.iter().enumerate().skip(start) {
+        out.push_str(&format!("{:>5}  {}\n", idx0 + 1, cmd));
+    }
+    out.into_bytes()
+}
+
+// `history [n]` lists; -a appends the entries added since the last -a (or
+// since startup) to the history file, -n reads the lines other sessions added
+// to it since the last -n or -r, -r reads the whole file, -w rewrites it.
+// A file name after the flag stands in for the history file.
+fn history_builtin(ctx: &mut ShellContext, args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let (flag, rest) = match args.split_first() {
+        Some((flag, rest)) if ["-a", "-n", "-r", "-w"].contains(&flag.as_str()) => (flag.as_str(), rest),
+        _ => {
+            return match args {
+                [] => (history_output(&ctx.history, None), vec![], 0),
+                [count] => match count.parse::<usize>() {
+                    Ok(n) => (history_output(&ctx.history, Some(n)), vec![], 0),
+                    Err(_) => (
+                        vec![],
+                        format!("history: {}: numeric argument required\n", display_str(count)).into_bytes(),
+                        1,
+                    ),
+                },
+                _ => (vec![], b"history: too many arguments\n".to_vec(), 1),
+            };
+        }
+    };
+    let path = match rest {
+        [] => match &ctx.history_settings.file {
+            Some(path) => path.clone(),
+            None => return (vec![], b"history: no history file\n".to_vec(), 1),
+        },
+        [file] => PathBuf::from(encode_os(file)),
+        _ => return (vec![], b"history: too many arguments\n".to_vec(), 1),
+    };
+
+    let result = match flag {
+        "-a" => append_new_history(&path, &ctx.history, ctx.history_write_offset).map(|()| {
+            // Our own lines aren't news for -n
+            ctx.history_file_lines += ctx.history.len() - ctx.history_write_offset.min(ctx.history.len());
+            ctx.history_write_offset = ctx.history.len();
+        }),
+        "-w" => write_history(&path, &ctx.history).map(|()| {
+            ctx.history_write_offset = ctx.history.len();
+            ctx.history_file_lines = ctx.history.len();
+        }),
+        _ => fs::read(&path).map(|bytes| {
+            let text = decode_bytes(&bytes);
+            let lines: Vec<&str> = text.lines().filter(|l| !l.is_empty()).collect();
+            let skip = if flag == "-n" { ctx.history_file_lines.min(lines.len()) } else { 0 };
+            let new = lines.len() - skip;
+            ctx.history.extend(lines[skip..].iter().map(|l| l.to_string()));
+            ctx.history_file_lines = lines.len();
+            // What came from the file doesn't go back to it with -a
+            ctx.history_write_offset += new;
+            ctx.trim_history();
+        }),
+    };
+    match result {
+        Ok(()) => (vec![], vec![], 0),
+        Err(e) => (vec![], format!("history: {}: {e}\n", path.display()).into_bytes(), 1),
+    }
+}
+
+// Appends `entries[from..]`, leaving what the file already has alone
+fn append_new_history(path: &Path, entries: &[String], from: usize) -> io::Result<()> {
+    let mut out = Vec::new();
+    for line in entries.iter().skip(from) {
+        out.extend(encode_bytes(line));
+        out.push(b'\n');
+    }
+    if out.is_empty() {
+        return Ok(());
+    }
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(&out)
+}
+
+fn write_history(path: &Path, entries: &[String]) -> io::Result<()> {
+    let mut out = Vec::new();
+    for line in entries {
+        out.extend(encode_bytes(line));
+        out.push(b'\n');
+    }
+    fs::write(path, out)
+}
+
+// ---------- fc ----------
+// `fc -l` lists history, `fc [first [last]]` edits entries in an editor and
+// runs what was saved, `fc -s [old=new] [command]` runs one again with a
+// change. `history` here ends before the fc command itself.
+
+#[derive(Default)]
+struct FcOptions {
+    list: bool,
+    no_numbers: bool,
+    reverse: bool,
+    substitute: bool,
+    editor: Option<String>,
+    operands: Vec<String>,
+}
+
+fn fc_options(args: &[String]) -> Result<FcOptions, String> {
+    let mut opts = FcOptions::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        // `-5` is an operand (five commands back), not a flag
+        let is_flag = arg.len() > 1 && arg.starts_with('-') && !arg[1..].starts_with(|c: char| c.is_ascii_digit());
+        if !is_flag || !opts.operands.is_empty() {
+            opts.operands.push(arg.clone());
+            continue;
+        }
+        for flag in arg[1..].chars() {
+            match flag {
+                'l' => opts.list = true,
+                'n' => opts.no_numbers = true,
+                'r' => opts.reverse = true,
+                's' => opts.substitute = true,
+                'e' => opts.editor = Some(iter.next().ok_or("fc: -e: option requires an argument")?.clone()),
+                _ => return Err(format!("fc: -{flag}: invalid option")),
+            }
+        }
+    }
+    Ok(opts)
+}
+
+// Index of the entry an operand names: n, -n from the end, or the newest
+// command starting with the text
+fn fc_event(history: &[String], spec: &str) -> Option<usize> {
+    match spec.parse::<i64>() {
+        Ok(n) if n > 0 => (n as usize <= history.len()).then(|| n as usize - 1),
+        Ok(n) if n < 0 => history.len().checked_sub(n.unsigned_abs() as usize),
+        Ok(_) => history.len().checked_sub(1),
+        Err(_) => history.iter().rposition(|h| h.starts_with(spec)),
+    }
+}
+
+// First and last entry of the operands' range, defaulting to `back` entries
+fn fc_range(history: &[String], operands: &[String], back: usize) -> Result<(usize, usize), String> {
+    let out_of_range = || "fc: history specification out of range".to_string();
+    let newest = history.len().checked_sub(1).ok_or_else(out_of_range)?;
+    let event = |spec: &String| fc_event(history, spec).ok_or_else(out_of_range);
+    Ok(match operands {
+        [] => (newest.saturating_sub(back - 1), newest),
+        [first] if back > 1 => (event(first)?, newest),
+        [first] => (event(first)?, event(first)?),
+        [first, last, ..] => (event(first)?, event(last)?),
+    })
+}
+
+fn fc_list(history: &[String], opts: &FcOptions) -> (Vec<u8>, Vec<u8>, i32) {
+    let (first, last) = match fc_range(history, &opts.operands, 16) {
+        Ok(range) => range,
+        Err(msg) => return (vec![], format!("{msg}\n").into_bytes(), 1),
+    };
+    let mut indices: Vec<usize> = (first.min(last)..=first.max(last)).collect();
+    if (first > last) != opts.reverse {
+        indices.reverse();
+    }
+
+    let mut out = String::new();
+    for i in indices {
+        if opts.no_numbers {
+            out.push_str(&format!("\t {}\n", history[i]));
+        } else {
+            out.push_str(&format!("{}\t {}\n", i + 1, history[i]));
+        }
+    }
+    (encode_bytes(&out), vec![], 0)
+}
+
+// `-e`, then $FCEDIT, then $EDITOR, then vi
+fn fc_editor(ctx: &ShellContext, requested: Option<&str>) -> String {
+    requested
+        .map(str::to_string)
+        .or_else(|| ctx.var("FCEDIT").filter(|e| !e.is_empty()))
+        .or_else(|| ctx.var("EDITOR").filter(|e| !e.is_empty()))
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+// Lets the user edit `text` in `editor` (which may carry its own arguments)
+// on the terminal; the saved text, or None if the editor failed
+fn edit_in_editor(text: &str, editor: &str) -> Option<String> {
+    let (path, mut file) = create_edit_file().ok()?;
+    let written = file.write_all(&encode_bytes(&format!("{text}\n")));
+    drop(file);
+    if written.is_err() {
+        let _ = fs::remove_file(&path);
+        return None;
+    }
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("mysh")
+        .arg(&path)
+        .status();
+    let edited = fs::read(&path).ok().map(|bytes| decode_bytes(&bytes));
+    let _ = fs::remove_file(&path);
+    match status {
+        Ok(s) if s.success() => edited,
+        _ => None,
+    }
+}
+
+// A new file only we can read, under the temp dir. create_new won't follow a
+// link planted at the name; another name is tried if one is already there.
+fn create_edit_file() -> io::Result<(PathBuf, File)> {
+    let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+    let mut last_err = io::Error::from(io::ErrorKind::AlreadyExists);
+    for attempt in 0..100u32 {
+        let name = format!("mysh-edit-{}-{:08x}.sh", std::process::id(), nanos.wrapping_add(attempt.wrapping_mul(0x9e37_79b9)));
+        let path = env::temp_dir().join(name);
+        let mut opts = OpenOptions::new();
+        opts.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        match opts.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => last_err = e,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_err)
+}
+
+// What fc wants run next (maybe nothing), or an error message and status
+fn fc_builtin(ctx: &ShellContext, history: &[String], args: &[String]) -> Result<String, (String, i32)> {
+    let opts = fc_options(args).map_err(|msg| (msg, 2))?;
+
+    if opts.substitute {
+        let (change, spec) = match opts.operands.split_first() {
+            Some((first, rest)) if first.contains('=') => (first.split_once('='), rest.first()),
+            _ => (None, opts.operands.first()),
+        };
+        let index = match spec {
+            Some(spec) => fc_event(history, spec),
+            None => history.len().checked_sub(1),
+        };
+        let command = index.map(|i| history[i].clone()).ok_or(("fc: no command found".to_string(), 1))?;
+        return match change {
+            Some((old, _)) if !old.is_empty() && !command.contains(old) => {
+                Err((format!("fc: {old}: not found in `{command}'"), 1))
+            }
+            Some((old, new)) if !old.is_empty() => Ok(command.replacen(old, new, 1)),
+            _ => Ok(command),
+        };
+    }
+
+    let (first, last) = fc_range(history, &opts.operands, 1).map_err(|msg| (msg, 1))?;
+    let mut indices: Vec<usize> = (first.min(last)..=first.max(last)).collect();
+    if first > last {
+        indices.reverse();
+    }
+    let text: Vec<&str> = indices.iter().map(|&i| history[i].as_str()).collect();
+    // A failed or killed editor runs nothing
+    edit_in_editor(&text.join("\n"), &fc_editor(ctx, opts.editor.as_deref())).ok_or((String::new(), 1))
+}
+
+// ---------- echo ----------
+// Leading `-n`, `-e` and `-E` words (or clusters like `-ne`) are flags; the
+// first other word starts the text
+
+fn echo_bytes(args: &[String]) -> Vec<u8> {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut words = args;
+    while let Some((first, rest)) = words.split_first() {
+        let Some(flags) = first.strip_prefix('-') else { break };
+        if flags.is_empty() || !flags.chars().all(|c| matches!(c, 'n' | 'e' | 'E')) {
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        words = rest;
+    }
+
+    let text = words.join(" ");
+    if !escapes {
+        let mut out = encode_bytes(&text);
+        if newline {
+            out.push(b'\n');
+        }
+        return out;
+    }
+    let (mut out, stopped) = echo_escapes(&text);
+    if newline && !stopped {
+        out.push(b'\n');
+    }
+    out
+}
+
+// `\xHH` and `\0NNN` give raw bytes, `\uXXXX` a character; `\c` ends the
+// output there (true in the result, no newline follows either)
+fn echo_escapes(text: &str) -> (Vec<u8>, bool) {
+    let mut out = Vec::new();
+    let mut chars = text.chars().peekable();
+    // Up to `max` digits in `radix` from the input
+    let number = |chars: &mut std::iter::Peekable<std::str::Chars>, radix: u32, max: usize| {
+        let mut value: Option<u32> = None;
+        for _ in 0..max {
+            match chars.peek().and_then(|c| c.to_digit(radix)) {
+                Some(d) => {
+                    value = Some(value.unwrap_or(0) * radix + d);
+                    chars.next();
+                }
+                None => break,
+            }
+        }
+        value
+    };
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.extend(encode_bytes(&c.to_string()));
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('a') => 0x07,
+            Some('b') => 0x08,
+            Some('f') => 0x0c,
+            Some('v') => 0x0b,
+            Some('e') => 0x1b,
+            Some('\\') => b'\\',
+            Some('c') => return (out, true),
+            Some('0') => number(&mut chars, 8, 3).unwrap_or(0) as u8,
+            Some('x') => match number(&mut chars, 16, 2) {
+                Some(v) => v as u8,
+                None => {
+                    out.extend_from_slice(b"\\x");
+                    continue;
+                }
+            },
+            Some('u') => match number(&mut chars, 16, 4).and_then(char::from_u32) {
+                Some(ch) => {
+                    out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+                    continue;
+                }
+                None => {
+                    out.extend_from_slice(b"\\u");
+                    continue;
+                }
+            },
+            Some(other) => {
+                out.push(b'\\');
+                out.extend(encode_bytes(&other.to_string()));
+                continue;
+            }
+            None => b'\\',
+        };
+        out.push(byte);
+    }
+    (out, false)
+}
+
+// ---------- builtin output bytes ----------
+// `stdin_bytes` is the stage's whole input for builtins that read it (see
+// builtin_reads_stdin), None otherwise
+fn builtin_bytes(
+    cmd: &str,
+    args: &[String],
+    ctx: &ShellContext,
+    stdin_bytes: Option<Vec<u8>>,
+) -> (Vec<u8>, Vec<u8>, i32) {
+    match cmd {
+        "echo" => (echo_bytes(args), vec![], 0),
+        "pwd" => match shell_cwd() {
+            Ok(p) => (encode_bytes(&format!("{}\n", decode_os(p.as_os_str()))), vec![], 0),
+            Err(e) => (vec![], format!("pwd: {e}\n").into_bytes(), 1),
+        },
+        "type" => {
+            if args.is_empty() {
+                return (vec![], b"type: missing operand\n".to_vec(), 1);
+            }
+            let target = args[0].as_str();
+            let text = if let Some(value) = ctx.aliases.get(target) {
+                format!("{target} is aliased to `{value}'\n")
+            } else if let Some(body) = ctx.functions.get(target) {
+                format!("{target} is a function\n{}\n", function_text(target, body))
+            } else if is_builtin(target) || ctx.plugins.builtins.contains_key(target) {
+                format!("{target} is a shell builtin\n")
+            } else if let Some(p) = find_executable_in_path(target) {
+                format!("{target} is {}\n", decode_os(p.as_os_str()))
+            } else {
+                format!("{target} not found\n")
+            };
+            (encode_bytes(&text), vec![], 0)
+        }
+        "history" => history_builtin(&mut ctx.clone(), args),
+        "let" => let_builtin(&mut ctx.clone(), args),
+        "readonly" => readonly_builtin(&mut ctx.clone(), args),
+        "export" => export_builtin(&mut ctx.clone(), args),
+        "enable" => enable_builtin(args),
+        "help" => help_builtin(args),
+        "clear" => (clear_screen_bytes(), vec![], 0),
+        "wc" if optional_builtin_active(cmd) => wc_builtin(args, stdin_bytes),
+        "cat" if optional_builtin_active(cmd) => cat_builtin(args, stdin_bytes),
+        "jobs" => {
+            let mut out = Vec::new();
+            for line in job_table().report(true) {
+                out.extend(format!("{line}\n").into_bytes());
+            }
+            (out, vec![], 0)
+        }
+        "wait" => wait_builtin(args),
+        "kill" => kill_builtin(args),
+        "bg" => bg_builtin(args),
+        "disown" => disown_builtin(args),
+        // Needs the terminal, which a pipeline stage can't hand over
+        "fg" => (vec![], b"fg: no job control in a pipeline\n".to_vec(), 1),
+        // pipeline "set"/"hash": run on a copy, so only listing has any effect
+        "set" => set_builtin(&mut ctx.options.clone(), args),
+        "hash" => hash_builtin(&mut ctx.named_dirs.clone(), args),
+        // Like a subshell: changes don't outlive the pipeline
+        "alias" => alias_builtin(&mut ctx.aliases.clone(), args),
+        "unalias" => unalias_builtin(&mut ctx.aliases.clone(), args),
+        "unset" => unset_builtin(&mut ctx.clone(), args),
+        "trap" => trap_builtin(&mut ctx.traps.clone(), args),
+        "dirs" => match env::current_dir() {
+            Ok(cwd) if args.first().is_some_and(|a| a == "-l") => {
+                (encode_bytes(&format!("{}\n", decode_os(cwd.as_os_str()))), vec![], 0)
+            }
+            Ok(cwd) => (encode_bytes(&format!("{}\n", abbreviate_dir(&cwd, &ctx.named_dirs))), vec![], 0),
+            Err(e) => (vec![], format!("dirs: {e}\n").into_bytes(), 1),
+        },
+        // pipeline "cd"/"exit"/"exec": treated as no-op (parent effects only in single-command mode)
+        "cd" => (vec![], vec![], 0),
+        "exit" => (vec![], vec![], 0),
+        "exec" => (vec![], vec![], 0),
+        // pipeline "read": consumes its line, but like bash's subshell the
+        // variables don't outlive the stage
+        // pipeline "mapfile": like read, the array goes away with the stage
+        "mapfile" => match mapfile_options(args) {
+            Ok(opts) => match mapfile_input(&opts, stdin_bytes) {
+                Ok(_) => (vec![], vec![], 0),
+                Err(msg) => (vec![], msg.into_bytes(), 1),
+            },
+            Err(msg) => (vec![], msg.into_bytes(), 2),
+        },
+        "read" => match read_options(args) {
+            Ok((raw, names)) => {
+                let (_, code) = read_fields(raw, &names, &stdin_bytes.unwrap_or_default());
+                (vec![], vec![], code)
+            }
+            Err((msg, code)) => (vec![], msg.into_bytes(), code),
+        },
+        "record" => (vec![], b"record: cannot be used in a pipeline\n".to_vec(), 1),
+        "load_plugin" => (vec![], b"load_plugin: cannot be used in a pipeline\n".to_vec(), 1),
+        "source" | "." => (vec![], format!("{cmd}: cannot be used in a pipeline\n").into_bytes(), 1),
+        "z" => match args.split_first() {
+            None => (z_listing(&[]), vec![], 0),
+            Some((flag, patterns)) if flag == "-l" => (z_listing(patterns), vec![], 0),
+            Some(_) => (vec![], b"z: cannot be used in a pipeline\n".to_vec(), 1),
+        },
+        "fc" => match fc_options(args) {
+            Ok(opts) if opts.list => fc_list(&ctx.history[..ctx.history.len().saturating_sub(1)], &opts),
+            Ok(_) => (vec![], b"fc: cannot run commands in a pipeline\n".to_vec(), 1),
+            Err(msg) => (vec![], format!("{msg}\n").into_bytes(), 2),
+        },
+        "complete" => (vec![], b"complete: cannot be used in a pipeline\n".to_vec(), 1),
+        "bind" => match args {
+            [] => (list_key_bindings(&ctx.key_bindings), vec![], 0),
+            [flag] if flag == "-p" => (list_key_bindings(&ctx.key_bindings), vec![], 0),
+            _ => (vec![], b"bind: bindings can't be changed in a pipeline\n".to_vec(), 1),
+        },
+        _ => match ctx.plugins.builtins.get(cmd) {
+            Some(f) => run_plugin_builtin(*f, cmd, args),
+            None => (vec![], format!("{cmd}: command not found\n").into_bytes(), 127),
+        },
+    }
+}
+
+// 128 + SIGPIPE: the status of a stage whose reader went away
+const BROKEN_PIPE_STATUS: i32 = 141;
+
+// A reader that quits early must not kill the shell mid-write: with SIGPIPE
+// ignored the write fails with BrokenPipe instead. Children get the default
+// back when spawned (std's Command resets it), so `yes | head` still ends.
+#[cfg(unix)]
+fn ignore_sigpipe() {
+    unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN) };
+}
+
+#[cfg(not(unix))]
+fn ignore_sigpipe() {}
+
+fn write_output(w: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+    w.write_all(bytes)?;
+    w.flush()
+}
+
+// A write into a pipe whose reader is gone reports like a SIGPIPE death;
+// bash stays silent about it too
+fn output_failure(result: io::Result<()>) -> Option<i32> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Some(BROKEN_PIPE_STATUS),
+        _ => None,
+    }
+}
+
+// Also used from pipeline threads, so no panicking eprintln! here
+fn write_routed_stderr(stderr_bytes: &[u8], stderr_redir: &StderrRedirect, cmd_name: &str) {
+    match stderr_redir {
+        StderrRedirect::Inherit => {
+            if !stderr_bytes.is_empty() {
+                let mut e = io::stderr();
+                let _ = e.write_all(stderr_bytes);
+                let _ = e.flush();
+            }
+        }
+        _ => match open_for_stderr(stderr_redir) {
+            Ok(Some(mut f)) => {
+                let _ = f.write_all(stderr_bytes);
+                let _ = f.flush();
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "{cmd_name}: {e}");
+            }
+        },
+    }
+}
+
+// ---------- single builtin output routing ----------
+// Returns a status overriding the builtin's own when the output couldn't be
+// delivered (closed pipe, redirection that failed to open)
+fn write_routed_output(
+    stdout_bytes: &[u8],
+    stderr_bytes: &[u8],
+    stdout_redir: &StdoutRedirect,
+    stderr_redir: &StderrRedirect,
+    cmd_name: &str,
+    options: &ShellOptions,
+) -> Option<i32> {
+    write_routed_stderr(stderr_bytes, stderr_redir, cmd_name);
+
+    // stdout
+    match stdout_redir {
+        StdoutRedirect::Inherit => {
+            if stdout_bytes.is_empty() {
+                return None;
+            }
+            output_failure(write_output(&mut io::stdout(), stdout_bytes))
+        }
+        _ => match open_for_stdout(stdout_redir, options) {
+            Ok(Some(mut f)) => output_failure(write_output(&mut f, stdout_bytes)),
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("{cmd_name}: {e}");
+                Some(1)
+            }
+        },
+    }
+}
+
+// A builtin's output once its command closed stdout or stderr with `>&-` /
+// `2>&-`: stderr's is dropped, and stdout's fails like a write to a closed
+// descriptor
+fn closed_builtin_output(stage: &ParsedCommand, (out, err, code): (Vec<u8>, Vec<u8>, i32)) -> (Vec<u8>, Vec<u8>, i32) {
+    let closes = |n: i32| stage.fds.iter().any(|r| matches!(r, FdRedirect::Close(m) if *m == n));
+    let mut err = if closes(2) { Vec::new() } else { err };
+    if closes(1) && !out.is_empty() {
+        if !closes(2) {
+            err.extend(format!("{}: write error: Bad file descriptor\n", stage.cmd).into_bytes());
+        }
+        return (Vec::new(), err, 1);
+    }
+    (out, err, code)
+}
+
+// ---------- run single external ----------
+fn run_single_external(stage: &ParsedCommand, options: &ShellOptions) -> i32 {
+    let path = match resolve_command(&stage.cmd) {
+        CommandLookup::Found(p) => p,
+        lookup => return report_unrunnable(&stage.cmd, &lookup),
+    };
+
+    let mut cmd = external_command(&stage.cmd, &path);
+    cmd.args(stage.args.iter().map(|a| encode_os(a)));
+
+    // stdin
+    match open_for_stdin(&stage.stdin) {
+        Ok(Some(f)) => {
+            cmd.stdin(Stdio::from(f));
+        }
+        Ok(None) => {
+            cmd.stdin(Stdio::inherit());
+        }
+        Err(e) => {
+            eprintln!("{}: {e}", stage.cmd);
+            return 1;
+        }
+    }
+
+    // stdout
+    match &stage.stdout {
+        StdoutRedirect::Inherit => {
+            cmd.stdout(Stdio::inherit());
+        }
+        _ => match open_for_stdout(&stage.stdout, options) {
+            Ok(Some(f)) => {
+                cmd.stdout(Stdio::from(f));
+            }
+            Ok(None) => {
+                cmd.stdout(Stdio::inherit());
+            }
+            Err(e) => {
+                eprintln!("{}: {e}", stage.cmd);
+                return 1;
+            }
+        },
+    }
+
+    // stderr
+    match &stage.stderr {
+        StderrRedirect::Inherit => {
+            cmd.stderr(Stdio::inherit());
+        }
+        _ => match open_for_stderr(&stage.stderr) {
+            Ok(Some(f)) => {
+                cmd.stderr(Stdio::from(f));
+            }
+            Ok(None) => {
+                cmd.stderr(Stdio::inherit());
+            }
+            Err(e) => {
+                eprintln!("{}: {e}", stage.cmd);
+                return 1;
+            }
+        },
+    }
+
+    // 3-9, and any of 0-2 closed with `>&-`
+    let _passed = match pass_shell_fds(&mut cmd, &stage.fds, options) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{}: {e}", stage.cmd);
+            return 1;
+        }
+    };
+
+    join_process_group(&mut cmd, 0, true);
+    let _title = RunningTitle::show(&stage.cmd);
+    let started = Instant::now();
+    let child = match cmd.spawn() {
+        Ok(c) => c,
+        Err(e) => return report_spawn_error(&stage.cmd, &e),
+    };
+
+    let status = wait_foreground(vec![StageHandle::External(child)], command_text(std::slice::from_ref(stage)));
+    report_if_slow(started, status, std::slice::from_ref(stage));
+    status
+}
+
+// ---------- slow command report ----------
+// MYSH_REPORT_TIME_MS=N: a foreground command or pipeline that ran longer
+// than N ms is followed by `elapsed: 12.4s  exit: 0  cmd` on stderr.
+// Unset or 0 turns it off.
+fn report_if_slow(started: Instant, status: i32, stages: &[ParsedCommand]) {
+    let threshold = shell_setting("MYSH_REPORT_TIME_MS")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|&ms| ms > 0);
+    let Some(threshold) = threshold else {
+        return;
+    };
+    let elapsed = started.elapsed();
+    if elapsed < Duration::from_millis(threshold) {
+        return;
+    }
+    let _ = writeln!(
+        io::stderr(),
+        "elapsed: {:.1}s  exit: {status}  {}",
+        elapsed.as_secs_f64(),
+        display_str(&command_text(stages))
+    );
+}
+
+// `cmd args | cmd args` for messages about a pipeline
+fn command_text(stages: &[ParsedCommand]) -> String {
+    let command: Vec<String> = stages
+        .iter()
+        .map(|s| std::iter::once(&s.cmd).chain(&s.args).map(String::as_str).collect::<Vec<_>>().join(" "))
+        .collect();
+    command.join(" | ")
+}
+
+// ---------- exit status helpers ----------
+// Message bash prints when a foreground job dies from `sig`
+#[cfg(unix)]
+fn signal_description(sig: i32) -> Option<&'static str> {
+    let msg = match sig {
+        libc::SIGHUP => "Hangup",
+        libc::SIGQUIT => "Quit",
+        libc::SIGILL => "Illegal instruction",
+        libc::SIGTRAP => "Trace/breakpoint trap",
+        libc::SIGABRT => "Aborted",
+        libc::SIGBUS => "Bus error",
+        libc::SIGFPE => "Floating point exception",
+        libc::SIGKILL => "Killed",
+        libc::SIGUSR1 => "User defined signal 1",
+        libc::SIGSEGV => "Segmentation fault",
+        libc::SIGUSR2 => "User defined signal 2",
+        libc::SIGALRM => "Alarm clock",
+        libc::SIGTERM => "Terminated",
+        libc::SIGSYS => "Bad system call",
+        _ => return None,
+    };
+    Some(msg)
+}
+
+// Convert a child's status to a shell exit code (128+signum for signals),
+// reporting abnormal terminations on stderr. SIGINT (the user's own Ctrl-C)
+// and SIGPIPE (normal pipeline shutdown) are silent, like bash.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(sig) = status.signal() {
+        if sig != libc::SIGINT && sig != libc::SIGPIPE {
+            if let Some(msg) = signal_description(sig) {
+                if status.core_dumped() {
+                    eprintln!("{msg} (core dumped)");
+                } else {
+                    eprintln!("{msg}");
+                }
+            }
+        }
+        return 128 + sig;
+    }
+    status.code().unwrap_or(1)
+}
+
+fn builtin_reads_stdin(cmd: &str, args: &[String]) -> bool {
+    match cmd {
+        "read" => true,
+        "mapfile" => matches!(mapfile_options(args), Ok(opts) if opts.file.is_none()),
+        // Only without file operands (or a bad flag, which fails up front)
+        "wc" => {
+            optional_builtin_active(cmd)
+                && args.iter().all(|a| {
+                    a.strip_prefix('-')
+                        .is_some_and(|f| !f.is_empty() && f.chars().all(|c| "lwc".contains(c)))
+                })
+        }
+        "cat" => optional_builtin_active(cmd) && (args.is_empty() || args.iter().any(|a| a == "-")),
+        _ => false,
+    }
+}
+
+// Whole input of a pipeline builtin; no pipe (first stage) reads as empty
+fn read_stdin_bytes(fd: Option<File>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Some(mut f) = fd {
+        let _ = f.read_to_end(&mut buf);
+    }
+    buf
+}
+
+// Builtin stage writes output either to next pipe writer OR to terminal/file (if last stage)
+fn builtin_stage_run(
+    stage: ParsedCommand,
+    stdin_file: Option<File>,
+    stdout_pipe: Option<File>,
+    ctx: ShellContext,
+) -> i32 {
+    // Builtins that don't read stdin close their end right away so the upstream
+    // stage gets EPIPE/SIGPIPE instead of blocking on (or endlessly feeding) us.
+    // An explicit `<` wins over the pipe, as for externals.
+    let stdin_bytes = if builtin_reads_stdin(&stage.cmd, &stage.args) {
+        let input = match open_for_stdin(&stage.stdin) {
+            Ok(Some(f)) => Some(f),
+            Ok(None) => stdin_file,
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "{}: {e}", stage.cmd);
+                return 1;
+            }
+        };
+        // `read` takes a single line and leaves the rest (`yes | read` ends)
+        if stage.cmd == "read" {
+            let raw = stage.args.first().is_some_and(|a| a == "-r");
+            let line = input.map(|f| read_input_line(&mut io::BufReader::new(f), raw));
+            Some(line.and_then(Result::ok).unwrap_or_default())
+        } else {
+            Some(read_stdin_bytes(input))
+        }
+    } else {
+        drop(stdin_file);
+        None
+    };
+
+    let (out, err, code) = builtin_bytes(&stage.cmd, &stage.args, &ctx, stdin_bytes);
+
+    write_routed_stderr(&err, &stage.stderr, &stage.cmd);
+
+    // stdout routing
+    if let Some(mut pipe_writer) = stdout_pipe {
+        // Pipeline semantics: write stdout into pipe (ignore stdout redirection here).
+        // A reader that quit early stops the write at the first EPIPE; the stage
+        // then ends quietly with the SIGPIPE status, like bash.
+        let result = write_output(&mut pipe_writer, &out);
+        drop(pipe_writer);
+        return output_failure(result).unwrap_or(code);
+    }
+
+    // Last stage: honor stdout redirection
+    let failure = match stage.stdout {
+        StdoutRedirect::Inherit if out.is_empty() => None,
+        StdoutRedirect::Inherit => output_failure(write_output(&mut io::stdout(), &out)),
+        _ => match open_for_stdout(&stage.stdout, &ctx.options) {
+            Ok(Some(mut f)) => output_failure(write_output(&mut f, &out)),
+            Ok(None) => None,
+            Err(e) => {
+                // Not eprintln!: this runs on the stage thread, which must not panic
+                let _ = writeln!(io::stderr(), "{}: {e}", stage.cmd);
+                Some(1)
+            }
+        },
+    };
+    failure.unwrap_or(code)
+}
+
+// A running pipeline stage: builtins run on threads, externals as children.
+// Stages that couldn't start, or builtins that already ran, just carry their
+// status; dropping their pipe ends lets the neighbours see EOF.
+enum StageHandle {
+    Builtin(std::thread::JoinHandle<i32>),
+    External(Child),
+    Finished(i32),
+}
+
+// ---------- FULL pipeline execution (supports N stages, builtins + externals) ----------
+// Returns the exit status of the last stage
+fn execute_pipeline(stages: &[ParsedCommand], ctx: &ShellContext) -> i32 {
+    if stages.iter().all(|s| is_builtin(&s.cmd) || ctx.plugins.builtins.contains_key(&s.cmd)) {
+        return run_builtin_chain(stages, ctx);
+    }
+    let names: Vec<&str> = stages.iter().map(|s| s.cmd.as_str()).collect();
+    let _title = RunningTitle::show(&names.join(" | "));
+    let started = Instant::now();
+    let status = wait_foreground(spawn_pipeline(stages, ctx, false), command_text(stages));
+    report_if_slow(started, status, stages);
+    status
+}
+
+// Pipelines made only of builtins run one stage after another on this
+// thread, each stage's output handed straight to the next as its stdin:
+// no threads and no OS pipes
+fn run_builtin_chain(stages: &[ParsedCommand], ctx: &ShellContext) -> i32 {
+    let mut input: Vec<u8> = Vec::new();
+    let mut code = 0;
+    for (i, stage) in stages.iter().enumerate() {
+        let previous = std::mem::take(&mut input);
+        let stdin_bytes = match open_for_stdin(&stage.stdin) {
+            Err(e) => {
+                eprintln!("{}: {e}", stage.cmd);
+                code = 1;
+                continue;
+            }
+            Ok(file) if builtin_reads_stdin(&stage.cmd, &stage.args) => match file {
+                Some(f) => Some(read_stdin_bytes(Some(f))),
+                None => Some(previous),
+            },
+            Ok(_) => None,
+        };
+
+        let (out, err, status) = closed_builtin_output(stage, builtin_bytes(&stage.cmd, &stage.args, ctx, stdin_bytes));
+        if i + 1 == stages.len() {
+            code = write_routed_output(&out, &err, &stage.stdout, &stage.stderr, &stage.cmd, &ctx.options)
+                .unwrap_or(status);
+        } else {
+            write_routed_stderr(&err, &stage.stderr, &stage.cmd);
+            input = out;
+            code = status;
+        }
+    }
+    code
+}
+
+// Starts every stage and returns without waiting. Background pipelines read
+// /dev/null instead of the terminal unless they redirect stdin themselves.
+//
+// Pipes are made stage by stage, only when needed: a builtin that doesn't
+// read stdin runs right away, and when it prints nothing (`true | cmd`) the
+// next stage gets an empty stdin instead of a pipe.
+fn spawn_pipeline(stages: &[ParsedCommand], ctx: &ShellContext, background: bool) -> Vec<StageHandle> {
+    let mut handles: Vec<StageHandle> = Vec::new();
+    // Read end for the next stage; None with `empty_input` means no pipe at all
+    let mut next_stdin: Option<File> = None;
+    let mut empty_input = false;
+    // Process group of the pipeline, once its first external stage runs
+    let mut group: u32 = 0;
+
+    for (i, stage) in stages.iter().enumerate() {
+        let stage = stage.clone();
+        let is_last = i + 1 == stages.len();
+        let is_builtin_stage = is_builtin(&stage.cmd) || ctx.plugins.builtins.contains_key(&stage.cmd);
+
+        let stdin_file = next_stdin.take();
+        let stdin_empty = std::mem::take(&mut empty_input);
+
+        if is_builtin_stage && !is_last && !builtin_reads_stdin(&stage.cmd, &stage.args) {
+            drop(stdin_file);
+            let (out, err, code) = builtin_bytes(&stage.cmd, &stage.args, ctx, None);
+            write_routed_stderr(&err, &stage.stderr, &stage.cmd);
+            if out.is_empty() {
+                empty_input = true;
+                handles.push(StageHandle::Finished(code));
+                continue;
+            }
+            let (r, mut w) = match platform::make_pipe() {
+                Ok(ends) => ends,
+                Err(e) => {
+                    eprintln!("pipe: {e}");
+                    handles.push(StageHandle::Finished(1));
+                    empty_input = true;
+                    continue;
+                }
+            };
+            next_stdin = Some(r);
+            // Written on a thread: the reader isn't running yet
+            let h = std::thread::spawn(move || {
+                let result = write_output(&mut w, &out);
+                drop(w);
+                output_failure(result).unwrap_or(code)
+            });
+            handles.push(StageHandle::Builtin(h));
+            continue;
+        }
+
+        // stdout pipe: write end of a new pipe whose read end feeds the next stage
+        let stdout_pipe: Option<File> = if is_last {
+            None
+        } else {
+            match platform::make_pipe() {
+                Ok((r, w)) => {
+                    next_stdin = Some(r);
+                    Some(w)
+                }
+                Err(e) => {
+                    eprintln!("pipe: {e}");
+                    handles.push(StageHandle::Finished(1));
+                    empty_input = true;
+                    continue;
+                }
+            }
+        };
+
+        if is_builtin_stage {
+            let snapshot = ctx.clone();
+            let h = std::thread::spawn(move || {
+                builtin_stage_run(stage, stdin_file, stdout_pipe, snapshot)
+            });
+            handles.push(StageHandle::Builtin(h));
+            continue;
+        }
+
+        // External
+        let path = match resolve_command(&stage.cmd) {
+            CommandLookup::Found(p) => p,
+            lookup => {
+                handles.push(StageHandle::Finished(report_unrunnable(&stage.cmd, &lookup)));
+                continue;
+            }
+        };
+
+        let mut cmd = external_command(&stage.cmd, &path);
+        cmd.args(stage.args.iter().map(|a| encode_os(a)));
+
+        // stdin: an explicit `<` wins over the pipe (which is then just dropped)
+        match open_for_stdin(&stage.stdin) {
+            Ok(Some(f)) => {
+                cmd.stdin(Stdio::from(f));
+            }
+            Ok(None) => match stdin_file {
+                Some(f) => {
+                    cmd.stdin(Stdio::from(f));
+                }
+                None if background || stdin_empty => {
+                    cmd.stdin(Stdio::null());
+                }
+                None => {
+                    cmd.stdin(Stdio::inherit());
+                }
+            },
+            Err(e) => {
+                eprintln!("{}: {e}", stage.cmd);
+                handles.push(StageHandle::Finished(1));
+                continue;
+            }
+        }
+
+        // stdout
+        if let Some(f) = stdout_pipe {
+            // Not last stage: connect to pipe
+            cmd.stdout(Stdio::from(f));
+        } else {
+            // Last stage: honor stdout redirection
+            match &stage.stdout {
+                StdoutRedirect::Inherit => {
+                    cmd.stdout(Stdio::inherit());
+                }
+                _ => match open_for_stdout(&stage.stdout, &ctx.options) {
+                    Ok(Some(f)) => {
+                        cmd.stdout(Stdio::from(f));
+                    }
+                    Ok(None) => {
+                        cmd.stdout(Stdio::inherit());
+                    }
+                    Err(e) => {
+                        eprintln!("{}: {e}", stage.cmd);
+                        handles.push(StageHandle::Finished(1));
+                        continue;
+                    }
+                },
+            }
+        }
+
+        // stderr
+        match &stage.stderr {
+            StderrRedirect::Inherit => {
+                cmd.stderr(Stdio::inherit());
+            }
+            _ => match open_for_stderr(&stage.stderr) {
+                Ok(Some(f)) => {
+                    cmd.stderr(Stdio::from(f));
+                }
+                Ok(None) => {
+                    cmd.stderr(Stdio::inherit());
+                }
+                Err(e) => {
+                    eprintln!("{}: {e}", stage.cmd);
+                    handles.push(StageHandle::Finished(1));
+                    continue;
+                }
+            },
+        }
+
+        // 3-9
+        let _passed = match pass_shell_fds(&mut cmd, &stage.fds, &ctx.options) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("{}: {e}", stage.cmd);
+                handles.push(StageHandle::Finished(1));
+                continue;
+            }
+        };
+
+        join_process_group(&mut cmd, group, !background);
+        match cmd.spawn() {
+            Ok(child) => {
+                if group == 0 {
+                    group = child.id();
+                }
+                handles.push(StageHandle::External(child));
+            }
+            Err(e) => handles.push(StageHandle::Finished(report_spawn_error(&stage.cmd, &e))),
+        }
+    }
+
+    handles
+}
+
+// ---------- background jobs ----------
+// `cmd &` starts a pipeline without waiting for it. Finished jobs are
+// reported on stderr, once, before the next prompt (right away with `set -b`)
+// and then dropped from the table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JobState {
+    Running,
+    // Ctrl-Z'd while in the foreground
+    Stopped,
+    Exited(i32),
+    // signal number, core dumped
+    Signaled(i32, bool),
+}
+
+impl JobState {
+    fn from_status(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        if let Some(sig) = status.signal() {
+            return JobState::Signaled(sig, status.core_dumped());
+        }
+        JobState::Exited(status.code().unwrap_or(1))
+    }
+
+    // Still in the table: running or stopped
+    fn is_live(self) -> bool {
+        matches!(self, JobState::Running | JobState::Stopped)
+    }
+
+    // The status column of `jobs` and of the notifications
+    fn describe(self) -> String {
+        match self {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Exited(0) => "Done".to_string(),
+            JobState::Exited(code) => format!("Exit {code}"),
+            JobState::Signaled(sig, core) => {
+                let name = signal_description(sig)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("Signal {sig}"));
+                if core {
+                    format!("{name} (core dumped)")
+                } else {
+                    name
+                }
+            }
+        }
+    }
+}
+
+impl StageHandle {
+    // Final state once the stage has ended, without blocking
+    fn try_finish(&mut self) -> Option<JobState> {
+        match self {
+            StageHandle::External(child) => match child.try_wait() {
+                Ok(Some(status)) => Some(JobState::from_status(status)),
+                Ok(None) => None,
+                Err(_) => Some(JobState::Exited(1)),
+            },
+            StageHandle::Builtin(h) if !h.is_finished() => None,
+            StageHandle::Builtin(_) => {
+                let StageHandle::Builtin(h) = std::mem::replace(self, StageHandle::Finished(1)) else {
+                    return None;
+                };
+                let code = h.join().unwrap_or(1);
+                *self = StageHandle::Finished(code);
+                Some(JobState::Exited(code))
+            }
+            StageHandle::Finished(code) => Some(JobState::Exited(*code)),
+        }
+    }
+}
+
+struct Job {
+    id: usize,
+    // The command line as typed, without the `&`
+    command: String,
+    stages: Vec<StageHandle>,
+    stopped: bool,
+}
+
+impl Job {
+    // Running until every stage has ended; then the last stage's state
+    fn poll(&mut self) -> JobState {
+        let states: Vec<Option<JobState>> = self.stages.iter_mut().map(StageHandle::try_finish).collect();
+        match states.last() {
+            Some(Some(last)) if states.iter().all(Option::is_some) => *last,
+            None => JobState::Exited(0),
+            _ if self.stopped => JobState::Stopped,
+            _ => JobState::Running,
+        }
+    }
+
+    fn pid(&self) -> Option<u32> {
+        self.stages.iter().rev().find_map(|s| match s {
+            StageHandle::External(c) => Some(c.id()),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Default)]
+struct JobTable {
+    // Ordered by id; the last one is the current job (`+`), the one before
+    // it the previous job (`-`)
+    jobs: Vec<Job>,
+}
+
+impl JobTable {
+    // New jobs get one more than the highest id in use
+    fn add(&mut self, command: String, stages: Vec<StageHandle>) -> &Job {
+        let id = self.jobs.last().map_or(1, |j| j.id + 1);
+        self.jobs.push(Job { id, command, stages, stopped: false });
+        &self.jobs[self.jobs.len() - 1]
+    }
+
+    fn marker(&self, idx: usize) -> char {
+        match self.jobs.len() - idx {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+
+    fn format_line(&self, idx: usize, state: JobState) -> String {
+        let job = &self.jobs[idx];
+        let amp = if state == JobState::Running { " &" } else { "" };
+        format!(
+            "[{}]{}  {:<24}{}{amp}",
+            job.id,
+            self.marker(idx),
+            state.describe(),
+            job.command
+        )
+    }
+
+    // Lines for every job (`all`) or only the finished ones; finished jobs
+    // are removed, so each is reported exactly once
+    fn report(&mut self, all: bool) -> Vec<String> {
+        let states: Vec<JobState> = self.jobs.iter_mut().map(Job::poll).collect();
+        let mut lines = Vec::new();
+        for (idx, state) in states.iter().enumerate() {
+            if all || !state.is_live() {
+                lines.push(self.format_line(idx, *state));
+            }
+        }
+        let mut states = states.into_iter();
+        self.jobs.retain(|_| states.next().is_some_and(JobState::is_live));
+        lines
+    }
+}
+
+static JOBS: Mutex<JobTable> = Mutex::new(JobTable { jobs: Vec::new() });
+
+fn job_table() -> std::sync::MutexGuard<'static, JobTable> {
+    JOBS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// PID of the job a spec names: %N by id, %string by the start of its command
+// and %?string anywhere in it (newest first), %%/%+ the current job and %-
+// the previous one
+fn resolve_job_id(spec: &str, table: &JobTable) -> Option<u32> {
+    let rest = spec.strip_prefix('%')?;
+    let jobs = &table.jobs;
+    let job = match rest {
+        "" | "%" | "+" => jobs.last(),
+        "-" => jobs.len().checked_sub(2).map(|i| &jobs[i]),
+        _ => match (rest.parse::<usize>(), rest.strip_prefix('?')) {
+            (Ok(n), _) => jobs.iter().find(|j| j.id == n),
+            (_, Some(text)) => jobs.iter().rev().find(|j| j.command.contains(text)),
+            _ => jobs.iter().rev().find(|j| j.command.starts_with(rest)),
+        },
+    };
+    job.and_then(Job::pid)
+}
+
+// Index of the job a %spec names, or of the job one of whose processes has
+// the PID
+fn job_index(table: &JobTable, spec: &str) -> Option<usize> {
+    let pid = if spec.starts_with('%') {
+        resolve_job_id(spec, table)?
+    } else {
+        spec.parse().ok()?
+    };
+    let owns = |job: &Job| {
+        job.stages.iter().any(|s| matches!(s, StageHandle::External(c) if c.id() == pid))
+    };
+    table.jobs.iter().position(owns)
+}
+
+// Only an interactive shell announces jobs (`[1] pid`, `[1]+ Done ...`);
+// scripts and -c reap them silently, as bash does
+static JOB_NOTICES: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+fn job_notices() -> bool {
+    JOB_NOTICES.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+// `set -b`: report jobs as soon as they finish instead of at the next prompt
+static NOTIFY_ASYNC: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// How `set -b` notifications are printed: through the line editor, so they
+// don't clobber a half-typed line. Only there while the option is on, since
+// with an external printer rustyline takes typed-ahead input a key at a time.
+type AsyncPrinter = Box<dyn FnMut(String) + Send>;
+static ASYNC_PRINTER: Mutex<Option<AsyncPrinter>> = Mutex::new(None);
+
+fn start_job_notifier() {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_millis(200));
+        let waiting = WAITING_FOR_JOBS.load(std::sync::atomic::Ordering::Relaxed);
+        if !NOTIFY_ASYNC.load(std::sync::atomic::Ordering::Relaxed) || waiting || !job_notices() {
+            continue;
+        }
+        let lines = job_table().report(false);
+        if lines.is_empty() {
+            continue;
+        }
+        let msg: String = lines.iter().map(|l| format!("{l}\n")).collect();
+        match ASYNC_PRINTER.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            Some(print) => print(msg),
+            None => eprint!("{msg}"),
+        }
+    });
+}
+
+// Creates the printer when `set -b` goes on and drops it when it goes off
+fn sync_async_printer(rl: &mut Editor<ShellHelper, DefaultHistory>, on: bool) {
+    let mut slot = ASYNC_PRINTER.lock().unwrap_or_else(|e| e.into_inner());
+    if !on {
+        *slot = None;
+    } else if slot.is_none() {
+        *slot = rl.create_external_printer().ok().map(|mut printer| {
+            Box::new(move |msg| {
+                let _ = printer.print(msg);
+            }) as AsyncPrinter
+        });
+    }
+}
+
+fn notify_finished_jobs() {
+    let lines = job_table().report(false);
+    if job_notices() {
+        for line in lines {
+            eprintln!("{line}");
+        }
+    }
+}
+
+// Starts the pipeline as a job and prints `[id] pid`
+fn launch_background(stages: &[ParsedCommand], ctx: &ShellContext, command: String) -> i32 {
+    let handles = spawn_pipeline(stages, ctx, true);
+    let mut table = job_table();
+    let job = table.add(command, handles);
+    if job_notices() {
+        match job.pid() {
+            Some(pid) => eprintln!("[{}] {pid}", job.id),
+            None => eprintln!("[{}]", job.id),
+        }
+    }
+    0
+}
+
+// ---------- wait ----------
+// `wait` waits for every running job, `wait id ...` for those (%specs or PIDs)
+// and `wait -n [id ...]` for whichever of them ends first. Jobs that end are
+// dropped, and reported like notifications when interactive; the status is
+// the last one's.
+
+// Set while `wait` polls, so the `set -b` notifier leaves finished jobs alone
+static WAITING_FOR_JOBS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+// Ctrl-C arrived during `wait`
+static WAIT_INTERRUPTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn note_wait_interrupt(_sig: libc::c_int) {
+    WAIT_INTERRUPTED.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+// With job control Ctrl-C interrupts `wait` rather than killing the shell;
+// returns the handler to put back
+#[cfg(unix)]
+fn catch_wait_interrupt() -> Option<libc::sighandler_t> {
+    if shell_pgid() == 0 {
+        return None;
+    }
+    WAIT_INTERRUPTED.store(false, std::sync::atomic::Ordering::SeqCst);
+    let handler = note_wait_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    Some(unsafe { libc::signal(libc::SIGINT, handler) })
+}
+
+#[cfg(unix)]
+fn restore_wait_interrupt(old: Option<libc::sighandler_t>) {
+    if let Some(old) = old {
+        unsafe { libc::signal(libc::SIGINT, old) };
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_wait_interrupt() -> Option<()> {
+    None
+}
+
+#[cfg(not(unix))]
+fn restore_wait_interrupt(_old: Option<()>) {}
+
+// What `$?` becomes for a job `wait` saw end (or stop)
+fn job_exit_code(state: JobState) -> i32 {
+    match state {
+        JobState::Exited(code) => code,
+        JobState::Signaled(sig, _) => 128 + sig,
+        #[cfg(unix)]
+        JobState::Stopped => 128 + libc::SIGTSTP,
+        _ => 0,
+    }
+}
+
+// Job id for a %spec or the PID of one of a job's processes
+fn wait_target(table: &JobTable, spec: &str) -> Result<usize, (String, i32)> {
+    if let Some(idx) = job_index(table, spec) {
+        return Ok(table.jobs[idx].id);
+    }
+    if spec.starts_with('%') {
+        return Err((format!("wait: {spec}: no such job"), 127));
+    }
+    match spec.parse::<u32>() {
+        Ok(pid) => Err((format!("wait: pid {pid} is not a child of this shell"), 127)),
+        Err(_) => Err((format!("wait: `{spec}': not a pid or valid job spec"), 2)),
+    }
+}
+
+fn wait_builtin(args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let (any, specs) = match args.first().map(String::as_str) {
+        Some("-n") => (true, &args[1..]),
+        _ => (false, args),
+    };
+    let specs = match specs.first().map(String::as_str) {
+        Some("--") => &specs[1..],
+        _ => specs,
+    };
+
+    let mut err = Vec::new();
+    let mut status = 0;
+    let mut pending: Vec<usize> = Vec::new();
+    {
+        let table = job_table();
+        if specs.is_empty() {
+            pending.extend(table.jobs.iter().filter(|j| !j.stopped).map(|j| j.id));
+        }
+        for spec in specs {
+            match wait_target(&table, spec) {
+                Ok(id) => {
+                    pending.push(id);
+                    status = 0;
+                }
+                Err((msg, code)) => {
+                    err.extend(format!("{msg}\n").into_bytes());
+                    status = code;
+                }
+            }
+        }
+    }
+    if pending.is_empty() {
+        // Nothing to wait for: -n fails, plain `wait` succeeds
+        let code = if any { 127 } else { status };
+        return (vec![], err, code);
+    }
+
+    WAITING_FOR_JOBS.store(true, std::sync::atomic::Ordering::SeqCst);
+    let old_handler = catch_wait_interrupt();
+    loop {
+        let mut table = job_table();
+        let states: Vec<JobState> = table.jobs.iter_mut().map(Job::poll).collect();
+        let mut ended = Vec::new();
+        let mut changed = false;
+        for (idx, state) in states.iter().enumerate() {
+            let id = table.jobs[idx].id;
+            if !pending.contains(&id) || *state == JobState::Running {
+                continue;
+            }
+            if *state != JobState::Stopped {
+                if job_notices() {
+                    err.extend(format!("{}\n", table.format_line(idx, *state)).into_bytes());
+                }
+                ended.push(id);
+            }
+            pending.retain(|p| *p != id);
+            status = job_exit_code(*state);
+            changed = true;
+            if any {
+                break;
+            }
+        }
+        table.jobs.retain(|j| !ended.contains(&j.id));
+        // Jobs gone from the table some other way count as done
+        pending.retain(|p| table.jobs.iter().any(|j| j.id == *p));
+        drop(table);
+
+        if pending.is_empty() || (any && changed) {
+            break;
+        }
+        if WAIT_INTERRUPTED.swap(false, std::sync::atomic::Ordering::SeqCst) {
+            status = 130;
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    restore_wait_interrupt(old_handler);
+    WAITING_FOR_JOBS.store(false, std::sync::atomic::Ordering::SeqCst);
+    (vec![], err, status)
+}
+
+// ---------- kill, fg, bg, disown ----------
+// Jobs are named by %specs (see resolve_job_id) or a PID; fg, bg and disown
+// default to the current job.
+
+// Number of a signal given as a name (TERM, SIGTERM, any case) or a number
+#[cfg(unix)]
+fn signal_number(spec: &str) -> Option<i32> {
+    if let Ok(n) = spec.parse::<i32>() {
+        return (0..65).contains(&n).then_some(n);
+    }
+    let upper = spec.to_ascii_uppercase();
+    Some(match upper.strip_prefix("SIG").unwrap_or(&upper) {
+        "ABRT" => libc::SIGABRT,
+        "ALRM" => libc::SIGALRM,
+        "CHLD" => libc::SIGCHLD,
+        "CONT" => libc::SIGCONT,
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "KILL" => libc::SIGKILL,
+        "PIPE" => libc::SIGPIPE,
+        "QUIT" => libc::SIGQUIT,
+        "STOP" => libc::SIGSTOP,
+        "TERM" => libc::SIGTERM,
+        "TSTP" => libc::SIGTSTP,
+        "TTIN" => libc::SIGTTIN,
+        "TTOU" => libc::SIGTTOU,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "WINCH" => libc::SIGWINCH,
+        _ => return None,
+    })
+}
+
+#[cfg(not(unix))]
+fn signal_number(_spec: &str) -> Option<i32> {
+    None
+}
+
+// Signals the job's process group under job control, else each process
+fn signal_job(job: &Job, sig: i32) -> io::Result<()> {
+    let pids: Vec<i32> = job
+        .stages
+        .iter()
+        .filter_map(|s| match s {
+            StageHandle::External(c) => Some(c.id() as i32),
+            _ => None,
+        })
+        .collect();
+    let targets = match pids.first() {
+        Some(&leader) if shell_pgid() != 0 => vec![-leader],
+        _ => pids,
+    };
+    for pid in targets {
+        platform::send_signal(pid, sig)?;
+    }
+    Ok(())
+}
+
+fn continue_job(job: &mut Job) {
+    if let Some(sig) = signal_number("CONT") {
+        let _ = signal_job(job, sig);
+    }
+    job.stopped = false;
+}
+
+// The job a builtin's argument names, the current job without one
+fn job_arg(table: &JobTable, builtin: &str, spec: Option<&String>) -> Result<usize, String> {
+    let spec = spec.map_or("%+", String::as_str);
+    job_index(table, spec).ok_or_else(|| {
+        let shown = if spec == "%+" { "current" } else { spec };
+        format!("{builtin}: {shown}: no such job\n")
+    })
+}
+
+// `kill [-s sig | -sig] id ...` and `kill -l`
+// `kill -l` lists the signal names; `kill -l spec ...` turns numbers (or
+// 128 + a number, as in $?) into names and names into numbers
+fn kill_list(specs: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    if specs.is_empty() {
+        return (format!("{}\n", SIGNAL_NAMES.join(" ")).into_bytes(), vec![], 0);
+    }
+    let (mut out, mut err) = (String::new(), String::new());
+    for spec in specs {
+        let found = match spec.parse::<i32>() {
+            Ok(n) => {
+                let n = if n > 128 { n - 128 } else { n };
+                SIGNAL_NAMES.iter().find(|name| signal_number(name) == Some(n)).map(|name| name.to_string())
+            }
+            Err(_) => signal_number(spec).map(|n| n.to_string()),
+        };
+        match found {
+            Some(text) => out.push_str(&format!("{text}\n")),
+            None => err.push_str(&format!("kill: {spec}: invalid signal specification\n")),
+        }
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (out.into_bytes(), err.into_bytes(), code)
+}
+
+fn kill_builtin(args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let invalid = |spec: &str| format!("kill: {spec}: invalid signal specification\n").into_bytes();
+    let (sig, targets) = match args.first().map(String::as_str) {
+        Some("-l") => return kill_list(&args[1..]),
+        Some("-s") => match args.get(1) {
+            Some(name) => (signal_number(name).ok_or_else(|| invalid(name)), &args[2..]),
+            None => (Err(b"kill: -s: option requires an argument\n".to_vec()), &args[1..]),
+        },
+        Some("--") => (signal_number("TERM").ok_or_else(|| invalid("TERM")), &args[1..]),
+        Some(flag) if flag.len() > 1 && flag.starts_with('-') => {
+            (signal_number(&flag[1..]).ok_or_else(|| invalid(&flag[1..])), &args[1..])
+        }
+        _ => (signal_number("TERM").ok_or_else(|| invalid("TERM")), args),
+    };
+    let sig = match sig {
+        Ok(sig) => sig,
+        Err(msg) => return (vec![], msg, 1),
+    };
+    if targets.is_empty() {
+        return (vec![], b"kill: usage: kill [-s sigspec | -sigspec] pid | jobspec ... or kill -l\n".to_vec(), 2);
+    }
+
+    let mut err = Vec::new();
+    for target in targets {
+        let sent = if target.starts_with('%') {
+            let table = job_table();
+            match job_index(&table, target) {
+                Some(idx) => {
+                    let job = &table.jobs[idx];
+                    let sent = signal_job(job, sig);
+                    // A stopped job only acts on the signal once running again
+                    if job.stopped && sent.is_ok() {
+                        let _ = signal_number("CONT").map(|cont| signal_job(job, cont));
+                    }
+                    sent.map_err(|e| format!("kill: {target}: {e}\n"))
+                }
+                None => Err(format!("kill: {target}: no such job\n")),
+            }
+        } else {
+            match target.parse::<i32>() {
+                Ok(pid) => platform::send_signal(pid, sig).map_err(|e| format!("kill: ({pid}) - {e}\n")),
+                Err(_) => Err(format!("kill: {target}: arguments must be process or job IDs\n")),
+            }
+        };
+        if let Err(msg) = sent {
+            err.extend(msg.into_bytes());
+        }
+    }
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err, code)
+}
+
+// Brings a job to the foreground, continuing it if stopped, and waits for it
+fn fg_builtin(args: &[String]) -> i32 {
+    let mut table = job_table();
+    let idx = match job_arg(&table, "fg", args.first()) {
+        Ok(idx) => idx,
+        Err(msg) => {
+            eprint!("{msg}");
+            return 1;
+        }
+    };
+    let mut job = table.jobs.remove(idx);
+    drop(table);
+    println!("{}", job.command);
+    // Stages already reaped by a poll keep only their status
+    for stage in &mut job.stages {
+        if let Some(state) = stage.try_finish() {
+            *stage = StageHandle::Finished(job_exit_code(state));
+        }
+    }
+    continue_job(&mut job);
+    wait_foreground(job.stages, job.command)
+}
+
+// Continues a stopped job in the background
+fn bg_builtin(args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let mut table = job_table();
+    let idx = match job_arg(&table, "bg", args.first()) {
+        Ok(idx) => idx,
+        Err(msg) => return (vec![], msg.into_bytes(), 1),
+    };
+    if !table.jobs[idx].stopped {
+        let msg = format!("bg: job {} already in background\n", table.jobs[idx].id);
+        return (vec![], msg.into_bytes(), 0);
+    }
+    continue_job(&mut table.jobs[idx]);
+    let job = &table.jobs[idx];
+    let line = format!("[{}]{} {} &\n", job.id, table.marker(idx), job.command);
+    (line.into_bytes(), vec![], 0)
+}
+
+// Forgets jobs without touching their processes; -a forgets them all
+fn disown_builtin(args: &[String]) -> (Vec<u8>, Vec<u8>, i32) {
+    let mut table = job_table();
+    if args.first().is_some_and(|a| a == "-a") {
+        table.jobs.clear();
+        return (vec![], vec![], 0);
+    }
+    let specs: Vec<Option<&String>> = match args {
+        [] => vec![None],
+        _ => args.iter().map(Some).collect(),
+    };
+    let mut err = Vec::new();
+    let mut ids = Vec::new();
+    for spec in specs {
+        match job_arg(&table, "disown", spec) {
+            Ok(idx) => ids.push(table.jobs[idx].id),
+            Err(msg) => err.extend(msg.into_bytes()),
+        }
+    }
+    table.jobs.retain(|j| !ids.contains(&j.id));
+    let code = if err.is_empty() { 0 } else { 1 };
+    (vec![], err, code)
+}
+
+// ---------- job control ----------
+// An interactive shell on a terminal runs in its own process group, and every
+// pipeline gets a new one led by its first external stage. A foreground
+// pipeline's group owns the terminal while it runs, so Ctrl-C and Ctrl-Z reach
+// only its processes; the shell takes the terminal back afterwards. Background
+// pipelines get a group but never the terminal.
+
+// The shell's process group, 0 without job control
+static SHELL_PGID: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+// Group that had the terminal before us, handed it back on exit
+static ORIGINAL_PGID: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+// The terminal, close-on-exec so children can still claim it before exec
+static TTY_FD: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+fn shell_pgid() -> i32 {
+    SHELL_PGID.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+fn tty_fd() -> i32 {
+    TTY_FD.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+#[cfg(unix)]
+fn init_job_control() {
+    use std::sync::atomic::Ordering;
+    unsafe {
+        let fd = libc::fcntl(libc::STDIN_FILENO, libc::F_DUPFD_CLOEXEC, 10);
+        if fd < 0 {
+            return;
+        }
+        let original = libc::tcgetpgrp(fd);
+        // Fails for a session leader, which leads its group already
+        if libc::getpgrp() != libc::getpid() {
+            libc::setpgid(0, 0);
+        }
+        let pgid = libc::getpgrp();
+        set_terminal_group(fd, pgid);
+        TTY_FD.store(fd, Ordering::Relaxed);
+        ORIGINAL_PGID.store(original, Ordering::Relaxed);
+        SHELL_PGID.store(pgid, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(unix))]
+fn init_job_control() {}
+
+// tcsetpgrp with SIGTTOU ignored, since from a background group the call
+// would stop us. Only async-signal-safe calls: children make it before exec.
+#[cfg(unix)]
+fn set_terminal_group(fd: i32, pgid: i32) {
+    unsafe {
+        let old = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(fd, pgid);
+        libc::signal(libc::SIGTTOU, old);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_terminal_group(_fd: i32, _pgid: i32) {}
+
+// Puts a stage in process group `leader`, 0 making it the leader of a new
+// one; a foreground stage also claims the terminal for its group
+#[cfg(unix)]
+fn join_process_group(cmd: &mut Command, leader: u32, foreground: bool) {
+    if shell_pgid() == 0 {
+        return;
+    }
+    platform::set_process_group(cmd, leader);
+    if foreground {
+        let fd = tty_fd();
+        unsafe {
+            cmd.pre_exec(move || {
+                set_terminal_group(fd, libc::getpgrp());
+                Ok(())
+            });
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn join_process_group(_cmd: &mut Command, _leader: u32, _foreground: bool) {}
+
+fn reclaim_terminal() {
+    if shell_pgid() != 0 {
+        set_terminal_group(tty_fd(), shell_pgid());
+    }
+}
+
+// On exit, back to whoever had the terminal before
+fn release_terminal() {
+    let original = ORIGINAL_PGID.load(std::sync::atomic::Ordering::Relaxed);
+    if shell_pgid() != 0 && original > 0 {
+        set_terminal_group(tty_fd(), original);
+    }
+}
+
+enum Waited {
+    Exited(ExitStatus),
+    // Stopped by this signal (Ctrl-Z sends SIGTSTP)
+    Stopped(i32),
+}
+
+// Like `child.wait()`, but with job control also returns when it stops
+#[cfg(unix)]
+fn wait_untraced(child: &mut Child) -> io::Result<Waited> {
+    if shell_pgid() == 0 {
+        return child.wait().map(Waited::Exited);
+    }
+    let mut status = 0;
+    loop {
+        let rc = unsafe { libc::waitpid(child.id() as libc::pid_t, &mut status, libc::WUNTRACED) };
+        if rc >= 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    if libc::WIFSTOPPED(status) {
+        return Ok(Waited::Stopped(libc::WSTOPSIG(status)));
+    }
+    Ok(Waited::Exited(ExitStatus::from_raw(status)))
+}
+
+#[cfg(not(unix))]
+fn wait_untraced(child: &mut Child) -> io::Result<Waited> {
+    child.wait().map(Waited::Exited)
+}
+
+// Waits for a foreground pipeline's stages in order, its group holding the
+// terminal meanwhile. A stopped stage sends the rest of the pipeline to the
+// job table as a stopped job; the status is then 128 + the signal.
+fn wait_foreground(handles: Vec<StageHandle>, command: String) -> i32 {
+    let leader = handles.iter().find_map(|h| match h {
+        StageHandle::External(c) => Some(c.id()),
+        _ => None,
+    });
+    if let (Some(pgid), true) = (leader, shell_pgid() != 0) {
+        set_terminal_group(tty_fd(), pgid as i32);
+    }
+
+    let mut status = 0;
+    let mut waited: Vec<StageHandle> = Vec::new();
+    let mut handles = handles.into_iter();
+    while let Some(h) = handles.next() {
+        status = match h {
+            StageHandle::Builtin(t) => t.join().unwrap_or(1),
+            StageHandle::External(mut c) => match wait_untraced(&mut c) {
+                Ok(Waited::Exited(s)) => exit_code(s),
+                Ok(Waited::Stopped(sig)) => {
+                    reclaim_terminal();
+                    waited.push(StageHandle::External(c));
+                    waited.extend(handles);
+                    let mut table = job_table();
+                    table.add(command, waited);
+                    let idx = table.jobs.len() - 1;
+                    table.jobs[idx].stopped = true;
+                    eprintln!("\n{}", table.format_line(idx, JobState::Stopped));
+                    return 128 + sig;
+                }
+                Err(e) => {
+                    eprintln!("wait: {e}");
+                    1
+                }
+            },
+            StageHandle::Finished(code) => code,
+        };
+        waited.push(StageHandle::Finished(status));
+    }
+    reclaim_terminal();
+    status
+}
+
+// ---------- session recording (script/scriptreplay compatible) ----------
+// `record [file]` / `--record file` run a fresh shell on a pseudoterminal and
+// sit between it and the real terminal, logging everything that passes
+// through. Output goes to `file` and timing to `file.timing` in util-linux's
+// "advanced" format (`I`/`O` <delay> <bytes>), so
+// `scriptreplay --log-io file --log-timing file.timing` can play it back, as
+// can `--replay file`.
+#[cfg(unix)]
+fn record_session(path: Option<&str>) -> i32 {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    let path = path.unwrap_or("typescript");
+    if let Ok(active) = env::var("MYSH_RECORDING") {
+        eprintln!("record: already recording to {active}");
+        return 1;
+    }
+
+    let open_log = |p: &str| OpenOptions::new().create(true).write(true).truncate(true).open(p);
+    let (mut log, mut timing) = match (open_log(path), open_log(&format!("{path}.timing"))) {
+        (Ok(l), Ok(t)) => (l, t),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("record: {path}: {e}");
+            return 1;
+        }
+    };
+
+    // Everything the child needs is prepared before fork
+    let Ok(exe) = env::current_exe() else {
+        eprintln!("record: cannot locate the shell executable");
+        return 1;
+    };
+    let Ok(exe_c) = CString::new(exe.as_os_str().as_bytes()) else {
+        return 1;
+    };
+    let argv = [exe_c.as_ptr(), std::ptr::null()];
+    env::set_var("MYSH_RECORDING", path);
+
+    let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+    if master < 0
+        || unsafe { libc::grantpt(master) } != 0
+        || unsafe { libc::unlockpt(master) } != 0
+    {
+        eprintln!("record: {}", io::Error::last_os_error());
+        env::remove_var("MYSH_RECORDING");
+        return 1;
+    }
+    let slave_name = unsafe {
+        let p = libc::ptsname(master);
+        if p.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(p).to_owned())
+        }
+    };
+    let Some(slave_name) = slave_name else {
+        eprintln!("record: {}", io::Error::last_os_error());
+        unsafe { libc::close(master) };
+        env::remove_var("MYSH_RECORDING");
+        return 1;
+    };
+
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    let have_ws = unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut ws) } == 0;
+    if have_ws {
+        unsafe { libc::ioctl(master, libc::TIOCSWINSZ, &ws) };
+    }
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        eprintln!("record: fork: {}", io::Error::last_os_error());
+        unsafe { libc::close(master) };
+        env::remove_var("MYSH_RECORDING");
+        return 1;
+    }
+    if pid == 0 {
+        // Child: new session with the pty slave as controlling terminal + stdio
+        unsafe {
+            libc::close(master);
+            libc::setsid();
+            let slave = libc::open(slave_name.as_ptr(), libc::O_RDWR);
+            if slave < 0 {
+                libc::_exit(127);
+            }
+            libc::ioctl(slave, libc::TIOCSCTTY, 0);
+            libc::dup2(slave, 0);
+            libc::dup2(slave, 1);
+            libc::dup2(slave, 2);
+            if slave > 2 {
+                libc::close(slave);
+            }
+            libc::execv(exe_c.as_ptr(), argv.as_ptr());
+            libc::_exit(127);
+        }
+    }
+    env::remove_var("MYSH_RECORDING");
+
+    let _ = writeln!(
+        log,
+        "Script started on {} [COMMAND=\"{}\" TERM=\"{}\"]",
+        local_timestamp(),
+        exe.display(),
+        env::var("TERM").unwrap_or_default()
+    );
+
+    // Real terminal to raw mode so keystrokes flow straight to the inner shell
+    let stdin_fd = io::stdin().as_raw_fd();
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    let is_tty = unsafe { libc::tcgetattr(stdin_fd, &mut saved) } == 0;
+    if is_tty {
+        let mut raw = saved;
+        unsafe {
+            libc::cfmakeraw(&mut raw);
+            libc::tcsetattr(stdin_fd, libc::TCSANOW, &raw);
+        }
+    }
+
+    let mut last = Instant::now();
+    let mut log_chunk = |kind: char, data: &[u8], log: &mut File, timing: &mut File| {
+        let now = Instant::now();
+        let delay = now.duration_since(last).as_secs_f64();
+        last = now;
+        let _ = log.write_all(data);
+        let _ = writeln!(timing, "{kind} {delay:.6} {}", data.len());
+    };
+
+    let mut stdout = io::stdout();
+    let mut buf = [0u8; 8192];
+    let mut stdin_open = true;
+    loop {
+        // Follow window resizes of the outer terminal
+        if have_ws {
+            let mut now_ws: libc::winsize = unsafe { std::mem::zeroed() };
+            if unsafe { libc::ioctl(stdin_fd, libc::TIOCGWINSZ, &mut now_ws) } == 0
+                && (now_ws.ws_row, now_ws.ws_col) != (ws.ws_row, ws.ws_col)
+            {
+                ws = now_ws;
+                unsafe { libc::ioctl(master, libc::TIOCSWINSZ, &ws) };
+            }
+        }
+
+        let mut fds = [
+            libc::pollfd {
+                fd: if stdin_open { stdin_fd } else { -1 },
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: master,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let rc = unsafe { libc::poll(fds.as_mut_ptr(), 2, 200) };
+        if rc < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            break;
+        }
+
+        if fds[1].revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0 {
+            let n = unsafe { libc::read(master, buf.as_mut_ptr().cast(), buf.len()) };
+            if n <= 0 {
+                break; // EIO once the inner shell is gone
+            }
+            let data = &buf[..n as usize];
+            let _ = stdout.write_all(data);
+            let _ = stdout.flush();
+            log_chunk('O', data, &mut log, &mut timing);
+        }
+
+        if fds[0].revents & (libc::POLLIN | libc::POLLHUP) != 0 {
+            let n = unsafe { libc::read(stdin_fd, buf.as_mut_ptr().cast(), buf.len()) };
+            if n <= 0 {
+                stdin_open = false;
+                continue;
+            }
+            let data = &buf[..n as usize];
+            unsafe { libc::write(master, data.as_ptr().cast(), data.len()) };
+            log_chunk('I', data, &mut log, &mut timing);
+        }
+    }
+
+    if is_tty {
+        unsafe { libc::tcsetattr(stdin_fd, libc::TCSANOW, &saved) };
+    }
+    unsafe { libc::close(master) };
+
+    let mut status = 0;
+    unsafe { libc::waitpid(pid, &mut status, 0) };
+    let code = if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else {
+        128 + libc::WTERMSIG(status)
+    };
+
+    let _ = writeln!(
+        log,
+        "\nScript done on {} [COMMAND_EXIT_CODE=\"{code}\"]",
+        local_timestamp()
+    );
+    eprintln!("record: session saved to {path}");
+    code
+}
+
+#[cfg(not(unix))]
+fn record_session(_path: Option<&str>) -> i32 {
+    eprintln!("record: not supported on this platform");
+    1
+}
+
+// Local time as "YYYY-mm-dd HH:MM:SS+zzzz", like script(1) headers
+#[cfg(unix)]
+fn local_timestamp() -> String {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    let mut buf = [0u8; 64];
+    let n = unsafe {
+        libc::strftime(
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+            c"%Y-%m-%d %H:%M:%S%z".as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..n]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn local_timestamp() -> String {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    now.map_or(0, |d| d.as_secs()).to_string()
+}
+
+// Play back a recording made by `record`, honoring the captured delays.
+// Only output is replayed; input chunks are skipped. Classic two-column
+// timing files (script -t) work too.
+fn replay_session(path: &str) -> i32 {
+    let (log, timing) = match (fs::read(path), fs::read_to_string(format!("{path}.timing"))) {
+        (Ok(l), Ok(t)) => (l, t),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("--replay: {path}: {e}");
+            return 1;
+        }
+    };
+
+    // Skip the "Script started on ..." header line
+    let mut offset = if log.starts_with(b"Script started") {
+        log.iter().position(|&b| b == b'\n').map(|i| i + 1).unwrap_or(log.len())
+    } else {
+        0
+    };
+
+    let mut out = io::stdout();
+    for line in timing.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (kind, delay, len) = match fields.as_slice() {
+            [kind, delay, len, ..] if kind.len() == 1 => (*kind, *delay, *len),
+            [delay, len] => ("O", *delay, *len),
+            _ => continue,
+        };
+        let (Ok(delay), Ok(len)) = (delay.parse::<f64>(), len.parse::<usize>()) else {
+            continue;
+        };
+        // H(eader)/S(ignal) entries carry no log bytes
+        if kind != "I" && kind != "O" {
+            continue;
+        }
+
+        let end = (offset + len).min(log.len());
+        if kind == "O" {
+            std::thread::sleep(Duration::from_secs_f64(delay.max(0.0)));
+            let _ = out.write_all(&log[offset..end]);
+            let _ = out.flush();
+        }
+        offset = end;
+    }
+    0
+}
+
+// ---------- audit log ----------
+// With $MYSH_AUDIT_FILE set, each command line the user enters is logged once
+// it finishes: "time<TAB>cwd<TAB>status<TAB>command". The file is created
+// owner-only and only appended to. Logging is best effort: the first failed
+// write warns, later ones don't.
+
+static AUDIT_WARNED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+fn audit_enabled() -> bool {
+    shell_setting("MYSH_AUDIT_FILE").is_some_and(|p| !p.is_empty())
+}
+
+fn audit_command(line: &str, cwd: &str, status: i32) {
+    let Some(path) = shell_setting("MYSH_AUDIT_FILE").filter(|p| !p.is_empty()) else { return };
+    let path = PathBuf::from(encode_os(&path));
+    let entry = format!("{}\t{cwd}\t{status}\t{line}\n", local_timestamp());
+    let written = open_audit_file(&path).and_then(|mut f| f.write_all(&encode_bytes(&entry)));
+    if let Err(e) = written {
+        if !AUDIT_WARNED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            eprintln!("mysh: audit: {}: {e}", path.display());
+        }
+    }
+}
+
+fn open_audit_file(path: &Path) -> io::Result<File> {
+    let mut opts = OpenOptions::new();
+    opts.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts.open(path)
+}
+
+// ---------- history expansion ----------
+// With `set -H`: `!!` is the previous command and `!$` its last word. The
+// last word is the one Alt+. (yank-last-arg) inserts, so both agree.
+
+// Last word of each command in history, oldest first, for Alt+.
+static LAST_WORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Final word of a command line as the tokenizer sees it (quotes removed),
+// quoted again so it reads back as one word
+fn last_word(line: &str) -> Option<String> {
+    let tokens = tokenize_literal(line);
+    let word = tokens.iter().rev().find_map(Token::as_word)?;
+    Some(quote_word(word))
+}
+
+fn remember_last_word(line: &str) {
+    if let Some(word) = last_word(line) {
+        LAST_WORDS.lock().unwrap_or_else(|e| e.into_inner()).push(word);
+    }
+}
+
+// Ends the text of a `!string` event
+fn is_event_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ':' | ';' | '&' | '|' | '(' | ')' | '<' | '>' | '"' | '\'')
+}
+
+// The line with history events replaced; None if it has none: `!!` the
+// previous command, `!$` its last word, `!n` / `!-n` by number, `!string`
+// the newest command starting with string, `!?string[?]` the newest one
+// containing it. `:s/old/new/` after an event edits it, `:p` only prints the
+// result. Nothing inside single quotes or after a backslash is touched.
+fn expand_history(line: &str, history: &[String]) -> Result<Option<HistoryExpansion>, String> {
+    let mut out = String::new();
+    let mut expanded = false;
+    let mut print_only = false;
+    let mut in_single = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !in_single => {
+                out.push(c);
+                if let Some(next) = chars.next() {
+                    out.push(next);
+                }
+                continue;
+            }
+            '\'' => in_single = !in_single,
+            // Not an event: `$!`, and `!` before a blank, `=`, `(` or the end
+            '!' if !in_single && !out.ends_with('$') => {
+                let Some(&next) = chars.peek().filter(|n| !n.is_whitespace() && !matches!(n, '=' | '(')) else {
+                    out.push(c);
+                    continue;
+                };
+                let (event, entry) = match next {
+                    '!' | '$' => {
+                        chars.next();
+                        (format!("!{next}"), history.last())
+                    }
+                    '?' => {
+                        chars.next();
+                        let text: String = chars.by_ref().take_while(|&c| c != '?').collect();
+                        (format!("!?{text}"), history.iter().rev().find(|h| h.contains(text.as_str())))
+                    }
+                    _ => {
+                        let mut text = String::new();
+                        while let Some(&c) = chars.peek().filter(|c| !is_event_delimiter(**c)) {
+                            text.push(c);
+                            chars.next();
+                        }
+                        if text.is_empty() {
+                            out.push(c);
+                            continue;
+                        }
+                        (format!("!{text}"), fc_event(history, &text).map(|i| &history[i]))
+                    }
+                };
+                let entry = entry.ok_or_else(|| format!("{event}: event not found"))?;
+                let mut text = match next {
+                    '$' => last_word(entry).unwrap_or_default(),
+                    _ => entry.clone(),
+                };
+                while let Some(modifier) = history_modifier(&mut chars) {
+                    match modifier {
+                        HistoryModifier::Print => print_only = true,
+                        HistoryModifier::Substitute { pat, rep, global, spec } => {
+                            if pat.is_empty() || !text.contains(pat.as_str()) {
+                                return Err(format!("{spec}: substitution failed"));
+                            }
+                            let rep = substitution_text(&rep, &pat);
+                            text = if global { text.replace(&pat, &rep) } else { text.replacen(&pat, &rep, 1) };
+                        }
+                    }
+                }
+                out.push_str(&text);
+                expanded = true;
+                continue;
+            }
+            _ => {}
+        }
+        out.push(c);
+    }
+    Ok(expanded.then_some(HistoryExpansion { line: out, print_only }))
+}
+
+struct HistoryExpansion {
+    line: String,
+    // `:p` asked for the line to be shown (and remembered), not run
+    print_only: bool,
+}
+
+enum HistoryModifier {
+    // `:s/pat/rep/`, `:gs/pat/rep/` or `:s/pat/rep/g`; `spec` as typed, for errors
+    Substitute { pat: String, rep: String, global: bool, spec: String },
+    Print,
+}
+
+// The `:s`, `:gs` or `:p` modifier next on the line, consumed; None leaves a
+// `:` that starts anything else where it is. Any character can stand in for
+// the `/` delimiter, and the last one may be left off at the end of the line.
+fn history_modifier(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> Option<HistoryModifier> {
+    let mut probe = chars.clone();
+    if probe.next()? != ':' {
+        return None;
+    }
+    let mut global = false;
+    match probe.next()? {
+        'p' => {
+            *chars = probe;
+            return Some(HistoryModifier::Print);
+        }
+        'g' if probe.next_if_eq(&'s').is_some() => global = true,
+        's' => {}
+        _ => return None,
+    }
+    let delim = probe.next().filter(|c| !c.is_whitespace())?;
+    let part = |probe: &mut std::iter::Peekable<std::str::Chars<'_>>| {
+        let mut text = String::new();
+        while let Some(c) = probe.next() {
+            match c {
+                '\\' if probe.peek() == Some(&delim) => text.push(delim),
+                c if c == delim => break,
+                c => text.push(c),
+            }
+        }
+        text
+    };
+    let pat = part(&mut probe);
+    let rep = part(&mut probe);
+    if probe.next_if_eq(&'g').is_some() {
+        global = true;
+    }
+    let spec = format!(":{}s{delim}{pat}{delim}{rep}{delim}", if global { "g" } else { "" });
+    *chars = probe;
+    Some(HistoryModifier::Substitute { pat, rep, global, spec })
+}
+
+// `&` in the replacement stands for the matched text, `\&` for itself
+fn substitution_text(rep: &str, pat: &str) -> String {
+    let mut out = String::new();
+    let mut chars = rep.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'&') => out.push(chars.next().unwrap_or('&')),
+            '&' => out.push_str(pat),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Alt+.: the previous command's last word at the cursor; pressed again right
+// away, it swaps in the word of the command before that
+#[derive(Default)]
+struct YankLastArg {
+    // Line and cursor it left, how far back it went, and the word it inserted
+    last: Mutex<Option<(String, usize, usize, String)>>,
+}
+
+// Text before and after the cursor to start the line editor on again
+static REEDIT_LINE: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+fn take_reedit_line() -> Option<(String, String)> {
+    REEDIT_LINE.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+impl rustyline::ConditionalEventHandler for YankLastArg {
+    fn handle(
+        &self,
+        _: &rustyline::Event,
+        _: rustyline::RepeatCount,
+        _: bool,
+        ctx: &rustyline::EventContext,
+    ) -> Option<Cmd> {
+        let words = LAST_WORDS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let (back, replaced) = match &*last {
+            Some((line, pos, back, word)) if line == ctx.line() && *pos == ctx.pos() => (back + 1, Some(word.clone())),
+            _ => (0, None),
+        };
+        let Some(word) = words.iter().rev().nth(back) else {
+            return Some(Cmd::Noop);
+        };
+
+        let (line, pos) = (ctx.line(), ctx.pos());
+        let start = pos - replaced.as_ref().map_or(0, String::len);
+        let new_line = format!("{}{word}{}", &line[..start], &line[pos..]);
+        *last = Some((new_line, start + word.len(), back, word.clone()));
+        if replaced.is_none() {
+            return Some(Cmd::Insert(1, word.clone()));
+        }
+        // No editor command both replaces text and leaves the cursor after it,
+        // so the line is accepted and the editor restarted on the new one
+        let before = format!("{}{word}", &line[..start]);
+        *REEDIT_LINE.lock().unwrap_or_else(|e| e.into_inner()) = Some((before, line[pos..].to_string()));
+        Some(Cmd::AcceptLine)
+    }
+}
+
+// Columns `text` takes on screen, escape sequences left out
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: up to the final byte
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: up to BEL or ST
+                Some(']') => {
+                    for c in chars.by_ref() {
+                        if c == '\x07' || c == '\\' {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            c if c.is_control() => {}
+            _ => width += 1,
+        }
+    }
+    width
+}
+
+// Takes an accepted line and its prompt back off the screen, leaving the
+// cursor where the prompt started
+fn erase_accepted_line(prompt: &str, line: &str) {
+    let cols = terminal_cols().unwrap_or(80).max(1);
+    let (earlier, last) = prompt.rsplit_once('\n').unwrap_or(("", prompt));
+    let prompt_rows = if prompt.contains('\n') { earlier.matches('\n').count() + 1 } else { 0 };
+    let rows = prompt_rows + (visible_width(last) + visible_width(line)).max(1).div_ceil(cols);
+    print!("\x1b[{rows}A\r\x1b[J");
+    let _ = io::stdout().flush();
+}
+
+// ---------- key bindings ----------
+// readline-style bindings: `"\C-g": "git status\n"` inserts a macro (run at
+// once when it ends in a newline), `"\C-xu": undo` runs an editing function.
+// Keys are chords (\C-x, \M-x, \ex) or plain characters, possibly several in
+// a row; the unquoted forms C-x / Control-x / M-x / Meta-x work too.
+
+// Key sequence and action as `bind -p` shows them, in binding order
+type KeyBindings = Vec<(String, String)>;
+
+// The line a newline-terminated macro asked to run, picked up after readline
+static MACRO_LINE: Mutex<Option<String>> = Mutex::new(None);
+
+fn take_macro_line() -> Option<String> {
+    MACRO_LINE.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+// Inserts the macro at the cursor and accepts the resulting line
+struct RunMacro(String);
+
+impl rustyline::ConditionalEventHandler for RunMacro {
+    fn handle(
+        &self,
+        _: &rustyline::Event,
+        _: rustyline::RepeatCount,
+        _: bool,
+        ctx: &rustyline::EventContext,
+    ) -> Option<Cmd> {
+        let (before, after) = ctx.line().split_at(ctx.pos());
+        *MACRO_LINE.lock().unwrap_or_else(|e| e.into_inner()) = Some(format!("{before}{}{after}", self.0));
+        Some(Cmd::AcceptLine)
+    }
+}
+
+// Line (before and after the cursor) Ctrl-X Ctrl-E wants edited in $EDITOR
+static EDIT_LINE: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+fn take_edit_line() -> Option<(String, String)> {
+    EDIT_LINE.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+// Accepts the line so the editor runs with the terminal back to normal
+struct EditAndExecute;
+
+impl rustyline::ConditionalEventHandler for EditAndExecute {
+    fn handle(
+        &self,
+        _: &rustyline::Event,
+        _: rustyline::RepeatCount,
+        _: bool,
+        ctx: &rustyline::EventContext,
+    ) -> Option<Cmd> {
+        let (before, after) = ctx.line().split_at(ctx.pos());
+        *EDIT_LINE.lock().unwrap_or_else(|e| e.into_inner()) = Some((before.to_string(), after.to_string()));
+        Some(Cmd::AcceptLine)
+    }
+}
+
+// ---------- fzf ----------
+// With `set -o fzf` and fzf on PATH, Ctrl-R picks a history entry for the
+// line and Ctrl-T inserts picked file names. Like Ctrl-X Ctrl-E the key
+// accepts the line, fzf runs with the terminal back to normal, and the line
+// editor starts again on the result (the old line if fzf was cancelled).
+// Otherwise the keys keep their usual meaning.
+
+static FZF_ENABLED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// Most paths Ctrl-T collects under the cwd
+const FZF_FILE_LIMIT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+enum FzfSource {
+    History,
+    Files,
+}
+
+// Line (before and after the cursor) waiting for an fzf pick
+static FZF_REQUEST: Mutex<Option<(FzfSource, String, String)>> = Mutex::new(None);
+
+fn take_fzf_request() -> Option<(FzfSource, String, String)> {
+    FZF_REQUEST.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+struct FzfWidget(FzfSource);
+
+impl rustyline::ConditionalEventHandler for FzfWidget {
+    fn handle(
+        &self,
+        _: &rustyline::Event,
+        _: rustyline::RepeatCount,
+        _: bool,
+        ctx: &rustyline::EventContext,
+    ) -> Option<Cmd> {
+        // None: the key's default action
+        if !FZF_ENABLED.load(std::sync::atomic::Ordering::Relaxed) || find_executable_in_path("fzf").is_none() {
+            return None;
+        }
+        let (before, after) = ctx.line().split_at(ctx.pos());
+        *FZF_REQUEST.lock().unwrap_or_else(|e| e.into_inner()) = Some((self.0, before.to_string(), after.to_string()));
+        Some(Cmd::AcceptLine)
+    }
+}
+
+// What fzf printed for the pick, None when cancelled (Esc, Ctrl-C) or failed
+fn run_fzf(items: &[String], query: &str, flags: &[&str]) -> Option<String> {
+    let mut child = Command::new("fzf")
+        .args(["--height=40%", "--reverse"])
+        .args(flags)
+        .arg(format!("--query={query}"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()?;
+    // Fed from a thread: fzf may quit before reading everything
+    let mut stdin = child.stdin.take()?;
+    let input = encode_bytes(&items.join("\n"));
+    let writer = std::thread::spawn(move || {
+        let _ = stdin.write_all(&input);
+    });
+    let out = child.wait_with_output().ok()?;
+    let _ = writer.join();
+    let pick = decode_bytes(&out.stdout).trim_end_matches('\n').to_string();
+    (out.status.success() && !pick.is_empty()).then_some(pick)
+}
+
+// Paths under the cwd, hidden ones and symlinked directories not descended
+fn fzf_file_list() -> Vec<String> {
+    let mut out = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let read = fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { &dir });
+        for entry in read.into_iter().flatten().flatten() {
+            let name = entry.file_name();
+            if name.as_encoded_bytes().starts_with(b".") {
+                continue;
+            }
+            let path = dir.join(&name);
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                dirs.push(path.clone());
+            }
+            out.push(decode_os(path.as_os_str()));
+            if out.len() >= FZF_FILE_LIMIT {
+                return out;
+            }
+        }
+    }
+    out.sort();
+    out
+}
+
+// The line to edit next: history's pick replaces it, Ctrl-T's picks go in
+// at the cursor
+fn fzf_pick(source: FzfSource, left: String, right: String, history: &[String]) -> (String, String) {
+    match source {
+        FzfSource::History => {
+            let mut seen = HashSet::new();
+            let newest_first: Vec<String> = history.iter().rev().filter(|h| seen.insert(h.as_str())).cloned().collect();
+            match run_fzf(&newest_first, &format!("{left}{right}"), &["--tiebreak=index"]) {
+                Some(pick) => (pick, String::new()),
+                None => (left, right),
+            }
+        }
+        FzfSource::Files => match run_fzf(&fzf_file_list(), "", &["--multi"]) {
+            Some(picks) => {
+                let words: Vec<String> = picks.lines().map(quote_word).collect();
+                let gap = if left.is_empty() || left.ends_with(' ') { "" } else { " " };
+                (format!("{left}{gap}{} ", words.join(" ")), right)
+            }
+            None => (left, right),
+        },
+    }
+}
+
+enum BindAction {
+    Macro(String),
+    Function(&'static str, rustyline::EventHandler),
+}
+
+// Editing functions by their readline names
+fn bind_function(name: &str) -> Option<(&'static str, rustyline::EventHandler)> {
+    use rustyline::{Anchor, At, Movement as M, Word};
+    let cmd = match name {
+        "yank-last-arg" => {
+            let name = BIND_FUNCTION_NAMES.iter().find(|n| **n == name)?;
+            return Some((name, rustyline::EventHandler::Conditional(Box::new(YankLastArg::default()))));
+        }
+        "edit-and-execute-command" => {
+            let name = BIND_FUNCTION_NAMES.iter().find(|n| **n == name)?;
+            return Some((name, rustyline::EventHandler::Conditional(Box::new(EditAndExecute))));
+        }
+        "fzf-history-widget" | "fzf-file-widget" => {
+            let source = if name == "fzf-file-widget" { FzfSource::Files } else { FzfSource::History };
+            let name = BIND_FUNCTION_NAMES.iter().find(|n| **n == name)?;
+            return Some((name, rustyline::EventHandler::Conditional(Box::new(FzfWidget(source)))));
+        }
+        "abort" => Cmd::Abort,
+        "accept-line" => Cmd::AcceptLine,
+        "backward-char" => Cmd::Move(M::BackwardChar(1)),
+        "backward-delete-char" => Cmd::Kill(M::BackwardChar(1)),
+        "backward-kill-word" => Cmd::Kill(M::BackwardWord(1, Word::Emacs)),
+        "backward-word" => Cmd::Move(M::BackwardWord(1, Word::Emacs)),
+        "beginning-of-history" => Cmd::BeginningOfHistory,
+        "beginning-of-line" => Cmd::Move(M::BeginningOfLine),
+        "capitalize-word" => Cmd::CapitalizeWord,
+        "clear-screen" => Cmd::ClearScreen,
+        "complete" => Cmd::Complete,
+        "delete-char" => Cmd::Kill(M::ForwardChar(1)),
+        "downcase-word" => Cmd::DowncaseWord,
+        "end-of-history" => Cmd::EndOfHistory,
+        "end-of-line" => Cmd::Move(M::EndOfLine),
+        "forward-char" => Cmd::Move(M::ForwardChar(1)),
+        "forward-search-history" => Cmd::ForwardSearchHistory,
+        "forward-word" => Cmd::Move(M::ForwardWord(1, At::AfterEnd, Word::Emacs)),
+        "history-search-backward" => Cmd::HistorySearchBackward,
+        "history-search-forward" => Cmd::HistorySearchForward,
+        "kill-line" => Cmd::Kill(M::EndOfLine),
+        "kill-whole-line" => Cmd::Kill(M::WholeLine),
+        "kill-word" => Cmd::Kill(M::ForwardWord(1, At::AfterEnd, Word::Emacs)),
+        "next-history" => Cmd::NextHistory,
+        "previous-history" => Cmd::PreviousHistory,
+        "quoted-insert" => Cmd::QuotedInsert,
+        "reverse-search-history" => Cmd::ReverseSearchHistory,
+        "transpose-chars" => Cmd::TransposeChars,
+        "transpose-words" => Cmd::TransposeWords(1),
+        "undo" => Cmd::Undo(1),
+        "unix-line-discard" => Cmd::Kill(M::BeginningOfLine),
+        "unix-word-rubout" => Cmd::Kill(M::BackwardWord(1, Word::Big)),
+        "upcase-word" => Cmd::UpcaseWord,
+        "yank" => Cmd::Yank(1, Anchor::Before),
+        "yank-pop" => Cmd::YankPop,
+        _ => return None,
+    };
+    // Hand back the name with a 'static lifetime for listing
+    let name = BIND_FUNCTION_NAMES.iter().find(|n| **n == name)?;
+    Some((name, cmd.into()))
+}
+
+const BIND_FUNCTION_NAMES: &[&str] = &[
+    "abort", "accept-line", "backward-char", "backward-delete-char", "backward-kill-word",
+    "backward-word", "beginning-of-history", "beginning-of-line", "capitalize-word", "clear-screen",
+    "complete", "delete-char", "downcase-word", "edit-and-execute-command", "end-of-history",
+    "end-of-line", "forward-char", "forward-search-history", "forward-word", "fzf-file-widget",
+    "fzf-history-widget",
+    "history-search-backward", "history-search-forward", "kill-line", "kill-whole-line",
+    "kill-word", "next-history", "previous-history", "quoted-insert", "reverse-search-history",
+    "transpose-chars", "transpose-words", "undo", "unix-line-discard", "unix-word-rubout",
+    "upcase-word", "yank", "yank-last-arg", "yank-pop",
+];
+
+// Splits `keys: action`, the keys quoted or not
+fn parse_binding(spec: &str) -> Result<(Vec<KeyEvent>, BindAction), String> {
+    let spec = spec.trim();
+    let (keys, rest) = if let Some(quoted) = spec.strip_prefix('"') {
+        let end = closing_quote(quoted, '"').ok_or_else(|| format!("{spec}: missing closing quote"))?;
+        let keys = parse_quoted_keys(&quoted[..end])?;
+        (keys, &quoted[end + 1..])
+    } else {
+        let colon = spec.find(':').ok_or_else(|| format!("{spec}: no colon after the key sequence"))?;
+        (vec![parse_key_name(spec[..colon].trim())?], &spec[colon..])
+    };
+    let action = rest
+        .trim_start()
+        .strip_prefix(':')
+        .ok_or_else(|| format!("{spec}: no colon after the key sequence"))?
+        .trim();
+    if keys.is_empty() {
+        return Err(format!("{spec}: empty key sequence"));
+    }
+
+    let action = match action.chars().next() {
+        Some(q @ ('"' | '\'')) => {
+            let body = &action[1..];
+            let end = closing_quote(body, q).ok_or_else(|| format!("{action}: missing closing quote"))?;
+            BindAction::Macro(unescape_macro(&body[..end]))
+        }
+        Some(_) => {
+            let (name, cmd) = bind_function(action).ok_or_else(|| format!("{action}: unknown function name"))?;
+            BindAction::Function(name, cmd)
+        }
+        None => return Err(format!("{spec}: missing function name or macro")),
+    };
+    Ok((keys, action))
+}
+
+// Index of the first unescaped `quote`
+fn closing_quote(text: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+// `\C-x`, `\M-x`, `\ex`, `\e`, `\t`, `\r`, escaped `\ " '` and plain characters
+fn parse_quoted_keys(text: &str) -> Result<Vec<KeyEvent>, String> {
+    let bad = || format!("\"{text}\": unknown key syntax");
+    let mut keys = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            keys.push(KeyEvent::new(c, Modifiers::NONE));
+            continue;
+        }
+        let mut mods = Modifiers::NONE;
+        let mut escape = chars.next().ok_or_else(bad)?;
+        // Any run of \C- and \M- prefixes, then the key itself
+        loop {
+            match escape {
+                'C' | 'M' if chars.peek() == Some(&'-') => {
+                    chars.next();
+                    mods |= if escape == 'C' { Modifiers::CTRL } else { Modifiers::ALT };
+                    match chars.next().ok_or_else(bad)? {
+                        '\\' => escape = chars.next().ok_or_else(bad)?,
+                        key => {
+                            keys.push(chord(key, mods)?);
+                            break;
+                        }
+                    }
+                }
+                'e' if mods.is_empty() => {
+                    // ESC followed by a key is how terminals send Alt+key
+                    match chars.next() {
+                        Some('\\') => {
+                            mods |= Modifiers::ALT;
+                            escape = chars.next().ok_or_else(bad)?;
+                        }
+                        Some(key) => {
+                            keys.push(chord(key, Modifiers::ALT)?);
+                            break;
+                        }
+                        None => {
+                            keys.push(KeyEvent(KeyCode::Esc, Modifiers::NONE));
+                            break;
+                        }
+                    }
+                }
+                key => {
+                    let key = match key {
+                        't' => '\t',
+                        'r' => '\r',
+                        '\\' | '"' | '\'' => key,
+                        'e' => '\x1b',
+                        _ => return Err(bad()),
+                    };
+                    keys.push(KeyEvent::new(key, mods));
+                    break;
+                }
+            }
+        }
+    }
+    Ok(keys)
+}
+
+fn chord(key: char, mods: Modifiers) -> Result<KeyEvent, String> {
+    if mods.contains(Modifiers::CTRL) && key == '?' {
+        return Ok(KeyEvent(KeyCode::Backspace, mods.difference(Modifiers::CTRL)));
+    }
+    if mods.contains(Modifiers::CTRL) && !key.is_ascii_graphic() {
+        return Err(format!("\\C-{key}: unknown key syntax"));
+    }
+    Ok(KeyEvent::normalize(KeyEvent::new(key, mods)))
+}
+
+// Unquoted `C-x` / `Control-x` / `M-x` / `Meta-x`, or a plain key or key name
+fn parse_key_name(text: &str) -> Result<KeyEvent, String> {
+    let mut mods = Modifiers::NONE;
+    let mut rest = text;
+    loop {
+        if let Some(r) = rest.strip_prefix("Control-").or_else(|| rest.strip_prefix("C-")) {
+            mods |= Modifiers::CTRL;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("Meta-").or_else(|| rest.strip_prefix("M-")) {
+            mods |= Modifiers::ALT;
+            rest = r;
+        } else {
+            break;
+        }
+    }
+    let key = match rest.to_ascii_lowercase().as_str() {
+        "tab" => '\t',
+        "return" | "ret" | "newline" => '\r',
+        "escape" | "esc" => '\x1b',
+        "space" | "spc" => ' ',
+        "rubout" | "del" => '\x7f',
+        _ => {
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(format!("{text}: unknown key syntax")),
+            }
+        }
+    };
+    chord(key, mods)
+}
+
+fn unescape_macro(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('a') => out.push('\x07'),
+            Some('e') => out.push('\x1b'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// `"\C-g"`-style text for a key sequence
+fn key_seq_text(keys: &[KeyEvent]) -> String {
+    let mut out = String::new();
+    for KeyEvent(code, mods) in keys {
+        if mods.contains(Modifiers::ALT) {
+            out.push_str("\\M-");
+        }
+        if mods.contains(Modifiers::CTRL) {
+            out.push_str("\\C-");
+        }
+        match code {
+            KeyCode::Char(c) if mods.contains(Modifiers::CTRL) => out.push(c.to_ascii_lowercase()),
+            KeyCode::Char(c @ ('"' | '\\')) => {
+                out.push('\\');
+                out.push(*c);
+            }
+            KeyCode::Char(c) => out.push(*c),
+            KeyCode::Esc => out.push_str("\\e"),
+            KeyCode::Tab => out.push_str("\\t"),
+            KeyCode::Enter => out.push_str("\\r"),
+            KeyCode::Backspace => out.push_str("\\C-?"),
+            other => out.push_str(&format!("<{other:?}>")),
+        }
+    }
+    out
+}
+
+fn macro_text(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\x1b' => out.push_str("\\e"),
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Parses and installs one binding, replacing any earlier one for the keys
+fn add_key_binding<H: Helper>(
+    rl: &mut Editor<H, DefaultHistory>,
+    bindings: &mut KeyBindings,
+    spec: &str,
+) -> Result<(), String> {
+    let (keys, action) = parse_binding(spec)?;
+    let seq = rustyline::Event::KeySeq(keys.clone());
+    let shown = match &action {
+        BindAction::Macro(text) => macro_text(text),
+        BindAction::Function(name, _) => name.to_string(),
+    };
+    match action {
+        BindAction::Macro(text) => match text.strip_suffix('\n') {
+            Some(line_part) => {
+                let handler = rustyline::EventHandler::Conditional(Box::new(RunMacro(line_part.to_string())));
+                rl.bind_sequence(seq, handler)
+            }
+            None => rl.bind_sequence(seq, Cmd::Insert(1, text)),
+        },
+        BindAction::Function(_, cmd) => rl.bind_sequence(seq, cmd),
+    };
+    let keys = key_seq_text(&keys);
+    bindings.retain(|(k, _)| *k != keys);
+    bindings.push((keys, shown));
+    Ok(())
+}
+
+fn list_key_bindings(bindings: &KeyBindings) -> Vec<u8> {
+    let mut out = String::new();
+    for (keys, action) in bindings {
+        out.push_str(&format!("\"{keys}\": {action}\n"));
+    }
+    encode_bytes(&out)
+}
+
+// `bind -p` lists the shell's bindings, `bind 'keys: action'` adds one,
+// `bind -r keys` removes one
+fn bind_builtin<H: Helper>(
+    rl: &mut Editor<H, DefaultHistory>,
+    bindings: &mut KeyBindings,
+    args: &[String],
+) -> (Vec<u8>, Vec<u8>, i32) {
+    match args {
+        [] => (list_key_bindings(bindings), vec![], 0),
+        [flag] if flag == "-p" => (list_key_bindings(bindings), vec![], 0),
+        [flag, keys] if flag == "-r" => {
+            // Quotes around the escape form are optional here
+            let parsed = match keys.strip_prefix('"').and_then(|k| k.strip_suffix('"')) {
+                Some(quoted) => parse_quoted_keys(quoted),
+                None if keys.contains('\\') => parse_quoted_keys(keys),
+                None => parse_key_name(keys).map(|k| vec![k]),
+            };
+            match parsed {
+                Ok(keys) => {
+                    rl.unbind_sequence(rustyline::Event::KeySeq(keys.clone()));
+                    let keys = key_seq_text(&keys);
+                    bindings.retain(|(k, _)| *k != keys);
+                    (vec![], vec![], 0)
+                }
+                Err(e) => (vec![], format!("bind: {e}\n").into_bytes(), 1),
+            }
+        }
+        [flag, ..] if flag.starts_with('-') => {
+            (vec![], format!("bind: {}: invalid option\n", display_str(flag)).into_bytes(), 2)
+        }
+        specs => {
+            let mut err = String::new();
+            for spec in specs {
+                if let Err(e) = add_key_binding(rl, bindings, spec) {
+                    err.push_str(&format!("bind: {e}\n"));
+                }
+            }
+            let code = if err.is_empty() { 0 } else { 1 };
+            (vec![], err.into_bytes(), code)
+        }
+    }
+}
+
+// ---------- inputrc ----------
+// ~/.inputrc (or $INPUTRC), read as GNU readline reads it so bash and this
+// shell can share one: key bindings, `set editing-mode vi|emacs` and `set
+// completion-ignore-case on|off`, optionally inside `$if mysh` / `$if
+// term=...` / `$if mode=...` ... `$else` ... `$endif`. Other variables, and
+// functions this shell doesn't have, are skipped quietly. The config file
+// has the last word.
+
+#[derive(Debug, Default)]
+struct Inputrc {
+    vi: Option<bool>,
+    ignore_case: Option<bool>,
+    // Checked with parse_binding already
+    bindings: Vec<String>,
+}
+
+// Deepest `$include` nesting before giving up (a file including itself)
+const INPUTRC_INCLUDE_MAX: usize = 10;
+
+fn inputrc_path() -> Option<PathBuf> {
+    match env::var_os("INPUTRC") {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => home_dir().map(|home| home.join(".inputrc")),
+    }
+}
+
+// Nothing set without the file; lines it can't use are warned about
+fn load_inputrc() -> Inputrc {
+    let mut inputrc = Inputrc::default();
+    if let Some(path) = inputrc_path() {
+        read_inputrc(&path, &mut inputrc, 0);
+    }
+    inputrc
+}
+
+fn read_inputrc(path: &Path, inputrc: &mut Inputrc, depth: usize) {
+    let Ok(bytes) = fs::read(path) else {
+        return;
+    };
+    let term = env::var("TERM").unwrap_or_default();
+    let shown = display_str(&decode_os(path.as_os_str()));
+    // Per open `$if`: whether the lines around it are read, and whether the
+    // current branch is
+    let mut conditions: Vec<(bool, bool)> = Vec::new();
+    for (n, line) in decode_bytes(&bytes).lines().enumerate() {
+        let line = line.trim();
+        let warn = |msg: &str| eprintln!("warning: {shown}:{}: {msg}", n + 1);
+        let active = conditions.last().is_none_or(|&(outer, branch)| outer && branch);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(directive) = line.strip_prefix('$') {
+            let (name, arg) = directive.split_once(char::is_whitespace).unwrap_or((directive, ""));
+            match name {
+                "if" => conditions.push((active, inputrc_test(arg.trim(), &term, inputrc.vi.unwrap_or(false)))),
+                "else" => match conditions.last_mut() {
+                    Some((_, branch)) => *branch = !*branch,
+                    None => warn("$else without $if"),
+                },
+                "endif" if conditions.pop().is_none() => warn("$endif without $if"),
+                "include" if active && depth < INPUTRC_INCLUDE_MAX => {
+                    let file = match arg.trim().strip_prefix("~/") {
+                        Some(rest) => home_dir().map(|home| home.join(rest)),
+                        None => Some(PathBuf::from(encode_os(arg.trim()))),
+                    };
+                    if let Some(file) = file {
+                        read_inputrc(&file, inputrc, depth + 1);
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        if !active {
+            continue;
+        }
+
+        if let Some(setting) = line.strip_prefix("set").filter(|s| s.starts_with(char::is_whitespace)) {
+            let mut words = setting.split_whitespace();
+            let (name, value) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+            match name.to_ascii_lowercase().as_str() {
+                "editing-mode" => match value {
+                    "vi" => inputrc.vi = Some(true),
+                    "emacs" => inputrc.vi = Some(false),
+                    _ => warn(&format!("{value}: unknown editing mode")),
+                },
+                "completion-ignore-case" => inputrc.ignore_case = Some(value.eq_ignore_ascii_case("on") || value == "1"),
+                _ => {}
+            }
+            continue;
+        }
+
+        match parse_binding(line) {
+            Ok(_) => inputrc.bindings.push(line.to_string()),
+            Err(e) if e.ends_with("unknown function name") => {}
+            Err(e) => warn(&e),
+        }
+    }
+}
+
+// `$if` tests: `term=name` (the whole of $TERM or the part before its first
+// `-`), `mode=vi|emacs`, or an application name
+fn inputrc_test(test: &str, term: &str, vi: bool) -> bool {
+    match test.split_once('=') {
+        Some(("term", name)) => {
+            let name = name.trim();
+            term == name || term.split('-').next() == Some(name)
+        }
+        Some(("mode", mode)) => mode.trim() == if vi { "vi" } else { "emacs" },
+        Some(_) => false,
+        None => ["mysh", "myshell"].iter().any(|app| test.eq_ignore_ascii_case(app)),
+    }
+}
+
+// ---------- command line ----------
+const USAGE: &str = "\
+usage: mysh [options] [script [args ...]]
+       mysh [options] -c command [name [args ...]]
+
+  -c command       run command and exit
+  -i               interactive even when stdin isn't a terminal
+  -l, --login      act as a login shell
+  -r, --restricted start restricted (see `help set`)
+  --norc           skip the config file
+  --config path    read this config file instead of the default
+  --record [file]  record a session (default: typescript)
+  --replay file    play back a recorded session
+  -V, --version    print the version and exit
+  -h, --help       print this help and exit
+";
+
+// What to do once started
+#[derive(Debug, Default)]
+enum StartupMode {
+    #[default]
+    Interactive,
+    // The command, then $0 and its arguments if given
+    Command(String, Vec<String>),
+    // The script path, then its arguments
+    Script(String, Vec<String>),
+    Record(Option<String>),
+    Replay(String),
+    Help,
+    Version,
+}
+
+#[derive(Debug, Default)]
+struct StartupOptions {
+    mode: StartupMode,
+    config_path: Option<PathBuf>,
+    norc: bool,
+    interactive: bool,
+    login: bool,
+    restricted: bool,
+}
+
+// Options come first; the first operand is the script, anything after it
+// belongs to the script. A leading `-` on argv[0] also means login shell.
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<StartupOptions, String> {
+    let mut args = args.into_iter();
+    let mut opts = StartupOptions {
+        login: args.next().is_some_and(|argv0| argv0.starts_with('-')),
+        ..StartupOptions::default()
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => opts.mode = StartupMode::Help,
+            "-V" | "--version" => opts.mode = StartupMode::Version,
+            "--norc" => opts.norc = true,
+            "--login" => opts.login = true,
+            "--restricted" => opts.restricted = true,
+            "--config" => {
+                let path = args.next().ok_or("--config: missing file operand")?;
+                opts.config_path = Some(PathBuf::from(path));
+            }
+            "--record" => opts.mode = StartupMode::Record(args.next()),
+            "--replay" => {
+                let path = args.next().ok_or("--replay: missing file operand")?;
+                opts.mode = StartupMode::Replay(path);
+            }
+            "--" => {
+                if let Some(script) = args.next() {
+                    opts.mode = StartupMode::Script(script, args.by_ref().collect());
+                }
+                break;
+            }
+            flags if flags.starts_with('-') && flags.len() > 1 && !flags.starts_with("--") => {
+                for flag in flags[1..].chars() {
+                    match flag {
+                        'i' => opts.interactive = true,
+                        'l' => opts.login = true,
+                        'r' => opts.restricted = true,
+                        'c' => {
+                            let text = args.next().ok_or("-c: option requires an argument")?;
+                            opts.mode = StartupMode::Command(text, Vec::new());
+                        }
+                        'h' => opts.mode = StartupMode::Help,
+                        'V' => opts.mode = StartupMode::Version,
+                        other => return Err(format!("-{other}: invalid option")),
+                    }
+                }
+                // With -c, what follows is $0 and arguments, not options
+                if let StartupMode::Command(_, words) = &mut opts.mode {
+                    words.extend(args.by_ref());
+                    break;
+                }
+            }
+            flag if flag.starts_with("--") => return Err(format!("{flag}: invalid option")),
+            script => {
+                opts.mode = StartupMode::Script(script.to_string(), args.by_ref().collect());
+                break;
+            }
+        }
+    }
+    Ok(opts)
+}
+
+// Where command lines come from: the line editor, or the lines of a -c
+// string or script file
+enum LineSource {
+    Editor,
+    Lines(std::vec::IntoIter<String>),
+}
+
+impl LineSource {
+    fn from_text(text: &str) -> LineSource {
+        LineSource::Lines(script_lines(text).into_iter())
+    }
+}
+
+// Deepest `source` nesting before giving up (a file sourcing itself)
+const SOURCE_DEPTH_MAX: usize = 100;
+
+// A file being run by `source`, with what it temporarily replaced
+struct SourceFrame {
+    lines: std::vec::IntoIter<String>,
+    saved_positional: Vec<String>,
+    saved_shlvl: Option<std::ffi::OsString>,
+}
+
+// `source file [args]`: the file's lines run next, with $0 / $1... set,
+// the file on top of BASH_SOURCE and SHLVL one deeper
+fn begin_source(ctx: &mut ShellContext, cmd: &str, args: &[String], depth: usize) -> Result<SourceFrame, (String, i32)> {
+    let Some((file, rest)) = args.split_first() else {
+        return Err((format!("{cmd}: filename argument required\n{cmd}: usage: {cmd} filename [arguments]"), 2));
+    };
+    if depth >= SOURCE_DEPTH_MAX {
+        return Err((format!("{cmd}: {}: maximum nesting level exceeded", display_str(file)), 1));
+    }
+    let text = match fs::read(encode_os(file)) {
+        Ok(bytes) => decode_bytes(&bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err((format!("{cmd}: {}: No such file or directory", display_str(file)), 1));
+        }
+        Err(e) => return Err((format!("{cmd}: {}: {e}", display_str(file)), 1)),
+    };
+
+    let mut positional = vec![file.clone()];
+    positional.extend(rest.iter().cloned());
+    let saved_positional = std::mem::replace(&mut ctx.positional, positional);
+    ctx.arrays.entry("BASH_SOURCE".to_string()).or_default().insert(0, file.clone());
+    let saved_shlvl = env::var_os("SHLVL");
+    let level = saved_shlvl.as_ref().and_then(|v| v.to_str()?.parse::<u32>().ok()).unwrap_or(0);
+    env::set_var("SHLVL", (level + 1).to_string());
+
+    Ok(SourceFrame {
+        lines: script_lines(&text).into_iter(),
+        saved_positional,
+        saved_shlvl,
+    })
+}
+
+// The sourced file is done (or stopped): put back what it replaced
+fn end_source(ctx: &mut ShellContext, frame: SourceFrame) {
+    ctx.positional = frame.saved_positional;
+    if let Some(stack) = ctx.arrays.get_mut("BASH_SOURCE") {
+        if !stack.is_empty() {
+            stack.remove(0);
+        }
+        if stack.is_empty() {
+            ctx.arrays.remove("BASH_SOURCE");
+        }
+    }
+    match frame.saved_shlvl {
+        Some(level) => env::set_var("SHLVL", level),
+        None => env::remove_var("SHLVL"),
+    }
+}
+
+fn main() {
+    ignore_sigpipe();
+    let startup = match parse_args(env::args_os().map(|a| decode_os(&a))) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprintln!("mysh: {msg}");
+            eprint!("{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    // $0 and the positional parameters
+    let positional = match &startup.mode {
+        StartupMode::Script(path, rest) => std::iter::once(path.clone()).chain(rest.iter().cloned()).collect(),
+        StartupMode::Command(_, words) if !words.is_empty() => words.clone(),
+        _ => vec!["mysh".to_string()],
+    };
+    let mut source = match startup.mode {
+        StartupMode::Help => {
+            print!("{USAGE}");
+            return;
+        }
+        StartupMode::Version => {
+            println!("mysh {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        // Recording/replay modes take over the whole process
+        StartupMode::Record(path) => std::process::exit(record_session(path.as_deref())),
+        StartupMode::Replay(path) => std::process::exit(replay_session(&path)),
+        StartupMode::Command(text, _) => LineSource::from_text(&text),
+        StartupMode::Script(path, _) => match fs::read(encode_os(&path)) {
+            Ok(bytes) => LineSource::from_text(&decode_bytes(&bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!("mysh: {}: No such file or directory", display_str(&path));
+                std::process::exit(127);
+            }
+            Err(e) => {
+                eprintln!("mysh: {}: {e}", display_str(&path));
+                std::process::exit(126);
+            }
+        },
+        StartupMode::Interactive => LineSource::Editor,
+    };
+    let interactive =
+        startup.interactive || (matches!(source, LineSource::Editor) && io::stdin().is_terminal());
+    if interactive && matches!(source, LineSource::Editor) && io::stdin().is_terminal() {
+        init_job_control();
+    }
+    JOB_NOTICES.store(interactive, std::sync::atomic::Ordering::Relaxed);
+    update_pwd();
+
+    let mut ctx = ShellContext {
+        // What started us set $_ to our path, as bash does
+        last_argument: env::var("_").ok().or_else(|| env::args().next()).unwrap_or_default(),
+        positional,
+        seconds_origin: Some(Instant::now()),
+        ..ShellContext::default()
+    };
+    // One level deeper than whatever started us; children inherit it. Nothing
+    // to undo on exit: the parent's own environment never changed.
+    let level = env::var("SHLVL").ok().and_then(|v| v.trim().parse::<u32>().ok());
+    env::set_var("SHLVL", level.map_or(1, |n| n + 1).to_string());
+    let shell_config = if startup.norc {
+        ShellConfig::default()
+    } else {
+        load_config(startup.config_path.as_deref())
+    };
+    let inputrc = if interactive && !startup.norc { load_inputrc() } else { Inputrc::default() };
+    if let Some(vi) = inputrc.vi {
+        ctx.options.vi = vi;
+    }
+    apply_config(&mut ctx, &shell_config);
+    // After the config, which may still set PATH
+    ctx.options.restricted |= startup.restricted;
+    ctx.options.publish();
+    report_cwd();
+    let _ = ACTIVE_THEME.set(load_theme(shell_config.highlight));
+    let _ = TERMINAL_TITLE.set(shell_config.title);
+
+    let completion_type = if shell_config.completion.menu_complete {
+        CompletionType::Circular
+    } else {
+        CompletionType::List
+    };
+    let config = Config::builder()
+        .completion_type(completion_type)
+        .completion_show_all_if_ambiguous(true)
+        .edit_mode(ctx.options.edit_mode())
+        .build();
+
+    let mut completions = CompletionRegistry::default();
+    completions.register_builtin_specs();
+    for spec in &shell_config.completions {
+        let args: Vec<String> = tokenize_literal(spec).iter().filter_map(Token::as_word).map(str::to_string).collect();
+        let (_, err, _) = complete_builtin(&mut completions, &args);
+        if !err.is_empty() {
+            eprint!("warning: config: {}", decode_bytes(&err));
+        }
+    }
+
+    let mut helper = ShellHelper::new(completions);
+    helper.case_insensitive = shell_config.completion.case_insensitive || inputrc.ignore_case == Some(true);
+    helper.menu_complete = shell_config.completion.menu_complete;
+    if let Some(n) = shell_config.completion.query_items {
+        helper.query_items = n;
+    }
+    helper.hide_system_users = shell_config.completion.hide_system_users;
+
+    let mut rl: Editor<ShellHelper, DefaultHistory> = Editor::with_config(config).unwrap();
+    rl.set_helper(Some(helper));
+    // Clear the screen, keep the line being typed
+    let _ = add_key_binding(&mut rl, &mut ctx.key_bindings, r#""\C-l": clear-screen"#);
+    let _ = add_key_binding(&mut rl, &mut ctx.key_bindings, r#""\M-.": yank-last-arg"#);
+    let _ = add_key_binding(&mut rl, &mut ctx.key_bindings, r#""\C-x\C-e": edit-and-execute-command"#);
+    // Reverse search and transpose-chars unless `set -o fzf`
+    let _ = add_key_binding(&mut rl, &mut ctx.key_bindings, r#""\C-r": fzf-history-widget"#);
+    let _ = add_key_binding(&mut rl, &mut ctx.key_bindings, r#""\C-t": fzf-file-widget"#);
+    for spec in &inputrc.bindings {
+        let _ = add_key_binding(&mut rl, &mut ctx.key_bindings, spec);
+    }
+    for spec in &shell_config.bindings {
+        if let Err(e) = add_key_binding(&mut rl, &mut ctx.key_bindings, spec) {
+            eprintln!("warning: config: bind: {e}");
+        }
+    }
+
+    if interactive {
+        ctx.load_history_file();
+    }
+    for line in &ctx.history {
+        let _ = rl.add_history_entry(line.as_str());
+        remember_last_word(line);
+    }
+
+    start_job_notifier();
+    sync_async_printer(&mut rl, ctx.options.notify);
+
+    // Files being run by `source`, innermost last
+    let mut sourcing: Vec<SourceFrame> = Vec::new();
+    let mut queued = LineQueue::new();
+    // Trap the line being run belongs to, if any
+    let mut current_trap: Option<&'static str> = None;
+    // Set once a line gets as far as running, for errexit and the ERR trap
+    let mut ran_command = false;
+    // errexit's status, held while the ERR trap runs first
+    let mut errexit_status: Option<i32> = None;
+    // Line waiting for the DEBUG trap and preexec hooks to finish before it runs
+    let mut after_debug: Option<String> = None;
+    // A line the user entered is running: postexec hooks are due after it
+    let mut postexec_due = false;
+    // Line the user entered and the directory it started in, for the audit log
+    let mut audit_line: Option<(String, String)> = None;
+    // The user's $? while preexec or postexec hooks run
+    let mut hook_status: Option<i32> = None;
+    // `!` words before the line that just ran
+    let mut negations = 0;
+    // The user's $? while PROMPT_COMMAND runs, given back before the prompt
+    let mut prompt_status: Option<i32> = None;
+
+    // Status the shell was leaving with while its EXIT trap runs
+    let mut exiting: Option<i32> = None;
+    // SIGHUP/SIGTERM that ended the shell, sent again once the trap has run
+    let mut fatal_signal: Option<i32> = None;
+    loop {
+        loop {
+            if let Some(sig) = take_caught_signal() {
+                fatal_signal = Some(sig);
+                ctx.last_exit = 128 + sig;
+                break;
+            }
+
+            // A negated pipeline's status is flipped, and its failures are
+            // expected: no ERR trap or errexit for them
+            let negated = negations > 0;
+            if ran_command {
+                for _ in 0..std::mem::take(&mut negations) {
+                    ctx.last_exit = i32::from(ctx.last_exit == 0);
+                }
+            }
+            // Failing hooks are only reported
+            let hook_line = matches!(current_trap, Some("PROMPT_COMMAND" | "preexec" | "postexec"));
+            let failed = std::mem::take(&mut ran_command) && !negated && ctx.last_exit != 0 && !hook_line;
+            if postexec_due && current_trap.is_none() && after_debug.is_none() {
+                postexec_due = false;
+                if let Some((line, cwd)) = audit_line.take() {
+                    audit_command(&line, &cwd, ctx.last_exit);
+                }
+                if !ctx.hooks.postexec.is_empty() {
+                    run_hook(&mut queued, &ctx.hooks.postexec, "postexec");
+                    hook_status.get_or_insert(ctx.last_exit);
+                }
+            }
+            if failed
+                && current_trap != Some("ERR")
+                && run_trap(&ctx, &mut queued, "ERR")
+                && ctx.options.errexit
+            {
+                errexit_status = Some(ctx.last_exit);
+            }
+            let err_trap_next = queued.front().is_some_and(|(_, trap)| *trap == Some("ERR"));
+            let errexit = match errexit_status {
+                _ if err_trap_next => false,
+                Some(status) => {
+                    ctx.last_exit = status;
+                    errexit_status = None;
+                    true
+                }
+                None => failed && ctx.options.errexit,
+            };
+            if errexit {
+                // A failing command ends every file being sourced, and the shell
+                // with the same status
+                while let Some(frame) = sourcing.pop() {
+                    end_source(&mut ctx, frame);
+                }
+                break;
+            }
+
+            if !queued.iter().any(|(_, hook)| matches!(hook, Some("preexec" | "postexec"))) {
+                if let Some(status) = hook_status.take() {
+                    ctx.last_exit = status;
+                }
+            }
+
+            // The EXIT trap's run of the loop reads nothing more
+            // Empty between commands, but kept for the traps a command sets off
+            if queued.front().is_none_or(|(_, trap)| trap.is_none()) && after_debug.is_none() {
+                ctx.variables.insert("BASH_COMMAND".to_string(), String::new());
+            }
+
+            if exiting.is_some() && queued.is_empty() && after_debug.is_none() {
+                break;
+            }
+
+            let debug_done = queued.front().is_none_or(|(_, trap)| !matches!(trap, Some("DEBUG" | "preexec")));
+            let resumed = if debug_done { after_debug.take() } else { None };
+            let from_source = resumed.is_none() && queued.is_empty() && !sourcing.is_empty();
+            current_trap = None;
+            let line = if let Some(l) = resumed.clone() {
+                l
+            } else if let Some((l, trap)) = queued.pop_front() {
+                current_trap = trap;
+                l
+            } else if let Some(frame) = sourcing.last_mut() {
+                match frame.lines.next() {
+                    Some(l) => l,
+                    None => {
+                        if let Some(frame) = sourcing.pop() {
+                            end_source(&mut ctx, frame);
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                match &mut source {
+                    LineSource::Lines(lines) => match lines.next() {
+                        Some(l) => l,
+                        None => break,
+                    },
+                    LineSource::Editor => {
+                        // Jobs that ended since the last prompt, reported once
+                        // (at once instead with `set -b`)
+                        if prompt_status.is_none() {
+                            notify_finished_jobs();
+                        }
+                        // $PROMPT_COMMAND runs first, then the prompt shows the
+                        // status the user's own command left
+                        match prompt_status.take() {
+                            Some(status) => ctx.last_exit = status,
+                            None if interactive => {
+                                let hook = ctx.var("PROMPT_COMMAND").unwrap_or_default();
+                                let lines: Vec<&str> = hook.lines().filter(|l| !l.trim().is_empty()).collect();
+                                if !lines.is_empty() {
+                                    queued.extend(lines.iter().map(|l| (l.to_string(), Some("PROMPT_COMMAND"))));
+                                    prompt_status = Some(ctx.last_exit);
+                                    continue;
+                                }
+                            }
+                            None => {}
+                        }
+                        run_precmd_hooks(&ctx.plugins, ctx.last_exit);
+
+                        // Plain "$ " unless PS1 asks for more
+                        let prompt = match ctx.var("PS1") {
+                            Some(ps1) => expand_ps1(&ps1, ctx.last_exit, active_theme(), &ctx.named_dirs),
+                            None => "$ ".to_string(),
+                        };
+                        if let Some(h) = rl.helper() {
+                            *h.prompt.borrow_mut() = prompt.clone();
+                        }
+                        publish_shell_variables(&ctx);
+
+                        show_prompt_title();
+                        let mut initial: Option<(String, String)> = None;
+                        let read = loop {
+                            let read = match &initial {
+                                Some((left, right)) => rl.readline_with_initial(&prompt, (left, right)),
+                                None => rl.readline(&prompt),
+                            };
+                            // Ctrl-X Ctrl-E: what the editor saved runs like fc's
+                            // lines; nothing saved goes back to the prompt as it was
+                            let read = match (read, take_edit_line()) {
+                                (Ok(accepted), Some((left, right))) => {
+                                    let edited = edit_in_editor(&format!("{left}{right}"), &fc_editor(&ctx, None));
+                                    match edited.filter(|text| !text.trim().is_empty()) {
+                                        Some(text) => {
+                                            for l in text.lines().filter(|l| !l.trim().is_empty()) {
+                                                println!("{l}");
+                                                queued.push_back((l.to_string(), None));
+                                            }
+                                            break Ok(String::new());
+                                        }
+                                        None => {
+                                            erase_accepted_line(&prompt, &accepted);
+                                            initial = Some((left, right));
+                                            continue;
+                                        }
+                                    }
+                                }
+                                (read, _) => read,
+                            };
+                            let read = match (read, take_fzf_request()) {
+                                (Ok(accepted), Some((source, left, right))) => {
+                                    erase_accepted_line(&prompt, &accepted);
+                                    initial = Some(fzf_pick(source, left, right, &ctx.history));
+                                    continue;
+                                }
+                                (read, _) => read,
+                            };
+                            // Alt+. cycling hands back a line to keep editing
+                            match (read, take_reedit_line()) {
+                                (Ok(accepted), Some(edit)) => {
+                                    erase_accepted_line(&prompt, &accepted);
+                                    initial = Some(edit);
+                                }
+                                (read, _) => break read,
+                            }
+                        };
+                        match read {
+                            // A macro ending in a newline runs its own version of the line
+                            Ok(l) => escape_raw_chars(&take_macro_line().unwrap_or(l)),
+                            // Ctrl-C drops the line and whatever was pending for it
+                            Err(ReadlineError::Interrupted) => {
+                                take_macro_line();
+                                if let Some(h) = rl.helper() {
+                                    h.reset_completion();
+                                }
+                                continue;
+                            }
+                            Err(ReadlineError::Eof) => break,
+                            Err(e) => {
+                                eprintln!("readline error: {e}");
+                                break;
+                            }
+                        }
+                    }
+                }
+            };
+
+            // The function whose commands just ran gives back the caller's $1...
+            if current_trap == Some("function-return") {
+                end_function(&mut ctx);
+                continue;
+            }
+
+            let mut line = line.trim_end().to_string();
+            if line.is_empty() {
+                continue;
+            }
+            // Not from a sourced file or a trap, nor already seen before the DEBUG trap
+            let typed = !from_source && current_trap.is_none() && resumed.is_none();
+
+            if ctx.options.histexpand && typed {
+                match expand_history(&line, &ctx.history) {
+                    // `:p`: shown and kept in history, but not run
+                    Ok(Some(HistoryExpansion { line: expanded, print_only: true })) => {
+                        println!("{expanded}");
+                        if ctx.add_history(&expanded) {
+                            let _ = rl.add_history_entry(expanded.as_str());
+                        }
+                        continue;
+                    }
+                    Ok(Some(HistoryExpansion { line: expanded, .. })) => {
+                        // Show what actually runs
+                        println!("{expanded}");
+                        line = expanded;
+                    }
+                    Ok(None) => {}
+                    Err(msg) => {
+                        print_error(&format!("mysh: {msg}"));
+                        continue;
+                    }
+                }
+            }
+
+            publish_shell_variables(&ctx);
+
+            // Add to our command history so "history" builtin prints what tester expects,
+            // and to rustyline history so up/down arrows work; only typed lines
+            if typed && ctx.add_history(&line) {
+                let _ = rl.add_history_entry(line.as_str());
+                remember_last_word(&line);
+            }
+
+            if typed {
+                run_preexec_hooks(&ctx.plugins, &line);
+                postexec_due = true;
+                // Lines kept out of history by a leading space stay out of the log
+                let hidden = ctx.history_settings.ignorespace && line.starts_with(' ');
+                if audit_enabled() && !hidden {
+                    let cwd = env::current_dir().map(|d| decode_os(d.as_os_str())).unwrap_or_default();
+                    audit_line = Some((line.clone(), cwd));
+                }
+            }
+
+            let preexec = typed && !ctx.hooks.preexec.is_empty();
+            if preexec {
+                env::set_var("MYSH_COMMAND", &line);
+                run_hook(&mut queued, &ctx.hooks.preexec, "preexec");
+                hook_status.get_or_insert(ctx.last_exit);
+            }
+            let debug = current_trap.is_none() && resumed.is_none() && ctx.traps.action("DEBUG").is_some();
+            if debug {
+                let command = bash_command_text(&line, &ctx);
+                ctx.variables.insert("BASH_COMMAND".to_string(), command);
+                run_trap(&ctx, &mut queued, "DEBUG");
+            }
+            if preexec || debug {
+                after_debug = Some(line);
+                continue;
+            }
+
+            // Trap commands leave it naming the command that set the trap off
+            if current_trap.is_none() {
+                let command = bash_command_text(&line, &ctx);
+                ctx.variables.insert("BASH_COMMAND".to_string(), command);
+            }
+            let (count, command) = leading_negations(&line);
+            if let Some(expr) = arith_command(command) {
+                negations = count;
+                ran_command = true;
+                ctx.last_exit = arith_status(&mut ctx, expr);
+                continue;
+            }
+            if let Some(inner) = cond_command(command) {
+                negations = count;
+                ran_command = true;
+                ctx.last_exit = cond_status(&mut ctx, inner);
+                continue;
+            }
+            if let Some(definition) = function_definition(command) {
+                ctx.last_exit = match definition {
+                    Ok((name, body)) => {
+                        ctx.functions.insert(name, body);
+                        0
+                    }
+                    Err(msg) => {
+                        print_error(&format!("mysh: {msg}"));
+                        2
+                    }
+                };
+                continue;
+            }
+            if let Some(result) = array_assignment(&mut ctx, command) {
+                ran_command = true;
+                ctx.last_exit = match result {
+                    Ok(()) => 0,
+                    Err(msg) => {
+                        print_error(&format!("mysh: {msg}"));
+                        1
+                    }
+                };
+                continue;
+            }
+            if let Some(select) = select_command(command) {
+                negations = count;
+                ran_command = true;
+                ctx.last_exit = match select {
+                    Ok(select) => {
+                        let again = current_trap == Some("select-again");
+                        select_round(&mut ctx, &select, command, again, &mut queued)
+                    }
+                    Err(msg) => {
+                        print_error(&format!("mysh: select: {msg}"));
+                        2
+                    }
+                };
+                continue;
+            }
+            if matches!(command, "break" | "continue") {
+                if current_trap == Some("select") {
+                    select_jump(command, &mut queued);
+                } else {
+                    print_error(&format!("mysh: {command}: only meaningful in a select loop"));
+                }
+                ctx.last_exit = 0;
+                continue;
+            }
+            if let Some(quote) = open_quote(&line) {
+                print_error(&format!("mysh: unexpected EOF while looking for matching `{quote}'"));
+                ctx.last_exit = 2;
+                continue;
+            }
+            if let Err(msg) = assign_parameter_defaults(&mut ctx, &line) {
+                print_error(&format!("mysh: {msg}"));
+                ctx.last_exit = 1;
+                continue;
+            }
+            let mut tokens = tokenize(&line, &ctx);
+            negations = count.min(tokens.len());
+            tokens.drain(..negations);
+            let mut tokens = expand_aliases(tokens, &ctx);
+            // A trailing `&` runs the whole line as a background job
+            let background = tokens.last().is_some_and(|t| t.is_op("&"));
+            if background {
+                tokens.pop();
+            }
+            // Words that all expanded to nothing run nothing, successfully
+            if tokens.is_empty() {
+                ctx.last_exit = 0;
+                continue;
+            }
+            let Some(chunks) = split_pipeline(&tokens) else {
+                ctx.last_exit = 2;
+                continue;
+            };
+
+            let mut stages: Vec<ParsedCommand> = Vec::new();
+            for chunk in chunks {
+                match parse_command(&chunk) {
+                    Ok(pc) => stages.push(pc),
+                    Err(status) => {
+                        ctx.last_exit = status;
+                        stages.clear();
+                        break;
+                    }
+                }
+            }
+            if stages.is_empty() {
+                continue;
+            }
+            ran_command = true;
+            // `$_` from now on; pipelines leave it as it was
+            if let [s] = &stages[..] {
+                let assignment = s.args.is_empty() && assignment_target(&s.cmd).is_some();
+                ctx.last_argument = if assignment { String::new() } else { s.args.last().unwrap_or(&s.cmd).clone() };
+            }
+
+            if ctx.options.restricted {
+                if let Some(msg) = restricted_violation(&stages) {
+                    print_error(&format!("mysh: {msg}"));
+                    ctx.last_exit = 1;
+                    continue;
+                }
+            }
+
+            if background {
+                let command = line.trim_end().trim_end_matches('&').trim_end().to_string();
+                ctx.last_exit = launch_background(&stages, &ctx, command);
+                continue;
+            }
+
+            // SINGLE COMMAND: parent effects + builtins + externals
+            if stages.len() == 1 {
+                let s = &stages[0];
+
+                if s.args.is_empty() {
+                    if let Some(result) = assign_word(&mut ctx, &s.cmd) {
+                        ctx.last_exit = match result {
+                            Ok(()) => 0,
+                            Err(msg) => {
+                                print_error(&format!("mysh: {msg}"));
+                                1
+                            }
+                        };
+                        continue;
+                    }
+                }
+
+                if ctx.functions.contains_key(&s.cmd) {
+                    ctx.last_exit = call_function(&mut ctx, &s.cmd, &s.args, &mut queued);
+                    continue;
+                }
+
+                if s.cmd == "return" {
+                    ctx.last_exit = return_builtin(&mut ctx, &s.args, &mut queued);
+                    continue;
+                }
+
+                if s.cmd == "exit" {
+                    if let Some(code) = s.args.first().and_then(|a| a.parse::<i32>().ok()) {
+                        ctx.last_exit = code;
+                    }
+                    // From the EXIT trap this status is the final one
+                    exiting = None;
+                    break;
+                }
+
+                if s.cmd == "load_plugin" {
+                    let Some(helper) = rl.helper_mut() else { continue };
+                    ctx.last_exit = load_plugin(&s.args, &mut ctx.plugins, &mut helper.completions);
+                    continue;
+                }
+
+                if s.cmd == "source" || s.cmd == "." {
+                    match begin_source(&mut ctx, &s.cmd, &s.args, sourcing.len()) {
+                        Ok(frame) => {
+                            sourcing.push(frame);
+                            // An empty file succeeds
+                            ctx.last_exit = 0;
+                            ran_command = false;
+                        }
+                        Err((msg, code)) => {
+                            eprintln!("{msg}");
+                            ctx.last_exit = code;
+                        }
+                    }
+                    continue;
+                }
+
+                if s.cmd == "fc" && fc_options(&s.args).is_ok_and(|o| !o.list) {
+                    // The newest entry is this fc command
+                    let history = &ctx.history[..ctx.history.len().saturating_sub(1)];
+                    match fc_builtin(&ctx, history, &s.args) {
+                        Ok(text) => {
+                            // Shown, then run like typed lines
+                            for l in text.lines().filter(|l| !l.trim().is_empty()) {
+                                println!("{l}");
+                                queued.push_back((l.to_string(), None));
+                            }
+                            ctx.last_exit = 0;
+                        }
+                        Err((msg, code)) => {
+                            if !msg.is_empty() {
+                                eprintln!("{msg}");
+                            }
+                            ctx.last_exit = code;
+                        }
+                    }
+                    continue;
+                }
+
+                if s.cmd == "record" {
+                    ctx.last_exit = record_session(s.args.first().map(String::as_str));
+                    continue;
+                }
+
+                if s.cmd == "exec" {
+                    ctx.last_exit = if s.args.is_empty() {
+                        exec_redirections(s, &ctx.options)
+                    } else {
+                        exec_command(s, &ctx.options)
+                    };
+                    continue;
+                }
+
+                if s.cmd == "cd" {
+                    // Same line without tilde expansion, for messages
+                    let typed = split_pipeline(&tokenize_literal(&line))
+                        .and_then(|chunks| parse_command(&chunks[0]).ok())
+                        .map(|pc| pc.args)
+                        .unwrap_or_default();
+                    ctx.last_exit = cd_builtin(&s.args, &typed, &ctx.var("CDPATH").unwrap_or_default(), ctx.options.cdspell, interactive);
+                    continue;
+                }
+
+                // Listing goes through builtin_bytes like other output
+                if s.cmd == "z" && s.args.first().is_some_and(|a| a != "-l") {
+                    ctx.last_exit = z_jump(&s.args, interactive);
+                    continue;
+                }
+
+                if s.cmd == "fg" {
+                    ctx.last_exit = fg_builtin(&s.args);
+                    continue;
+                }
+
+                if s.cmd == "read" {
+                    ctx.last_exit = read_into_vars(&mut ctx, s);
+                    continue;
+                }
+
+                if s.cmd == "mapfile" {
+                    ctx.last_exit = mapfile_into_array(&mut ctx, s);
+                    continue;
+                }
+
+                if is_builtin(&s.cmd) || ctx.plugins.builtins.contains_key(&s.cmd) {
+                    // Builtins that read stdin get the `<` file or the shell's own stdin;
+                    // for the rest a missing `<` file is still an error
+                    let stdin_bytes = match open_for_stdin(&s.stdin) {
+                        Err(e) => {
+                            eprintln!("{}: {e}", s.cmd);
+                            ctx.last_exit = 1;
+                            continue;
+                        }
+                        Ok(f) if builtin_reads_stdin(&s.cmd, &s.args) => {
+                            let mut buf = Vec::new();
+                            match f {
+                                Some(f) => buf = read_stdin_bytes(Some(f)),
+                                None => {
+                                    let _ = io::stdin().lock().read_to_end(&mut buf);
+                                }
+                            }
+                            Some(buf)
+                        }
+                        Ok(_) => None,
+                    };
+                    let (out, err, code) = if s.cmd == "set" {
+                        let result = set_builtin(&mut ctx.options, &s.args);
+                        ctx.options.publish();
+                        sync_async_printer(&mut rl, ctx.options.notify);
+                        // Same editor, so history and completion state carry over
+                        rl.set_edit_mode(ctx.options.edit_mode());
+                        result
+                    } else if s.cmd == "hash" {
+                        hash_builtin(&mut ctx.named_dirs, &s.args)
+                    } else if s.cmd == "history" {
+                        history_builtin(&mut ctx, &s.args)
+                    } else if s.cmd == "export" {
+                        export_builtin(&mut ctx, &s.args)
+                    } else if s.cmd == "readonly" {
+                        readonly_builtin(&mut ctx, &s.args)
+                    } else if s.cmd == "let" {
+                        let_builtin(&mut ctx, &s.args)
+                    } else if s.cmd == "alias" {
+                        alias_builtin(&mut ctx.aliases, &s.args)
+                    } else if s.cmd == "unalias" {
+                        unalias_builtin(&mut ctx.aliases, &s.args)
+                    } else if s.cmd == "unset" {
+                        unset_builtin(&mut ctx, &s.args)
+                    } else if s.cmd == "trap" {
+                        let result = trap_builtin(&mut ctx.traps, &s.args);
+                        watch_exit_signals(ctx.traps.action("EXIT").is_some());
+                        result
+                    } else if s.cmd == "bind" {
+                        bind_builtin(&mut rl, &mut ctx.key_bindings, &s.args)
+                    } else if s.cmd == "complete" {
+                        match rl.helper_mut() {
+                            Some(helper) => complete_builtin(&mut helper.completions, &s.args),
+                            None => (vec![], vec![], 0),
+                        }
+                    } else {
+                        builtin_bytes(&s.cmd, &s.args, &ctx, stdin_bytes)
+                    };
+                    let (out, err, code) = closed_builtin_output(s, (out, err, code));
+                    ctx.last_exit = write_routed_output(&out, &err, &s.stdout, &s.stderr, &s.cmd, &ctx.options)
+                        .unwrap_or(code);
+                    continue;
+                }
+
+                // autocd, only once the name turned out not to be a command
+                let autocd = ctx.options.autocd && interactive && !from_source && current_trap.is_none();
+                if autocd
+                    && s.args.is_empty()
+                    && !matches!(resolve_command(&s.cmd), CommandLookup::Found(_))
+                    && Path::new(&encode_os(&s.cmd)).is_dir()
+                {
+                    println!("cd -- {}", display_str(&s.cmd));
+                    ctx.last_exit = cd_builtin(std::slice::from_ref(&s.cmd), &[], &ctx.var("CDPATH").unwrap_or_default(), ctx.options.cdspell, interactive);
+                    continue;
+                }
+
+                ctx.last_exit = run_single_external(s, &ctx.options);
+                continue;
+            }
+
+            // PIPELINE (builtins + externals, N stages)
+            ctx.last_exit = execute_pipeline(&stages, &ctx);
+        }
+
+        // Leaving: the EXIT trap gets one more run of the loop, and the status
+        // from before it stands unless it calls exit
+        match exiting.take() {
+            Some(status) => {
+                ctx.last_exit = status;
+                break;
+            }
+            None if run_exit_traps(&mut ctx, &mut queued) => exiting = Some(ctx.last_exit),
+            None => break,
+        }
+    }
+
+    if interactive {
+        ctx.save_history_file();
+        if startup.login {
+            eprintln!("logout");
+        }
+    }
+    release_terminal();
+    if let Some(sig) = fatal_signal {
+        resend_signal(sig);
+    }
+    std::process::exit(ctx.last_exit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Names no environment sets, so only `vars` decide what's set
+    fn context(vars: &[(&str, &str)]) -> ShellContext {
+        let mut ctx = ShellContext::default();
+        for (name, value) in vars {
+            ctx.variables.insert(name.to_string(), value.to_string());
+        }
+        ctx
+    }
+
+    fn expand(expr: &str, ctx: &ShellContext) -> Result<String, String> {
+        parameter_values(expr, ctx).map(|values| values.join(" "))
+    }
+
+    // An empty directory of its own under the temp dir, for tests that need
+    // real files; emptied again by the next run
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mysh-test-{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn make_dirs(base: &Path, names: &[&str]) {
+        for name in names {
+            fs::create_dir_all(base.join(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn cdspell_fixes_a_transposition() {
+        let dir = scratch_dir("cdspell-swap");
+        make_dirs(&dir, &["projects/src"]);
+        let base = dir.display();
+        assert_eq!(correct_path(&format!("{base}/porjects/src")), Some(format!("{base}/projects/src")));
+        assert_eq!(correct_path(&format!("{base}/projects/rsc")), Some(format!("{base}/projects/src")));
+    }
+
+    #[test]
+    fn cdspell_fixes_a_missing_character() {
+        let dir = scratch_dir("cdspell-missing");
+        make_dirs(&dir, &["projects"]);
+        fs::write(dir.join("project"), "a file, not a candidate").unwrap();
+        let base = dir.display();
+        assert_eq!(correct_path(&format!("{base}/projcts")), Some(format!("{base}/projects")));
+    }
+
+    #[test]
+    fn cdspell_refuses_an_ambiguous_correction() {
+        let dir = scratch_dir("cdspell-ambiguous");
+        make_dirs(&dir, &["data1", "data2"]);
+        assert_eq!(correct_path(&format!("{}/data3", dir.display())), None);
+    }
+
+    #[test]
+    fn cdspell_needs_a_candidate_one_typo_away() {
+        let dir = scratch_dir("cdspell-none");
+        make_dirs(&dir, &["projects"]);
+        assert_eq!(correct_path(&format!("{}/prjcts", dir.display())), None);
+        assert_eq!(correct_path(&format!("{}/xyzzy", dir.display())), None);
+        // Nothing to correct
+        assert_eq!(correct_path(&format!("{}/projects", dir.display())), None);
+    }
+
+    // Matches of `pattern`, every `*`, `?` and `[` in it unquoted, with globstar on
+    fn globstar(pattern: &str) -> Vec<String> {
+        let meta: Vec<usize> = pattern.match_indices(['*', '?', '[']).map(|(i, _)| i).collect();
+        let options = ShellOptions { globstar: true, ..Default::default() };
+        glob_word(pattern, &meta, &options)
+    }
+
+    #[test]
+    fn globstar_walks_a_deep_tree() {
+        let dir = scratch_dir("glob-deep");
+        let mut path = dir.clone();
+        let mut expected = Vec::new();
+        for level in 0..40 {
+            path.push(format!("d{level}"));
+            fs::create_dir_all(&path).unwrap();
+            if level % 10 == 9 {
+                fs::write(path.join("f.txt"), "").unwrap();
+                expected.push(path.join("f.txt").display().to_string());
+            }
+        }
+        fs::write(path.join("other.md"), "").unwrap();
+        // Sorted as the shell sorts matches
+        expected.sort();
+        assert_eq!(globstar(&format!("{}/**/*.txt", dir.display())), expected);
+        assert_eq!(globstar(&format!("{}/**/other.md", dir.display())).len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn globstar_ends_despite_symlink_loops() {
+        let dir = scratch_dir("glob-loop");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/f.txt"), "").unwrap();
+        // Back up to the top, to its own directory, and to itself
+        std::os::unix::fs::symlink(&dir, dir.join("a/b/up")).unwrap();
+        std::os::unix::fs::symlink(".", dir.join("a/here")).unwrap();
+        std::os::unix::fs::symlink("self", dir.join("a/self")).unwrap();
+
+        let base = dir.display();
+        assert_eq!(globstar(&format!("{base}/**/*.txt")), [format!("{base}/a/b/f.txt")]);
+        // The links themselves still match, but aren't walked into
+        let all = globstar(&format!("{base}/**"));
+        assert!(all.contains(&format!("{base}/a/b/up")));
+        assert!(all.contains(&format!("{base}/a/self")));
+        assert!(!all.iter().any(|p| p.contains("/up/") || p.contains("/here/")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn globstar_skips_unreadable_directories() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("glob-eacces");
+        fs::create_dir_all(dir.join("open")).unwrap();
+        fs::create_dir_all(dir.join("locked/inner")).unwrap();
+        fs::write(dir.join("open/f.txt"), "").unwrap();
+        fs::write(dir.join("locked/inner/g.txt"), "").unwrap();
+        let locked = dir.join("locked");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+
+        // Root reads it anyway, so there's no EACCES to see
+        let denied = fs::read_dir(&locked).is_err();
+        let found = globstar(&format!("{}/**/*.txt", dir.display()));
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        if !denied {
+            return;
+        }
+        assert_eq!(found, [dir.join("open/f.txt").display().to_string()]);
+    }
+
+    fn common_prefix(candidates: &[&str]) -> String {
+        longest_common_prefix(&candidates.iter().map(|c| c.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn common_prefix_of_cyrillic_names() {
+        assert_eq!(common_prefix(&["привет", "привал"]), "прив");
+        // д and ж share their first byte
+        assert_eq!(common_prefix(&["д.txt", "ж.txt"]), "");
+        assert_eq!(common_prefix(&["файл", "файл-2"]), "файл");
+    }
+
+    #[test]
+    fn common_prefix_of_emoji_names() {
+        assert_eq!(common_prefix(&["😀a", "😀b"]), "😀");
+        // 😀 and 😁 differ only in their last byte
+        assert_eq!(common_prefix(&["😀", "😁"]), "");
+    }
+
+    #[test]
+    fn common_prefix_keeps_clusters_whole() {
+        // e + combining acute against plain e
+        assert_eq!(common_prefix(&["cafe\u{301}", "cafe"]), "caf");
+        assert_eq!(common_prefix(&["👍\u{1f3fb}x", "👍\u{1f3ff}x"]), "");
+        // Family emoji differing in the last member after a ZWJ
+        assert_eq!(common_prefix(&["a👨\u{200d}👩\u{200d}👧", "a👨\u{200d}👩\u{200d}👦"]), "a");
+        assert_eq!(common_prefix(&["x👍\u{1f3fb}1", "x👍\u{1f3fb}2"]), "x👍\u{1f3fb}");
+    }
+
+    #[test]
+    fn random_changes_between_reads() {
+        let mut ctx = context(&[]);
+        assert_eq!(assign_word(&mut ctx, "RANDOM=42"), Some(Ok(())));
+        let first = scalar_value(&ctx, "RANDOM");
+        let second = scalar_value(&ctx, "RANDOM");
+        assert_ne!(first, second);
+        assert!(first.parse::<u32>().is_ok_and(|n| n <= 32767));
+        // The same seed gives the same sequence again
+        assign_word(&mut ctx, "RANDOM=42");
+        assert_eq!(scalar_value(&ctx, "RANDOM"), first);
+    }
+
+    #[test]
+    fn assigning_seconds_resets_it() {
+        let mut ctx = context(&[]);
+        ctx.seconds_origin = Instant::now().checked_sub(Duration::from_secs(100));
+        assert_eq!(scalar_value(&ctx, "SECONDS"), "100");
+        assert_eq!(assign_word(&mut ctx, "SECONDS=0"), Some(Ok(())));
+        assert_eq!(scalar_value(&ctx, "SECONDS"), "0");
+        assign_word(&mut ctx, "SECONDS=50");
+        assert_eq!(scalar_value(&ctx, "SECONDS"), "50");
+        assert!(!ctx.variables.contains_key("SECONDS"));
+    }
+
+    #[test]
+    fn default_value_operator() {
+        let ctx = context(&[("MYSH_T_EMPTY", ""), ("MYSH_T_SET", "value")]);
+        assert_eq!(expand("MYSH_T_UNSET:-def", &ctx).unwrap(), "def");
+        assert_eq!(expand("MYSH_T_EMPTY:-def", &ctx).unwrap(), "def");
+        assert_eq!(expand("MYSH_T_SET:-def", &ctx).unwrap(), "value");
+        assert_eq!(expand("MYSH_T_UNSET-def", &ctx).unwrap(), "def");
+        assert_eq!(expand("MYSH_T_EMPTY-def", &ctx).unwrap(), "");
+    }
+
+    #[test]
+    fn assign_default_operator() {
+        let mut ctx = context(&[("MYSH_T_EMPTY", "")]);
+        assign_parameter_defaults(&mut ctx, "echo ${MYSH_T_UNSET:=zz} ${MYSH_T_EMPTY=yy}").unwrap();
+        assert_eq!(ctx.variables["MYSH_T_UNSET"], "zz");
+        assert_eq!(ctx.variables["MYSH_T_EMPTY"], "");
+        assert_eq!(expand("MYSH_T_UNSET:=other", &ctx).unwrap(), "zz");
+
+        assign_parameter_defaults(&mut ctx, "echo ${MYSH_T_EMPTY:=yy} '${MYSH_T_QUOTED:=no}'").unwrap();
+        assert_eq!(ctx.variables["MYSH_T_EMPTY"], "yy");
+        assert!(!ctx.variables.contains_key("MYSH_T_QUOTED"));
+
+        assert_eq!(expand("1:=x", &ctx).unwrap_err(), "$1: cannot assign in this way");
+    }
+
+    #[test]
+    fn error_if_unset_operator() {
+        let ctx = context(&[("MYSH_T_EMPTY", ""), ("MYSH_T_SET", "value")]);
+        assert_eq!(expand("MYSH_T_UNSET:?", &ctx).unwrap_err(), "MYSH_T_UNSET: parameter null or not set");
+        assert_eq!(expand("MYSH_T_EMPTY:?gone $MYSH_T_SET", &ctx).unwrap_err(), "MYSH_T_EMPTY: gone value");
+        assert_eq!(expand("MYSH_T_EMPTY?gone", &ctx).unwrap(), "");
+        assert_eq!(expand("MYSH_T_SET:?gone", &ctx).unwrap(), "value");
+    }
+
+    #[test]
+    fn alternate_value_operator() {
+        let ctx = context(&[("MYSH_T_EMPTY", ""), ("MYSH_T_SET", "value")]);
+        assert_eq!(expand("MYSH_T_SET:+alt", &ctx).unwrap(), "alt");
+        assert_eq!(expand("MYSH_T_EMPTY:+alt", &ctx).unwrap(), "");
+        assert_eq!(expand("MYSH_T_EMPTY+alt", &ctx).unwrap(), "alt");
+        assert_eq!(expand("MYSH_T_UNSET+alt", &ctx).unwrap(), "");
+        assert_eq!(expand("MYSH_T_SET:+'a b'", &ctx).unwrap(), "a b");
+    }
+
+    #[test]
+    fn unknown_forms_are_bad_substitutions() {
+        let ctx = context(&[("MYSH_T_SET", "value")]);
+        for expr in ["MYSH_T_SET:x", "MYSH_T_SET%%", "#MYSH_T_SET:-", "a.b", ""] {
+            let err = expand(expr, &ctx).unwrap_err();
+            assert!(err.ends_with("bad substitution"), "{expr}: {err}");
+        }
+    }
+
+    #[test]
+    fn raw_bytes_round_trip_next_to_private_use_chars() {
+        let mut bytes = b"a\x80-\xff-".to_vec();
+        for ch in ['\u{10FF80}', '\u{10FFFF}', '\u{10FF00}'] {
+            bytes.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        assert_eq!(encode_bytes(&decode_bytes(&bytes)), bytes);
+        let typed = "x\u{10FF80}y\u{10FFFF}";
+        assert_eq!(encode_bytes(&escape_raw_chars(typed)), typed.as_bytes());
+    }
+
+    #[test]
+    fn function_definitions_span_lines_until_their_brace() {
+        let lines = script_lines("f() {\n  echo 'a; b'\n  echo c; }\necho d");
+        assert_eq!(lines, ["f() {\n  echo 'a; b'\n  echo c; }", "echo d"]);
+        let Some(Ok((name, body))) = function_definition(&lines[0]) else { panic!("{lines:?}") };
+        assert_eq!((name.as_str(), body), ("f", vec!["echo 'a; b'".to_string(), "echo c".to_string()]));
+        assert!(matches!(function_definition("function g() { true; }"), Some(Ok((name, _))) if name == "g"));
+        assert!(function_unfinished("g() { true"));
+        assert!(matches!(function_definition("g() { }"), Some(Err(_))));
+        assert!(function_definition("echo f() {").is_none());
+    }
+
+    #[test]
+    fn wordlist_commands_get_the_words_being_completed() {
+        let words = ["greet".to_string(), "x".to_string()];
+        let raw = "\"$#:$2:$COMP_CWORD\" \"$COMP_WORDS\"";
+        let found = cached_wordlist(raw, &RefCell::new(None), "greet x y", 9, &words, "y");
+        assert_eq!(found, ["3:x:2", "greet", "x", "y"]);
+    }
+
+    #[test]
+    fn tabs_after_a_history_listing_step_through_it() {
+        let helper = ShellHelper::new(CompletionRegistry::default());
+        let listed = vec!["echo alpha2".to_string(), "echo alpha1".to_string()];
+        helper.state.borrow_mut().history_cycle = Some(("echo al".to_string(), listed, 0));
+        let step = |line: &str| helper.next_history_entry(line).map(|p| p.replacement);
+        assert_eq!(step("echo al").as_deref(), Some("echo alpha2"));
+        assert_eq!(step("echo alpha2").as_deref(), Some("echo alpha1"));
+        assert_eq!(step("echo alpha1").as_deref(), Some("echo al"));
+        // An edited line ends the cycle
+        assert_eq!(step("echo alx"), None);
+        assert_eq!(step("echo al"), None);
+    }
 
-        // PIPELINE (builtins + externals, N stages)
-        execute_pipeline(&stages, &history_vec);
+    #[test]
+    fn cargo_test_names_come_from_built_test_binaries() {
+        // The binary running this test is one of them
+        let names = cargo_test_names();
+        assert!(names.iter().any(|n| n == "tests::cargo_test_names_come_from_built_test_binaries"), "{names:?}");
     }
 }
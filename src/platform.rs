@@ -0,0 +1,98 @@
+// ---------- platform layer ----------
+// OS-specific process plumbing (pipes, process groups, signals) behind one
+// interface so the executor doesn't care whether it's on Unix or Windows.
+// Redirections themselves are plain `File`s handed to `Stdio`, which std
+// already makes portable.
+
+use std::fs::File;
+use std::io;
+use std::process::Command;
+
+pub use imp::*;
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::process::CommandExt;
+
+    // Anonymous pipe as (read end, write end); both ends close-on-exec so they
+    // only reach a child through an explicit Stdio
+    pub fn make_pipe() -> io::Result<(File, File)> {
+        let mut fds = [0i32; 2];
+        let rc = unsafe { libc::pipe(fds.as_mut_ptr()) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        let read_end = unsafe { File::from_raw_fd(fds[0]) };
+        let write_end = unsafe { File::from_raw_fd(fds[1]) };
+        Ok((read_end, write_end))
+    }
+
+    // Put the spawned child into process group `pgid` (0 = its own new group)
+    pub fn set_process_group(cmd: &mut Command, pgid: u32) {
+        cmd.process_group(pgid as i32);
+    }
+
+    // A negative pid signals that process group
+    pub fn send_signal(pid: i32, sig: i32) -> io::Result<()> {
+        if unsafe { libc::kill(pid as libc::pid_t, sig) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::*;
+    use std::os::windows::io::FromRawHandle;
+    use std::os::windows::process::CommandExt;
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::Pipes::CreatePipe;
+    use windows_sys::Win32::System::Threading::{
+        OpenProcess, TerminateProcess, CREATE_NEW_PROCESS_GROUP, PROCESS_TERMINATE,
+    };
+
+    // Anonymous pipe as (read end, write end). The handles are created
+    // non-inheritable (null SECURITY_ATTRIBUTES); std duplicates an inheritable
+    // copy only for the child it's handed to, so the parent's ends never leak.
+    pub fn make_pipe() -> io::Result<(File, File)> {
+        let mut read: HANDLE = std::ptr::null_mut();
+        let mut write: HANDLE = std::ptr::null_mut();
+        let ok = unsafe { CreatePipe(&mut read, &mut write, std::ptr::null(), 0) };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let read_end = unsafe { File::from_raw_handle(read as _) };
+        let write_end = unsafe { File::from_raw_handle(write as _) };
+        Ok((read_end, write_end))
+    }
+
+    // Windows has no numeric process groups; a new console process group is
+    // the closest equivalent (it stops Ctrl-C aimed at the shell reaching it)
+    pub fn set_process_group(cmd: &mut Command, _pgid: u32) {
+        cmd.creation_flags(CREATE_NEW_PROCESS_GROUP);
+    }
+
+    // No signal delivery on Windows: any signal terminates the process, with
+    // the exit code a Unix shell would report for it. A negative pid (a Unix
+    // process group) ends just its leader.
+    pub fn send_signal(pid: i32, sig: i32) -> io::Result<()> {
+        unsafe {
+            let handle = OpenProcess(PROCESS_TERMINATE, 0, pid.unsigned_abs());
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let ok = TerminateProcess(handle, 128 + sig as u32);
+            CloseHandle(handle);
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
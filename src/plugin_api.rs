@@ -0,0 +1,79 @@
+// ---------- plugin ABI ----------
+// The C-compatible interface between the shell and plugins loaded with
+// `load_plugin path/to/plugin.so`. A plugin exports
+//
+//     int shell_plugin_init(ShellPluginContext *ctx);
+//
+// and calls the `register_*` functions in `ctx` to add builtins, completion
+// hooks and preexec/precmd callbacks, returning 0 on success. Everything here
+// is `#[repr(C)]` / `extern "C"` and only grows at the end, so plugins built
+// against an older version keep working; `api_version` tells a plugin which
+// fields it may use.
+//
+// This file depends on nothing but std so Rust plugins can include it as is
+// (see examples/hello_plugin.rs).
+
+use std::ffi::{c_char, c_int, c_void};
+
+pub const SHELL_PLUGIN_API_VERSION: u32 = 1;
+
+// Name of the entry point, NUL-terminated for symbol lookup
+pub const SHELL_PLUGIN_INIT_SYMBOL: &[u8] = b"shell_plugin_init\0";
+
+// Streams a builtin can write to
+pub const PLUGIN_STDOUT: c_int = 1;
+pub const PLUGIN_STDERR: c_int = 2;
+
+// Where a builtin sends its output: `write(sink, stream, data, len)`.
+// The shell routes it like any builtin's output (redirections, pipes).
+#[repr(C)]
+pub struct PluginIo {
+    pub sink: *mut c_void,
+    pub write: extern "C" fn(sink: *mut c_void, stream: c_int, data: *const u8, len: usize),
+}
+
+// Builtin: argv[0] is the command name, argv[argc] is NULL. Returns the exit status.
+pub type PluginBuiltinFn =
+    extern "C" fn(io: *const PluginIo, argc: c_int, argv: *const *const c_char) -> c_int;
+
+// Collects completion candidates: `add(sink, candidate)` once per candidate
+#[repr(C)]
+pub struct PluginCandidates {
+    pub sink: *mut c_void,
+    pub add: extern "C" fn(sink: *mut c_void, candidate: *const c_char),
+}
+
+// Completion hook: the words typed so far (command name first) and the
+// unquoted word under the cursor
+pub type PluginCompletionFn = extern "C" fn(
+    out: *const PluginCandidates,
+    argc: c_int,
+    argv: *const *const c_char,
+    current: *const c_char,
+);
+
+// Runs after a line is accepted, before it executes
+pub type PluginPreexecFn = extern "C" fn(command_line: *const c_char);
+
+// Runs before each prompt with the last exit status
+pub type PluginPrecmdFn = extern "C" fn(last_status: c_int);
+
+// Handed to `shell_plugin_init`. The register functions return 0 on success
+// and -1 for bad arguments (null or non-UTF-8 names).
+#[repr(C)]
+pub struct ShellPluginContext {
+    pub api_version: u32,
+    // Opaque to plugins, only passed back through `ctx`
+    pub shell: *mut c_void,
+    pub register_builtin:
+        extern "C" fn(ctx: *mut ShellPluginContext, name: *const c_char, f: PluginBuiltinFn) -> c_int,
+    pub register_completion: extern "C" fn(
+        ctx: *mut ShellPluginContext,
+        command: *const c_char,
+        f: PluginCompletionFn,
+    ) -> c_int,
+    pub register_preexec: extern "C" fn(ctx: *mut ShellPluginContext, f: PluginPreexecFn) -> c_int,
+    pub register_precmd: extern "C" fn(ctx: *mut ShellPluginContext, f: PluginPrecmdFn) -> c_int,
+}
+
+pub type ShellPluginInitFn = unsafe extern "C" fn(ctx: *mut ShellPluginContext) -> c_int;
@@ -0,0 +1,183 @@
+// ---------- ssh hosts ----------
+// Host names for ssh/scp/sftp/rsync completion: the `Host` entries of an
+// ssh_config (following its `Include`s) and the names in a known_hosts file.
+// Wildcard patterns, negations and hashed names can't be completed and are
+// left out; a missing or unreadable file just has no hosts.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// How deep `Include`s nest before the rest are ignored (a file including itself)
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// `Host` names from the config file at `path`. Relative `Include` paths are
+// taken from `ssh_dir`, as ssh does for ~/.ssh/config.
+pub fn config_hosts(path: &Path, ssh_dir: &Path) -> Vec<String> {
+    let mut out = Vec::new();
+    read_config(path, ssh_dir, 0, &mut out);
+    out
+}
+
+fn read_config(path: &Path, ssh_dir: &Path, depth: usize, out: &mut Vec<String>) {
+    let Ok(text) = fs::read_to_string(path) else {
+        return;
+    };
+    for line in text.lines() {
+        let line = line.trim();
+        let Some((key, rest)) = line.split_once(|c: char| c.is_whitespace() || c == '=') else {
+            continue;
+        };
+        let args = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '=').split_whitespace();
+        if key.eq_ignore_ascii_case("host") {
+            out.extend(args.filter(|pat| !pat.contains(['*', '?', '!'])).map(str::to_string));
+        } else if key.eq_ignore_ascii_case("include") && depth < MAX_INCLUDE_DEPTH {
+            for pattern in args {
+                for file in include_files(pattern, ssh_dir) {
+                    read_config(&file, ssh_dir, depth + 1, out);
+                }
+            }
+        }
+    }
+}
+
+// Files an `Include` names: `~/` is the home directory (the parent of
+// `ssh_dir`), a relative path is under `ssh_dir`, and `*` / `?` in the file
+// name match like a shell glob. Sorted, as ssh reads them.
+fn include_files(pattern: &str, ssh_dir: &Path) -> Vec<PathBuf> {
+    let path = match pattern.strip_prefix("~/") {
+        Some(rest) => ssh_dir.parent().unwrap_or(ssh_dir).join(rest),
+        None => ssh_dir.join(pattern),
+    };
+    let Some(name) = path.file_name().and_then(|n| n.to_str()).filter(|n| n.contains(['*', '?'])) else {
+        return vec![path];
+    };
+    let dir = path.parent().unwrap_or(ssh_dir);
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|e| e.file_name().to_str().is_some_and(|n| !n.starts_with('.') && wildcard_match(name, n)))
+        .map(|e| e.path())
+        .collect();
+    files.sort();
+    files
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    // Where the last `*` was and the name position it has swallowed up to
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// First field of known_hosts lines: comma-separated names, `[host]:port`
+// reduced to `host`, hashed (`|1|...`) entries dropped
+pub fn known_hosts(text: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    for line in text.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(mut first) = fields.next() else {
+            continue;
+        };
+        if first.starts_with('#') {
+            continue;
+        }
+        // "@cert-authority host key..." / "@revoked host key..."
+        if first.starts_with('@') {
+            let Some(next) = fields.next() else {
+                continue;
+            };
+            first = next;
+        }
+        for name in first.split(',') {
+            if name.starts_with('|') {
+                continue;
+            }
+            let host = match name.strip_prefix('[') {
+                Some(rest) => rest.split_once(']').map(|(h, _)| h).unwrap_or(rest),
+                None => name,
+            };
+            if !host.is_empty() && !host.contains(['*', '?', '!']) {
+                out.push(host.to_string());
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ssh")
+    }
+
+    #[test]
+    fn config_skips_wildcards_and_negations() {
+        let dir = fixtures();
+        let hosts = config_hosts(&dir.join("config"), &dir);
+        assert!(hosts.contains(&"bastion".to_string()));
+        assert!(hosts.contains(&"db1".to_string()));
+        assert!(hosts.contains(&"db2".to_string()));
+        assert!(hosts.contains(&"equals-form".to_string()));
+        assert!(!hosts.iter().any(|h| h.contains(['*', '?', '!'])), "{hosts:?}");
+    }
+
+    #[test]
+    fn config_follows_includes() {
+        let dir = fixtures();
+        let hosts = config_hosts(&dir.join("config"), &dir);
+        // `Include config.d/*`, in name order, then `Include extra`
+        let included: Vec<&String> = hosts.iter().filter(|h| h.starts_with("inc-")).collect();
+        assert_eq!(included, ["inc-alpha", "inc-beta", "inc-extra"]);
+        // The file that includes itself is read a bounded number of times
+        assert!(hosts.iter().filter(|h| *h == "looped").count() <= MAX_INCLUDE_DEPTH);
+    }
+
+    #[test]
+    fn missing_files_have_no_hosts() {
+        let dir = fixtures();
+        assert!(config_hosts(&dir.join("no-such-config"), &dir).is_empty());
+        assert_eq!(include_files("no-such-dir/*", &dir), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn known_hosts_entries() {
+        let text = fs::read_to_string(fixtures().join("known_hosts")).unwrap();
+        assert_eq!(
+            known_hosts(&text),
+            ["github.com", "140.82.121.4", "git.example.org", "10.0.0.7", "ca.example.net"]
+        );
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("*.conf", "work.conf"));
+        assert!(!wildcard_match("*.conf", "work.conf.bak"));
+        assert!(wildcard_match("h?st*", "host-a"));
+        assert!(!wildcard_match("h?st", "hst"));
+    }
+}
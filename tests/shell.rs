@@ -0,0 +1,168 @@
+// Runs the built shell on `-c` scripts and checks what it prints
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// An empty directory of its own under the temp dir; emptied again by the next run
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mysh-it-{name}"));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// The built shell in `cwd`, out of reach of the user's own setup: no config
+// file, and HOME and the XDG dirs under `cwd`
+fn shell(cwd: &Path) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"));
+    cmd.arg("--norc")
+        .current_dir(cwd)
+        .env("HOME", cwd)
+        .env("XDG_CONFIG_HOME", cwd.join(".config"))
+        .env("XDG_DATA_HOME", cwd.join(".local/share"))
+        .env_remove("CDPATH")
+        .env_remove("SHELL_THEME");
+    cmd
+}
+
+// `script`'s lines run by the shell in `cwd`
+fn run(cwd: &Path, script: &str) -> Output {
+    shell(cwd).arg("-c").arg(script).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn cd_finds_a_directory_through_cdpath() {
+    let dir = scratch_dir("cdpath");
+    fs::create_dir_all(dir.join("projects/sub")).unwrap();
+    fs::create_dir_all(dir.join("work")).unwrap();
+    let projects = dir.join("projects").display().to_string();
+
+    let output = run(&dir.join("work"), &format!("CDPATH=/nonexistent:{projects}\ncd sub\npwd"));
+    // cd names where it went, then pwd agrees
+    let target = format!("{projects}/sub");
+    assert_eq!(stdout(&output), format!("{target}\n{target}\n"));
+    assert!(output.status.success());
+}
+
+#[test]
+fn cd_prefers_the_current_directory_over_cdpath() {
+    let dir = scratch_dir("cdpath-local");
+    fs::create_dir_all(dir.join("projects/sub")).unwrap();
+    fs::create_dir_all(dir.join("work/sub")).unwrap();
+    let projects = dir.join("projects").display().to_string();
+
+    let output = run(&dir.join("work"), &format!("CDPATH={projects}\ncd sub\npwd"));
+    // Nothing printed by cd itself
+    assert_eq!(stdout(&output), format!("{}\n", dir.join("work/sub").display()));
+}
+
+#[test]
+fn functions_get_their_arguments_and_return_a_status() {
+    let dir = scratch_dir("functions");
+    let script = "greet() {\n  echo \"hi $1 ($#)\"\n  return 3\n  echo never\n}\n\
+                  greet bob ''\necho \"status $? args $#\"\nfunction two { greet \"$2\"; }\ntwo a b";
+    let output = run(&dir, script);
+    assert_eq!(stdout(&output), "hi bob (2)\nstatus 3 args 0\nhi b (1)\n");
+}
+
+#[test]
+fn a_completion_function_fills_compreply() {
+    let dir = scratch_dir("compreply");
+    // What complete -F runs in a copy of the shell for `greet x <Tab>`
+    let script = "_greet() { COMPREPLY=(\"$1\" \"$2\" \"$3\" \"${COMP_WORDS[1]}\" \"$COMP_CWORD\"); }\n\
+                  COMP_WORDS=('greet' 'x' '')\nCOMP_CWORD=2\nCOMPREPLY=()\n_greet 'greet' '' 'x'\n\
+                  printf '%s\\n' \"${COMPREPLY[@]}\"";
+    let output = run(&dir, script);
+    assert_eq!(stdout(&output), "greet\n\nx\nx\n2\n");
+}
+
+#[test]
+fn syntax_errors_leave_status_2() {
+    let dir = scratch_dir("syntax-status");
+    let script = "echo 'open\necho $?\necho a |\necho $?\n| cat\necho $?\necho >\necho $?\necho > $nothing\necho $?";
+    let output = run(&dir, script);
+    assert_eq!(stdout(&output), "2\n2\n2\n2\n1\n");
+}
+
+#[test]
+fn standard_descriptors_can_be_closed() {
+    let dir = scratch_dir("close-std");
+    let output = run(&dir, "echo gone >&-\necho $?\nls missing 2>&-\nexec 2>&-\necho $?\nls missing\necho end");
+    assert_eq!(stdout(&output), "1\n0\nend\n");
+    // Only echo's complaint, from before stderr was closed
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "echo: write error: Bad file descriptor\n");
+}
+
+#[test]
+fn errors_to_a_pipe_stay_uncolored_under_a_theme() {
+    let dir = scratch_dir("theme-pipe");
+    let output = shell(&dir).args(["-c", "no-such-command-here"]).env("SHELL_THEME", "dracula").output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "no-such-command-here: command not found\n");
+}
+
+#[test]
+fn scripts_run_background_jobs_without_notices() {
+    let dir = scratch_dir("job-notices");
+    let output = run(&dir, "sleep 30 &\nkill %1\nwait\nsleep 0.1 &\nsleep 0.3\necho done");
+    assert_eq!(stdout(&output), "done\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn quoted_words_end_at_pipes_redirects_and_blanks() {
+    let dir = scratch_dir("quoted-ends");
+    let output = run(&dir, "echo \"ab\" | cat\necho 'cd' > f\ncat f\nprintf '[%s]' \"ef\"  x\necho");
+    assert_eq!(stdout(&output), "ab\ncd\n[ef][x]\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn command_redirections_to_3_and_4_stay_apart() {
+    let dir = scratch_dir("fd-swap");
+    let output = run(&dir, "sh -c 'echo to3 >&3; echo to4 >&4' 4>fa 3>fb\ncat fa fb");
+    assert_eq!(stdout(&output), "to4\nto3\n");
+}
+
+#[test]
+fn scripts_and_commands_get_their_arguments() {
+    let dir = scratch_dir("startup-args");
+    fs::write(dir.join("s.sh"), "echo $0 $1 $2 $#\n").unwrap();
+    let script = shell(&dir).args(["s.sh", "x", "y"]).output().unwrap();
+    assert_eq!(stdout(&script), "s.sh x y 2\n");
+    let command = shell(&dir).args(["-c", "echo $0 $1 $#", "zz", "qq"]).output().unwrap();
+    assert_eq!(stdout(&command), "zz qq 1\n");
+}
+
+#[test]
+fn restricted_shells_refuse_arithmetic_and_array_assignments_to_path() {
+    let dir = scratch_dir("restricted-arith");
+    let script = "let PATH=1\n(( PATH=1 ))\n(( x=PATH=1 ))\nPATH=(1)\nPATH+=(1)\ntest \"$PATH\" = 1\necho $?";
+    let output = shell(&dir).args(["-r", "-c", script]).output().unwrap();
+    assert_eq!(stdout(&output), "1\n");
+}
+
+#[test]
+fn fc_edits_in_a_private_file() {
+    let dir = scratch_dir("fc-edit");
+    let output = run(&dir, "echo hi\nfc -e 'stat -c %a'\nfc -e 'sed -i s/hi/ho/'");
+    assert_eq!(stdout(&output), "hi\n600\necho hi\nhi\necho ho\nho\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn pwd_and_type_print_non_utf8_names_byte_for_byte() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let dir = scratch_dir("latin1").join(OsStr::from_bytes(b"d\xe9"));
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy("/bin/true", dir.join(OsStr::from_bytes(b"t\xe9"))).unwrap();
+    let output = shell(&dir).arg("-c").arg(OsStr::from_bytes(b"pwd\ntype t\xe9")).env("PATH", &dir).output().unwrap();
+    let path = dir.as_os_str().as_bytes();
+    assert_eq!(output.stdout, [path, b"\n", b"t\xe9 is ", path, b"/t\xe9\n"].concat());
+}